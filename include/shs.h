@@ -0,0 +1,52 @@
+/*
+ * C api of the secret_handshake crate, available with the `capi` feature.
+ * See src/capi.rs for documentation.
+ */
+
+#ifndef SHS_H
+#define SHS_H
+
+#include <stdint.h>
+
+#define SHS_MSG1_BYTES 64
+#define SHS_MSG2_BYTES 64
+#define SHS_MSG3_BYTES 112
+#define SHS_MSG4_BYTES 80
+
+typedef struct ShsClient ShsClient;
+typedef struct ShsServer ShsServer;
+
+typedef struct {
+  uint8_t encryption_key[32];
+  uint8_t encryption_nonce[24];
+  uint8_t decryption_key[32];
+  uint8_t decryption_nonce[24];
+  uint8_t peer_longterm_pk[32];
+} ShsOutcome;
+
+ShsClient *shs_client_new(const uint8_t *network_identifier,
+                          const uint8_t *longterm_pk,
+                          const uint8_t *longterm_sk,
+                          const uint8_t *ephemeral_pk,
+                          const uint8_t *ephemeral_sk,
+                          const uint8_t *server_longterm_pk);
+void shs_client_create_msg1(ShsClient *client, uint8_t *msg);
+int shs_client_verify_msg2(ShsClient *client, const uint8_t *msg);
+void shs_client_create_msg3(ShsClient *client, uint8_t *msg);
+int shs_client_verify_msg4(ShsClient *client, const uint8_t *msg);
+void shs_client_outcome(ShsClient *client, ShsOutcome *outcome);
+void shs_client_free(ShsClient *client);
+
+ShsServer *shs_server_new(const uint8_t *network_identifier,
+                          const uint8_t *longterm_pk,
+                          const uint8_t *longterm_sk,
+                          const uint8_t *ephemeral_pk,
+                          const uint8_t *ephemeral_sk);
+int shs_server_verify_msg1(ShsServer *server, const uint8_t *msg);
+void shs_server_create_msg2(ShsServer *server, uint8_t *msg);
+int shs_server_verify_msg3(ShsServer *server, const uint8_t *msg);
+void shs_server_create_msg4(ShsServer *server, uint8_t *msg);
+void shs_server_outcome(ShsServer *server, ShsOutcome *outcome);
+void shs_server_free(ShsServer *server);
+
+#endif
@@ -0,0 +1,44 @@
+// Performs a single handshake over stdio using the reference implementation.
+//
+// Usage: node shs-stdio.js client <network_identifier> <longterm_sk> <server_longterm_pk>
+//        node shs-stdio.js server <network_identifier> <longterm_sk>
+//
+// All keys are hex encoded. Exits with 0 once stdin has been closed by the
+// peer after a successful handshake, and with 1 if the handshake failed.
+
+var shs = require('secret-handshake')
+var pull = require('pull-stream')
+var toPull = require('stream-to-pull-stream')
+
+var mode = process.argv[2]
+var networkIdentifier = Buffer.from(process.argv[3], 'hex')
+var secretKey = Buffer.from(process.argv[4], 'hex')
+var keys = { publicKey: secretKey.slice(32), secretKey: secretKey }
+
+var succeeded = false
+
+function done (err) {
+  if (err) {
+    console.error('handshake failed: ' + err.message)
+    process.exit(1)
+  }
+  succeeded = true
+}
+
+var stream
+if (mode === 'client') {
+  var serverPk = Buffer.from(process.argv[5], 'hex')
+  stream = shs.createClient(keys, networkIdentifier)(serverPk, done)
+} else if (mode === 'server') {
+  var authorize = function (clientPk, cb) { cb(null, true) }
+  stream = shs.createServer(keys, authorize, networkIdentifier)(done)
+} else {
+  console.error('unknown mode: ' + mode)
+  process.exit(2)
+}
+
+pull(toPull.source(process.stdin), stream, toPull.sink(process.stdout))
+
+process.stdin.on('end', function () {
+  process.exit(succeeded ? 0 : 1)
+})
@@ -0,0 +1,642 @@
+//! Accept handshakes on a stream of incoming connections.
+//!
+//! An [`Acceptor`](struct.Acceptor.html) takes a stream of incoming
+//! connections (e.g. the sockets accepted by a tcp listener, together with
+//! their remote address), performs the server side of a handshake on each of
+//! them concurrently, and yields the authenticated connections.
+//!
+//! Connections whose handshake fails are dropped. Attach a `Metrics` to
+//! monitor them, an `AuditSender` to receive an event for each of them, and a
+//! `RateLimiter` to refuse sources of repeated crypto failures. An `Offload`,
+//! set via `set_offload`, moves the crypto of the handshakes out of the event
+//! loop, e.g. onto a thread pool.
+//!
+//! An admission hook, set via `set_admission`, decides about each new
+//! connection by its remote address before anything is read from it, e.g.
+//! to implement ip allow- and denylists or to account for connections. It
+//! can accept or reject the connection, or delay the handshake until a timer
+//! of your runtime fires. An `AdmissionController`, set via
+//! `set_admission_controller` instead, can additionally end the handshake
+//! once the peer proved knowledge of the network identifier, and once it
+//! revealed its longterm public key, e.g. to enforce quotas per key.
+//!
+//! By default, the acceptor performs any number of handshakes concurrently.
+//! `set_max_handshakes` bounds their number, further connections wait in a
+//! queue of up to `set_max_queued` connections for a free handshake slot.
+//! While both are full, the acceptor stops accepting connections, so that
+//! they pile up in the backlog of the listener instead of in memory.
+//! Authenticated connections are only yielded when the acceptor is polled,
+//! so an application that is slow to consume them slows down accepting as
+//! well.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::usize;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_core::{Poll, Future, Stream, Never};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::{Context, Waker};
+use futures_core::future::FutureResult;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use audit::{AuditEvent, AuditResult, AuditSender};
+use crypto::Outcome;
+use errors::FilteringHandshakeError;
+use identity::{Identity, SharedIdentity};
+use metrics::Metrics;
+use offload::Offload;
+use pool::{HandshakePool, EphemeralKeyPool};
+use rate_limit::RateLimiter;
+use server::{Checkpoint, OwningServerHandshakerWithFilter, const_async_true};
+
+/// A stream of authenticated connections, obtained by performing the server
+/// side of a handshake on each connection of a stream of `incoming`
+/// connections.
+pub struct Acceptor<L, S, FilterFn, AsyncBool> {
+    incoming: Option<L>,
+    identity: SharedIdentity,
+    filter_fn: FilterFn,
+    pending: Vec<(OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>,
+                  SocketAddr,
+                  SystemTime)>,
+    max_handshakes: usize,
+    queued: VecDeque<(S, SocketAddr, SystemTime)>, // connections waiting for a handshake slot
+    max_queued: usize,
+    admission: Option<Arc<Mutex<Box<AdmissionController>>>>,
+    delayed: Vec<(Box<Future<Item = (), Error = ()> + Send>, S, SocketAddr, SystemTime)>,
+    rate_limiter: Option<RateLimiter>,
+    metrics: Option<Metrics>,
+    audit: Option<AuditSender>,
+    pool: Option<HandshakePool>,
+    ephemeral_keys: Option<EphemeralKeyPool>,
+    offload: Option<Offload>,
+    shutdown: ShutdownHandle,
+}
+
+impl<L, S> Acceptor<L,
+                    S,
+                    fn(&sign::PublicKey) -> FutureResult<bool, Never>,
+                    FutureResult<bool, Never>>
+    where L: Stream<Item = (S, SocketAddr), Error = io::Error>,
+          S: AsyncRead + AsyncWrite
+{
+    /// Creates a new `Acceptor`, accepting the `incoming` connections of any
+    /// client that uses the right network identifier and knows the server's
+    /// longterm public key.
+    pub fn new(incoming: L, identity: Identity) -> Self {
+        Acceptor::with_filter(incoming, const_async_true, identity)
+    }
+}
+
+impl<L, S, FilterFn, AsyncBool> Acceptor<L, S, FilterFn, AsyncBool>
+    where L: Stream<Item = (S, SocketAddr), Error = io::Error>,
+          S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool + Clone,
+          AsyncBool: Future<Item = bool>
+{
+    /// Creates a new `Acceptor`, accepting the `incoming` connections of
+    /// clients that use the right network identifier, know the server's
+    /// longterm public key, and pass the `filter_fn` (see
+    /// `ServerHandshakerWithFilter`).
+    pub fn with_filter(incoming: L, filter_fn: FilterFn, identity: Identity) -> Self {
+        Acceptor {
+            incoming: Some(incoming),
+            identity: SharedIdentity::new(identity),
+            filter_fn,
+            pending: Vec::new(),
+            max_handshakes: usize::MAX,
+            queued: VecDeque::new(),
+            max_queued: 0,
+            admission: None,
+            delayed: Vec::new(),
+            rate_limiter: None,
+            metrics: None,
+            audit: None,
+            pool: None,
+            ephemeral_keys: None,
+            offload: None,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Perform at most `max` handshakes concurrently. Connections accepted
+    /// while all handshake slots are taken are queued, see `set_max_queued`.
+    ///
+    /// Panics if `max` is zero.
+    pub fn set_max_handshakes(&mut self, max: usize) {
+        assert!(max > 0, "an acceptor needs at least one handshake slot");
+        self.max_handshakes = max;
+    }
+
+    /// Queue up to `max` connections while all handshake slots are taken.
+    /// Once the queue is full, no further connections are accepted until a
+    /// handshake terminates. Defaults to `0`.
+    ///
+    /// Has no effect unless the number of concurrent handshakes is bounded
+    /// via `set_max_handshakes`.
+    pub fn set_max_queued(&mut self, max: usize) {
+        self.max_queued = max;
+    }
+
+    /// Decide about each new connection via the `admission` hook, before
+    /// reading from it. The hook is only called for connections that pass
+    /// the rate limiter, if any.
+    ///
+    /// Delayed connections count towards the queue bound of
+    /// `set_max_queued`.
+    pub fn set_admission<F>(&mut self, admission: F)
+        where F: FnMut(&SocketAddr) -> Admission + Send + 'static
+    {
+        self.set_admission_controller(AdmissionFn(admission));
+    }
+
+    /// Decide about each connection at several points of its handshake via
+    /// the admission `controller`, replacing any admission hook set via
+    /// `set_admission`.
+    ///
+    /// Handshakes ended by the controller after the connection was opened
+    /// fail with a `Rejected` error.
+    pub fn set_admission_controller<C: AdmissionController + 'static>(&mut self, controller: C) {
+        self.admission = Some(Arc::new(Mutex::new(Box::new(controller))));
+    }
+
+    /// Refuse connections from addresses refused by the `rate_limiter`, and
+    /// clients whose longterm public key it refuses. Crypto failures are
+    /// recorded with it, and so are rejected client keys. Successful
+    /// handshakes clear the records of their address and key.
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Count all handshakes and their results in the given `metrics`.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Send an event for each accepted, failed or refused connection to the
+    /// given `audit` channel.
+    pub fn set_audit(&mut self, audit: AuditSender) {
+        self.audit = Some(audit);
+    }
+
+    /// Reuse the allocations of finished handshakes from the given `pool`.
+    pub fn set_pool(&mut self, pool: HandshakePool) {
+        self.pool = Some(pool);
+    }
+
+    /// Draw the ephemeral keypairs of new handshakes from the given pool of
+    /// pre-generated `ephemeral_keys`, instead of generating them on demand.
+    pub fn set_ephemeral_key_pool(&mut self, ephemeral_keys: EphemeralKeyPool) {
+        self.ephemeral_keys = Some(ephemeral_keys);
+    }
+
+    /// Run the crypto of all handshakes through the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.offload = Some(offload);
+    }
+
+    /// Returns a handle through which the identity of the acceptor can be
+    /// replaced at runtime. Handshakes in flight complete with the identity
+    /// they started with, new connections use the new identity.
+    pub fn identity_handle(&self) -> SharedIdentity {
+        self.identity.clone()
+    }
+
+    /// Returns a handle through which the acceptor can be shut down.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Returns whether all handshake slots are taken and the queue is full,
+    // so that no further connections may be accepted.
+    fn is_saturated(&self) -> bool {
+        self.pending.len() >= self.max_handshakes &&
+        self.queued.len() + self.delayed.len() >= self.max_queued
+    }
+
+    // Decides about a newly accepted connection.
+    fn accept(&mut self, stream: S, addr: SocketAddr) {
+        let started = SystemTime::now();
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            if !rate_limiter.is_addr_allowed(&addr.ip()) {
+                self.audit(None, addr, AuditResult::RateLimited, started);
+                return;
+            }
+        }
+
+        let admission = match self.admission {
+            Some(ref controller) => controller.lock().unwrap().connection_opened(&addr),
+            None => Admission::Accept,
+        };
+        match admission {
+            Admission::Accept => self.admit(stream, addr, started),
+            Admission::Reject => self.audit(None, addr, AuditResult::NotAdmitted, started),
+            Admission::Delay(delay) => self.delayed.push((delay, stream, addr, started)),
+        }
+    }
+
+    // Admits the delayed connections whose delay has elapsed.
+    fn poll_delayed(&mut self, cx: &mut Context) {
+        let mut i = 0;
+        while i < self.delayed.len() {
+            let elapsed = match self.delayed[i].0.poll(cx) {
+                Ok(Pending) => false,
+                Ok(Ready(())) | Err(()) => true,
+            };
+
+            if elapsed {
+                let (_, stream, addr, started) = self.delayed.swap_remove(i);
+                self.admit(stream, addr, started);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // Begins a handshake on an admitted connection, or queues it if all
+    // handshake slots are taken.
+    fn admit(&mut self, stream: S, addr: SocketAddr, started: SystemTime) {
+        if self.pending.len() < self.max_handshakes {
+            self.start(stream, addr, started);
+        } else {
+            self.queued.push_back((stream, addr, started));
+        }
+    }
+
+    // Begins handshakes on queued connections while there are free slots.
+    fn start_queued(&mut self) {
+        while self.pending.len() < self.max_handshakes {
+            match self.queued.pop_front() {
+                Some((stream, addr, started)) => self.start(stream, addr, started),
+                None => return,
+            }
+        }
+    }
+
+    // Begins a handshake on a connection accepted at time `started`.
+    fn start(&mut self, stream: S, addr: SocketAddr, started: SystemTime) {
+        let (ephemeral_pk, ephemeral_sk) = match self.ephemeral_keys {
+            Some(ref ephemeral_keys) => ephemeral_keys.take(),
+            None => box_::gen_keypair(),
+        };
+        let identity = self.identity.load();
+        let mut handshaker = match self.pool {
+            Some(ref pool) => {
+                let keys = pool.take(&identity, ephemeral_pk, ephemeral_sk);
+                OwningServerHandshakerWithFilter::from_keys(stream, self.filter_fn.clone(), keys)
+            }
+            None => {
+                OwningServerHandshakerWithFilter::new(stream,
+                                                      self.filter_fn.clone(),
+                                                      *identity.network_identifier(),
+                                                      identity.longterm_pk().clone(),
+                                                      identity.longterm_sk().clone(),
+                                                      ephemeral_pk,
+                                                      ephemeral_sk)
+            }
+        };
+        if let Some(ref metrics) = self.metrics {
+            handshaker.set_metrics(metrics.clone());
+        }
+        if let Some(ref offload) = self.offload {
+            handshaker.set_offload(offload.clone());
+        }
+        if self.admission.is_some() || self.rate_limiter.is_some() {
+            let admission = self.admission.clone();
+            let rate_limiter = self.rate_limiter.clone();
+            handshaker.set_checkpoint(Box::new(move |checkpoint: Checkpoint| {
+                if let Checkpoint::ClientKeyRevealed(pk) = checkpoint {
+                    if let Some(ref rate_limiter) = rate_limiter {
+                        if !rate_limiter.is_key_allowed(pk) {
+                            return false;
+                        }
+                    }
+                }
+
+                let mut controller = match admission {
+                    Some(ref controller) => controller.lock().unwrap(),
+                    None => return true,
+                };
+                match checkpoint {
+                    Checkpoint::Msg1Verified => controller.msg1_verified(&addr),
+                    Checkpoint::ClientKeyRevealed(pk) => controller.client_key_revealed(&addr, pk),
+                }
+            }));
+        }
+
+        self.pending.push((handshaker, addr, started));
+    }
+
+    // Returns the allocation of a finished handshaker to the pool, if any.
+    fn recycle(&self, handshaker: OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>) {
+        if let Some(ref pool) = self.pool {
+            pool.put(handshaker.into_keys());
+        }
+    }
+
+    // Handles the failure of the handshake with the peer at `addr`.
+    fn fail(&mut self,
+            err: &FilteringHandshakeError<AsyncBool::Error>,
+            peer: Option<sign::PublicKey>,
+            addr: SocketAddr,
+            started: SystemTime) {
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            match *err {
+                // The key claimed in a msg3 that failed to verify is not
+                // known, see the `rate_limit` module.
+                FilteringHandshakeError::CryptoError => {
+                    rate_limiter.record_failure(addr.ip(), None)
+                }
+                FilteringHandshakeError::Rejected if peer.is_some() => {
+                    rate_limiter.record_failure(addr.ip(), peer.as_ref())
+                }
+                _ => {}
+            }
+        }
+
+        self.audit(peer, addr, AuditResult::Failed(err.into()), started);
+    }
+
+    // Sends an audit event, if an audit channel is attached.
+    fn audit(&self,
+             peer: Option<sign::PublicKey>,
+             addr: SocketAddr,
+             result: AuditResult,
+             started: SystemTime) {
+        if let Some(ref audit) = self.audit {
+            audit.send(AuditEvent {
+                           peer,
+                           addr,
+                           result,
+                           started,
+                           finished: SystemTime::now(),
+                       });
+        }
+    }
+}
+
+/// Stream implementation to asynchronously accept handshakes.
+///
+/// Io errors of the incoming connections are propagated, all other errors
+/// only affect the connection on which they occured.
+impl<L, S, FilterFn, AsyncBool> Stream for Acceptor<L, S, FilterFn, AsyncBool>
+    where L: Stream<Item = (S, SocketAddr), Error = io::Error>,
+          S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool + Clone,
+          AsyncBool: Future<Item = bool>
+{
+    type Item = (Outcome, S, SocketAddr);
+    type Error = io::Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.shutdown.register(cx.waker()) {
+            ShutdownMode::Running => {}
+            ShutdownMode::Graceful => self.incoming = None,
+            ShutdownMode::Immediate => {
+                self.incoming = None;
+                self.pending.clear();
+                self.queued.clear();
+                self.delayed.clear();
+            }
+        }
+
+        loop {
+            // Whether accepting stopped because of backpressure, in which
+            // case the incoming stream did not register for a wakeup.
+            let mut saturated = false;
+            loop {
+                self.start_queued();
+                if self.is_saturated() {
+                    saturated = true;
+                    break;
+                }
+
+                let next = match self.incoming {
+                    Some(ref mut incoming) => incoming.poll_next(cx)?,
+                    None => break,
+                };
+
+                match next {
+                    Ready(Some((stream, addr))) => self.accept(stream, addr),
+                    Ready(None) => self.incoming = None,
+                    Pending => break,
+                }
+            }
+
+            self.poll_delayed(cx);
+
+            let mut freed = false;
+            let mut i = 0;
+            while i < self.pending.len() {
+                let result = self.pending[i].0.poll(cx);
+                match result {
+                    Ok(Pending) => i += 1,
+                    Ok(Ready((outcome, stream))) => {
+                        let (handshaker, addr, started) = self.pending.swap_remove(i);
+                        self.recycle(handshaker);
+                        if let Some(ref rate_limiter) = self.rate_limiter {
+                            rate_limiter.record_success(&addr.ip(), &outcome.peer_longterm_pk());
+                        }
+                        self.audit(Some(outcome.peer_longterm_pk()),
+                                   addr,
+                                   AuditResult::Accepted,
+                                   started);
+                        return Ok(Ready(Some((outcome, stream, addr))));
+                    }
+                    Err((err, _)) => {
+                        let (handshaker, addr, started) = self.pending.swap_remove(i);
+                        let peer = match err {
+                            FilteringHandshakeError::SelfConnection => {
+                                Some(handshaker.server_longterm_pk())
+                            }
+                            _ => handshaker.client_longterm_pk(),
+                        };
+                        self.recycle(handshaker);
+                        self.fail(&err, peer, addr, started);
+                        freed = true;
+                    }
+                }
+            }
+
+            // Failed handshakes freed slots for queued connections, or made
+            // room for accepting again.
+            if !(freed && (saturated || !self.queued.is_empty())) {
+                break;
+            }
+        }
+
+        if self.incoming.is_none() && self.pending.is_empty() && self.queued.is_empty() &&
+           self.delayed.is_empty() {
+            self.shutdown.complete();
+            return Ok(Ready(None));
+        }
+
+        Ok(Pending)
+    }
+}
+
+// Notify anyone waiting for the shutdown, even if the acceptor was dropped
+// before terminating.
+impl<L, S, FilterFn, AsyncBool> Drop for Acceptor<L, S, FilterFn, AsyncBool> {
+    fn drop(&mut self) {
+        self.shutdown.complete();
+    }
+}
+
+/// Decides about the connections of an `Acceptor` at several points of their
+/// handshakes, see `Acceptor::set_admission_controller`.
+///
+/// All methods admit the connection by default.
+pub trait AdmissionController: Send {
+    /// Called for each new connection that passed the rate limiter, before
+    /// anything is read from it.
+    fn connection_opened(&mut self, _addr: &SocketAddr) -> Admission {
+        Admission::Accept
+    }
+
+    /// Called once the peer at `addr` sent a valid msg1, i.e. proved that it
+    /// knows the network identifier. Returning `false` closes the connection
+    /// instead of replying with msg2.
+    fn msg1_verified(&mut self, _addr: &SocketAddr) -> bool {
+        true
+    }
+
+    /// Called once the peer at `addr` authenticated with its longterm public
+    /// key, before the filter function of the acceptor is invoked. Returning
+    /// `false` rejects the client.
+    fn client_key_revealed(&mut self,
+                           _addr: &SocketAddr,
+                           _client_longterm_pk: &sign::PublicKey)
+                           -> bool {
+        true
+    }
+}
+
+// Adapts an admission hook to an `AdmissionController`.
+struct AdmissionFn<F>(F);
+
+impl<F: FnMut(&SocketAddr) -> Admission + Send> AdmissionController for AdmissionFn<F> {
+    fn connection_opened(&mut self, addr: &SocketAddr) -> Admission {
+        (self.0)(addr)
+    }
+}
+
+/// The decision of an admission hook or controller about a new connection,
+/// see `Acceptor::set_admission`.
+pub enum Admission {
+    /// Perform a handshake on the connection.
+    Accept,
+    /// Drop the connection without reading from it.
+    Reject,
+    /// Perform a handshake on the connection once the future resolves, e.g.
+    /// a timer of your runtime. Should the future error, the handshake is
+    /// performed as well.
+    Delay(Box<Future<Item = (), Error = ()> + Send>),
+}
+
+/// A handle through which an `Acceptor` can be shut down.
+///
+/// To give in-flight handshakes a grace period, call `shutdown`, wait for
+/// either the `completion` future or a timer of your runtime, and then call
+/// `shutdown_now`.
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Mutex<ShutdownState>>);
+
+struct ShutdownState {
+    mode: ShutdownMode,
+    done: bool,
+    acceptor: Option<Waker>,
+    waiting: Vec<Waker>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ShutdownMode {
+    Running,
+    Graceful,
+    Immediate,
+}
+
+impl ShutdownHandle {
+    fn new() -> ShutdownHandle {
+        ShutdownHandle(Arc::new(Mutex::new(ShutdownState {
+                                               mode: ShutdownMode::Running,
+                                               done: false,
+                                               acceptor: None,
+                                               waiting: Vec::new(),
+                                           })))
+    }
+
+    /// Stop accepting new connections, but allow in-flight handshakes to
+    /// complete. The acceptor terminates once all of them are done.
+    pub fn shutdown(&self) {
+        self.set_mode(ShutdownMode::Graceful);
+    }
+
+    /// Stop accepting new connections and abort all in-flight handshakes.
+    /// The acceptor terminates when it is polled next.
+    pub fn shutdown_now(&self) {
+        self.set_mode(ShutdownMode::Immediate);
+    }
+
+    /// Returns whether the acceptor has terminated.
+    pub fn is_complete(&self) -> bool {
+        self.0.lock().unwrap().done
+    }
+
+    /// Returns a future that resolves once the acceptor has terminated.
+    pub fn completion(&self) -> Completion {
+        Completion(self.clone())
+    }
+
+    fn set_mode(&self, mode: ShutdownMode) {
+        let mut state = self.0.lock().unwrap();
+        if state.mode != ShutdownMode::Immediate {
+            state.mode = mode;
+        }
+        if let Some(waker) = state.acceptor.take() {
+            waker.wake();
+        }
+    }
+
+    // Registers the acceptor task to be woken upon shutdown, and returns the
+    // current mode.
+    fn register(&self, waker: &Waker) -> ShutdownMode {
+        let mut state = self.0.lock().unwrap();
+        state.acceptor = Some(waker.clone());
+        state.mode
+    }
+
+    fn complete(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.done = true;
+        for waker in state.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A future that resolves once an `Acceptor` has terminated, see
+/// `ShutdownHandle::completion`.
+pub struct Completion(ShutdownHandle);
+
+impl Future for Completion {
+    type Item = ();
+    type Error = Never;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let mut state = (self.0).0.lock().unwrap();
+        if state.done {
+            Ok(Ready(()))
+        } else {
+            // Polling again from the same task must not register it again.
+            if !state.waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+                state.waiting.push(cx.waker().clone());
+            }
+            Ok(Pending)
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! A stream of structured events about the handshakes of an `Acceptor`.
+//!
+//! Create a channel with `audit::channel`, attach the `AuditSender` to one or
+//! more acceptors via `Acceptor::set_audit`, and consume the `AuditEvents`
+//! stream from an operator task, e.g. to persist the events for abuse
+//! analysis or to feed ban tooling.
+//!
+//! The channel is bounded. Acceptors never wait for the consumer: if the
+//! channel is full, new events are dropped and counted, see
+//! `AuditEvents::dropped`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use sodiumoxide::crypto::sign;
+use futures_core::{Poll, Stream, Never};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::{Context, Waker};
+
+use errors::FilteringHandshakeError;
+
+/// A handshake attempt of a single peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// The longterm public key of the peer, if it has been authenticated.
+    pub peer: Option<sign::PublicKey>,
+    /// The remote address of the connection.
+    pub addr: SocketAddr,
+    /// How the attempt ended.
+    pub result: AuditResult,
+    /// When the connection was accepted.
+    pub started: SystemTime,
+    /// When the handshake completed, failed, or was refused.
+    pub finished: SystemTime,
+}
+
+/// How a handshake attempt ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditResult {
+    /// The handshake completed and the connection was yielded by the
+    /// acceptor.
+    Accepted,
+    /// The connection was refused by the rate limiter without performing a
+    /// handshake.
+    RateLimited,
+    /// The connection was refused by the admission hook without performing
+    /// a handshake.
+    NotAdmitted,
+    /// The handshake failed.
+    Failed(FailureReason),
+}
+
+/// Why a handshake failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// An io error of the given kind occured.
+    IoError(io::ErrorKind),
+    /// The filter function errored.
+    FilterError,
+    /// The client did not provide correct authentication.
+    CryptoError,
+    /// The client was rejected by the filter function or the admission
+    /// controller.
+    Rejected,
+    /// The client used the server's own longterm public key.
+    SelfConnection,
+    /// The handshake exceeded its resource budget.
+    BudgetExceeded,
+}
+
+impl<'a, E> From<&'a FilteringHandshakeError<E>> for FailureReason {
+    fn from(err: &'a FilteringHandshakeError<E>) -> FailureReason {
+        match *err {
+            FilteringHandshakeError::IoError(ref err) => FailureReason::IoError(err.kind()),
+            FilteringHandshakeError::FilterError(_) => FailureReason::FilterError,
+            FilteringHandshakeError::CryptoError => FailureReason::CryptoError,
+            FilteringHandshakeError::Rejected => FailureReason::Rejected,
+            FilteringHandshakeError::SelfConnection => FailureReason::SelfConnection,
+            FilteringHandshakeError::BudgetExceeded => FailureReason::BudgetExceeded,
+        }
+    }
+}
+
+struct Shared {
+    events: VecDeque<AuditEvent>,
+    capacity: usize,
+    dropped: u64,
+    senders: usize,
+    consumer: Option<Waker>,
+}
+
+/// Creates a channel that buffers up to `capacity` audit events.
+pub fn channel(capacity: usize) -> (AuditSender, AuditEvents) {
+    let shared = Arc::new(Mutex::new(Shared {
+                                         events: VecDeque::with_capacity(capacity),
+                                         capacity,
+                                         dropped: 0,
+                                         senders: 1,
+                                         consumer: None,
+                                     }));
+    (AuditSender(shared.clone()), AuditEvents(shared))
+}
+
+/// The sending half of an audit channel, to be attached to acceptors.
+pub struct AuditSender(Arc<Mutex<Shared>>);
+
+impl AuditSender {
+    // Queues the event, or drops it if the channel is full.
+    pub(crate) fn send(&self, event: AuditEvent) {
+        let mut shared = self.0.lock().unwrap();
+        if shared.events.len() >= shared.capacity {
+            shared.dropped += 1;
+            return;
+        }
+
+        shared.events.push_back(event);
+        if let Some(waker) = shared.consumer.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clone for AuditSender {
+    fn clone(&self) -> AuditSender {
+        self.0.lock().unwrap().senders += 1;
+        AuditSender(self.0.clone())
+    }
+}
+
+// The stream of events ends once all senders have been dropped.
+impl Drop for AuditSender {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.consumer.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The receiving half of an audit channel, a stream of audit events.
+///
+/// The stream ends once all `AuditSender`s have been dropped and all buffered
+/// events have been yielded.
+pub struct AuditEvents(Arc<Mutex<Shared>>);
+
+impl AuditEvents {
+    /// The number of events that were dropped because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.0.lock().unwrap().dropped
+    }
+}
+
+impl Stream for AuditEvents {
+    type Item = AuditEvent;
+    type Error = Never;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut shared = self.0.lock().unwrap();
+        match shared.events.pop_front() {
+            Some(event) => Ok(Ready(Some(event))),
+            None if shared.senders == 0 => Ok(Ready(None)),
+            None => {
+                shared.consumer = Some(cx.waker().clone());
+                Ok(Pending)
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Use the handshake for mutual authentication only.
+//!
+//! When the connection is already encrypted, e.g. by a VPN between the
+//! hosts, the session keys of a handshake are not needed. `auth_only` wraps
+//! any handshaker, and turns its outcome into an `Authenticated` as soon as
+//! the handshake completes, so the session keys are zeroed before they can
+//! be (re)used by accident.
+//!
+//! The handshake itself is unchanged, so the peer does not need to know
+//! whether the keys are used:
+//!
+//! ```rust,ignore
+//! let (authenticated, stream) = await!(auth_only(handshaker))?;
+//! serve(authenticated.peer_longterm_pk, stream);
+//! ```
+
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+
+use crypto::{Outcome, Authenticated};
+
+/// Wraps a handshaker so that it yields only the authenticated identities
+/// instead of an `Outcome`.
+pub fn auth_only<H, S>(handshaker: H) -> AuthOnly<H>
+    where H: Future<Item = (Outcome, S)>
+{
+    AuthOnly(handshaker)
+}
+
+/// Future that performs a handshake without exposing its session keys, see
+/// `auth_only`.
+///
+/// Fails with the error of the wrapped handshaker.
+pub struct AuthOnly<H>(H);
+
+impl<H> AuthOnly<H> {
+    /// Consumes the `AuthOnly`, returning the wrapped handshaker.
+    pub fn into_inner(self) -> H {
+        self.0
+    }
+}
+
+impl<H, S> Future for AuthOnly<H>
+    where H: Future<Item = (Outcome, S)>
+{
+    type Item = (Authenticated, S);
+    type Error = H::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll(cx)? {
+            Ready((outcome, stream)) => Ok(Ready((outcome.into_authenticated(), stream))),
+            Pending => Ok(Pending),
+        }
+    }
+}
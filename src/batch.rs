@@ -0,0 +1,116 @@
+//! Handshake with many servers at once.
+//!
+//! A [`BatchDial`](struct.BatchDial.html) takes a list of targets, each an
+//! address together with the longterm public key of the server reachable
+//! under it. It dials them via a `transport::Transport`, and performs the
+//! client side of a handshake over each connection, with fresh ephemeral
+//! keys. At most a configurable number of targets are dialed and
+//! handshaked concurrently, further targets are started as earlier ones
+//! complete.
+//!
+//! The `BatchDial` is a `Stream` that yields the result of each target in
+//! the order in which they complete, and ends once all targets are done.
+//! Failing to reach a target does not affect the other ones.
+//!
+//! ```rust,ignore
+//! let pubs = vec![(addr_a, pub_a), (addr_b, pub_b), (addr_c, pub_c)];
+//! let batch = BatchDial::new(pubs, &identity, 2, Tcp);
+//! batch.for_each(|(addr, server_longterm_pk, result)| {
+//!     // ...
+//! });
+//! ```
+
+use std::cmp::max;
+use std::vec;
+
+use sodiumoxide::crypto::sign;
+use futures_core::{Poll, Future, Stream, Never};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+
+use crypto::Outcome;
+use errors::ConnectError;
+use identity::Identity;
+use transport::{Transport, Connect, connect};
+
+/// Stream of the results of handshaking with several servers, see the
+/// module documentation.
+pub struct BatchDial<A, T: Transport<A>> {
+    transport: T,
+    identity: Identity,
+    targets: vec::IntoIter<(A, sign::PublicKey)>,
+    parallelism: usize,
+    running: Vec<(A, sign::PublicKey, Connect<T::Connecting, T::Stream>)>,
+}
+
+impl<A, T: Transport<A>> BatchDial<A, T> {
+    /// Creates a new `BatchDial`, which connects to the address of each
+    /// target via the `transport`, and then performs a handshake with the
+    /// server key of the target over the resulting connection.
+    ///
+    /// At most `parallelism` targets are in progress at any time. A value of
+    /// zero is treated as one.
+    pub fn new(targets: Vec<(A, sign::PublicKey)>,
+               identity: &Identity,
+               parallelism: usize,
+               transport: T)
+               -> BatchDial<A, T> {
+        BatchDial {
+            transport,
+            identity: identity.clone(),
+            targets: targets.into_iter(),
+            parallelism: max(parallelism, 1),
+            running: Vec::new(),
+        }
+    }
+
+    /// Returns the number of targets that have not been started yet.
+    pub fn remaining(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Returns the number of targets that are currently being dialed or
+    /// handshaked with.
+    pub fn in_progress(&self) -> usize {
+        self.running.len()
+    }
+}
+
+/// Yields the address and server key of each target together with the
+/// outcome of the handshake and the connection, or with the error that
+/// prevented it.
+impl<A, T: Transport<A>> Stream for BatchDial<A, T> {
+    type Item = (A, sign::PublicKey, Result<(Outcome, T::Stream), ConnectError>);
+    type Error = Never;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>, Never> {
+        while self.running.len() < self.parallelism {
+            match self.targets.next() {
+                Some((addr, server_longterm_pk)) => {
+                    let connecting = connect(&mut self.transport,
+                                             &addr,
+                                             &self.identity,
+                                             &server_longterm_pk);
+                    self.running.push((addr, server_longterm_pk, connecting));
+                }
+                None => break,
+            }
+        }
+
+        if self.running.is_empty() {
+            return Ok(Ready(None));
+        }
+
+        for i in 0..self.running.len() {
+            let result = match self.running[i].2.poll(cx) {
+                Ok(Ready(connection)) => Ok(connection),
+                Ok(Pending) => continue,
+                Err(err) => Err(err),
+            };
+            let (addr, server_longterm_pk, _) = self.running.swap_remove(i);
+            return Ok(Ready(Some((addr, server_longterm_pk, result))));
+        }
+
+        Ok(Pending)
+    }
+}
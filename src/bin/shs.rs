@@ -0,0 +1,270 @@
+// A command line tool for debugging secret-handshake peers.
+//
+// Requires the `cli` feature: `cargo run --features cli --bin shs -- help`
+
+extern crate base64;
+extern crate secret_handshake;
+extern crate serde_json;
+extern crate sodiumoxide;
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+
+use sodiumoxide::crypto::{box_, sign};
+
+use secret_handshake::{Outcome, NETWORK_IDENTIFIER_BYTES};
+use secret_handshake::encoding::KeyEncoding;
+use secret_handshake::sans_io::HandshakeState;
+
+const USAGE: &str = "\
+Usage:
+  shs keygen [<new-secret-file>]
+  shs id <secret-file>
+  shs connect <host:port> <server-id> [options]
+  shs listen <host:port> [options]
+
+Options:
+  --secret <file>     the secret file to authenticate with, a new keypair is
+                      generated if omitted
+  --network <key>     the base64 encoded network identifier, defaults to the
+                      main ssb network
+  --show-keys         also print the session keys and nonces of the outcome
+
+Keys are read and written in the format of ssb secret files, ids are printed
+as @<base64>.ed25519.";
+
+// The network identifier of the main ssb network.
+const MAIN_NETWORK: &str = "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=";
+
+fn main() {
+    sodiumoxide::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("keygen") => keygen(&args[1..]),
+        Some("id") => id(&args[1..]),
+        Some("connect") => connect(&args[1..]),
+        Some("listen") => listen(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// Writes a new keypair to the given file, or to stdout. The file must not
+// exist yet, and is only readable by its owner on unix.
+fn keygen(args: &[String]) -> Result<(), String> {
+    let (pk, sk) = sign::gen_keypair();
+    let secret = format_secret(&pk, &sk);
+
+    match args.first() {
+        Some(path) => {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(path).map_err(|err| format!("{}: {}", path, err))?;
+            file.write_all(secret.as_bytes())
+                .map_err(|err| format!("{}: {}", path, err))?;
+            println!("{}", format_id(&pk));
+        }
+        None => print!("{}", secret),
+    }
+    Ok(())
+}
+
+fn id(args: &[String]) -> Result<(), String> {
+    let path = args.first().ok_or_else(|| USAGE.to_string())?;
+    let (pk, _) = read_secret(path)?;
+    println!("{}", format_id(&pk));
+    Ok(())
+}
+
+fn connect(args: &[String]) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+    let options = Options::parse(&args[2..])?;
+    let server_pk = parse_id(&args[1])?;
+    let (pk, sk) = options.keypair()?;
+    let (eph_pk, eph_sk) = box_::gen_keypair();
+
+    let stream = TcpStream::connect(&args[0])
+        .map_err(|err| format!("{}: {}", args[0], err))?;
+    let mut state = HandshakeState::client(&options.network_identifier,
+                                           &pk,
+                                           &sk,
+                                           &eph_pk,
+                                           &eph_sk,
+                                           &server_pk);
+
+    let outcome = handshake(&mut state, &stream)?;
+    print_outcome(&outcome, options.show_keys);
+    Ok(())
+}
+
+// Accepts connections one at a time, and prints the outcome or error of each
+// handshake.
+fn listen(args: &[String]) -> Result<(), String> {
+    let addr = args.first().ok_or_else(|| USAGE.to_string())?;
+    let options = Options::parse(&args[1..])?;
+    let (pk, sk) = options.keypair()?;
+
+    let listener = TcpListener::bind(addr).map_err(|err| format!("{}: {}", addr, err))?;
+    println!("listening on {} as {}", addr, format_id(&pk));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("failed to accept: {}", err);
+                continue;
+            }
+        };
+        let peer_addr = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown address".to_string());
+
+        let (eph_pk, eph_sk) = box_::gen_keypair();
+        let mut state = HandshakeState::server(&options.network_identifier,
+                                               &pk,
+                                               &sk,
+                                               &eph_pk,
+                                               &eph_sk);
+
+        match handshake(&mut state, &stream) {
+            Ok(outcome) => {
+                println!("handshake with {} succeeded", peer_addr);
+                print_outcome(&outcome, options.show_keys);
+            }
+            Err(err) => eprintln!("handshake with {} failed: {}", peer_addr, err),
+        }
+    }
+    Ok(())
+}
+
+struct Options {
+    secret: Option<String>,
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    show_keys: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            secret: None,
+            network_identifier: decode_network(MAIN_NETWORK)?,
+            show_keys: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--secret" => {
+                    let path = args.next().ok_or("--secret requires a file")?;
+                    options.secret = Some(path.clone());
+                }
+                "--network" => {
+                    let network = args.next().ok_or("--network requires a key")?;
+                    options.network_identifier = decode_network(network)?;
+                }
+                "--show-keys" => options.show_keys = true,
+                _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn keypair(&self) -> Result<(sign::PublicKey, sign::SecretKey), String> {
+        match self.secret {
+            Some(ref path) => read_secret(path),
+            None => Ok(sign::gen_keypair()),
+        }
+    }
+}
+
+// Performs a handshake over a blocking stream.
+fn handshake(state: &mut HandshakeState, stream: &TcpStream) -> Result<Outcome, String> {
+    state
+        .run_blocking(&mut &*stream, &mut &*stream)
+        .map_err(|err| err.to_string())
+}
+
+fn print_outcome(outcome: &Outcome, show_keys: bool) {
+    println!("peer:               {}", format_id(&outcome.peer_longterm_pk()));
+    println!("local ephemeral:    {}", outcome.local_ephemeral_pk().to_base64());
+    println!("peer ephemeral:     {}", outcome.peer_ephemeral_pk().to_base64());
+    if show_keys {
+        println!("encryption key:     {}", base64::encode(&outcome.send_params().key.0));
+        println!("encryption nonce:   {}", base64::encode(&outcome.send_params().nonce.0));
+        println!("decryption key:     {}", base64::encode(&outcome.recv_params().key.0));
+        println!("decryption nonce:   {}", base64::encode(&outcome.recv_params().nonce.0));
+    }
+}
+
+fn format_id(pk: &sign::PublicKey) -> String {
+    format!("@{}.ed25519", pk.to_base64())
+}
+
+fn parse_id(id: &str) -> Result<sign::PublicKey, String> {
+    let key = id.trim_left_matches('@').trim_right_matches(".ed25519");
+    sign::PublicKey::from_base64(key).ok_or_else(|| format!("invalid id {}", id))
+}
+
+fn decode_network(network: &str) -> Result<[u8; NETWORK_IDENTIFIER_BYTES], String> {
+    KeyEncoding::from_base64(network).ok_or_else(|| format!("invalid network key {}", network))
+}
+
+// Formats a keypair as an ssb secret file.
+fn format_secret(pk: &sign::PublicKey, sk: &sign::SecretKey) -> String {
+    let mut secret = serde_json::Map::new();
+    secret.insert("curve".to_string(), "ed25519".into());
+    secret.insert("public".to_string(),
+                  format!("{}.ed25519", pk.to_base64()).into());
+    secret.insert("private".to_string(),
+                  format!("{}.ed25519", sk.to_base64()).into());
+    secret.insert("id".to_string(), format_id(pk).into());
+
+    let secret = serde_json::Value::Object(secret);
+    format!("{}\n", serde_json::to_string_pretty(&secret).unwrap())
+}
+
+// Reads the keypair from an ssb secret file. Lines starting with `#` are
+// comments.
+fn read_secret(path: &str) -> Result<(sign::PublicKey, sign::SecretKey), String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| format!("{}: {}", path, err))?;
+
+    let json: String = contents
+        .lines()
+        .filter(|line| !line.trim_left().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let secret: serde_json::Value = serde_json::from_str(&json)
+        .map_err(|err| format!("{}: {}", path, err))?;
+
+    let field = |name: &str| secret[name].as_str().map(|key| key.trim_right_matches(".ed25519"));
+    let pk = field("public").and_then(sign::PublicKey::from_base64);
+    let sk = field("private").and_then(sign::SecretKey::from_base64);
+
+    match (pk, sk) {
+        (Some(pk), Some(sk)) => Ok((pk, sk)),
+        _ => Err(format!("{}: not a valid secret file", path)),
+    }
+}
@@ -0,0 +1,59 @@
+// Client executable for the shs1-testsuite
+// (https://github.com/AljoschaMeyer/shs1-testsuite).
+//
+// Invoked as `shs1-testsuite-client <network_identifier> <server_longterm_pk>`
+// with hex encoded arguments. Performs a handshake over stdin and stdout with
+// a fresh keypair, then writes the encryption key and nonce and the
+// decryption key and nonce to stdout. Exits with a non-zero status if the
+// handshake fails.
+//
+// Requires the `testsuite` feature.
+
+extern crate secret_handshake;
+extern crate sodiumoxide;
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use sodiumoxide::crypto::{box_, sign};
+
+use secret_handshake::hex;
+use secret_handshake::sans_io::HandshakeState;
+
+fn main() {
+    sodiumoxide::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: shs1-testsuite-client <network_identifier> <server_longterm_pk>");
+        process::exit(2);
+    }
+
+    let network_identifier = hex::decode_network_identifier(&args[0])
+        .expect("invalid network identifier");
+    let server_longterm_pk = hex::decode_longterm_pk(&args[1]).expect("invalid server key");
+    let (client_longterm_pk, client_longterm_sk) = sign::gen_keypair();
+    let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+
+    let mut state = HandshakeState::client(&network_identifier,
+                                           &client_longterm_pk,
+                                           &client_longterm_sk,
+                                           &client_ephemeral_pk,
+                                           &client_ephemeral_sk,
+                                           &server_longterm_pk);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let outcome = match state.run_blocking(&mut stdin.lock(), &mut stdout.lock()) {
+        Ok(outcome) => outcome,
+        Err(_) => process::exit(1),
+    };
+
+    let mut stdout = stdout.lock();
+    stdout.write_all(&outcome.send_params().key.0).unwrap();
+    stdout.write_all(&outcome.send_params().nonce.0).unwrap();
+    stdout.write_all(&outcome.recv_params().key.0).unwrap();
+    stdout.write_all(&outcome.recv_params().nonce.0).unwrap();
+    stdout.flush().unwrap();
+}
@@ -0,0 +1,98 @@
+//! Bound the resources a single handshake may consume.
+//!
+//! A `Deadline` bounds how long a handshake may take in total, but a peer
+//! can still make the most of that time by drip-feeding its messages a byte
+//! at a time, causing a wakeup and a read for every byte. A `Budget`,
+//! attached via the `set_budget` method of a handshaker, additionally
+//! bounds:
+//!
+//! - the wall-clock time of the handshake,
+//! - the number of reads from the stream over the whole handshake, and
+//! - the number of reads it may take to receive any single message.
+//!
+//! Once any of these is exceeded, the handshake fails with a
+//! `BudgetExceeded` error.
+//!
+//! ```rust,ignore
+//! let mut budget = Budget::unlimited();
+//! budget.max_duration = Some(Duration::from_secs(5));
+//! budget.max_reads_per_message = Some(8);
+//! server.set_budget(budget);
+//! ```
+//!
+//! The handshakers can not create timers, so the time is only checked when
+//! the handshake is polled. Combine the budget with a `Deadline` to also
+//! bound peers that send nothing at all.
+
+use std::time::{Duration, Instant};
+
+/// The resources a handshake may consume, `None` meaning unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Budget {
+    /// The maximum time from attaching the budget until the handshake
+    /// completes.
+    pub max_duration: Option<Duration>,
+    /// The maximum number of reads that return data, over the whole
+    /// handshake.
+    pub max_reads: Option<usize>,
+    /// The maximum number of reads that return data, for receiving a single
+    /// message. For a tarpitting server, discarding data after an invalid
+    /// msg1 counts towards msg1, and exceeding the budget ends it early.
+    pub max_reads_per_message: Option<usize>,
+}
+
+impl Budget {
+    /// A budget that does not bound anything.
+    pub fn unlimited() -> Budget {
+        Budget::default()
+    }
+}
+
+// Tracks the consumption of a `Budget` by a single handshake.
+#[derive(Debug)]
+pub(crate) struct BudgetTracker {
+    budget: Budget,
+    started: Instant,
+    reads: usize,
+    message_reads: usize, // reads since the last message was received
+}
+
+impl BudgetTracker {
+    pub(crate) fn new(budget: Budget) -> BudgetTracker {
+        BudgetTracker {
+            budget,
+            started: Instant::now(),
+            reads: 0,
+            message_reads: 0,
+        }
+    }
+
+    // Records a read that returned data, and returns whether the budget still
+    // holds.
+    pub(crate) fn record_read(&mut self) -> bool {
+        self.reads += 1;
+        self.message_reads += 1;
+        within(self.reads, self.budget.max_reads) &&
+        within(self.message_reads, self.budget.max_reads_per_message)
+    }
+
+    // Records that a message has been received completely.
+    pub(crate) fn message_received(&mut self) {
+        self.message_reads = 0;
+    }
+
+    // Returns whether the handshake is still within its time budget.
+    pub(crate) fn has_time_left(&self) -> bool {
+        match self.budget.max_duration {
+            Some(max) => self.started.elapsed() <= max,
+            None => true,
+        }
+    }
+}
+
+fn within(count: usize, max: Option<usize>) -> bool {
+    match max {
+        Some(max) => count <= max,
+        None => true,
+    }
+}
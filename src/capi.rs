@@ -0,0 +1,285 @@
+//! A C api to perform handshakes, for embedding this crate in other
+//! languages.
+//!
+//! The api is io-agnostic: the caller creates a client or server context from
+//! the raw key bytes, and then alternately produces messages to send and
+//! feeds in the messages received from the peer, in protocol order:
+//!
+//! - client: `create_msg1`, `verify_msg2`, `create_msg3`, `verify_msg4`,
+//!   `outcome`
+//! - server: `verify_msg1`, `create_msg2`, `verify_msg3`, `create_msg4`,
+//!   `outcome`
+//!
+//! The functions are declared in `include/shs.h`. All pointers to keys and
+//! messages must point to buffers of the appropriate length, contexts must be
+//! released with the corresponding `free` function.
+//!
+//! This module requires the `capi` feature. To build a shared library, run
+//! `cargo rustc --lib --release --features capi --crate-type cdylib`.
+
+use std::mem::uninitialized;
+use std::ptr::copy_nonoverlapping;
+
+use libc::c_int;
+use sodiumoxide::crypto::{box_, secretbox, sign};
+use sodiumoxide::utils::memzero;
+
+use crypto::*;
+
+/// The keys of a client context. Boxed, so that the pointers held by the
+/// `Client` remain valid when the context is moved.
+struct ClientKeys {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: [u8; sign::PUBLICKEYBYTES],
+    longterm_sk: [u8; sign::SECRETKEYBYTES],
+    ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+    ephemeral_sk: [u8; box_::SECRETKEYBYTES],
+    server_longterm_pk: [u8; sign::PUBLICKEYBYTES],
+}
+
+impl Drop for ClientKeys {
+    fn drop(&mut self) {
+        memzero(&mut self.longterm_sk);
+        memzero(&mut self.ephemeral_sk);
+    }
+}
+
+/// The keys of a server context.
+struct ServerKeys {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: [u8; sign::PUBLICKEYBYTES],
+    longterm_sk: [u8; sign::SECRETKEYBYTES],
+    ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+    ephemeral_sk: [u8; box_::SECRETKEYBYTES],
+}
+
+impl Drop for ServerKeys {
+    fn drop(&mut self) {
+        memzero(&mut self.longterm_sk);
+        memzero(&mut self.ephemeral_sk);
+    }
+}
+
+/// Opaque context for the client side of a handshake.
+pub struct ShsClient {
+    client: Client,
+    _keys: Box<ClientKeys>,
+}
+
+/// Opaque context for the server side of a handshake.
+pub struct ShsServer {
+    server: Server,
+    _keys: Box<ServerKeys>,
+}
+
+/// The outcome of a handshake, as exposed to C.
+#[repr(C)]
+pub struct ShsOutcome {
+    /// Key for encrypting data sent to the peer.
+    pub encryption_key: [u8; secretbox::KEYBYTES],
+    /// Initial nonce for encrypting data sent to the peer.
+    pub encryption_nonce: [u8; secretbox::NONCEBYTES],
+    /// Key for decrypting data received from the peer.
+    pub decryption_key: [u8; secretbox::KEYBYTES],
+    /// Initial nonce for decrypting data received from the peer.
+    pub decryption_nonce: [u8; secretbox::NONCEBYTES],
+    /// The longterm public key of the peer.
+    pub peer_longterm_pk: [u8; sign::PUBLICKEYBYTES],
+}
+
+impl<'a> From<&'a Outcome> for ShsOutcome {
+    fn from(outcome: &'a Outcome) -> ShsOutcome {
+        ShsOutcome {
+            encryption_key: outcome.send().key.0,
+            encryption_nonce: outcome.send().nonce.0,
+            decryption_key: outcome.recv().key.0,
+            decryption_nonce: outcome.recv().nonce.0,
+            peer_longterm_pk: outcome.peer_longterm_pk().0,
+        }
+    }
+}
+
+/// Creates a new client context, or returns null if any argument is null.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_new(network_identifier: *const u8,
+                                        longterm_pk: *const u8,
+                                        longterm_sk: *const u8,
+                                        ephemeral_pk: *const u8,
+                                        ephemeral_sk: *const u8,
+                                        server_longterm_pk: *const u8)
+                                        -> *mut ShsClient {
+    if network_identifier.is_null() || longterm_pk.is_null() || longterm_sk.is_null() ||
+       ephemeral_pk.is_null() || ephemeral_sk.is_null() ||
+       server_longterm_pk.is_null() {
+        return 0 as *mut ShsClient;
+    }
+
+    let mut keys = Box::new(ClientKeys {
+                                network_identifier: [0; NETWORK_IDENTIFIER_BYTES],
+                                longterm_pk: [0; sign::PUBLICKEYBYTES],
+                                longterm_sk: [0; sign::SECRETKEYBYTES],
+                                ephemeral_pk: [0; box_::PUBLICKEYBYTES],
+                                ephemeral_sk: [0; box_::SECRETKEYBYTES],
+                                server_longterm_pk: [0; sign::PUBLICKEYBYTES],
+                            });
+    copy_nonoverlapping(network_identifier,
+                        keys.network_identifier.as_mut_ptr(),
+                        NETWORK_IDENTIFIER_BYTES);
+    copy_nonoverlapping(longterm_pk, keys.longterm_pk.as_mut_ptr(), sign::PUBLICKEYBYTES);
+    copy_nonoverlapping(longterm_sk, keys.longterm_sk.as_mut_ptr(), sign::SECRETKEYBYTES);
+    copy_nonoverlapping(ephemeral_pk, keys.ephemeral_pk.as_mut_ptr(), box_::PUBLICKEYBYTES);
+    copy_nonoverlapping(ephemeral_sk, keys.ephemeral_sk.as_mut_ptr(), box_::SECRETKEYBYTES);
+    copy_nonoverlapping(server_longterm_pk,
+                        keys.server_longterm_pk.as_mut_ptr(),
+                        sign::PUBLICKEYBYTES);
+
+    let client = Client::new(&keys.network_identifier,
+                             &keys.longterm_pk,
+                             &keys.longterm_sk,
+                             &keys.ephemeral_pk,
+                             &keys.ephemeral_sk,
+                             &keys.server_longterm_pk);
+    Box::into_raw(Box::new(ShsClient {
+                               client,
+                               _keys: keys,
+                           }))
+}
+
+/// Writes msg1 (64 bytes) into `msg`.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_create_msg1(client: *mut ShsClient, msg: *mut u8) {
+    (*client)
+        .client
+        .create_msg1(&mut *(msg as *mut [u8; MSG1_BYTES]))
+}
+
+/// Verifies msg2 (64 bytes), returns nonzero if it is valid.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_verify_msg2(client: *mut ShsClient, msg: *const u8) -> c_int {
+    (*client)
+        .client
+        .verify_msg2(&*(msg as *const [u8; MSG2_BYTES])) as c_int
+}
+
+/// Writes msg3 (112 bytes) into `msg`.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_create_msg3(client: *mut ShsClient, msg: *mut u8) {
+    (*client)
+        .client
+        .create_msg3(&mut *(msg as *mut [u8; MSG3_BYTES]));
+}
+
+/// Verifies msg4 (80 bytes), returns nonzero if it is valid.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_verify_msg4(client: *mut ShsClient, msg: *const u8) -> c_int {
+    (*client)
+        .client
+        .verify_msg4(&*(msg as *const [u8; MSG4_BYTES])) as c_int
+}
+
+/// Writes the outcome of the handshake into `outcome`. Must only be called
+/// after msg4 has been verified.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_outcome(client: *mut ShsClient, outcome: *mut ShsOutcome) {
+    let mut full = uninitialized();
+    (*client).client.outcome(&mut full);
+    *outcome = ShsOutcome::from(&full);
+}
+
+/// Frees a client context, zeroing all sensitive data. Does nothing if
+/// `client` is null.
+#[no_mangle]
+pub unsafe extern "C" fn shs_client_free(client: *mut ShsClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Creates a new server context, or returns null if any argument is null.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_new(network_identifier: *const u8,
+                                        longterm_pk: *const u8,
+                                        longterm_sk: *const u8,
+                                        ephemeral_pk: *const u8,
+                                        ephemeral_sk: *const u8)
+                                        -> *mut ShsServer {
+    if network_identifier.is_null() || longterm_pk.is_null() || longterm_sk.is_null() ||
+       ephemeral_pk.is_null() || ephemeral_sk.is_null() {
+        return 0 as *mut ShsServer;
+    }
+
+    let mut keys = Box::new(ServerKeys {
+                                network_identifier: [0; NETWORK_IDENTIFIER_BYTES],
+                                longterm_pk: [0; sign::PUBLICKEYBYTES],
+                                longterm_sk: [0; sign::SECRETKEYBYTES],
+                                ephemeral_pk: [0; box_::PUBLICKEYBYTES],
+                                ephemeral_sk: [0; box_::SECRETKEYBYTES],
+                            });
+    copy_nonoverlapping(network_identifier,
+                        keys.network_identifier.as_mut_ptr(),
+                        NETWORK_IDENTIFIER_BYTES);
+    copy_nonoverlapping(longterm_pk, keys.longterm_pk.as_mut_ptr(), sign::PUBLICKEYBYTES);
+    copy_nonoverlapping(longterm_sk, keys.longterm_sk.as_mut_ptr(), sign::SECRETKEYBYTES);
+    copy_nonoverlapping(ephemeral_pk, keys.ephemeral_pk.as_mut_ptr(), box_::PUBLICKEYBYTES);
+    copy_nonoverlapping(ephemeral_sk, keys.ephemeral_sk.as_mut_ptr(), box_::SECRETKEYBYTES);
+
+    let server = Server::new(&keys.network_identifier,
+                             &keys.longterm_pk,
+                             &keys.longterm_sk,
+                             &keys.ephemeral_pk,
+                             &keys.ephemeral_sk);
+    Box::into_raw(Box::new(ShsServer {
+                               server,
+                               _keys: keys,
+                           }))
+}
+
+/// Verifies msg1 (64 bytes), returns nonzero if it is valid.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_verify_msg1(server: *mut ShsServer, msg: *const u8) -> c_int {
+    (*server)
+        .server
+        .verify_msg1(&*(msg as *const [u8; MSG1_BYTES])) as c_int
+}
+
+/// Writes msg2 (64 bytes) into `msg`.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_create_msg2(server: *mut ShsServer, msg: *mut u8) {
+    (*server)
+        .server
+        .create_msg2(&mut *(msg as *mut [u8; MSG2_BYTES]))
+}
+
+/// Verifies msg3 (112 bytes), returns nonzero if it is valid.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_verify_msg3(server: *mut ShsServer, msg: *const u8) -> c_int {
+    (*server)
+        .server
+        .verify_msg3(&*(msg as *const [u8; MSG3_BYTES])) as c_int
+}
+
+/// Writes msg4 (80 bytes) into `msg`.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_create_msg4(server: *mut ShsServer, msg: *mut u8) {
+    (*server)
+        .server
+        .create_msg4(msg as *mut [u8; MSG4_BYTES])
+}
+
+/// Writes the outcome of the handshake into `outcome`. Must only be called
+/// after msg4 has been created.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_outcome(server: *mut ShsServer, outcome: *mut ShsOutcome) {
+    let mut full = uninitialized();
+    (*server).server.outcome(&mut full);
+    *outcome = ShsOutcome::from(&full);
+}
+
+/// Frees a server context, zeroing all sensitive data. Does nothing if
+/// `server` is null.
+#[no_mangle]
+pub unsafe extern "C" fn shs_server_free(server: *mut ShsServer) {
+    if !server.is_null() {
+        drop(Box::from_raw(server));
+    }
+}
@@ -1,18 +1,26 @@
 //! Asynchronously initiate handshakes.
 
+use std::fmt::{self, Debug, Formatter};
 use std::marker::PhantomData;
-use std::mem::uninitialized;
 use std::io::ErrorKind::{WriteZero, UnexpectedEof};
 
 use sodiumoxide::crypto::{box_, sign};
-use sodiumoxide::utils::memzero;
 use futures_core::{Poll, Future};
 use futures_core::Async::{Ready, Pending};
 use futures_core::task::Context;
 use futures_io::{AsyncRead, AsyncWrite, Error};
 
+use budget::{Budget, BudgetTracker};
 use crypto::*;
-use errors::HandshakeError;
+use errors::{HandshakeError, FilteringHandshakeError};
+use offload::{self, Offload, Verification};
+use sans_io::HandshakeState;
+use stage::Stage;
+use stats::HandshakeTimer;
+use trace::{self, Reason, Side};
+
+// Panic message for accessing the stream after the handshake returned it.
+const TERMINATED: &str = "The handshake already terminated and returned the stream";
 
 /// Performs the client side of a handshake.
 pub struct ClientHandshaker<'a, S>(UnsafeClientHandshaker<S>, PhantomData<&'a u8>);
@@ -37,6 +45,62 @@ impl<'a, S: AsyncRead + AsyncWrite> ClientHandshaker<'a, S> {
                                                      server_longterm_pk),
                          PhantomData)
     }
+
+    /// Fail with a `SelfConnection` error, without sending any data, if the
+    /// server longterm public key is the client's own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.set_reject_self_connection(reject)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        self.0.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.0.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.0.get_mut()
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -49,18 +113,35 @@ impl<'a, S: AsyncRead + AsyncWrite> Future for ClientHandshaker<'a, S> {
     }
 }
 
+/// Shows the progress of the handshake, but no keys or message data.
+impl<'a, S> Debug for ClientHandshaker<'a, S> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_as("ClientHandshaker", f)
+    }
+}
+
 /// Performs the client side of a handshake. This copies the keys so that it isn't constrainted by
 /// their lifetime.
+///
+/// The keys are stored inline, so creating and driving an
+/// `OwningClientHandshaker` does not allocate.
 pub struct OwningClientHandshaker<S> {
-    network_identifier: Box<[u8; NETWORK_IDENTIFIER_BYTES]>,
-    client_longterm_pk: Box<sign::PublicKey>,
-    client_longterm_sk: Box<sign::SecretKey>,
-    client_ephemeral_pk: Box<box_::PublicKey>,
-    client_ephemeral_sk: Box<box_::SecretKey>,
-    server_longterm_pk: Box<sign::PublicKey>,
+    keys: ClientKeys,
     inner: UnsafeClientHandshaker<S>,
 }
 
+// The keys of an `OwningClientHandshaker`. The handshaker may be moved
+// between polls, so the core is pointed at their current location whenever
+// it is polled.
+struct ClientKeys {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    client_longterm_pk: sign::PublicKey,
+    client_longterm_sk: sign::SecretKey,
+    client_ephemeral_pk: box_::PublicKey,
+    client_ephemeral_sk: box_::SecretKey,
+    server_longterm_pk: sign::PublicKey,
+}
+
 impl<S: AsyncRead + AsyncWrite> OwningClientHandshaker<S> {
     /// Creates a new OwningClientHandshaker to connect to a server with known public key
     /// and app key over the given `stream`.
@@ -72,29 +153,82 @@ impl<S: AsyncRead + AsyncWrite> OwningClientHandshaker<S> {
                client_ephemeral_sk: box_::SecretKey,
                server_longterm_pk: sign::PublicKey)
                -> OwningClientHandshaker<S> {
-        let network_identifier = Box::new(network_identifier.clone());
-        let client_longterm_pk = Box::new(client_longterm_pk.clone());
-        let client_longterm_sk = Box::new(client_longterm_sk.clone());
-        let client_ephemeral_pk = Box::new(client_ephemeral_pk.clone());
-        let client_ephemeral_sk = Box::new(client_ephemeral_sk.clone());
-        let server_longterm_pk = Box::new(server_longterm_pk.clone());
-
-        OwningClientHandshaker {
-            inner: UnsafeClientHandshaker::new(stream,
-                                               network_identifier.as_ref(),
-                                               client_longterm_pk.as_ref(),
-                                               client_longterm_sk.as_ref(),
-                                               client_ephemeral_pk.as_ref(),
-                                               client_ephemeral_sk.as_ref(),
-                                               server_longterm_pk.as_ref()),
+        let keys = ClientKeys {
             network_identifier,
             client_longterm_pk,
             client_longterm_sk,
             client_ephemeral_pk,
             client_ephemeral_sk,
             server_longterm_pk,
+        };
+
+        OwningClientHandshaker {
+            inner: UnsafeClientHandshaker::new(stream,
+                                               &keys.network_identifier,
+                                               &keys.client_longterm_pk,
+                                               &keys.client_longterm_sk,
+                                               &keys.client_ephemeral_pk,
+                                               &keys.client_ephemeral_sk,
+                                               &keys.server_longterm_pk),
+            keys,
         }
     }
+
+    /// Fail with a `SelfConnection` error, without sending any data, if the
+    /// server longterm public key is the client's own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.inner.set_reject_self_connection(reject)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.inner.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.inner.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.inner.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        self.inner.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -103,17 +237,621 @@ impl<S: AsyncRead + AsyncWrite> Future for OwningClientHandshaker<S> {
     type Error = (HandshakeError, S);
 
     fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        self.inner
+            .set_keys(&self.keys.network_identifier,
+                      &self.keys.client_longterm_pk,
+                      &self.keys.client_longterm_sk,
+                      &self.keys.client_ephemeral_pk,
+                      &self.keys.client_ephemeral_sk,
+                      &self.keys.server_longterm_pk);
         self.inner.poll(cx)
     }
 }
 
-// Performs the client side of a handshake.
+/// Shows the progress of the handshake, but no keys or message data.
+impl<S> Debug for OwningClientHandshaker<S> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        self.inner.fmt_as("OwningClientHandshaker", f)
+    }
+}
+
+/// Performs the client side of a handshake. Allows verifying the server's
+/// longterm public key once it has been authenticated.
+///
+/// The protocol requires the client to know the server's longterm public key
+/// before the handshake begins, e.g. from the address it dials. The filter
+/// function allows applying a policy to that key after the server proved
+/// ownership of it, e.g. checking it against a store of pinned keys, or
+/// trusting and storing it on first use.
+pub struct ClientHandshakerWithFilter<'a, S, FilterFn, AsyncBool>(FilteringClient<ClientHandshaker<'a, S>, S, FilterFn, AsyncBool>);
+
+impl<'a, S, FilterFn, AsyncBool> ClientHandshakerWithFilter<'a, S, FilterFn, AsyncBool>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    /// Creates a new ClientHandshakerWithFilter to connect to a server with known public key
+    /// and app key over the given `stream`.
+    ///
+    /// Once the server has been authenticated, `filter_fn` is invoked with
+    /// its longterm public key. If the returned `AsyncBool` resolves to
+    /// `Ok(Ready(false))`, the handshake fails with a `Rejected` error.
+    pub fn new(stream: S,
+               filter_fn: FilterFn,
+               network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: &'a sign::PublicKey,
+               client_longterm_sk: &'a sign::SecretKey,
+               client_ephemeral_pk: &'a box_::PublicKey,
+               client_ephemeral_sk: &'a box_::SecretKey,
+               server_longterm_pk: &'a sign::PublicKey)
+               -> ClientHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        ClientHandshakerWithFilter(FilteringClient::new(ClientHandshaker::new(stream,
+                                                                              network_identifier,
+                                                                              client_longterm_pk,
+                                                                              client_longterm_sk,
+                                                                              client_ephemeral_pk,
+                                                                              client_ephemeral_sk,
+                                                                              server_longterm_pk),
+                                                        filter_fn))
+    }
+
+    /// Fail with a `SelfConnection` error, without sending any data, if the
+    /// server longterm public key is the client's own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.handshaker.set_reject_self_connection(reject)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    ///
+    /// The timer covers the handshake itself, not the filter function.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.handshaker.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.handshaker.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.handshaker.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        if let Some(aborted) = self.0.abort_filtering() {
+            return Some(aborted);
+        }
+        (self.0).handshaker.0.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        match (self.0).filtering {
+            Some((_, _, ref stream)) => stream,
+            None => (self.0).handshaker.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        match (self.0).filtering {
+            Some((_, _, ref mut stream)) => stream,
+            None => (self.0).handshaker.get_mut(),
+        }
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<'a, S, FilterFn, AsyncBool> Future for ClientHandshakerWithFilter<'a, S, FilterFn, AsyncBool>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    type Item = (Outcome, S);
+    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        self.0.poll(cx)
+    }
+}
+
+/// Shows the progress of the handshake, but no keys or message data.
+impl<'a, S, FilterFn, AsyncBool> Debug for ClientHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_as("ClientHandshakerWithFilter", f)
+    }
+}
+
+/// Performs the client side of a handshake. Allows verifying the server's
+/// longterm public key once it has been authenticated. This copies the keys so that it isn't
+/// constrainted by their lifetime.
+pub struct OwningClientHandshakerWithFilter<S, FilterFn, AsyncBool>(FilteringClient<OwningClientHandshaker<S>, S, FilterFn, AsyncBool>);
+
+impl<S, FilterFn, AsyncBool> OwningClientHandshakerWithFilter<S, FilterFn, AsyncBool>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    /// Creates a new OwningClientHandshakerWithFilter to connect to a server with known public
+    /// key and app key over the given `stream`.
+    ///
+    /// Once the server has been authenticated, `filter_fn` is invoked with
+    /// its longterm public key. If the returned `AsyncBool` resolves to
+    /// `Ok(Ready(false))`, the handshake fails with a `Rejected` error.
+    pub fn new(stream: S,
+               filter_fn: FilterFn,
+               network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: sign::PublicKey,
+               client_longterm_sk: sign::SecretKey,
+               client_ephemeral_pk: box_::PublicKey,
+               client_ephemeral_sk: box_::SecretKey,
+               server_longterm_pk: sign::PublicKey)
+               -> OwningClientHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        OwningClientHandshakerWithFilter(FilteringClient::new(OwningClientHandshaker::new(stream,
+                                                                                          network_identifier,
+                                                                                          client_longterm_pk,
+                                                                                          client_longterm_sk,
+                                                                                          client_ephemeral_pk,
+                                                                                          client_ephemeral_sk,
+                                                                                          server_longterm_pk),
+                                                              filter_fn))
+    }
+
+    /// Fail with a `SelfConnection` error, without sending any data, if the
+    /// server longterm public key is the client's own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.handshaker.set_reject_self_connection(reject)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    ///
+    /// The timer covers the handshake itself, not the filter function.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.handshaker.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.handshaker.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.handshaker.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        if let Some(aborted) = self.0.abort_filtering() {
+            return Some(aborted);
+        }
+        (self.0).handshaker.inner.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        match (self.0).filtering {
+            Some((_, _, ref stream)) => stream,
+            None => (self.0).handshaker.get_ref(),
+        }
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        match (self.0).filtering {
+            Some((_, _, ref mut stream)) => stream,
+            None => (self.0).handshaker.get_mut(),
+        }
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S, FilterFn, AsyncBool> Future for OwningClientHandshakerWithFilter<S, FilterFn, AsyncBool>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    type Item = (Outcome, S);
+    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        self.0.poll(cx)
+    }
+}
+
+/// Shows the progress of the handshake, but no keys or message data.
+impl<S, FilterFn, AsyncBool> Debug for OwningClientHandshakerWithFilter<S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_as("OwningClientHandshakerWithFilter", f)
+    }
+}
+
+// Drives a client handshaker, then runs the filter function on the
+// authenticated server key.
+struct FilteringClient<H, S, FilterFn, AsyncBool> {
+    handshaker: H,
+    filter_fn: Option<FilterFn>,
+    filtering: Option<(AsyncBool, Outcome, S)>,
+}
+
+impl<H, S, FilterFn, AsyncBool> FilteringClient<H, S, FilterFn, AsyncBool> {
+    fn new(handshaker: H, filter_fn: FilterFn) -> FilteringClient<H, S, FilterFn, AsyncBool> {
+        FilteringClient {
+            handshaker,
+            filter_fn: Some(filter_fn),
+            filtering: None,
+        }
+    }
+
+    // Takes the stream if the filter function is running.
+    fn abort_filtering(&mut self) -> Option<(S, Stage)> {
+        self.filtering.take().map(|(_, _, stream)| (stream, Stage::Filtering))
+    }
+}
+
+impl<H: Debug, S, FilterFn, AsyncBool> FilteringClient<H, S, FilterFn, AsyncBool> {
+    // Formats the progress of the handshake under the name of a public
+    // handshaker type.
+    fn fmt_as(&self, name: &str, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct(name)
+            .field("handshaker", &self.handshaker)
+            .field("filtering", &self.filtering.is_some())
+            .finish()
+    }
+}
+
+impl<H, S, FilterFn, AsyncBool> Future for FilteringClient<H, S, FilterFn, AsyncBool>
+    where H: Future<Item = (Outcome, S), Error = (HandshakeError, S)>,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    type Item = (Outcome, S);
+    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some((mut filter, outcome, stream)) = self.filtering.take() {
+                return match filter.poll(cx) {
+                           Ok(Ready(accepted)) => {
+                               trace::filter_decision(Side::Client,
+                                                      &outcome.peer_longterm_pk(),
+                                                      accepted);
+                               if accepted {
+                                   Ok(Ready((outcome, stream)))
+                               } else {
+                                   let err = FilteringHandshakeError::Rejected;
+                                   trace::failed(Side::Client, &Reason(&err));
+                                   Err((err, stream))
+                               }
+                           }
+                           Ok(Pending) => {
+                               self.filtering = Some((filter, outcome, stream));
+                               Ok(Pending)
+                           }
+                           Err(e) => {
+                               let err = FilteringHandshakeError::FilterError(e);
+                               trace::failed(Side::Client, &Reason(&err));
+                               Err((err, stream))
+                           }
+                       };
+            }
+
+            match self.handshaker.poll(cx) {
+                Ok(Ready((outcome, stream))) => {
+                    let filter_fn = self.filter_fn
+                        .take()
+                        .expect("Polled ClientHandshakerWithFilter after completion");
+                    let filter = filter_fn(&outcome.peer_longterm_pk());
+                    self.filtering = Some((filter, outcome, stream));
+                }
+                Ok(Pending) => return Ok(Pending),
+                Err((err, stream)) => return Err((filtering_error(err), stream)),
+            }
+        }
+    }
+}
+
+// Converts the error of a plain handshake into the error of a handshake with
+// a filter or resolver function.
+fn filtering_error<FnErr>(err: HandshakeError) -> FilteringHandshakeError<FnErr> {
+    match err {
+        HandshakeError::IoError(e) => FilteringHandshakeError::IoError(e),
+        HandshakeError::CryptoError => FilteringHandshakeError::CryptoError,
+        HandshakeError::SelfConnection => FilteringHandshakeError::SelfConnection,
+        HandshakeError::BudgetExceeded => FilteringHandshakeError::BudgetExceeded,
+    }
+}
+
+/// Performs the client side of a handshake with a server whose longterm
+/// public key is not known up front, but looked up by an alias, e.g. from a
+/// contact database or a DHT. This copies the keys so that it isn't
+/// constrainted by their lifetime.
+///
+/// Msg1 and msg2 do not depend on the server key. The resolver function is
+/// invoked with the alias once msg2 has been received, and the handshake
+/// continues by verifying msg2 and sending msg3 once the returned future
+/// yields the key. Looking up the key thus overlaps with the first round
+/// trip.
+pub struct OwningClientHandshakerWithResolver<S, A, ResolveFn, AsyncKey> {
+    handshaker: OwningClientHandshaker<S>,
+    alias: A,
+    resolve_fn: Option<ResolveFn>,
+    resolving: Option<AsyncKey>,
+}
+
+impl<S, A, ResolveFn, AsyncKey> OwningClientHandshakerWithResolver<S, A, ResolveFn, AsyncKey>
+    where S: AsyncRead + AsyncWrite,
+          ResolveFn: FnOnce(&A) -> AsyncKey,
+          AsyncKey: Future<Item = Option<sign::PublicKey>>
+{
+    /// Creates a new OwningClientHandshakerWithResolver to connect to the
+    /// server known under the given `alias`, over the given `stream`.
+    ///
+    /// If the `AsyncKey` returned by `resolve_fn` resolves to `None`, the
+    /// handshake fails with a `Rejected` error, if it errors, the handshake
+    /// fails with a `FilterError`.
+    pub fn new(stream: S,
+               alias: A,
+               resolve_fn: ResolveFn,
+               network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: sign::PublicKey,
+               client_longterm_sk: sign::SecretKey,
+               client_ephemeral_pk: box_::PublicKey,
+               client_ephemeral_sk: box_::SecretKey)
+               -> OwningClientHandshakerWithResolver<S, A, ResolveFn, AsyncKey> {
+        // Replaced by the resolved key before msg2 is verified, which is when
+        // the core first reads the server key.
+        let placeholder = sign::PublicKey([0; sign::PUBLICKEYBYTES]);
+        let mut handshaker = OwningClientHandshaker::new(stream,
+                                                         network_identifier,
+                                                         client_longterm_pk,
+                                                         client_longterm_sk,
+                                                         client_ephemeral_pk,
+                                                         client_ephemeral_sk,
+                                                         placeholder);
+        handshaker.inner.await_server_key = true;
+
+        OwningClientHandshakerWithResolver {
+            handshaker,
+            alias,
+            resolve_fn: Some(resolve_fn),
+            resolving: None,
+        }
+    }
+
+    /// The alias of the server.
+    pub fn alias(&self) -> &A {
+        &self.alias
+    }
+
+    /// Fail with a `SelfConnection` error, before sending msg3, if the
+    /// resolved server longterm public key is the client's own longterm
+    /// public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.handshaker.set_reject_self_connection(reject)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    ///
+    /// The timer includes the time spent resolving the server key.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.handshaker.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.handshaker.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.handshaker.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(self) -> Option<(S, Stage)> {
+        self.handshaker.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.handshaker.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.handshaker.get_mut()
+    }
+
+    // Takes the stream to fail with the given error.
+    fn fail(&mut self,
+            err: FilteringHandshakeError<AsyncKey::Error>)
+            -> (FilteringHandshakeError<AsyncKey::Error>, S) {
+        let (stream, _) = self.handshaker.inner.abort().expect(TERMINATED);
+        trace::failed(Side::Client, &Reason(&err));
+        (err, stream)
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S, A, ResolveFn, AsyncKey> Future for OwningClientHandshakerWithResolver<S, A, ResolveFn, AsyncKey>
+    where S: AsyncRead + AsyncWrite,
+          ResolveFn: FnOnce(&A) -> AsyncKey,
+          AsyncKey: Future<Item = Option<sign::PublicKey>>
+{
+    type Item = (Outcome, S);
+    type Error = (FilteringHandshakeError<AsyncKey::Error>, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if self.handshaker.inner.awaits_server_key() {
+                if self.resolving.is_none() {
+                    let resolve_fn = self.resolve_fn
+                        .take()
+                        .expect("Polled OwningClientHandshakerWithResolver after completion");
+                    self.resolving = Some(resolve_fn(&self.alias));
+                }
+
+                let resolved = self.resolving.as_mut().unwrap().poll(cx);
+                match resolved {
+                    Ok(Ready(Some(server_longterm_pk))) => {
+                        self.resolving = None;
+                        // Msg2 has not been verified yet, so the key may
+                        // still change, see `set_keys`.
+                        self.handshaker.keys.server_longterm_pk = server_longterm_pk;
+                        self.handshaker.inner.await_server_key = false;
+                    }
+                    Ok(Ready(None)) => {
+                        self.resolving = None;
+                        return Err(self.fail(FilteringHandshakeError::Rejected));
+                    }
+                    Ok(Pending) => return Ok(Pending),
+                    Err(e) => {
+                        self.resolving = None;
+                        return Err(self.fail(FilteringHandshakeError::FilterError(e)));
+                    }
+                }
+            }
+
+            match self.handshaker.poll(cx) {
+                Ok(Ready(result)) => return Ok(Ready(result)),
+                Ok(Pending) => {
+                    if !self.handshaker.inner.awaits_server_key() {
+                        return Ok(Pending);
+                    }
+                }
+                Err((err, stream)) => return Err((filtering_error(err), stream)),
+            }
+        }
+    }
+}
+
+/// Shows the progress of the handshake, but no keys or message data.
+impl<S, A, ResolveFn, AsyncKey> Debug for OwningClientHandshakerWithResolver<S, A, ResolveFn, AsyncKey> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("OwningClientHandshakerWithResolver")
+            .field("handshaker", &self.handshaker)
+            .field("resolving", &self.resolving.is_some())
+            .finish()
+    }
+}
+
+// Performs the client side of a handshake, as an adapter over a
+// `HandshakeState`.
 struct UnsafeClientHandshaker<S> {
     stream: Option<S>,
-    client: Client,
-    state: State,
-    data: [u8; MSG3_BYTES], // used to hold and cache the results of `client.create_client_challenge` and `client.create_client_auth`, and any data read from the server
-    offset: usize, // offset into the data array at which to read/write
+    state: HandshakeState<'static>,
+    flushing: bool, // whether the last message has been written but not flushed yet
+    reject_self_connection: bool,
+    timer: Option<HandshakeTimer>,
+    budget: Option<BudgetTracker>,
+    offload: Option<Offload>,
+    verification: Option<Verification>, // of the received message, by the offload
+    received: usize, // bytes completing the current incoming message, not yet verified
+    await_server_key: bool, // whether to hold back msg2 until the server key is known
+}
+
+impl<S> UnsafeClientHandshaker<S> {
+    // Formats the progress of the handshake under the name of a public
+    // handshaker type.
+    fn fmt_as(&self, name: &str, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct(name)
+            .field("state", &self.state)
+            .field("flushing", &self.flushing)
+            .field("has_stream", &self.stream.is_some())
+            .finish()
+    }
+
+    // Takes the stream, unless the handshake has already terminated.
+    fn abort(&mut self) -> Option<(S, Stage)> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return None,
+        };
+
+        if let Some(ref timer) = self.timer {
+            timer.finish(false);
+        }
+
+        // While flushing, the state already waits for the reply to the
+        // flushed message.
+        let stage = match (self.flushing, self.state.stage()) {
+            (true, Stage::Msg2) => Stage::Msg1,
+            (true, _) => Stage::Msg3,
+            (false, stage) => stage,
+        };
+        Some((stream, stage))
+    }
+
+    fn get_ref(&self) -> &S {
+        self.stream.as_ref().expect(TERMINATED)
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.stream.as_mut().expect(TERMINATED)
+    }
 }
 
 impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
@@ -127,32 +865,96 @@ impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
            client_ephemeral_sk: *const box_::SecretKey,
            server_longterm_pk: *const sign::PublicKey)
            -> UnsafeClientHandshaker<S> {
-        unsafe {
-            let mut ret = UnsafeClientHandshaker {
-                stream: Some(stream),
-                client: Client::new(network_identifier,
-                                    &(*client_longterm_pk).0,
-                                    &(*client_longterm_sk).0,
-                                    &(*client_ephemeral_pk).0,
-                                    &(*client_ephemeral_sk).0,
-                                    &(*server_longterm_pk).0),
-                state: WriteMsg1,
-                data: [0; MSG3_BYTES],
-                offset: 0,
-            };
-            ret.client
-                .create_msg1(&mut *(&mut ret.data as *mut [u8; MSG3_BYTES] as
-                                    *mut [u8; MSG1_BYTES]));
+        UnsafeClientHandshaker {
+            stream: Some(stream),
+            state: unsafe {
+                HandshakeState::client_unchecked(network_identifier,
+                                                 client_longterm_pk,
+                                                 client_longterm_sk,
+                                                 client_ephemeral_pk,
+                                                 client_ephemeral_sk,
+                                                 server_longterm_pk)
+            },
+            flushing: false,
+            reject_self_connection: false,
+            timer: None,
+            budget: None,
+            offload: None,
+            verification: None,
+            received: 0,
+            await_server_key: false,
+        }
+    }
+
+    fn set_reject_self_connection(&mut self, reject: bool) {
+        self.reject_self_connection = reject;
+    }
+
+    fn set_timer(&mut self, timer: HandshakeTimer) {
+        timer.start();
+        self.timer = Some(timer);
+    }
+
+    fn set_budget(&mut self, budget: Budget) {
+        self.budget = Some(BudgetTracker::new(budget));
+    }
 
-            ret
+    fn set_offload(&mut self, offload: Offload) {
+        self.offload = Some(offload);
+    }
+
+    // Returns whether msg2 has been received completely, but is held back
+    // until the server longterm public key has been set. Verifying msg2 is
+    // the first step that depends on the server key.
+    fn awaits_server_key(&self) -> bool {
+        self.await_server_key && self.received > 0 && self.state.stage() == Stage::Msg2
+    }
+
+    // Records a read that returned data, and returns whether the handshake
+    // is still within its budget.
+    fn record_read(&mut self) -> bool {
+        match self.budget {
+            Some(ref mut budget) => budget.record_read(),
+            None => true,
         }
     }
-}
 
-// Zero buffered handshake data on dropping.
-impl<S> Drop for UnsafeClientHandshaker<S> {
-    fn drop(&mut self) {
-        memzero(&mut self.data);
+    fn has_time_left(&self) -> bool {
+        match self.budget {
+            Some(ref budget) => budget.has_time_left(),
+            None => true,
+        }
+    }
+
+    // Records that the current message has been completely read or written.
+    fn message_completed(&self) {
+        if let Some(ref timer) = self.timer {
+            timer.message_completed();
+        }
+    }
+
+    // Points the core at the current location of the keys. The keys must have
+    // the same values as those passed to `new`, with one exception: the
+    // server longterm public key may be replaced as long as
+    // `awaits_server_key` holds. The core reads it first when verifying
+    // msg2, which is held back until then, so it must not change once msg2
+    // has been verified.
+    fn set_keys(&mut self,
+                network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
+                client_longterm_pk: *const sign::PublicKey,
+                client_longterm_sk: *const sign::SecretKey,
+                client_ephemeral_pk: *const box_::PublicKey,
+                client_ephemeral_sk: *const box_::SecretKey,
+                server_longterm_pk: *const sign::PublicKey) {
+        unsafe {
+            self.state
+                .set_client_keys(network_identifier,
+                                 client_longterm_pk,
+                                 client_longterm_sk,
+                                 client_ephemeral_pk,
+                                 client_ephemeral_sk,
+                                 server_longterm_pk)
+        }
     }
 }
 
@@ -162,37 +964,68 @@ impl<S: AsyncRead + AsyncWrite> Future for UnsafeClientHandshaker<S> {
     type Error = (HandshakeError, S);
 
     fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        let mut stream = self.stream
-            .take()
-            .expect("Polled UnsafeClientHandshaker after completion");
-
-        match self.state {
-            WriteMsg1 => {
-                while self.offset < MSG1_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG1_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((Error::new(WriteZero, "failed to write msg1").into(),
-                                            stream));
-                            }
-                            self.offset += written;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
+        let result = self.poll_handshake(cx);
+
+        if let Err((ref err, _)) = result {
+            trace::failed(Side::Client, err);
+        }
+
+        if let Some(ref timer) = self.timer {
+            match result {
+                Ok(Pending) => {}
+                Ok(Ready(_)) => timer.finish(true),
+                Err(_) => timer.finish(false),
+            }
+        }
+
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
+    // Drives the handshake state machine.
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<(Outcome, S), (HandshakeError, S)> {
+        loop {
+            let mut stream = match self.stream.take() {
+                Some(stream) => stream,
+                // The handshake has already completed, stay in that terminal state.
+                None => return Ok(Pending),
+            };
+
+            if !self.has_time_left() {
+                return Err((HandshakeError::BudgetExceeded, stream));
+            }
+
+            if self.awaits_server_key() {
+                self.stream = Some(stream);
+                return Ok(Pending);
+            }
+
+            if self.received > 0 {
+                let received = self.received;
+                match offload::poll_advance_read(cx,
+                                                 &mut self.state,
+                                                 received,
+                                                 self.offload.as_ref(),
+                                                 &mut self.verification) {
+                    Ready(Ok(())) => {}
+                    Ready(Err(e)) => return Err((e, stream)),
+                    Pending => {
+                        self.stream = Some(stream);
+                        return Ok(Pending);
                     }
                 }
 
                 self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg1;
-
-                return self.poll(cx);
+                self.received = 0;
+                self.message_completed();
+                if let Some(ref mut budget) = self.budget {
+                    budget.message_received();
+                }
+                continue;
             }
 
-            FlushMsg1 => {
+            if self.flushing {
                 match stream.poll_flush(cx) {
                     Ok(Ready(())) => {}
                     Ok(Pending) => {
@@ -203,71 +1036,25 @@ impl<S: AsyncRead + AsyncWrite> Future for UnsafeClientHandshaker<S> {
                 }
 
                 self.stream = Some(stream);
-                self.state = ReadMsg2;
-                return self.poll(cx);
+                self.flushing = false;
+                self.message_completed();
+                continue;
             }
 
-            ReadMsg2 => {
-                while self.offset < MSG2_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG2_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((Error::new(UnexpectedEof, "failed to read msg2")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += read;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
+            if self.state.wants_write() > 0 {
+                if self.reject_self_connection && self.state.connects_to_self() {
+                    return Err((HandshakeError::SelfConnection, stream));
                 }
 
-                if !self.client
-                        .verify_msg2(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG2_BYTES])
-                                     }) {
-                    return Err((HandshakeError::CryptoError, stream));
-                }
-
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = WriteMsg3;
-                self.client.create_msg3(&mut self.data);
-                return self.poll(cx);
-            }
-
-            WriteMsg3 => {
-                while self.offset < MSG3_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG3_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((Error::new(WriteZero, "failed to write msg3").into(),
-                                            stream));
-                            }
-                            self.offset += written;
+                match stream.poll_write(cx, self.state.outgoing()) {
+                    Ok(Ready(written)) => {
+                        if written == 0 {
+                            let err = Error::new(WriteZero, "failed to write handshake message");
+                            return Err((err.into(), stream));
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
+                        self.state.advance_write(written);
+                        self.flushing = self.state.wants_write() == 0;
                     }
-                }
-
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg3;
-                return self.poll(cx);
-            }
-
-            FlushMsg3 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
                     Ok(Pending) => {
                         self.stream = Some(stream);
                         return Ok(Pending);
@@ -276,52 +1063,42 @@ impl<S: AsyncRead + AsyncWrite> Future for UnsafeClientHandshaker<S> {
                 }
 
                 self.stream = Some(stream);
-                self.state = ReadMsg4;
-                return self.poll(cx);
+                continue;
             }
 
-            ReadMsg4 => {
-                while self.offset < MSG4_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG4_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((Error::new(UnexpectedEof, "failed to read msg4")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += read;
+            if self.state.wants_read() > 0 {
+                match stream.poll_read(cx, self.state.incoming()) {
+                    Ok(Ready(read)) => {
+                        if read == 0 {
+                            let err = Error::new(UnexpectedEof, "failed to read handshake message");
+                            return Err((err.into(), stream));
+                        }
+                        if !self.record_read() {
+                            return Err((HandshakeError::BudgetExceeded, stream));
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
+                        // Completing a message is verified on the next
+                        // iteration, via the offload.
+                        if read == self.state.wants_read() {
+                            self.received = read;
+                        } else if let Err(e) = self.state.advance_read(read) {
+                            return Err((e, stream));
                         }
-                        Err(e) => return Err((e.into(), stream)),
                     }
+                    Ok(Pending) => {
+                        self.stream = Some(stream);
+                        return Ok(Pending);
+                    }
+                    Err(e) => return Err((e.into(), stream)),
                 }
 
-                if !self.client
-                        .verify_msg4(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG4_BYTES])
-                                     }) {
-                    return Err((HandshakeError::CryptoError, stream));
-                }
-
-                let mut outcome = unsafe { uninitialized() };
-                self.client.outcome(&mut outcome);
-                return Ok(Ready((outcome, stream)));
+                self.stream = Some(stream);
+                continue;
             }
+
+            let outcome = self.state
+                .outcome()
+                .expect("client handshake state neither reads, writes nor is done");
+            return Ok(Ready((outcome, stream)));
         }
     }
 }
-
-// State for the future state machine.
-enum State {
-    WriteMsg1,
-    FlushMsg1,
-    ReadMsg2,
-    WriteMsg3,
-    FlushMsg3,
-    ReadMsg4,
-}
-use client::State::*;
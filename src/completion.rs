@@ -0,0 +1,173 @@
+//! Drive handshakes over completion-based io, as provided by io_uring based
+//! runtimes.
+//!
+//! Completion-based io takes ownership of a buffer for the duration of an
+//! operation and returns it on completion, rather than signalling readiness
+//! and then borrowing a buffer. Implement [`CompletionIo`](trait.CompletionIo.html)
+//! for a handle to such a connection, and wrap it in a
+//! [`CompletionStream`](struct.CompletionStream.html) to run any of the
+//! handshakers over it:
+//!
+//! ```rust,ignore
+//! let client = OwningClientHandshaker::new(CompletionStream::new(connection), ...);
+//! ```
+//!
+//! The `CompletionStream` copies the data of each `poll_write` into the
+//! buffer of a new write operation, and reports it as written right away.
+//! `poll_flush` and `poll_close` drive the operation to completion,
+//! resubmitting the rest of the data after a partial write. Once the
+//! handshake is done, use `into_inner` to get the connection back.
+
+use std::cmp::min;
+use std::io::{self, ErrorKind};
+
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+/// A connection supporting completion-based reads and writes.
+///
+/// The returned futures own the buffer while the operation is in progress,
+/// and must not borrow from `self`.
+pub trait CompletionIo {
+    /// Future of a read operation, yielding the buffer and the number of
+    /// bytes read into its beginning.
+    type Read: Future<Item = (Vec<u8>, usize), Error = io::Error>;
+    /// Future of a write operation, yielding the buffer and the number of
+    /// bytes written from its beginning.
+    type Write: Future<Item = (Vec<u8>, usize), Error = io::Error>;
+
+    /// Starts reading up to `buf.len()` bytes into `buf`.
+    fn read(&self, buf: Vec<u8>) -> Self::Read;
+
+    /// Starts writing up to `buf.len()` bytes from `buf`.
+    fn write(&self, buf: Vec<u8>) -> Self::Write;
+}
+
+/// Adapts a `CompletionIo` connection to `AsyncRead` and `AsyncWrite`.
+pub struct CompletionStream<S: CompletionIo> {
+    io: S,
+    reading: Option<S::Read>,
+    writing: Option<S::Write>, // data that was accepted but not written yet
+    // data that was read but did not fit into the buffer of `poll_read`
+    read_buf: Vec<u8>,
+    read_offset: usize,
+}
+
+impl<S: CompletionIo> CompletionStream<S> {
+    /// Creates a new `CompletionStream` over the connection `io`.
+    pub fn new(io: S) -> CompletionStream<S> {
+        CompletionStream {
+            io,
+            reading: None,
+            writing: None,
+            read_buf: Vec::new(),
+            read_offset: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying connection.
+    pub fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    /// Consumes the `CompletionStream`, returning the underlying connection.
+    ///
+    /// Returns the `CompletionStream` itself instead if it still has an
+    /// operation in progress or read data that was not consumed yet, which
+    /// would be lost otherwise. A write can be completed via `poll_flush`.
+    pub fn into_inner(self) -> Result<S, CompletionStream<S>> {
+        if self.reading.is_some() || self.writing.is_some() ||
+           self.read_offset < self.read_buf.len() {
+            Err(self)
+        } else {
+            Ok(self.io)
+        }
+    }
+
+    // Drives the write operation in progress, if any, to completion.
+    fn poll_writing(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        loop {
+            let mut writing = match self.writing.take() {
+                Some(writing) => writing,
+                None => return Ok(Ready(())),
+            };
+
+            match writing.poll(cx)? {
+                Ready((mut buf, written)) => {
+                    if written == 0 {
+                        return Err(io::Error::new(ErrorKind::WriteZero,
+                                                  "failed to write buffered data"));
+                    }
+                    if written < buf.len() {
+                        buf.drain(..written);
+                        self.writing = Some(self.io.write(buf));
+                    }
+                }
+                Pending => {
+                    self.writing = Some(writing);
+                    return Ok(Pending);
+                }
+            }
+        }
+    }
+}
+
+impl<S: CompletionIo> AsyncRead for CompletionStream<S> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        if self.read_offset >= self.read_buf.len() {
+            let mut reading = match self.reading.take() {
+                Some(reading) => reading,
+                None => self.io.read(vec![0; buf.len()]),
+            };
+
+            match reading.poll(cx)? {
+                Ready((read_buf, read)) => {
+                    self.read_buf = read_buf;
+                    self.read_buf.truncate(read);
+                    self.read_offset = 0;
+                    if read == 0 {
+                        return Ok(Ready(0));
+                    }
+                }
+                Pending => {
+                    self.reading = Some(reading);
+                    return Ok(Pending);
+                }
+            }
+        }
+
+        let available = &self.read_buf[self.read_offset..];
+        let read = min(buf.len(), available.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.read_offset += read;
+        Ok(Ready(read))
+    }
+}
+
+impl<S: CompletionIo> AsyncWrite for CompletionStream<S> {
+    // Accepts the data of `buf` once the previous write has completed, so
+    // that at most one write is in progress.
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        if let Pending = self.poll_writing(cx)? {
+            return Ok(Pending);
+        }
+        if buf.is_empty() {
+            return Ok(Ready(0));
+        }
+
+        self.writing = Some(self.io.write(buf.to_vec()));
+        // Start the operation right away, it completes on flushing.
+        let _ = self.poll_writing(cx)?;
+        Ok(Ready(buf.len()))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.poll_writing(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.poll_writing(cx)
+    }
+}
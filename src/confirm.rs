@@ -0,0 +1,147 @@
+//! Confirm that both peers derived the same keys before sending data.
+//!
+//! A completed handshake guarantees that the peer knows the keys it claims,
+//! but not that the application on top uses the outcome correctly, e.g. an
+//! outcome that was mixed up with the one of another connection, or a peer
+//! whose key derivation is broken. Such mistakes otherwise only show up as
+//! undecryptable data later on.
+//!
+//! `confirm` exchanges a single encrypted frame in each direction over the
+//! stream, right after the handshake, and fails if the frame of the peer does
+//! not decrypt under the keys of the local outcome. Both peers have to
+//! perform the confirmation, it is not part of the secret-handshake protocol.
+//!
+//! ```rust,ignore
+//! let (outcome, stream) = await!(client)?;
+//! let stream = await!(confirm(stream, &outcome)).map_err(|(err, _)| err)?;
+//! let stream = SecretStream::new(&outcome, stream);
+//! ```
+//!
+//! The frames are encrypted under keys derived via `Outcome::derive_key`,
+//! which are independent of the keys of the session, so the session keys and
+//! nonces remain unused.
+
+use std::io;
+use std::io::ErrorKind::{UnexpectedEof, WriteZero};
+
+use sodiumoxide::crypto::secretbox;
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::Outcome;
+use errors::HandshakeError;
+
+// Prefix of the labels from which the keys of the frames are derived. Each
+// label ends with the longterm public key of the sender of the frame.
+const CONFIRM_LABEL: &'static [u8] = b"shs1-key-confirmation:";
+// The plaintext of each frame.
+const CONFIRM_PLAINTEXT: &'static [u8; 16] = b"shs1-key-confirm";
+// The length of a frame in bytes.
+const FRAME_BYTES: usize = 16 + secretbox::MACBYTES;
+const NONCE: secretbox::Nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+
+/// Exchanges confirmation frames over the `stream`, see the module
+/// documentation.
+pub fn confirm<S>(stream: S, outcome: &Outcome) -> Confirm<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let send_key = frame_key(outcome, &outcome.local_longterm_pk().0);
+    let recv_key = frame_key(outcome, &outcome.peer_longterm_pk().0);
+
+    // Each key encrypts a single frame, so a constant nonce is fine.
+    let mut frame = [0; FRAME_BYTES];
+    frame.copy_from_slice(&secretbox::seal(CONFIRM_PLAINTEXT, &NONCE, &send_key));
+
+    Confirm {
+        stream: Some(stream),
+        frame,
+        written: 0,
+        flushed: false,
+        recv_key,
+        peer_frame: [0; FRAME_BYTES],
+        read: 0,
+    }
+}
+
+fn frame_key(outcome: &Outcome, sender_longterm_pk: &[u8]) -> secretbox::Key {
+    let mut label = Vec::with_capacity(CONFIRM_LABEL.len() + sender_longterm_pk.len());
+    label.extend_from_slice(CONFIRM_LABEL);
+    label.extend_from_slice(sender_longterm_pk);
+
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    outcome.derive_key(&label, &mut key.0);
+    key
+}
+
+/// Future that exchanges confirmation frames, see `confirm`.
+///
+/// Yields the stream once the frame of the peer has been verified. Fails with
+/// `HandshakeError::CryptoError` if it could not be, giving back the stream.
+pub struct Confirm<S> {
+    stream: Option<S>,
+    frame: [u8; FRAME_BYTES],
+    written: usize,
+    flushed: bool,
+    recv_key: secretbox::Key,
+    peer_frame: [u8; FRAME_BYTES],
+    read: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite> Confirm<S> {
+    // Makes progress on writing the own frame and on reading the peer's
+    // frame at the same time, so that peers with small buffers do not
+    // deadlock. Returns whether both are done.
+    fn poll_exchange(&mut self, cx: &mut Context) -> Result<bool, io::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            // The exchange has already completed, stay in that terminal state.
+            None => return Ok(false),
+        };
+
+        while self.written < FRAME_BYTES {
+            match stream.poll_write(cx, &self.frame[self.written..])? {
+                Ready(0) => return Err(io::Error::new(WriteZero, "failed to write frame")),
+                Ready(written) => self.written += written,
+                Pending => break,
+            }
+        }
+        if self.written == FRAME_BYTES && !self.flushed {
+            if let Ready(()) = stream.poll_flush(cx)? {
+                self.flushed = true;
+            }
+        }
+
+        while self.read < FRAME_BYTES {
+            match stream.poll_read(cx, &mut self.peer_frame[self.read..])? {
+                Ready(0) => return Err(io::Error::new(UnexpectedEof, "failed to read frame")),
+                Ready(read) => self.read += read,
+                Pending => break,
+            }
+        }
+
+        Ok(self.flushed && self.read == FRAME_BYTES)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Confirm<S> {
+    type Item = S;
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.poll_exchange(cx) {
+            Ok(false) => return Ok(Pending),
+            Ok(true) => {}
+            Err(err) => {
+                return Err((HandshakeError::IoError(err), self.stream.take().unwrap()));
+            }
+        }
+
+        let stream = self.stream.take().unwrap();
+        match secretbox::open(&self.peer_frame, &NONCE, &self.recv_key) {
+            Ok(ref plaintext) if plaintext[..] == CONFIRM_PLAINTEXT[..] => Ok(Ready(stream)),
+            _ => Err((HandshakeError::CryptoError, stream)),
+        }
+    }
+}
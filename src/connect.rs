@@ -0,0 +1,830 @@
+//! Dial a server and perform the client side of a handshake in one go.
+//!
+//! When a server resolves to both IPv6 and IPv4 addresses, the addresses of
+//! both families are dialed in parallel, and the handshake is performed over
+//! the first connection that is established (RFC 8305, "happy eyeballs").
+//! This way, a broken IPv6 route does not delay the connection until it
+//! times out.
+//!
+//! Host names are resolved via the operating system by default. The `_with`
+//! variants of the connect functions, and `Dialer::set_resolver`, take a
+//! `Resolve` implementation instead, e.g. to use a DNS-over-HTTPS client, a
+//! cache, or a custom scheme for local names.
+//!
+//! Resolving and connecting, and the handshake over the established
+//! connection, can be bounded by separate timeouts, see
+//! `ConnectTcp::set_connect_timeout` and `ConnectTcp::set_handshake_timeout`.
+//! A connect timeout usually means the address is unreachable, while a
+//! handshake timeout points to an overloaded or misbehaving server.
+//!
+//! Connections can optionally be tunneled through a `proxy::Proxy`, e.g. to
+//! reach onion addresses via tor.
+//!
+//! On unix, servers on the same machine can also be reached via unix domain
+//! sockets, see `connect_unix`. For other local transports such as windows
+//! named pipes, hand the connected pipe to an `OwningClientHandshaker`
+//! directly.
+//!
+//! With the `secret-stream` feature, `connect_and_encrypt` and
+//! `accept_and_encrypt` additionally wrap the connections in a
+//! `SecretStream`.
+//!
+//! This module requires the `tokio` feature.
+
+use std::io;
+use std::io::ErrorKind::InvalidInput;
+use std::net::{SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+use std::vec;
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_core::{Poll, Future};
+#[cfg(feature = "secret-stream")]
+use futures_core::{Stream, Never};
+#[cfg(feature = "secret-stream")]
+use futures_core::future::FutureResult;
+use futures_core::Async;
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_core::future::result;
+use futures_io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(feature = "secret-stream")]
+use tokio::net::{TcpListener, Incoming};
+#[cfg(unix)]
+use tokio::net::unix::{UnixStream, ConnectFuture as UnixConnectFuture};
+#[cfg(unix)]
+use transport::{self, Unix};
+
+#[cfg(feature = "secret-stream")]
+use acceptor::Acceptor;
+use client::OwningClientHandshaker;
+use crypto::Outcome;
+use errors::ConnectError;
+use identity::Identity;
+use multiserver::{Address, Transform};
+use proxy::{Proxy, Tunnel};
+use version::Version;
+#[cfg(feature = "secret-stream")]
+use secret_stream::SecretStream;
+
+/// Resolves `addr`, connects to the first of the resolved addresses that
+/// accepts a tcp connection, and performs the client side of a handshake with
+/// the server with the given longterm public key.
+///
+/// The addresses of each address family are tried in order, while the
+/// families are raced against each other, see
+/// `ConnectTcp::set_attempt_delay`.
+///
+/// Resolution is performed synchronously via `ToSocketAddrs`, before this
+/// function returns.
+pub fn connect_tcp<A: ToSocketAddrs>(addr: A,
+                                     identity: &Identity,
+                                     server_longterm_pk: &sign::PublicKey)
+                                     -> ConnectTcp {
+    let (addrs, error) = match addr.to_socket_addrs() {
+        Ok(addrs) => (addrs.collect::<Vec<_>>(), None),
+        Err(err) => (Vec::new(), Some(err)),
+    };
+
+    ConnectTcp::new(addrs, error, identity, server_longterm_pk)
+}
+
+/// Resolves host names to socket addresses, see the module documentation.
+pub trait Resolve {
+    /// Returns a future of the addresses of `host`, with the given `port`.
+    /// Yielding no addresses counts as failing to resolve.
+    fn resolve(&self, host: &str, port: u16) -> Resolving;
+}
+
+/// The future of the addresses of a host, see `Resolve`.
+pub type Resolving = Box<Future<Item = Vec<SocketAddr>, Error = io::Error> + Send>;
+
+/// Resolves host names synchronously via the operating system, like
+/// `connect_tcp` does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Resolving {
+        Box::new(result((host, port).to_socket_addrs().map(|addrs| addrs.collect())))
+    }
+}
+
+/// Resolves `host` via the `resolver`, and then connects and performs a
+/// handshake like `connect_tcp`.
+pub fn connect_tcp_with<R: Resolve + ?Sized>(host: &str,
+                                             port: u16,
+                                             resolver: &R,
+                                             identity: &Identity,
+                                             server_longterm_pk: &sign::PublicKey)
+                                             -> ConnectTcp {
+    let mut connect = ConnectTcp::new(Vec::new(), None, identity, server_longterm_pk);
+    connect.resolving = Some(resolver.resolve(host, port));
+    connect
+}
+
+/// Connects to the `net` transport of a multiserver address and performs a
+/// handshake with the server key of its `shs` transform.
+///
+/// The returned future fails with an error of kind `InvalidInput` if the
+/// address lacks either of them.
+pub fn connect_multiserver(address: &Address, identity: &Identity) -> ConnectTcp {
+    match (address.net(), address.shs()) {
+        (Some(net), Some(server_longterm_pk)) => connect_tcp(net, identity, server_longterm_pk),
+        _ => {
+            let err = io::Error::new(InvalidInput, "address has no net transport or shs key");
+            ConnectTcp::new(Vec::new(), Some(err), identity, &sign::PublicKey([0; 32]))
+        }
+    }
+}
+
+/// Like `connect_multiserver`, but resolves the host via the `resolver`.
+///
+/// Addresses with an `onion` transport are dialed as well, so that the
+/// resolver can map them, e.g. to a local tunnel. To reach onion addresses
+/// via tor, use `connect_multiserver_via_proxy` instead.
+pub fn connect_multiserver_with<R: Resolve + ?Sized>(address: &Address,
+                                                     resolver: &R,
+                                                     identity: &Identity)
+                                                     -> ConnectTcp {
+    match (address.net().or(address.onion()), address.shs()) {
+        (Some((host, port)), Some(server_longterm_pk)) => {
+            connect_tcp_with(host, port, resolver, identity, server_longterm_pk)
+        }
+        _ => {
+            let err = io::Error::new(InvalidInput,
+                                     "address has no net or onion transport or no shs key");
+            ConnectTcp::new(Vec::new(), Some(err), identity, &sign::PublicKey([0; 32]))
+        }
+    }
+}
+
+/// Connects to the `net` transport of a multiserver address and performs a
+/// handshake with the protocol version indicated by its first transform that
+/// names a supported version, e.g. `shs` for version 1.
+///
+/// Yields the version that was used along with the outcome. The returned
+/// future fails with an error of kind `InvalidInput` if the address has no
+/// `net` transport or no transform of a supported version. The latter error
+/// lists the transforms the address does have.
+pub fn connect_negotiated(address: &Address, identity: &Identity) -> ConnectNegotiated {
+    let version = address
+        .transforms
+        .iter()
+        .filter_map(|transform| match *transform {
+                        Transform::Shs { .. } => Some(Version::V1),
+                        Transform::Other(ref protocol) => {
+                            Version::from_multiserver_name(&protocol.name)
+                        }
+                    })
+        .next();
+
+    match version {
+        Some(Version::V1) => {
+            ConnectNegotiated {
+                inner: connect_multiserver(address, identity),
+                version: Version::V1,
+            }
+        }
+        None => {
+            let offered: Vec<&str> = address
+                .transforms
+                .iter()
+                .filter_map(|transform| match *transform {
+                                Transform::Other(ref protocol) => Some(&protocol.name[..]),
+                                Transform::Shs { .. } => None,
+                            })
+                .collect();
+            let err = io::Error::new(InvalidInput,
+                                     format!("address has no supported handshake version, \
+                                              only the transforms [{}]",
+                                             offered.join(", ")));
+            ConnectNegotiated {
+                inner: ConnectTcp::new(Vec::new(), Some(err), identity, &sign::PublicKey([0; 32])),
+                version: Version::V1,
+            }
+        }
+    }
+}
+
+/// Future that connects to a server and performs a handshake of the version
+/// advertised by its address, see `connect_negotiated`.
+pub struct ConnectNegotiated {
+    inner: ConnectTcp,
+    version: Version,
+}
+
+impl Future for ConnectNegotiated {
+    type Item = (Outcome, TcpStream, Version);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll(cx)? {
+            Ready((outcome, stream)) => Ok(Ready((outcome, stream, self.version))),
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Connects to `host:port` through the given `proxy`, and performs the client
+/// side of a handshake with the server with the given longterm public key.
+///
+/// The host name is resolved by the proxy.
+pub fn connect_via_proxy(proxy: &Proxy,
+                         host: &str,
+                         port: u16,
+                         identity: &Identity,
+                         server_longterm_pk: &sign::PublicKey)
+                         -> ConnectTcp {
+    let mut connect = ConnectTcp::new(vec![proxy.addr()], None, identity, server_longterm_pk);
+    connect.target = Some((*proxy, host.to_string(), port));
+    connect
+}
+
+/// Connects to the `net` or `onion` transport of a multiserver address
+/// through the given `proxy`, and performs a handshake with the server key of
+/// its `shs` transform.
+///
+/// The returned future fails with an error of kind `InvalidInput` if the
+/// address lacks either of them.
+pub fn connect_multiserver_via_proxy(address: &Address,
+                                     proxy: &Proxy,
+                                     identity: &Identity)
+                                     -> ConnectTcp {
+    match (address.net().or(address.onion()), address.shs()) {
+        (Some((host, port)), Some(server_longterm_pk)) => {
+            connect_via_proxy(proxy, host, port, identity, server_longterm_pk)
+        }
+        _ => {
+            let err = io::Error::new(InvalidInput,
+                                     "address has no net or onion transport or no shs key");
+            ConnectTcp::new(Vec::new(), Some(err), identity, &sign::PublicKey([0; 32]))
+        }
+    }
+}
+
+/// Like `connect_tcp`, but accepts a server that authenticates with any of the
+/// given longterm public keys, e.g. while it rotates its key.
+///
+/// Since the client has to commit to a server key before learning whether
+/// it is correct, the keys are tried in order, with a new connection for each
+/// of them. The key that authenticated is available via
+/// `Outcome::peer_longterm_pk`. Only failed handshakes lead to trying the
+/// next key, failing to connect at all aborts immediately.
+pub fn connect_tcp_pinned<A: ToSocketAddrs>(addr: A,
+                                            identity: &Identity,
+                                            server_longterm_pks: &[sign::PublicKey])
+                                            -> ConnectTcpPinned {
+    let (addrs, error) = match addr.to_socket_addrs() {
+        Ok(addrs) => (addrs.collect::<Vec<_>>(), None),
+        Err(err) => (Vec::new(), Some(ConnectError::IoError(err))),
+    };
+
+    ConnectTcpPinned {
+        keys: if error.is_some() {
+            Vec::new().into_iter()
+        } else {
+            server_longterm_pks.to_vec().into_iter()
+        },
+        addrs,
+        error,
+        current: None,
+        identity: identity.clone(),
+    }
+}
+
+/// Future that connects to a server and performs the client side of a
+/// handshake, see `connect_tcp`.
+pub struct ConnectTcp {
+    resolving: Option<Resolving>,
+    lanes: Vec<Lane>, // the addresses of each address family
+    dial: Box<Dial>, // starts a connection attempt
+    started: usize, // the number of lanes that have been started
+    attempt_delay: Option<(Duration, Box<MakeDelay>)>,
+    delay: Option<Box<Future<Item = (), Error = ()> + Send>>, // until the next lane is started
+    connect_timeout: Option<(Duration, Box<MakeDelay>)>, // taken once the timer is started
+    handshake_timeout: Option<(Duration, Box<MakeDelay>)>, // taken once the timer is started
+    timeout: Option<Box<Future<Item = (), Error = ()> + Send>>, // of the current phase
+    error: Option<io::Error>, // the error of the most recent failed resolution or connection
+    target: Option<(Proxy, String, u16)>, // where to tunnel to if connecting to a proxy
+    progress: Option<Box<FnMut(Progress) + Send>>,
+    resolved: bool, // whether the resolved addresses have been reported
+    tunneling: Option<Tunnel<TcpStream>>,
+    handshaking: Option<OwningClientHandshaker<TcpStream>>,
+    identity: Identity,
+    server_longterm_pk: sign::PublicKey,
+}
+
+impl ConnectTcp {
+    fn new(addrs: Vec<SocketAddr>,
+           error: Option<io::Error>,
+           identity: &Identity,
+           server_longterm_pk: &sign::PublicKey)
+           -> ConnectTcp {
+        ConnectTcp {
+            resolving: None,
+            lanes: lanes(addrs),
+            dial: Box::new(|addr: &SocketAddr| Box::new(TcpStream::connect(addr)) as Dialing),
+            started: 0,
+            attempt_delay: None,
+            delay: None,
+            connect_timeout: None,
+            handshake_timeout: None,
+            timeout: None,
+            error,
+            target: None,
+            progress: None,
+            resolved: false,
+            tunneling: None,
+            handshaking: None,
+            identity: identity.clone(),
+            server_longterm_pk: server_longterm_pk.clone(),
+        }
+    }
+
+    /// Start dialing the next address family only once the attempts of the
+    /// previous one did not succeed within `attempt_delay`, rather than
+    /// right away. `make_delay` is called to create a delay future of your
+    /// runtime, a delay that errors counts as elapsed.
+    ///
+    /// RFC 8305 recommends 250 milliseconds, which prefers the first family,
+    /// usually IPv6, while barely delaying connections over the other one.
+    pub fn set_attempt_delay<F>(&mut self, attempt_delay: Duration, make_delay: F)
+        where F: FnMut(Duration) -> Box<Future<Item = (), Error = ()> + Send> + Send + 'static
+    {
+        self.attempt_delay = Some((attempt_delay, Box::new(make_delay)));
+    }
+
+    /// Fail with `ConnectError::ConnectTimedOut` if resolving the address,
+    /// connecting, and tunneling through a proxy do not complete within
+    /// `timeout` after the future is first polled. `make_delay` is called to
+    /// create a delay future of your runtime, a delay that errors counts as
+    /// elapsed.
+    pub fn set_connect_timeout<F>(&mut self, timeout: Duration, make_delay: F)
+        where F: FnMut(Duration) -> Box<Future<Item = (), Error = ()> + Send> + Send + 'static
+    {
+        self.connect_timeout = Some((timeout, Box::new(make_delay)));
+    }
+
+    /// Fail with `ConnectError::HandshakeTimedOut` if the handshake does not
+    /// complete within `timeout` after the connection was established.
+    /// `make_delay` is called to create a delay future of your runtime, a
+    /// delay that errors counts as elapsed.
+    pub fn set_handshake_timeout<F>(&mut self, timeout: Duration, make_delay: F)
+        where F: FnMut(Duration) -> Box<Future<Item = (), Error = ()> + Send> + Send + 'static
+    {
+        self.handshake_timeout = Some((timeout, Box::new(make_delay)));
+    }
+
+    // Starts connection attempts via `dial` instead of `TcpStream::connect`.
+    #[cfg(test)]
+    pub(crate) fn set_dial<F>(&mut self, dial: F)
+        where F: FnMut(&SocketAddr) -> Dialing + Send + 'static
+    {
+        self.dial = Box::new(dial);
+    }
+
+    // Calls `progress` at each step of connecting, see `Progress`.
+    pub(crate) fn set_progress<F: FnMut(Progress) + Send + 'static>(&mut self, progress: F) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    // Starts the handshake over the established connection, replacing the
+    // connect timer with the handshake timer.
+    fn start_handshake(&mut self, stream: TcpStream) {
+        if let Some(ref mut progress) = self.progress {
+            progress(Progress::Handshaking(stream.peer_addr().ok()));
+        }
+        self.handshaking = Some(handshake(stream, &self.identity, &self.server_longterm_pk));
+        self.timeout = self.handshake_timeout
+            .take()
+            .map(|(timeout, mut make_delay)| make_delay(timeout));
+    }
+
+    // Returns whether the timer of the current phase has elapsed.
+    fn poll_timeout(&mut self, cx: &mut Context) -> bool {
+        match self.timeout {
+            Some(ref mut timeout) => {
+                match timeout.poll(cx) {
+                    Ok(Pending) => false,
+                    Ok(Ready(())) | Err(()) => true,
+                }
+            }
+            None => false,
+        }
+    }
+
+    // Dials the lanes, and yields the first connection that is established.
+    fn poll_connect(&mut self, cx: &mut Context) -> Async<io::Result<TcpStream>> {
+        loop {
+            let mut pending = false;
+            let mut connected = None;
+            for lane in self.lanes[..self.started].iter_mut() {
+                match lane.poll(cx, &mut *self.dial, &mut self.error) {
+                    Ready(Some(stream)) => {
+                        connected = Some(stream);
+                        break;
+                    }
+                    Ready(None) => {}
+                    Pending => pending = true,
+                }
+            }
+
+            if let Some(stream) = connected {
+                // Abandon the attempts of the other lanes.
+                self.lanes.clear();
+                self.started = 0;
+                self.delay = None;
+                return Ready(Ok(stream));
+            }
+
+            if self.started == self.lanes.len() {
+                if pending {
+                    return Pending;
+                }
+                let err = self.error
+                    .take()
+                    .unwrap_or_else(|| {
+                                        io::Error::new(InvalidInput,
+                                                       "could not resolve to any address")
+                                    });
+                return Ready(Err(err));
+            }
+
+            // Start the next lane right away if all started ones failed,
+            // otherwise once the attempt delay elapsed.
+            let start = !pending ||
+                        match self.delay {
+                            Some(ref mut delay) => {
+                                match delay.poll(cx) {
+                                    Ok(Pending) => false,
+                                    Ok(Ready(())) | Err(()) => true,
+                                }
+                            }
+                            None => self.attempt_delay.is_none(),
+                        };
+            if !start {
+                return Pending;
+            }
+
+            self.started += 1;
+            let more = self.started < self.lanes.len();
+            self.delay = if more {
+                self.attempt_delay
+                    .as_mut()
+                    .map(|&mut (attempt_delay, ref mut make_delay)| make_delay(attempt_delay))
+            } else {
+                None
+            };
+        }
+    }
+}
+
+// The steps of a `ConnectTcp`, as reported to its progress function.
+pub(crate) enum Progress<'a> {
+    // The addresses that are dialed, grouped by address family. For a
+    // proxied connection, this is the address of the proxy.
+    Resolved(&'a [SocketAddr]),
+    // A connection has been established, and the handshake starts over it.
+    Handshaking(Option<SocketAddr>),
+}
+
+// Creates the delay futures between starting lanes.
+type MakeDelay = FnMut(Duration) -> Box<Future<Item = (), Error = ()> + Send> + Send;
+
+// A connection attempt.
+pub(crate) type Dialing = Box<Future<Item = TcpStream, Error = io::Error> + Send>;
+
+// Starts connection attempts.
+type Dial = FnMut(&SocketAddr) -> Dialing + Send;
+
+// The addresses of one address family, which are tried in order.
+struct Lane {
+    addrs: vec::IntoIter<SocketAddr>,
+    connecting: Option<Dialing>,
+}
+
+impl Lane {
+    // Yields the first connection that is established via `dial`, or `None`
+    // once connecting to all addresses failed. Errors are stored in `error`.
+    fn poll(&mut self,
+            cx: &mut Context,
+            dial: &mut Dial,
+            error: &mut Option<io::Error>)
+            -> Async<Option<TcpStream>> {
+        loop {
+            if let Some(mut connecting) = self.connecting.take() {
+                match connecting.poll(cx) {
+                    Ok(Ready(stream)) => return Ready(Some(stream)),
+                    Ok(Pending) => {
+                        self.connecting = Some(connecting);
+                        return Pending;
+                    }
+                    Err(err) => *error = Some(err),
+                }
+            }
+
+            match self.addrs.next() {
+                Some(addr) => self.connecting = Some(dial(&addr)),
+                None => return Ready(None),
+            }
+        }
+    }
+}
+
+// Splits the addresses into one lane per address family, keeping their
+// order. The family of the first address comes first.
+fn lanes(addrs: Vec<SocketAddr>) -> Vec<Lane> {
+    let ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return Vec::new(),
+    };
+    let (first, second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == ipv6);
+
+    let mut lanes = vec![first];
+    if !second.is_empty() {
+        lanes.push(second);
+    }
+    lanes
+        .into_iter()
+        .map(|addrs| {
+                 Lane {
+                     addrs: addrs.into_iter(),
+                     connecting: None,
+                 }
+             })
+        .collect()
+}
+
+impl Future for ConnectTcp {
+    type Item = (Outcome, TcpStream);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        if let Some((timeout, mut make_delay)) = self.connect_timeout.take() {
+            self.timeout = Some(make_delay(timeout));
+        }
+
+        loop {
+            if self.poll_timeout(cx) {
+                return Err(if self.handshaking.is_some() {
+                               ConnectError::HandshakeTimedOut
+                           } else {
+                               ConnectError::ConnectTimedOut
+                           });
+            }
+
+            if let Some(ref mut handshaker) = self.handshaking {
+                return handshaker
+                           .poll(cx)
+                           .map_err(|(err, _)| ConnectError::Handshake(err));
+            }
+
+            if let Some(mut tunneling) = self.tunneling.take() {
+                match tunneling.poll(cx) {
+                    Ok(Ready(stream)) => {
+                        self.start_handshake(stream);
+                        continue;
+                    }
+                    Ok(Pending) => {
+                        self.tunneling = Some(tunneling);
+                        return Ok(Pending);
+                    }
+                    Err(err) => return Err(ConnectError::IoError(err)),
+                }
+            }
+
+            if let Some(mut resolving) = self.resolving.take() {
+                match resolving.poll(cx) {
+                    Ok(Ready(addrs)) => self.lanes = lanes(addrs),
+                    Ok(Pending) => {
+                        self.resolving = Some(resolving);
+                        return Ok(Pending);
+                    }
+                    Err(err) => return Err(ConnectError::IoError(err)),
+                }
+            }
+
+            if !self.resolved {
+                self.resolved = true;
+                // A failed resolution is reported as the error of the future.
+                if let Some(ref mut progress) = self.progress {
+                    if self.error.is_none() {
+                        let addrs: Vec<SocketAddr> = self.lanes
+                            .iter()
+                            .flat_map(|lane| lane.addrs.as_slice().iter().cloned())
+                            .collect();
+                        progress(Progress::Resolved(&addrs));
+                    }
+                }
+            }
+
+            match self.poll_connect(cx) {
+                Ready(Ok(stream)) => {
+                    if let Some((ref proxy, ref host, port)) = self.target {
+                        self.tunneling = Some(proxy.tunnel(stream, host, port));
+                        continue;
+                    }
+                    self.start_handshake(stream);
+                }
+                Ready(Err(err)) => return Err(ConnectError::IoError(err)),
+                Pending => return Ok(Pending),
+            }
+        }
+    }
+}
+
+/// Future that connects to a server under one of several keys, see
+/// `connect_tcp_pinned`.
+pub struct ConnectTcpPinned {
+    addrs: Vec<SocketAddr>,
+    keys: vec::IntoIter<sign::PublicKey>,
+    error: Option<ConnectError>, // the error of the most recent failed attempt
+    current: Option<ConnectTcp>,
+    identity: Identity,
+}
+
+impl Future for ConnectTcpPinned {
+    type Item = (Outcome, TcpStream);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut current) = self.current.take() {
+                match current.poll(cx) {
+                    Ok(Ready(connection)) => return Ok(Ready(connection)),
+                    Ok(Pending) => {
+                        self.current = Some(current);
+                        return Ok(Pending);
+                    }
+                    Err(ConnectError::Handshake(err)) => {
+                        self.error = Some(ConnectError::Handshake(err))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+
+            match self.keys.next() {
+                Some(key) => {
+                    self.current = Some(ConnectTcp::new(self.addrs.clone(),
+                                                        None,
+                                                        &self.identity,
+                                                        &key))
+                }
+                None => {
+                    return Err(self.error.take().unwrap_or_else(|| {
+                        ConnectError::IoError(io::Error::new(InvalidInput,
+                                                             "no server keys given"))
+                    }))
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the unix domain socket at `path`, and performs the client side
+/// of a handshake with the server with the given longterm public key.
+///
+/// This is `transport::connect` over the `transport::Unix` transport.
+#[cfg(unix)]
+pub fn connect_unix<P: AsRef<Path>>(path: P,
+                                    identity: &Identity,
+                                    server_longterm_pk: &sign::PublicKey)
+                                    -> ConnectUnix {
+    transport::connect(&mut Unix, path.as_ref(), identity, server_longterm_pk)
+}
+
+/// Future that connects to a unix domain socket and performs the client side
+/// of a handshake, see `connect_unix`.
+#[cfg(unix)]
+pub type ConnectUnix = transport::Connect<UnixConnectFuture, UnixStream>;
+
+/// Resolves `addr`, connects to it and performs a handshake like
+/// `connect_tcp`, and then wraps the connection in a `SecretStream`.
+///
+/// Yields the encrypted stream together with the longterm public key of the
+/// server.
+#[cfg(feature = "secret-stream")]
+pub fn connect_and_encrypt<A: ToSocketAddrs>(addr: A,
+                                             identity: &Identity,
+                                             server_longterm_pk: &sign::PublicKey)
+                                             -> ConnectAndEncrypt {
+    ConnectAndEncrypt(connect_tcp(addr, identity, server_longterm_pk))
+}
+
+/// Future that connects to a server, performs a handshake and yields an
+/// encrypted stream, see `connect_and_encrypt`.
+#[cfg(feature = "secret-stream")]
+pub struct ConnectAndEncrypt(ConnectTcp);
+
+#[cfg(feature = "secret-stream")]
+impl Future for ConnectAndEncrypt {
+    type Item = (SecretStream<TcpStream>, sign::PublicKey);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll(cx)? {
+            Ready((outcome, stream)) => {
+                Ok(Ready((SecretStream::new(&outcome, stream), outcome.peer_longterm_pk())))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Accepts connections on the `listener`, performs the server side of a
+/// handshake on each of them via an `Acceptor`, and wraps the authenticated
+/// connections in a `SecretStream`.
+///
+/// Yields the encrypted streams together with the longterm public key and
+/// the address of the client.
+#[cfg(feature = "secret-stream")]
+pub fn accept_and_encrypt(listener: TcpListener, identity: &Identity) -> AcceptAndEncrypt {
+    AcceptAndEncrypt(Acceptor::new(IncomingWithAddr(listener.incoming()), identity.clone()))
+}
+
+/// Stream of encrypted connections, see `accept_and_encrypt`.
+#[cfg(feature = "secret-stream")]
+pub struct AcceptAndEncrypt(Acceptor<IncomingWithAddr,
+                                     TcpStream,
+                                     fn(&sign::PublicKey) -> FutureResult<bool, Never>,
+                                     FutureResult<bool, Never>>);
+
+#[cfg(feature = "secret-stream")]
+impl AcceptAndEncrypt {
+    /// Gets a mutable reference to the underlying `Acceptor`, e.g. to attach
+    /// a rate limiter or to obtain a shutdown handle.
+    pub fn acceptor_mut(&mut self)
+                        -> &mut Acceptor<IncomingWithAddr,
+                                         TcpStream,
+                                         fn(&sign::PublicKey) -> FutureResult<bool, Never>,
+                                         FutureResult<bool, Never>> {
+        &mut self.0
+    }
+}
+
+#[cfg(feature = "secret-stream")]
+impl Stream for AcceptAndEncrypt {
+    type Item = (SecretStream<TcpStream>, sign::PublicKey, SocketAddr);
+    type Error = io::Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.0.poll_next(cx)? {
+            Ready(Some((outcome, stream, addr))) => {
+                Ok(Ready(Some((SecretStream::new(&outcome, stream),
+                               outcome.peer_longterm_pk(),
+                               addr))))
+            }
+            Ready(None) => Ok(Ready(None)),
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// The connections accepted by a tcp listener, together with their remote
+/// address, as required by an `Acceptor`.
+#[cfg(feature = "secret-stream")]
+pub struct IncomingWithAddr(Incoming);
+
+#[cfg(feature = "secret-stream")]
+impl Stream for IncomingWithAddr {
+    type Item = (TcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match self.0.poll_next(cx)? {
+                Ready(Some(stream)) => {
+                    // Skip connections that were closed before their address could be queried.
+                    if let Ok(addr) = stream.peer_addr() {
+                        return Ok(Ready(Some((stream, addr))));
+                    }
+                }
+                Ready(None) => return Ok(Ready(None)),
+                Pending => return Ok(Pending),
+            }
+        }
+    }
+}
+
+// Creates a handshaker over a newly established connection, using fresh
+// ephemeral keys.
+fn handshake<S>(stream: S,
+                identity: &Identity,
+                server_longterm_pk: &sign::PublicKey)
+                -> OwningClientHandshaker<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    OwningClientHandshaker::new(stream,
+                                *identity.network_identifier(),
+                                identity.longterm_pk().clone(),
+                                identity.longterm_sk().clone(),
+                                ephemeral_pk,
+                                ephemeral_sk,
+                                server_longterm_pk.clone())
+}
@@ -1,15 +1,42 @@
 //! Low-level bindings to shs1-c. You probably don't need to use this
 //! module directly.
 
+use std::fmt::{self, Debug, Formatter};
 use std::mem::uninitialized;
 
 use sodiumoxide::crypto::{box_, sign, scalarmult, secretbox, auth};
+use sodiumoxide::crypto::auth::hmacsha256;
 use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::utils::memzero;
 
+use encoding::KeyEncoding;
+use messages::{ClientHello, ServerHello, ClientAuth, ServerAck};
+
 /// Length of a network identifier in bytes.
 pub const NETWORK_IDENTIFIER_BYTES: usize = 32;
 
+// Prefix of the HKDF info of `Outcome::derive_key`.
+const DERIVE_KEY_INFO: &'static [u8] = b"shs1-derive-key";
+
+// Prefix of the names hashed by `network_identifier_from_name`.
+const NETWORK_NAME_PREFIX: &'static [u8] = b"shs1-network-name:";
+
+/// Derives a network identifier from a name of any length, e.g. the
+/// human-readable name of a private network.
+///
+/// The identifier is the SHA-256 hash of `"shs1-network-name:"` followed by
+/// the `name`. The prefix separates these identifiers from other uses of
+/// SHA-256 on the same names, so all peers of a network must derive the
+/// identifier this way. Since anyone who knows the name can derive the
+/// identifier, use a name that is hard to guess if the network should be
+/// private.
+pub fn network_identifier_from_name(name: &[u8]) -> [u8; NETWORK_IDENTIFIER_BYTES] {
+    let mut input = Vec::with_capacity(NETWORK_NAME_PREFIX.len() + name.len());
+    input.extend_from_slice(NETWORK_NAME_PREFIX);
+    input.extend_from_slice(name);
+    sha256::hash(&input).0
+}
+
 /// Length of msg1 in bytes.
 pub const MSG1_BYTES: usize = 64;
 /// Length of msg2 in bytes.
@@ -19,56 +46,535 @@ pub const MSG3_BYTES: usize = 112;
 /// Length of msg4 in bytes.
 pub const MSG4_BYTES: usize = 80;
 
+// Stands in for secret data in `Debug` output.
+pub(crate) struct Redacted;
+
+impl Debug for Redacted {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "<redacted>")
+    }
+}
+
 /// The data resulting from a handshake: Keys and nonces suitable for encrypted
-/// two-way communication with the peer via box-stream-rs, and the longterm
-/// public key of the peer.
+/// two-way communication with the peer via box-stream-rs, the longterm
+/// public key of the peer, and the network identifier and own longterm public
+/// key that were used.
+///
+/// With the `box-stream` feature, `upgrade::upgrade` wraps the connection in
+/// a box-stream using these keys.
+///
+/// The `Debug` output shows the public keys, but redacts the keys, nonces and
+/// the network identifier.
+///
+/// With the `serialize-outcome` feature, outcomes implement serde's
+/// `Serialize` and `Deserialize`, e.g. to pass an established session to a
+/// worker process. The serialized form contains the session keys.
 #[repr(C)]
-#[derive(Debug)]
 pub struct Outcome {
-    encryption_key: [u8; secretbox::KEYBYTES],
-    encryption_nonce: [u8; secretbox::NONCEBYTES],
+    // `repr(C)` params, laid out like the key and nonce arrays of the C struct.
+    send: EncryptionParams,
     padding_encryption: [u8; 8],
-    decryption_key: [u8; secretbox::KEYBYTES],
-    decryption_nonce: [u8; secretbox::NONCEBYTES],
+    recv: DecryptionParams,
     padding_decryption: [u8; 8],
     peer_longterm_pk: [u8; sign::PUBLICKEYBYTES],
+    // Not part of the C struct, filled in on the Rust side.
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    local_longterm_pk: [u8; sign::PUBLICKEYBYTES],
+    local_ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+    peer_ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+}
+
+impl Debug for Outcome {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Outcome")
+            .field("send", &self.send)
+            .field("recv", &self.recv)
+            .field("peer_longterm_pk", &self.peer_longterm_pk)
+            .field("network_identifier", &Redacted)
+            .field("local_longterm_pk", &self.local_longterm_pk)
+            .field("local_ephemeral_pk", &self.local_ephemeral_pk)
+            .field("peer_ephemeral_pk", &self.peer_ephemeral_pk)
+            .finish()
+    }
 }
 
 /// Zero out all sensitive data when going out of scope
 impl Drop for Outcome {
     fn drop(&mut self) {
-        memzero(&mut self.encryption_key);
-        memzero(&mut self.encryption_nonce);
-        memzero(&mut self.decryption_key);
-        memzero(&mut self.decryption_nonce);
+        memzero(&mut self.send.key.0);
+        memzero(&mut self.send.nonce.0);
+        memzero(&mut self.recv.key.0);
+        memzero(&mut self.recv.nonce.0);
+        memzero(&mut self.network_identifier);
     }
 }
 
 impl Outcome {
-    /// The negotiated key that should be used to encrypt messages to the peer.
-    pub fn encryption_key(&self) -> secretbox::Key {
-        secretbox::Key(self.encryption_key)
+    /// The negotiated key and initial nonce that should be used to encrypt
+    /// messages sent to the peer.
+    ///
+    /// This returns a copy of the key, use `send_params` or `into_keys` to
+    /// avoid it.
+    pub fn send(&self) -> EncryptionParams {
+        self.send.clone()
     }
 
-    /// The negotiated initial nonce that should be used to encrypt messages to the peer.
-    pub fn encryption_nonce(&self) -> secretbox::Nonce {
-        secretbox::Nonce(self.encryption_nonce)
+    /// The negotiated key and initial nonce that should be used to decrypt
+    /// messages received from the peer.
+    ///
+    /// This returns a copy of the key, use `recv_params` or `into_keys` to
+    /// avoid it.
+    pub fn recv(&self) -> DecryptionParams {
+        self.recv.clone()
     }
 
-    /// The negotiated key that should be used to decrypt messages from the peer.
-    pub fn decryption_key(&self) -> secretbox::Key {
-        secretbox::Key(self.decryption_key)
+    /// Borrows the negotiated key and initial nonce that should be used to
+    /// encrypt messages sent to the peer.
+    pub fn send_params(&self) -> &EncryptionParams {
+        &self.send
     }
 
-    /// The negotiated initial nonce that should be used to decrypt messages from the peer.
-    pub fn decryption_nonce(&self) -> secretbox::Nonce {
-        secretbox::Nonce(self.decryption_nonce)
+    /// Borrows the negotiated key and initial nonce that should be used to
+    /// decrypt messages received from the peer.
+    pub fn recv_params(&self) -> &DecryptionParams {
+        &self.recv
+    }
+
+    /// Consumes the outcome and returns its keys and nonces. The outcome's
+    /// own copy is zeroed, so the returned `SessionKeys` hold the only copy
+    /// of the keys, which is in turn zeroed when they are dropped.
+    pub fn into_keys(self) -> SessionKeys {
+        SessionKeys {
+            send: self.send(),
+            recv: self.recv(),
+        }
+    }
+
+    /// Consumes the outcome and splits it into the sending and the receiving
+    /// direction of the session, e.g. to hand them to a writer and a reader
+    /// task. Like `into_keys`, this zeroes the outcome's own copy of the
+    /// keys.
+    ///
+    /// The halves are deliberately not `Clone`: two copies of the same
+    /// direction would encrypt different messages under the same nonces.
+    pub fn split(self) -> (SendHalf, RecvHalf) {
+        let peer_longterm_pk = self.peer_longterm_pk();
+        (SendHalf {
+             params: self.send(),
+             peer_longterm_pk,
+         },
+         RecvHalf {
+             params: self.recv(),
+             peer_longterm_pk,
+         })
+    }
+
+    /// Consumes the outcome and returns only the authenticated public keys,
+    /// for connections that are already encrypted by other means. The keys
+    /// and nonces of the session are zeroed right away.
+    pub fn into_authenticated(self) -> Authenticated {
+        Authenticated {
+            peer_longterm_pk: self.peer_longterm_pk(),
+            local_longterm_pk: self.local_longterm_pk(),
+        }
+    }
+
+    /// Derives additional key material bound to this session into `out`,
+    /// e.g. keys for a side channel. Both peers derive the same bytes for the
+    /// same `label`, different labels yield independent keys.
+    ///
+    /// This is HKDF with HMAC-SHA-256 (RFC 5869). The input keying material
+    /// is the concatenation of the two session keys, starting with the key
+    /// for encrypting the messages of the peer with the smaller (in
+    /// lexicographic order) ephemeral public key, so that it does not depend
+    /// on the direction. The salt is the network identifier, and the info is
+    /// `"shs1-derive-key"` followed by the `label`.
+    ///
+    /// Panics if `out` is longer than `255 * 32` bytes.
+    pub fn derive_key(&self, label: &[u8], out: &mut [u8]) {
+        assert!(out.len() <= 255 * hmacsha256::TAGBYTES,
+                "can not derive more than 8160 bytes");
+
+        // Ordered by public data, so that no branch depends on the keys.
+        let (first, second) = if self.local_ephemeral_pk < self.peer_ephemeral_pk {
+            (&self.send.key.0, &self.recv.key.0)
+        } else {
+            (&self.recv.key.0, &self.send.key.0)
+        };
+        let mut ikm = [0; 2 * secretbox::KEYBYTES];
+        ikm[..secretbox::KEYBYTES].copy_from_slice(first);
+        ikm[secretbox::KEYBYTES..].copy_from_slice(second);
+
+        // extract
+        let mut prk = hmacsha256::authenticate(&ikm,
+                                               &hmacsha256::Key(self.network_identifier));
+        memzero(&mut ikm);
+        let prk_key = hmacsha256::Key(prk.0);
+        memzero(&mut prk.0);
+
+        // expand
+        let mut input = Vec::with_capacity(hmacsha256::TAGBYTES + DERIVE_KEY_INFO.len() +
+                                           label.len() + 1);
+        let mut previous = [0; hmacsha256::TAGBYTES];
+        for (i, chunk) in out.chunks_mut(hmacsha256::TAGBYTES).enumerate() {
+            input.clear();
+            if i > 0 {
+                input.extend_from_slice(&previous);
+            }
+            input.extend_from_slice(DERIVE_KEY_INFO);
+            input.extend_from_slice(label);
+            input.push(i as u8 + 1);
+
+            let mut block = hmacsha256::authenticate(&input, &prk_key);
+            previous = block.0;
+            memzero(&mut block.0);
+            chunk.copy_from_slice(&previous[..chunk.len()]);
+        }
+        memzero(&mut previous);
+        memzero(&mut input);
     }
 
     /// The longterm public key of the peer.
     pub fn peer_longterm_pk(&self) -> sign::PublicKey {
         sign::PublicKey(self.peer_longterm_pk)
     }
+
+    /// The ssb feed id of the peer, which displays as `@<base64>.ed25519`.
+    pub fn peer_id(&self) -> PeerId {
+        PeerId(self.peer_longterm_pk())
+    }
+
+    /// The ssb feed id of the peer as a string, such as
+    /// `@Mhz+WRi3Uk1V4mRN/aMaNb5uWQmm2SbuXvJDEeSgXDs=.ed25519`.
+    pub fn peer_id_string(&self) -> String {
+        self.peer_id().to_string()
+    }
+
+    /// The network identifier under which the handshake was performed. For a
+    /// server accepting multiple network identifiers, this is the one the
+    /// client used.
+    pub fn network_identifier(&self) -> [u8; NETWORK_IDENTIFIER_BYTES] {
+        self.network_identifier
+    }
+
+    /// The own longterm public key used in the handshake. For a server with
+    /// multiple identities, this is the identity the client addressed.
+    pub fn local_longterm_pk(&self) -> sign::PublicKey {
+        sign::PublicKey(self.local_longterm_pk)
+    }
+
+    /// The ephemeral public key the peer used in the handshake.
+    pub fn peer_ephemeral_pk(&self) -> box_::PublicKey {
+        box_::PublicKey(self.peer_ephemeral_pk)
+    }
+
+    /// The own ephemeral public key used in the handshake.
+    pub fn local_ephemeral_pk(&self) -> box_::PublicKey {
+        box_::PublicKey(self.local_ephemeral_pk)
+    }
+
+    // Assembles the outcome of a session that was resumed via a ticket. The
+    // pairs are the longterm and ephemeral public keys of either side.
+    #[cfg(feature = "session-tickets")]
+    pub(crate) fn resumed(send: &EncryptionParams,
+                          recv: &DecryptionParams,
+                          network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                          local: (&sign::PublicKey, &box_::PublicKey),
+                          peer: (&sign::PublicKey, &box_::PublicKey))
+                          -> Outcome {
+        Outcome {
+            send: send.clone(),
+            padding_encryption: [0; 8],
+            recv: recv.clone(),
+            padding_decryption: [0; 8],
+            peer_longterm_pk: (peer.0).0,
+            network_identifier,
+            local_longterm_pk: (local.0).0,
+            local_ephemeral_pk: (local.1).0,
+            peer_ephemeral_pk: (peer.1).0,
+        }
+    }
+}
+
+// Serialization of outcomes, for handing connections over to other processes.
+//
+// An outcome is serialized as a single byte string of the concatenated
+// encryption key and nonce, decryption key and nonce, peer longterm public
+// key, network identifier, local longterm public key, local ephemeral public
+// key and peer ephemeral public key. Intermediate buffers are zeroed.
+#[cfg(feature = "serialize-outcome")]
+mod serialize {
+    use std::fmt::{self, Formatter};
+
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use serde::de::{self, Visitor, SeqAccess};
+    use sodiumoxide::crypto::{box_, sign, secretbox};
+    use sodiumoxide::utils::memzero;
+
+    use super::{Outcome, EncryptionParams, DecryptionParams, NETWORK_IDENTIFIER_BYTES};
+
+    const SERIALIZED_BYTES: usize = 2 * (secretbox::KEYBYTES + secretbox::NONCEBYTES) +
+                                    2 * sign::PUBLICKEYBYTES +
+                                    NETWORK_IDENTIFIER_BYTES +
+                                    2 * box_::PUBLICKEYBYTES;
+
+    fn to_bytes(outcome: &Outcome, bytes: &mut [u8; SERIALIZED_BYTES]) {
+        let fields: [&[u8]; 9] = [&outcome.send.key.0,
+                                  &outcome.send.nonce.0,
+                                  &outcome.recv.key.0,
+                                  &outcome.recv.nonce.0,
+                                  &outcome.peer_longterm_pk,
+                                  &outcome.network_identifier,
+                                  &outcome.local_longterm_pk,
+                                  &outcome.local_ephemeral_pk,
+                                  &outcome.peer_ephemeral_pk];
+
+        let mut offset = 0;
+        for field in fields.iter() {
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            offset += field.len();
+        }
+    }
+
+    // Zeroes `bytes` after reading them.
+    fn from_bytes(bytes: &mut [u8; SERIALIZED_BYTES]) -> Outcome {
+        let mut outcome = Outcome {
+            send: EncryptionParams {
+                key: secretbox::Key([0; secretbox::KEYBYTES]),
+                nonce: secretbox::Nonce([0; secretbox::NONCEBYTES]),
+            },
+            padding_encryption: [0; 8],
+            recv: DecryptionParams {
+                key: secretbox::Key([0; secretbox::KEYBYTES]),
+                nonce: secretbox::Nonce([0; secretbox::NONCEBYTES]),
+            },
+            padding_decryption: [0; 8],
+            peer_longterm_pk: [0; sign::PUBLICKEYBYTES],
+            network_identifier: [0; NETWORK_IDENTIFIER_BYTES],
+            local_longterm_pk: [0; sign::PUBLICKEYBYTES],
+            local_ephemeral_pk: [0; box_::PUBLICKEYBYTES],
+            peer_ephemeral_pk: [0; box_::PUBLICKEYBYTES],
+        };
+
+        {
+            let mut fields: [&mut [u8]; 9] = [&mut outcome.send.key.0,
+                                              &mut outcome.send.nonce.0,
+                                              &mut outcome.recv.key.0,
+                                              &mut outcome.recv.nonce.0,
+                                              &mut outcome.peer_longterm_pk,
+                                              &mut outcome.network_identifier,
+                                              &mut outcome.local_longterm_pk,
+                                              &mut outcome.local_ephemeral_pk,
+                                              &mut outcome.peer_ephemeral_pk];
+
+            let mut offset = 0;
+            for field in fields.iter_mut() {
+                let len = field.len();
+                field.copy_from_slice(&bytes[offset..offset + len]);
+                offset += len;
+            }
+        }
+
+        memzero(bytes);
+        outcome
+    }
+
+    /// Serializes as a byte string. This writes the session keys, only use
+    /// it to hand a connection to a trusted process.
+    impl Serialize for Outcome {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut bytes = [0; SERIALIZED_BYTES];
+            to_bytes(self, &mut bytes);
+            let result = serializer.serialize_bytes(&bytes);
+            memzero(&mut bytes);
+            result
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Outcome {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Outcome, D::Error> {
+            deserializer.deserialize_bytes(OutcomeVisitor)
+        }
+    }
+
+    struct OutcomeVisitor;
+
+    impl<'de> Visitor<'de> for OutcomeVisitor {
+        type Value = Outcome;
+
+        fn expecting(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+            write!(f, "a serialized outcome of {} bytes", SERIALIZED_BYTES)
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Outcome, E> {
+            if v.len() != SERIALIZED_BYTES {
+                return Err(E::invalid_length(v.len(), &self));
+            }
+
+            let mut bytes = [0; SERIALIZED_BYTES];
+            bytes.copy_from_slice(v);
+            Ok(from_bytes(&mut bytes))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, mut v: Vec<u8>) -> Result<Outcome, E> {
+            let result = self.visit_bytes(&v);
+            memzero(&mut v);
+            result
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Outcome, A::Error> {
+            let mut bytes = [0; SERIALIZED_BYTES];
+            let mut len = 0;
+            loop {
+                match seq.next_element::<u8>() {
+                    Ok(Some(byte)) if len < SERIALIZED_BYTES => {
+                        bytes[len] = byte;
+                        len += 1;
+                    }
+                    Ok(Some(_)) => len += 1,
+                    Ok(None) => break,
+                    Err(err) => {
+                        memzero(&mut bytes);
+                        return Err(err);
+                    }
+                }
+            }
+
+            if len != SERIALIZED_BYTES {
+                memzero(&mut bytes);
+                return Err(de::Error::invalid_length(len, &self));
+            }
+            Ok(from_bytes(&mut bytes))
+        }
+    }
+}
+
+/// The key and initial nonce for encrypting messages sent to the peer, see
+/// `Outcome::send`.
+#[derive(Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct EncryptionParams {
+    /// The negotiated key that should be used to encrypt messages to the peer.
+    pub key: secretbox::Key,
+    /// The negotiated initial nonce that should be used to encrypt messages to the peer.
+    pub nonce: secretbox::Nonce,
+}
+
+/// The key and initial nonce for decrypting messages received from the peer,
+/// see `Outcome::recv`.
+#[derive(Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct DecryptionParams {
+    /// The negotiated key that should be used to decrypt messages from the peer.
+    pub key: secretbox::Key,
+    /// The negotiated initial nonce that should be used to decrypt messages from the peer.
+    pub nonce: secretbox::Nonce,
+}
+
+impl Debug for EncryptionParams {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("EncryptionParams")
+            .field("key", &Redacted)
+            .field("nonce", &Redacted)
+            .finish()
+    }
+}
+
+impl Debug for DecryptionParams {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("DecryptionParams")
+            .field("key", &Redacted)
+            .field("nonce", &Redacted)
+            .finish()
+    }
+}
+
+/// The keys and nonces of an `Outcome`, see `Outcome::into_keys`.
+#[derive(Debug)]
+pub struct SessionKeys {
+    /// The parameters for encrypting messages sent to the peer.
+    pub send: EncryptionParams,
+    /// The parameters for decrypting messages received from the peer.
+    pub recv: DecryptionParams,
+}
+
+/// The ssb feed id of a peer, see `Outcome::peer_id`.
+///
+/// Displays as `@` followed by the padded base64 encoding of the longterm
+/// public key and the `.ed25519` suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId(sign::PublicKey);
+
+impl PeerId {
+    /// The longterm public key of the peer.
+    pub fn longterm_pk(&self) -> sign::PublicKey {
+        self.0
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "@{}.ed25519", self.0.to_base64())
+    }
+}
+
+/// The sending direction of a session, see `Outcome::split`.
+#[derive(Debug)]
+pub struct SendHalf {
+    params: EncryptionParams,
+    peer_longterm_pk: sign::PublicKey,
+}
+
+impl SendHalf {
+    /// The key and initial nonce for encrypting messages sent to the peer.
+    pub fn params(&self) -> &EncryptionParams {
+        &self.params
+    }
+
+    /// Consumes the half, returning the key and initial nonce.
+    pub fn into_params(self) -> EncryptionParams {
+        self.params
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_longterm_pk(&self) -> sign::PublicKey {
+        self.peer_longterm_pk
+    }
+}
+
+/// The receiving direction of a session, see `Outcome::split`.
+#[derive(Debug)]
+pub struct RecvHalf {
+    params: DecryptionParams,
+    peer_longterm_pk: sign::PublicKey,
+}
+
+impl RecvHalf {
+    /// The key and initial nonce for decrypting messages received from the
+    /// peer.
+    pub fn params(&self) -> &DecryptionParams {
+        &self.params
+    }
+
+    /// Consumes the half, returning the key and initial nonce.
+    pub fn into_params(self) -> DecryptionParams {
+        self.params
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_longterm_pk(&self) -> sign::PublicKey {
+        self.peer_longterm_pk
+    }
+}
+
+/// The identities that a handshake authenticated, without any session keys,
+/// see `Outcome::into_authenticated`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authenticated {
+    /// The longterm public key of the peer, as verified by the handshake.
+    pub peer_longterm_pk: sign::PublicKey,
+    /// The own longterm public key used in the handshake.
+    pub local_longterm_pk: sign::PublicKey,
 }
 
 /// The struct used in the C code to perform the client side of a handshake.
@@ -114,6 +620,43 @@ impl Client {
         }
     }
 
+    /// Points the `Client` at a new location of its inputs, e.g. after they
+    /// have been moved. The values of the inputs must not change, except for
+    /// `server_pub`, which is read first by `verify_msg2` and may be replaced
+    /// until then.
+    pub fn set_inputs(&mut self,
+                      app: *const [u8; auth::KEYBYTES],
+                      pub_: *const [u8; sign::PUBLICKEYBYTES],
+                      sec: *const [u8; sign::SECRETKEYBYTES],
+                      eph_pub: *const [u8; box_::PUBLICKEYBYTES],
+                      eph_sec: *const [u8; box_::SECRETKEYBYTES],
+                      server_pub: *const [u8; sign::PUBLICKEYBYTES]) {
+        self.app = app;
+        self.pub_ = pub_;
+        self.sec = sec;
+        self.eph_pub = eph_pub;
+        self.eph_sec = eph_sec;
+        self.server_pub = server_pub;
+    }
+
+    /// Creates a copy of the `Client`, including all intermediate results,
+    /// which points at the same inputs.
+    pub fn duplicate(&self) -> Client {
+        Client {
+            app: self.app,
+            pub_: self.pub_,
+            sec: self.sec,
+            eph_pub: self.eph_pub,
+            eph_sec: self.eph_sec,
+            server_pub: self.server_pub,
+            shared_secret: self.shared_secret,
+            server_lterm_shared: self.server_lterm_shared,
+            hello: self.hello,
+            shared_hash: self.shared_hash,
+            server_eph_pub: self.server_eph_pub,
+        }
+    }
+
     /// Writes the client challenge into `challenge` and updates the client state.
     pub fn create_msg1(&mut self, challenge: &mut [u8; MSG1_BYTES]) {
         unsafe { shs1_create_client_challenge(challenge, self) }
@@ -136,7 +679,43 @@ impl Client {
 
     /// Computes the outcome of the handshake and writes it into `outcome`.
     pub fn outcome(&mut self, outcome: &mut Outcome) {
-        unsafe { shs1_client_outcome(outcome, self) }
+        unsafe {
+            outcome.peer_ephemeral_pk = self.server_eph_pub;
+            shs1_client_outcome(outcome, self);
+            outcome.network_identifier = *self.app;
+            outcome.local_longterm_pk = *self.pub_;
+            outcome.local_ephemeral_pk = *self.eph_pub;
+        }
+    }
+
+    /// Creates the client hello and updates the client state.
+    pub fn client_hello(&mut self) -> ClientHello {
+        let mut msg = [0; MSG1_BYTES];
+        self.create_msg1(&mut msg);
+        ClientHello::from_bytes(msg)
+    }
+
+    /// Verifies the given server hello and updates the client state.
+    pub fn verify_server_hello(&mut self, hello: &ServerHello) -> bool {
+        self.verify_msg2(hello.as_bytes())
+    }
+
+    /// Creates the client authentication and updates the client state.
+    pub fn client_auth(&mut self) -> ClientAuth {
+        let mut msg = [0; MSG3_BYTES];
+        self.create_msg3(&mut msg);
+        ClientAuth::from_bytes(msg)
+    }
+
+    /// Verifies the given server acknowledgement and updates the client state.
+    pub fn verify_server_ack(&mut self, ack: &ServerAck) -> bool {
+        self.verify_msg4(ack.as_bytes())
+    }
+
+    /// Returns whether the server longterm public key equals the client's own
+    /// longterm public key.
+    pub fn connects_to_self(&self) -> bool {
+        unsafe { *self.pub_ == *self.server_pub }
     }
 
     /// Zeros out all sensitive data in the `Client`.
@@ -191,6 +770,27 @@ impl Server {
         }
     }
 
+    /// Points the `Server` at a new location of its inputs, e.g. after they
+    /// have been moved. The values of the inputs must not change.
+    pub fn set_inputs(&mut self,
+                      app: *const [u8; auth::KEYBYTES],
+                      pub_: *const [u8; sign::PUBLICKEYBYTES],
+                      sec: *const [u8; sign::SECRETKEYBYTES],
+                      eph_pub: *const [u8; box_::PUBLICKEYBYTES],
+                      eph_sec: *const [u8; box_::SECRETKEYBYTES]) {
+        self.app = app;
+        self.pub_ = pub_;
+        self.sec = sec;
+        self.eph_pub = eph_pub;
+        self.eph_sec = eph_sec;
+    }
+
+    /// Sets the network identifier against which the next client `challenge`
+    /// is verified.
+    pub fn set_network_identifier(&mut self, app: *const [u8; auth::KEYBYTES]) {
+        self.app = app;
+    }
+
     /// Verifies the given client `challenge` and updates the server state.
     pub fn verify_msg1(&mut self, challenge: &[u8; MSG1_BYTES]) -> bool {
         unsafe { shs1_verify_client_challenge(challenge, self) }
@@ -201,6 +801,32 @@ impl Server {
         unsafe { shs1_create_server_challenge(challenge, self) }
     }
 
+    /// Creates a copy of the `Server`, including all intermediate results,
+    /// which points at the same inputs.
+    pub fn duplicate(&self) -> Server {
+        self.with_longterm_keys(self.pub_, self.sec)
+    }
+
+    /// Creates a copy of the `Server`, including all intermediate results,
+    /// which uses different longterm keys for the remainder of the handshake.
+    pub fn with_longterm_keys(&self,
+                              pub_: *const [u8; sign::PUBLICKEYBYTES],
+                              sec: *const [u8; sign::SECRETKEYBYTES])
+                              -> Server {
+        Server {
+            app: self.app,
+            pub_,
+            sec,
+            eph_pub: self.eph_pub,
+            eph_sec: self.eph_sec,
+            client_hello: self.client_hello,
+            shared_hash: self.shared_hash,
+            client_eph_pub: self.client_eph_pub,
+            client_pub: self.client_pub,
+            box_sec: self.box_sec,
+        }
+    }
+
     /// Verifies the given client `auth`entication and updates the server state.
     pub fn verify_msg3(&mut self, auth: &[u8; MSG3_BYTES]) -> bool {
         unsafe { shs1_verify_client_auth(auth, self) }
@@ -211,9 +837,39 @@ impl Server {
         unsafe { shs1_create_server_ack(ack, self) }
     }
 
+    /// Verifies the given client hello and updates the server state.
+    pub fn verify_client_hello(&mut self, hello: &ClientHello) -> bool {
+        self.verify_msg1(hello.as_bytes())
+    }
+
+    /// Creates the server hello and updates the server state.
+    pub fn server_hello(&mut self) -> ServerHello {
+        let mut msg = [0; MSG2_BYTES];
+        self.create_msg2(&mut msg);
+        ServerHello::from_bytes(msg)
+    }
+
+    /// Verifies the given client authentication and updates the server state.
+    pub fn verify_client_auth(&mut self, auth: &ClientAuth) -> bool {
+        self.verify_msg3(auth.as_bytes())
+    }
+
+    /// Creates the server acknowledgement and updates the server state.
+    pub fn server_ack(&mut self) -> ServerAck {
+        let mut msg = [0; MSG4_BYTES];
+        self.create_msg4(&mut msg);
+        ServerAck::from_bytes(msg)
+    }
+
     /// Computes the outcome of the handshake and writes it into `outcome`.
     pub fn outcome(&mut self, outcome: &mut Outcome) {
-        unsafe { shs1_server_outcome(outcome, self) }
+        unsafe {
+            outcome.peer_ephemeral_pk = self.client_eph_pub;
+            shs1_server_outcome(outcome, self);
+            outcome.network_identifier = *self.app;
+            outcome.local_longterm_pk = *self.pub_;
+            outcome.local_ephemeral_pk = *self.eph_pub;
+        }
     }
 
     /// Zeros out all sensitive data in the `Server`.
@@ -221,11 +877,24 @@ impl Server {
         unsafe { shs1_server_clean(self) }
     }
 
+    /// Returns the ephemeral public key of the client. This will return
+    /// uninitialized memory if called before the server verified msg1.
+    pub unsafe fn client_ephemeral_pub(&self) -> [u8; box_::PUBLICKEYBYTES] {
+        self.client_eph_pub
+    }
+
     /// Returns the longterm public key of the client. This will return
     /// uninitialized memory if called before the server verified msg3.
     pub unsafe fn client_longterm_pub(&self) -> [u8; sign::PUBLICKEYBYTES] {
         self.client_pub
     }
+
+    /// Returns whether the longterm public key of the client equals the
+    /// server's own longterm public key. This will read uninitialized memory
+    /// if called before the server verified msg3.
+    pub unsafe fn accepts_self(&self) -> bool {
+        self.client_pub == *self.pub_
+    }
 }
 
 /// Zero out all sensitive data when going out of scope.
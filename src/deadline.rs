@@ -0,0 +1,102 @@
+//! Bound the time a handshake may take.
+//!
+//! The handshakers are agnostic of the runtime they are driven by, so they
+//! can not create timers themselves. Instead, wrap the stream in a
+//! [`Deadline`](struct.Deadline.html) together with any timer future of your
+//! runtime before handing it to a handshaker:
+//!
+//! ```rust,ignore
+//! let stream = Deadline::new(tcp_stream, Delay::new(Instant::now() + timeout));
+//! let server = OwningServerHandshaker::new(stream, ...);
+//! ```
+//!
+//! Once the timer resolves, all reads and writes on the stream fail with an
+//! error of kind `TimedOut`, so a peer that stalls the handshake (e.g. by
+//! sending only half of msg1) causes it to fail with
+//! `HandshakeError::IoError` rather than holding on to the server state
+//! forever. The original stream can be recovered via `into_inner`.
+
+use std::io;
+use std::io::ErrorKind::TimedOut;
+
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+/// Wraps a stream, failing all io with `TimedOut` once the `delay` resolved.
+pub struct Deadline<S, D> {
+    stream: S,
+    delay: D,
+    elapsed: bool,
+}
+
+impl<S, D: Future<Item = ()>> Deadline<S, D> {
+    /// Creates a new `Deadline`, which fails all io on the `stream` once
+    /// the `delay` resolves.
+    ///
+    /// Should the `delay` error, the deadline is treated as elapsed.
+    pub fn new(stream: S, delay: D) -> Deadline<S, D> {
+        Deadline {
+            stream,
+            delay,
+            elapsed: false,
+        }
+    }
+
+    /// Returns whether the deadline has been detected as elapsed.
+    pub fn has_elapsed(&self) -> bool {
+        self.elapsed
+    }
+
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `Deadline`, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // Returns an error if the deadline has elapsed, otherwise registers the
+    // current task to be woken up when it elapses.
+    fn check(&mut self, cx: &mut Context) -> Result<(), io::Error> {
+        if !self.elapsed {
+            match self.delay.poll(cx) {
+                Ok(Pending) => return Ok(()),
+                Ok(Ready(())) | Err(_) => self.elapsed = true,
+            }
+        }
+
+        Err(io::Error::new(TimedOut, "handshake deadline elapsed"))
+    }
+}
+
+impl<S: AsyncRead, D: Future<Item = ()>> AsyncRead for Deadline<S, D> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        self.check(cx)?;
+        self.stream.poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite, D: Future<Item = ()>> AsyncWrite for Deadline<S, D> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        self.check(cx)?;
+        self.stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.check(cx)?;
+        self.stream.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.stream.poll_close(cx)
+    }
+}
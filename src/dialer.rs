@@ -0,0 +1,339 @@
+//! Reach a peer via any of the addresses it advertises.
+//!
+//! A [`Dialer`](struct.Dialer.html) tries a list of multiserver addresses in
+//! order, performing a handshake over the first connection that can be
+//! established. If all of them fail, it waits for a backoff delay and starts
+//! over, up to a configurable number of rounds.
+//!
+//! Like the handshakers, the dialer can not create timers itself. It takes a
+//! function that creates a delay future of your runtime for a given duration.
+//!
+//! Each attempt can be bounded by a connect timeout and a handshake timeout,
+//! which are reported as different errors, see `Dialer::set_connect_timeout`.
+//!
+//! To follow the progress of a dial, e.g. to display it or to learn which
+//! addresses of a peer are dead, attach a function via `set_event_handler`,
+//! which is called with a `DialEvent` at each step of each attempt.
+//!
+//! Host names are resolved via the operating system, unless a custom
+//! resolver is set via `set_resolver`.
+//!
+//! This module requires the `tokio` feature.
+
+use std::cmp::min;
+use std::io;
+use std::io::ErrorKind::InvalidInput;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use tokio::net::TcpStream;
+
+use connect::{ConnectTcp, Progress, Resolve, connect_multiserver, connect_multiserver_with};
+use crypto::Outcome;
+use errors::ConnectError;
+use identity::Identity;
+use multiserver::Address;
+
+/// Dials a peer under a list of candidate addresses, with retries and
+/// exponential backoff.
+#[derive(Clone)]
+pub struct Dialer<MakeDelay> {
+    identity: Identity,
+    addresses: Vec<Address>,
+    make_delay: MakeDelay,
+    resolver: Option<Arc<Resolve + Send + Sync>>,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    make_timeout: Option<Arc<MakeTimeout>>,
+    on_event: Option<Arc<Fn(DialEvent) + Send + Sync>>,
+    rounds: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl<MakeDelay, D> Dialer<MakeDelay>
+    where MakeDelay: Fn(Duration) -> D + Clone,
+          D: Future<Item = ()>
+{
+    /// Creates a new `Dialer`, which tries the `addresses` in order.
+    ///
+    /// Only addresses with a `net` transport (or, with a custom resolver, an
+    /// `onion` transport) and a `shs` transform are dialed, all others count
+    /// as failed attempts. `make_delay` is called to wait between rounds, a
+    /// delay that errors counts as elapsed.
+    ///
+    /// By default, the addresses are tried in three rounds, with a backoff of
+    /// one second after the first round that doubles after each further
+    /// round, up to one minute.
+    pub fn new(identity: Identity, addresses: Vec<Address>, make_delay: MakeDelay) -> Self {
+        Dialer {
+            identity,
+            addresses,
+            make_delay,
+            resolver: None,
+            connect_timeout: None,
+            handshake_timeout: None,
+            make_timeout: None,
+            on_event: None,
+            rounds: 3,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Set how many times all addresses are tried before giving up. A value
+    /// of zero is treated as one.
+    pub fn set_rounds(&mut self, rounds: usize) {
+        self.rounds = rounds;
+    }
+
+    /// Set the delay after the first failed round, and the maximum the
+    /// doubling delay can grow to.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+    }
+
+    /// Resolve host names via the given `resolver`. This also dials addresses
+    /// with an `onion` transport, see `connect::connect_multiserver_with`.
+    pub fn set_resolver<R: Resolve + Send + Sync + 'static>(&mut self, resolver: R) {
+        self.resolver = Some(Arc::new(resolver));
+    }
+
+    /// Fail each attempt with `ConnectError::ConnectTimedOut` if resolving
+    /// the address and connecting to it take longer than `timeout`. The
+    /// timer is created via the `make_delay` function of the dialer.
+    ///
+    /// An attempt that timed out counts as failed, so the dialer moves on to
+    /// the next address.
+    pub fn set_connect_timeout(&mut self, timeout: Duration)
+        where MakeDelay: Send + Sync + 'static,
+              D: Send + 'static
+    {
+        self.connect_timeout = Some(timeout);
+        self.make_timeout = Some(self.make_timeout());
+    }
+
+    /// Fail each attempt with `ConnectError::HandshakeTimedOut` if the
+    /// handshake over an established connection takes longer than `timeout`.
+    /// The timer is created via the `make_delay` function of the dialer.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration)
+        where MakeDelay: Send + Sync + 'static,
+              D: Send + 'static
+    {
+        self.handshake_timeout = Some(timeout);
+        self.make_timeout = Some(self.make_timeout());
+    }
+
+    fn make_timeout(&self) -> Arc<MakeTimeout>
+        where MakeDelay: Send + Sync + 'static,
+              D: Send + 'static
+    {
+        let make_delay = self.make_delay.clone();
+        Arc::new(move |duration| -> Box<Future<Item = (), Error = ()> + Send> {
+                     Box::new(Timeout(make_delay(duration)))
+                 })
+    }
+
+    /// Call `on_event` at each step of dialing, see `DialEvent`.
+    pub fn set_event_handler<F>(&mut self, on_event: F)
+        where F: Fn(DialEvent) + Send + Sync + 'static
+    {
+        self.on_event = Some(Arc::new(on_event));
+    }
+
+    fn emit(&self, event: DialEvent) {
+        if let Some(ref on_event) = self.on_event {
+            on_event(event);
+        }
+    }
+
+    /// Returns a future that dials the addresses, yielding the outcome of the
+    /// first successful handshake together with the address it was performed
+    /// on.
+    pub fn dial(&self) -> Dial<MakeDelay, D> {
+        Dial {
+            dialer: self.clone(),
+            next: 0,
+            round: 1,
+            backoff: self.initial_backoff,
+            connecting: None,
+            waiting: None,
+            error: None,
+        }
+    }
+}
+
+/// A step of dialing a peer, as reported to the function set via
+/// `Dialer::set_event_handler`.
+#[derive(Debug, Clone, Copy)]
+pub enum DialEvent<'a> {
+    /// An attempt to reach the peer under `address` starts. `round` counts
+    /// the passes over all addresses, starting at one.
+    Connecting {
+        /// The address that is dialed.
+        address: &'a Address,
+        /// The current round.
+        round: usize,
+    },
+    /// The host of `address` resolved to `addrs`, which are dialed next.
+    Resolved {
+        /// The address that is dialed.
+        address: &'a Address,
+        /// The socket addresses of the host.
+        addrs: &'a [SocketAddr],
+    },
+    /// A connection to `address` was established, and the handshake starts.
+    Handshaking {
+        /// The address that is dialed.
+        address: &'a Address,
+        /// The remote socket address of the connection, if it could be
+        /// queried.
+        addr: Option<SocketAddr>,
+    },
+    /// The attempt failed. Unless this was the last address of the last
+    /// round, the dialer moves on.
+    Failed {
+        /// The address that could not be reached.
+        address: &'a Address,
+        /// Why the attempt failed.
+        error: &'a ConnectError,
+    },
+    /// The handshake completed, and the dial yields the connection.
+    Succeeded {
+        /// The address the peer was reached under.
+        address: &'a Address,
+    },
+}
+
+/// Future that dials a peer, see `Dialer::dial`.
+pub struct Dial<MakeDelay, D> {
+    dialer: Dialer<MakeDelay>,
+    next: usize, // index of the next address to dial
+    round: usize,
+    backoff: Duration,
+    connecting: Option<ConnectTcp>,
+    waiting: Option<D>,
+    error: Option<ConnectError>, // the error of the most recent attempt
+}
+
+impl<MakeDelay, D> Future for Dial<MakeDelay, D>
+    where MakeDelay: Fn(Duration) -> D + Clone,
+          D: Future<Item = ()>
+{
+    type Item = (Outcome, TcpStream, Address);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(mut connecting) = self.connecting.take() {
+                match connecting.poll(cx) {
+                    Ok(Ready((outcome, stream))) => {
+                        let address = self.dialer.addresses[self.next - 1].clone();
+                        self.dialer.emit(DialEvent::Succeeded { address: &address });
+                        return Ok(Ready((outcome, stream, address)));
+                    }
+                    Ok(Pending) => {
+                        self.connecting = Some(connecting);
+                        return Ok(Pending);
+                    }
+                    Err(err) => {
+                        self.dialer.emit(DialEvent::Failed {
+                                             address: &self.dialer.addresses[self.next - 1],
+                                             error: &err,
+                                         });
+                        self.error = Some(err);
+                    }
+                }
+            }
+
+            if let Some(mut waiting) = self.waiting.take() {
+                match waiting.poll(cx) {
+                    Ok(Pending) => {
+                        self.waiting = Some(waiting);
+                        return Ok(Pending);
+                    }
+                    Ok(Ready(())) | Err(_) => {}
+                }
+            }
+
+            if self.next < self.dialer.addresses.len() {
+                let address = &self.dialer.addresses[self.next];
+                let mut connecting = match self.dialer.resolver {
+                    Some(ref resolver) => {
+                        connect_multiserver_with(address, &**resolver, &self.dialer.identity)
+                    }
+                    None => connect_multiserver(address, &self.dialer.identity),
+                };
+                if let Some(ref make_timeout) = self.dialer.make_timeout {
+                    if let Some(timeout) = self.dialer.connect_timeout {
+                        let make_timeout = make_timeout.clone();
+                        connecting.set_connect_timeout(timeout, move |d| make_timeout(d));
+                    }
+                    if let Some(timeout) = self.dialer.handshake_timeout {
+                        let make_timeout = make_timeout.clone();
+                        connecting.set_handshake_timeout(timeout, move |d| make_timeout(d));
+                    }
+                }
+                if let Some(ref on_event) = self.dialer.on_event {
+                    on_event(DialEvent::Connecting {
+                                 address,
+                                 round: self.round,
+                             });
+                    let on_event = on_event.clone();
+                    let address = address.clone();
+                    connecting.set_progress(move |progress: Progress| match progress {
+                        Progress::Resolved(addrs) => {
+                            on_event(DialEvent::Resolved {
+                                         address: &address,
+                                         addrs,
+                                     })
+                        }
+                        Progress::Handshaking(addr) => {
+                            on_event(DialEvent::Handshaking {
+                                         address: &address,
+                                         addr,
+                                     })
+                        }
+                    });
+                }
+                self.connecting = Some(connecting);
+                self.next += 1;
+                continue;
+            }
+
+            if self.round >= self.dialer.rounds || self.dialer.addresses.is_empty() {
+                return Err(self.error.take().unwrap_or_else(|| {
+                    ConnectError::IoError(io::Error::new(InvalidInput, "no addresses to dial"))
+                }));
+            }
+
+            self.waiting = Some((self.dialer.make_delay)(self.backoff));
+            self.backoff = min(self.backoff * 2, self.dialer.max_backoff);
+            self.round += 1;
+            self.next = 0;
+        }
+    }
+}
+
+// Creates the timers of the timeouts of each attempt.
+type MakeTimeout = Fn(Duration) -> Box<Future<Item = (), Error = ()> + Send> + Send + Sync;
+
+// A delay of the dialer, as the timer of a timeout.
+struct Timeout<D>(D);
+
+impl<D: Future<Item = ()>> Future for Timeout<D> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<(), ()> {
+        match self.0.poll(cx) {
+            Ok(Pending) => Ok(Pending),
+            Ok(Ready(())) | Err(_) => Ok(Ready(())),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Hex and base64 encodings of keys and network identifiers.
+//!
+//! `KeyEncoding` is implemented for the longterm and ephemeral keys, for
+//! seeds of longterm keypairs and for network identifiers. Base64 uses the
+//! standard alphabet with padding, as ssb does for ids and network keys.
+//! Decoding is strict: the input must encode exactly as many bytes as the key
+//! has, and base64 must be canonical.
+//!
+//! Encoding and decoding take time independent of the key's value, so secret
+//! keys can be encoded without leaking them through timing. Intermediate
+//! buffers holding key material are zeroed.
+//!
+//! ```rust,ignore
+//! let server_longterm_pk = sign::PublicKey::from_base64("Mhz+WRi3Uk1V4mRN/aMaNb5uWQmm2SbuXvJDEeSgXDs=")?;
+//! ```
+
+use sodiumoxide::crypto::{box_, sign};
+use sodiumoxide::utils::memzero;
+
+use crypto::NETWORK_IDENTIFIER_BYTES;
+use hex;
+
+/// Conversion of keys from and to their hex and base64 encodings.
+pub trait KeyEncoding: Sized {
+    /// Encodes the key as lowercase hex.
+    fn to_hex(&self) -> String;
+
+    /// Decodes a key from hex of either case, or returns `None` if `s` is not
+    /// the hex encoding of a key.
+    fn from_hex(s: &str) -> Option<Self>;
+
+    /// Encodes the key as padded base64 in the standard alphabet.
+    fn to_base64(&self) -> String;
+
+    /// Decodes a key from padded base64 in the standard alphabet, or returns
+    /// `None` if `s` is not the canonical base64 encoding of a key.
+    fn from_base64(s: &str) -> Option<Self>;
+}
+
+macro_rules! key_encoding {
+    ($key:path, $len:expr) => {
+        impl KeyEncoding for $key {
+            fn to_hex(&self) -> String {
+                hex::encode(&self.0[..])
+            }
+
+            fn from_hex(s: &str) -> Option<$key> {
+                let mut bytes = [0; $len];
+                let key = if hex::decode_into(s, &mut bytes) {
+                    Some($key(bytes))
+                } else {
+                    None
+                };
+                memzero(&mut bytes);
+                key
+            }
+
+            fn to_base64(&self) -> String {
+                encode_base64(&self.0[..])
+            }
+
+            fn from_base64(s: &str) -> Option<$key> {
+                let mut bytes = [0; $len];
+                let key = if decode_base64(s, &mut bytes) {
+                    Some($key(bytes))
+                } else {
+                    None
+                };
+                memzero(&mut bytes);
+                key
+            }
+        }
+    }
+}
+
+key_encoding!(sign::PublicKey, sign::PUBLICKEYBYTES);
+key_encoding!(sign::SecretKey, sign::SECRETKEYBYTES);
+key_encoding!(box_::PublicKey, box_::PUBLICKEYBYTES);
+key_encoding!(box_::SecretKey, box_::SECRETKEYBYTES);
+key_encoding!(sign::Seed, sign::SEEDBYTES);
+
+/// Network identifiers.
+impl KeyEncoding for [u8; NETWORK_IDENTIFIER_BYTES] {
+    fn to_hex(&self) -> String {
+        hex::encode(self)
+    }
+
+    fn from_hex(s: &str) -> Option<[u8; NETWORK_IDENTIFIER_BYTES]> {
+        let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+        if hex::decode_into(s, &mut network_identifier) {
+            Some(network_identifier)
+        } else {
+            None
+        }
+    }
+
+    fn to_base64(&self) -> String {
+        encode_base64(self)
+    }
+
+    fn from_base64(s: &str) -> Option<[u8; NETWORK_IDENTIFIER_BYTES]> {
+        let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+        if decode_base64(s, &mut network_identifier) {
+            Some(network_identifier)
+        } else {
+            None
+        }
+    }
+}
+
+// Encodes as padded base64, without branching on or indexing by the bytes.
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = ((group[0] as u32) << 16) | ((group[1] as u32) << 8) | (group[2] as u32);
+
+        // A chunk of n bytes is encoded as n + 1 characters.
+        for i in 0..chunk.len() + 1 {
+            encoded.push(base64_char((bits >> (18 - 6 * i)) & 0x3f) as char);
+        }
+        for _ in chunk.len()..3 {
+            encoded.push('=');
+        }
+        memzero(&mut group);
+    }
+    encoded
+}
+
+// Decodes padded base64 into `out`, returns whether `s` is the canonical
+// encoding of exactly `out.len()` bytes. Only the validity of `s` as a whole
+// affects the control flow.
+fn decode_base64(s: &str, out: &mut [u8]) -> bool {
+    let s = s.as_bytes();
+    if s.len() != (out.len() + 2) / 3 * 4 {
+        return false;
+    }
+
+    let padding = (3 - out.len() % 3) % 3;
+    let (data, pad) = s.split_at(s.len() - padding);
+    if pad.iter().any(|c| *c != b'=') {
+        return false;
+    }
+
+    let mut invalid = 0u32;
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut written = 0;
+    for c in data {
+        let (value, valid) = base64_value(*c);
+        invalid |= !valid;
+        acc = (acc << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out[written] = (acc >> bits) as u8;
+            written += 1;
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    // Canonical encodings have no bits set beyond the last byte.
+    invalid |= acc;
+    invalid == 0
+}
+
+// `0xffffffff` if `lo <= c < hi`, `0` otherwise.
+fn range_mask(c: u32, lo: u32, hi: u32) -> u32 {
+    let c = c as i32;
+    ((((lo as i32) - 1 - c) & (c - (hi as i32))) >> 8) as u32
+}
+
+// The base64 character of a 6 bit value.
+fn base64_char(v: u32) -> u8 {
+    let upper = range_mask(v, 0, 26) & (v + 65);
+    let lower = range_mask(v, 26, 52) & (v + 71);
+    let digit = range_mask(v, 52, 62) & v.wrapping_sub(4);
+    let plus = range_mask(v, 62, 63) & (b'+' as u32);
+    let slash = range_mask(v, 63, 64) & (b'/' as u32);
+    (upper | lower | digit | plus | slash) as u8
+}
+
+// The value of a base64 character, and `0xffffffff` if `c` is valid or `0`
+// otherwise.
+fn base64_value(c: u8) -> (u32, u32) {
+    let c = c as u32;
+    let upper = range_mask(c, b'A' as u32, b'Z' as u32 + 1);
+    let lower = range_mask(c, b'a' as u32, b'z' as u32 + 1);
+    let digit = range_mask(c, b'0' as u32, b'9' as u32 + 1);
+    let plus = range_mask(c, b'+' as u32, b'+' as u32 + 1);
+    let slash = range_mask(c, b'/' as u32, b'/' as u32 + 1);
+
+    let value = (upper & c.wrapping_sub(65)) | (lower & c.wrapping_sub(71)) |
+                (digit & (c + 4)) | (plus & 62) | (slash & 63);
+    (value, upper | lower | digit | plus | slash)
+}
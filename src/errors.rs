@@ -14,6 +14,15 @@ pub enum HandshakeError {
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
     CryptoError,
+    /// The peer uses the own longterm public key, and self connections are
+    /// rejected.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    SelfConnection,
+    /// The handshake exceeded its `budget::Budget`.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    BudgetExceeded,
 }
 
 impl Display for HandshakeError {
@@ -21,6 +30,8 @@ impl Display for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => write!(f, "Handshake error: {}", err),
             HandshakeError::CryptoError => write!(f, "Handshake error: crypto error"),
+            HandshakeError::SelfConnection => write!(f, "Handshake error: connection to self"),
+            HandshakeError::BudgetExceeded => write!(f, "Handshake error: budget exceeded"),
         }
     }
 }
@@ -30,6 +41,8 @@ impl Error for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => err.description(),
             HandshakeError::CryptoError => "the peer did not provide valid authentication",
+            HandshakeError::SelfConnection => "the peer uses the own longterm public key",
+            HandshakeError::BudgetExceeded => "the handshake exceeded its resource budget",
         }
     }
 
@@ -37,6 +50,8 @@ impl Error for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => Some(err),
             HandshakeError::CryptoError => None,
+            HandshakeError::SelfConnection => None,
+            HandshakeError::BudgetExceeded => None,
         }
     }
 }
@@ -52,7 +67,8 @@ impl From<futures_io::Error> for HandshakeError {
 pub enum FilteringHandshakeError<FnErr> {
     /// An io error occured during the handshake.
     IoError(futures_io::Error),
-    /// The filter function errored.
+    /// The filter function errored, or for an
+    /// `OwningClientHandshakerWithResolver`, the resolver function.
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
     FilterError(FnErr),
@@ -60,10 +76,24 @@ pub enum FilteringHandshakeError<FnErr> {
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
     CryptoError,
-    /// The peer was rejected by the filter function.
+    /// The peer was rejected by the filter function. For a client, this
+    /// means the filter function did not accept the server's longterm public
+    /// key, although the server proved ownership of it. For a server run by
+    /// an `Acceptor`, the admission controller may have rejected the peer
+    /// instead. For a client with a resolver function, the alias of the
+    /// server could not be resolved to a key.
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
     Rejected,
+    /// The peer uses the own longterm public key, and self connections are
+    /// rejected.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    SelfConnection,
+    /// The handshake exceeded its `budget::Budget`.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    BudgetExceeded,
 }
 
 impl<FnErr: Display> Display for FilteringHandshakeError<FnErr> {
@@ -73,6 +103,12 @@ impl<FnErr: Display> Display for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::FilterError(ref err) => write!(f, "Handshake error: {}", err),
             FilteringHandshakeError::CryptoError => write!(f, "Handshake error: crypto error"),
             FilteringHandshakeError::Rejected => write!(f, "Handshake error: peer rejected"),
+            FilteringHandshakeError::SelfConnection => {
+                write!(f, "Handshake error: connection to self")
+            }
+            FilteringHandshakeError::BudgetExceeded => {
+                write!(f, "Handshake error: budget exceeded")
+            }
         }
     }
 }
@@ -84,6 +120,8 @@ impl<FnErr: Error> Error for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::FilterError(ref err) => err.description(),
             FilteringHandshakeError::CryptoError => "the peer did not provide valid authentication",
             FilteringHandshakeError::Rejected => "the peer was rejected by the filter function",
+            FilteringHandshakeError::SelfConnection => "the peer uses the own longterm public key",
+            FilteringHandshakeError::BudgetExceeded => "the handshake exceeded its resource budget",
         }
     }
 
@@ -93,6 +131,8 @@ impl<FnErr: Error> Error for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::FilterError(ref err) => Some(err),
             FilteringHandshakeError::CryptoError => None,
             FilteringHandshakeError::Rejected => None,
+            FilteringHandshakeError::SelfConnection => None,
+            FilteringHandshakeError::BudgetExceeded => None,
         }
     }
 }
@@ -102,3 +142,92 @@ impl<FnErr> From<futures_io::Error> for FilteringHandshakeError<FnErr> {
         FilteringHandshakeError::IoError(err)
     }
 }
+
+/// Errors of a filter function whose evaluation is bounded by a
+/// `filter::FilterTimeout`.
+#[derive(Debug)]
+pub enum FilterTimeoutError<FnErr> {
+    /// The wrapped filter function errored.
+    FilterError(FnErr),
+    /// The filter function did not decide in time, and the client was
+    /// rejected by default.
+    TimedOut,
+}
+
+impl<FnErr: Display> Display for FilterTimeoutError<FnErr> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            FilterTimeoutError::FilterError(ref err) => write!(f, "Filter error: {}", err),
+            FilterTimeoutError::TimedOut => write!(f, "Filter error: timed out"),
+        }
+    }
+}
+
+impl<FnErr: Error> Error for FilterTimeoutError<FnErr> {
+    fn description(&self) -> &str {
+        match *self {
+            FilterTimeoutError::FilterError(ref err) => err.description(),
+            FilterTimeoutError::TimedOut => "the filter function did not decide in time",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            FilterTimeoutError::FilterError(ref err) => Some(err),
+            FilterTimeoutError::TimedOut => None,
+        }
+    }
+}
+
+/// Errors that can occur when dialing a server and performing a handshake.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The address could not be resolved, or no connection could be
+    /// established.
+    IoError(futures_io::Error),
+    /// The handshake over the established connection failed.
+    Handshake(HandshakeError),
+    /// Resolving the address and establishing a connection took longer than
+    /// the connect timeout.
+    ConnectTimedOut,
+    /// The handshake over the established connection took longer than the
+    /// handshake timeout.
+    HandshakeTimedOut,
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConnectError::IoError(ref err) => write!(f, "Connect error: {}", err),
+            ConnectError::Handshake(ref err) => write!(f, "Connect error: {}", err),
+            ConnectError::ConnectTimedOut => write!(f, "Connect error: connecting timed out"),
+            ConnectError::HandshakeTimedOut => write!(f, "Connect error: handshake timed out"),
+        }
+    }
+}
+
+impl Error for ConnectError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectError::IoError(ref err) => err.description(),
+            ConnectError::Handshake(ref err) => err.description(),
+            ConnectError::ConnectTimedOut => "could not connect within the connect timeout",
+            ConnectError::HandshakeTimedOut => "the handshake did not complete in time",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ConnectError::IoError(ref err) => Some(err),
+            ConnectError::Handshake(ref err) => Some(err),
+            ConnectError::ConnectTimedOut |
+            ConnectError::HandshakeTimedOut => None,
+        }
+    }
+}
+
+impl From<futures_io::Error> for ConnectError {
+    fn from(err: futures_io::Error) -> ConnectError {
+        ConnectError::IoError(err)
+    }
+}
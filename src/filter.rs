@@ -0,0 +1,164 @@
+//! Helpers for filter functions of filtering server handshakers.
+//!
+//! A filter function that performs slow work (e.g. a database lookup) would
+//! stall the whole handshake if that work hangs. Bound it by wrapping the
+//! future returned by the filter function in a
+//! [`FilterTimeout`](struct.FilterTimeout.html), together with any timer
+//! future of your runtime:
+//!
+//! ```rust,ignore
+//! let filter_fn = |pk: &sign::PublicKey| {
+//!     FilterTimeout::new(lookup(pk), Delay::new(Instant::now() + timeout), OnTimeout::Reject)
+//! };
+//! let server = ServerHandshakerWithFilter::new(stream, filter_fn, ...);
+//! ```
+//!
+//! A [`SharedKeyFilter`](struct.SharedKeyFilter.html) filters clients by an
+//! allow- or denylist of longterm public keys, which an admin task can
+//! replace at runtime without recreating the acceptor:
+//!
+//! ```rust,ignore
+//! let keys = SharedKeyFilter::new(KeySet::allow(members));
+//! let filter = keys.clone();
+//! let acceptor = Acceptor::with_filter(incoming, move |pk: &sign::PublicKey| filter.filter(pk), identity);
+//!
+//! // Later, e.g. once an invite has been redeemed:
+//! keys.replace(KeySet::allow(new_members));
+//! ```
+
+use std::collections::HashSet;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use sodiumoxide::crypto::sign;
+use futures_core::{Poll, Future, Never};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_core::future::{FutureResult, ok};
+
+use errors::FilterTimeoutError;
+
+/// The decision of a `FilterTimeout` if the wrapped filter does not decide in
+/// time.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OnTimeout {
+    /// Reject the client, failing with `FilterTimeoutError::TimedOut`.
+    Reject,
+    /// Accept the client.
+    Accept,
+}
+
+/// Wraps the future returned by a filter function, deciding according to an
+/// `OnTimeout` if the filter did not decide before the `delay` resolved.
+///
+/// Should the `delay` error, it is treated as resolved.
+pub struct FilterTimeout<AsyncBool, D> {
+    filter: AsyncBool,
+    delay: D,
+    on_timeout: OnTimeout,
+}
+
+impl<AsyncBool, D> FilterTimeout<AsyncBool, D>
+    where AsyncBool: Future<Item = bool>,
+          D: Future<Item = ()>
+{
+    /// Creates a new `FilterTimeout`, which resolves to the decision of
+    /// `filter` unless `delay` resolves first.
+    pub fn new(filter: AsyncBool, delay: D, on_timeout: OnTimeout) -> FilterTimeout<AsyncBool, D> {
+        FilterTimeout {
+            filter,
+            delay,
+            on_timeout,
+        }
+    }
+}
+
+impl<AsyncBool, D> Future for FilterTimeout<AsyncBool, D>
+    where AsyncBool: Future<Item = bool>,
+          D: Future<Item = ()>
+{
+    type Item = bool;
+    type Error = FilterTimeoutError<AsyncBool::Error>;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.filter.poll(cx) {
+            Ok(Ready(is_authorized)) => return Ok(Ready(is_authorized)),
+            Ok(Pending) => {}
+            Err(err) => return Err(FilterTimeoutError::FilterError(err)),
+        }
+
+        match self.delay.poll(cx) {
+            Ok(Pending) => Ok(Pending),
+            Ok(Ready(())) | Err(_) => {
+                match self.on_timeout {
+                    OnTimeout::Reject => Err(FilterTimeoutError::TimedOut),
+                    OnTimeout::Accept => Ok(Ready(true)),
+                }
+            }
+        }
+    }
+}
+
+/// A set of client longterm public keys, together with whether the keys in
+/// it are accepted or rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySet {
+    keys: HashSet<[u8; sign::PUBLICKEYBYTES]>,
+    allow: bool,
+}
+
+impl KeySet {
+    /// Creates a set that accepts only the given keys.
+    pub fn allow<I: IntoIterator<Item = sign::PublicKey>>(keys: I) -> KeySet {
+        KeySet {
+            keys: keys.into_iter().map(|pk| pk.0).collect(),
+            allow: true,
+        }
+    }
+
+    /// Creates a set that accepts all but the given keys.
+    pub fn deny<I: IntoIterator<Item = sign::PublicKey>>(keys: I) -> KeySet {
+        KeySet {
+            keys: keys.into_iter().map(|pk| pk.0).collect(),
+            allow: false,
+        }
+    }
+
+    /// Returns whether a client with the given longterm public key is
+    /// accepted.
+    pub fn is_allowed(&self, client_longterm_pk: &sign::PublicKey) -> bool {
+        self.keys.contains(&client_longterm_pk.0) == self.allow
+    }
+}
+
+/// Shared, thread-safe handle to a `KeySet` that can be replaced at runtime.
+///
+/// Every filter evaluation decides on a single version of the set, so
+/// replacing it never affects a decision halfway.
+#[derive(Clone)]
+pub struct SharedKeyFilter(Arc<Mutex<Arc<KeySet>>>);
+
+impl SharedKeyFilter {
+    /// Creates a new `SharedKeyFilter` that decides by `keys`.
+    pub fn new(keys: KeySet) -> SharedKeyFilter {
+        SharedKeyFilter(Arc::new(Mutex::new(Arc::new(keys))))
+    }
+
+    /// Returns the current key set.
+    pub fn load(&self) -> Arc<KeySet> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Replaces the key set, and returns the previous one. Filter
+    /// evaluations from then on decide by the new set.
+    pub fn replace(&self, keys: KeySet) -> Arc<KeySet> {
+        let mut current = self.0.lock().unwrap();
+        mem::replace(&mut *current, Arc::new(keys))
+    }
+
+    /// A filter function for use with `ServerHandshakerWithFilter`, which
+    /// accepts clients according to the current key set.
+    pub fn filter(&self, client_longterm_pk: &sign::PublicKey) -> FutureResult<bool, Never> {
+        ok(self.load().is_allowed(client_longterm_pk))
+    }
+}
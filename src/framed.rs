@@ -0,0 +1,110 @@
+//! Perform handshakes over message-oriented transports.
+//!
+//! Some transports deliver whole messages rather than byte streams, e.g.
+//! WebRTC data channels or message queues. Implement
+//! [`FrameTransport`](trait.FrameTransport.html) for such a transport, and
+//! wrap it in a [`Framed`](struct.Framed.html) to run any of the handshakers
+//! over it. Each handshake message is sent as a single frame, received frames
+//! are reassembled into messages regardless of how the peer split them.
+
+use std::cmp::min;
+use std::io;
+
+use futures_core::Poll;
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+/// A transport that sends and receives discrete frames.
+pub trait FrameTransport {
+    /// Attempts to send a frame, returning `Ready` once the transport has
+    /// accepted the whole frame.
+    fn poll_send(&mut self, cx: &mut Context, frame: &[u8]) -> Poll<(), io::Error>;
+
+    /// Attempts to flush all frames accepted by `poll_send`.
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error>;
+
+    /// Attempts to receive the next frame, returning `None` once the
+    /// transport has been closed by the peer.
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<Vec<u8>>, io::Error>;
+}
+
+/// Adapts a `FrameTransport` to `AsyncRead` and `AsyncWrite`.
+///
+/// Written data is buffered and sent as a single frame upon flushing.
+pub struct Framed<T> {
+    transport: T,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+    incoming_offset: usize,
+}
+
+impl<T: FrameTransport> Framed<T> {
+    /// Creates a new `Framed` over the `transport`.
+    pub fn new(transport: T) -> Framed<T> {
+        Framed {
+            transport,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+            incoming_offset: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Gets a mutable reference to the underlying transport.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consumes the `Framed`, returning the underlying transport. Buffered
+    /// data that has not been flushed or read is lost.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: FrameTransport> AsyncRead for Framed<T> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        while self.incoming_offset >= self.incoming.len() {
+            match self.transport.poll_recv(cx)? {
+                Ready(Some(frame)) => {
+                    self.incoming = frame;
+                    self.incoming_offset = 0;
+                }
+                Ready(None) => return Ok(Ready(0)),
+                Pending => return Ok(Pending),
+            }
+        }
+
+        let available = &self.incoming[self.incoming_offset..];
+        let read = min(buf.len(), available.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.incoming_offset += read;
+        Ok(Ready(read))
+    }
+}
+
+impl<T: FrameTransport> AsyncWrite for Framed<T> {
+    fn poll_write(&mut self, _cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(Ready(buf.len()))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        if !self.outgoing.is_empty() {
+            if let Pending = self.transport.poll_send(cx, &self.outgoing)? {
+                return Ok(Pending);
+            }
+            self.outgoing.clear();
+        }
+        self.transport.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.poll_flush(cx)
+    }
+}
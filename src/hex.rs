@@ -0,0 +1,89 @@
+//! Hex encoding and decoding of keys and network identifiers, as used by the
+//! [shs1-testsuite](https://github.com/AljoschaMeyer/shs1-testsuite).
+//!
+//! Encoding and decoding take time independent of the bytes' values, so they
+//! can be used on secret keys. The `decode_*` functions for key types are
+//! shorthands for `encoding::KeyEncoding::from_hex`.
+
+use sodiumoxide::crypto::sign;
+use sodiumoxide::utils::memzero;
+
+use crypto::NETWORK_IDENTIFIER_BYTES;
+use encoding::KeyEncoding;
+
+/// Encodes the bytes as lowercase hex.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        encoded.push(digit(byte >> 4) as char);
+        encoded.push(digit(byte & 0xf) as char);
+    }
+    encoded
+}
+
+/// Decodes hex of either case, or returns `None` if `s` is not valid hex.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![0; s.len() / 2];
+    if decode_into(s, &mut decoded) {
+        Some(decoded)
+    } else {
+        memzero(&mut decoded);
+        None
+    }
+}
+
+/// Decodes a hex encoded network identifier, see `KeyEncoding::from_hex`.
+pub fn decode_network_identifier(s: &str) -> Option<[u8; NETWORK_IDENTIFIER_BYTES]> {
+    KeyEncoding::from_hex(s)
+}
+
+/// Decodes a hex encoded longterm public key, see `KeyEncoding::from_hex`.
+pub fn decode_longterm_pk(s: &str) -> Option<sign::PublicKey> {
+    sign::PublicKey::from_hex(s)
+}
+
+/// Decodes a hex encoded longterm secret key, see `KeyEncoding::from_hex`.
+pub fn decode_longterm_sk(s: &str) -> Option<sign::SecretKey> {
+    sign::SecretKey::from_hex(s)
+}
+
+// Decodes `s` into `out`, returns whether `s` is valid hex of exactly the
+// length of `out`. Only the validity of `s` as a whole affects the control
+// flow.
+pub(crate) fn decode_into(s: &str, out: &mut [u8]) -> bool {
+    let s = s.as_bytes();
+    if s.len() != 2 * out.len() {
+        return false;
+    }
+
+    let mut valid = 0xff;
+    for (byte, pair) in out.iter_mut().zip(s.chunks(2)) {
+        let (high, high_valid) = value(pair[0]);
+        let (low, low_valid) = value(pair[1]);
+        *byte = (high << 4) | low;
+        valid &= high_valid & low_valid;
+    }
+    valid == 0xff
+}
+
+// The hex digit of a nibble, computed without branches or table lookups.
+fn digit(nibble: u8) -> u8 {
+    let nibble = nibble as u32;
+    // `0xffffffff` for nibbles below 10, `0` otherwise.
+    let below_ten = nibble.wrapping_sub(10) >> 8;
+    (87 + nibble + (below_ten & !38)) as u8
+}
+
+// The value of a hex digit, and `0xff` if `c` is a valid digit or `0`
+// otherwise, computed without branches or table lookups.
+fn value(c: u8) -> (u8, u8) {
+    let c = c as u32;
+
+    let num = c ^ 48;
+    let num_valid = (num.wrapping_sub(10) >> 8) & 0xff;
+
+    let alpha = (c & !32).wrapping_sub(55);
+    let alpha_valid = ((alpha.wrapping_sub(10) ^ alpha.wrapping_sub(16)) >> 8) & 0xff;
+
+    (((num_valid & num) | (alpha_valid & alpha)) as u8, (num_valid | alpha_valid) as u8)
+}
@@ -0,0 +1,215 @@
+//! The longterm keys and network identifier a peer performs handshakes with.
+
+use std::fmt::{self, Debug, Formatter};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::sign;
+
+use crypto::{NETWORK_IDENTIFIER_BYTES, Redacted, network_identifier_from_name};
+
+/// The longterm keypair of a peer, together with the network identifier
+/// under which it performs handshakes.
+///
+/// `Identity::new` is a `const fn`, so identities can be embedded in statics:
+///
+/// ```rust,ignore
+/// static IDENTITY: Identity = Identity::new([...],
+///                                           sign::PublicKey([...]),
+///                                           sign::SecretKey([...]));
+/// ```
+///
+/// The `Debug` output only shows the longterm public key.
+#[derive(Clone)]
+pub struct Identity {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: sign::PublicKey,
+    longterm_sk: sign::SecretKey,
+}
+
+impl Identity {
+    /// Creates a new `Identity`.
+    pub const fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                     longterm_pk: sign::PublicKey,
+                     longterm_sk: sign::SecretKey)
+                     -> Identity {
+        Identity {
+            network_identifier,
+            longterm_pk,
+            longterm_sk,
+        }
+    }
+
+    /// Creates a new `Identity` in the network with the given name, see
+    /// `crypto::network_identifier_from_name`.
+    pub fn with_network_name(network_name: &[u8],
+                             longterm_pk: sign::PublicKey,
+                             longterm_sk: sign::SecretKey)
+                             -> Identity {
+        Identity::new(network_identifier_from_name(network_name), longterm_pk, longterm_sk)
+    }
+
+    /// The network identifier under which handshakes are performed.
+    pub fn network_identifier(&self) -> &[u8; NETWORK_IDENTIFIER_BYTES] {
+        &self.network_identifier
+    }
+
+    /// The longterm public key.
+    pub fn longterm_pk(&self) -> &sign::PublicKey {
+        &self.longterm_pk
+    }
+
+    /// The longterm secret key.
+    pub fn longterm_sk(&self) -> &sign::SecretKey {
+        &self.longterm_sk
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Identity")
+            .field("network_identifier", &Redacted)
+            .field("longterm_pk", &self.longterm_pk)
+            .field("longterm_sk", &Redacted)
+            .finish()
+    }
+}
+
+/// A network identifier, with constructors that derive it from secrets that
+/// are easier to share than 32 random bytes.
+///
+/// Handshakes only succeed between peers that use the same network
+/// identifier, so for a private network, it is the secret that grants
+/// access. `Identity` and the handshakers take the bytes of the identifier.
+///
+/// The `Debug` output does not show the identifier.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct NetworkIdentifier(pub [u8; NETWORK_IDENTIFIER_BYTES]);
+
+// Prefix of the salts hashed by `NetworkIdentifier::from_passphrase`.
+const PASSPHRASE_SALT_PREFIX: &'static [u8] = b"shs1-network-passphrase:";
+
+/// The parameters of deriving a network identifier from a passphrase, see
+/// `NetworkIdentifier::from_passphrase`. All peers of a network have to use
+/// the same parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassphraseParams {
+    /// Distinguishes networks that happen to use the same passphrase, e.g.
+    /// the name of the network. Empty by default.
+    pub salt: Vec<u8>,
+    /// The amount of computation of the derivation.
+    pub ops_limit: usize,
+    /// The amount of memory in bytes the derivation uses.
+    pub mem_limit: usize,
+}
+
+impl PassphraseParams {
+    /// Parameters that take a fraction of a second and 16 MiB of memory.
+    pub fn interactive() -> PassphraseParams {
+        PassphraseParams {
+            salt: Vec::new(),
+            ops_limit: pwhash::OPSLIMIT_INTERACTIVE.0,
+            mem_limit: pwhash::MEMLIMIT_INTERACTIVE.0,
+        }
+    }
+
+    /// Parameters that take several seconds and 1 GiB of memory, making
+    /// guessing the passphrase far more expensive.
+    pub fn sensitive() -> PassphraseParams {
+        PassphraseParams {
+            salt: Vec::new(),
+            ops_limit: pwhash::OPSLIMIT_SENSITIVE.0,
+            mem_limit: pwhash::MEMLIMIT_SENSITIVE.0,
+        }
+    }
+}
+
+/// The interactive parameters.
+impl Default for PassphraseParams {
+    fn default() -> PassphraseParams {
+        PassphraseParams::interactive()
+    }
+}
+
+impl NetworkIdentifier {
+    /// Derives the identifier from a name of any length, see
+    /// `crypto::network_identifier_from_name`.
+    pub fn from_name(name: &[u8]) -> NetworkIdentifier {
+        NetworkIdentifier(network_identifier_from_name(name))
+    }
+
+    /// Derives the identifier from a passphrase via scrypt, a memory-hard
+    /// key derivation function, so that the identifier of a private network
+    /// can be shared as a phrase.
+    ///
+    /// The salt of scrypt is the SHA-256 hash of
+    /// `"shs1-network-passphrase:"` followed by `params.salt`. Returns `None`
+    /// if the derivation fails, e.g. because the memory limit could not be
+    /// allocated.
+    ///
+    /// A passphrase is only as strong as it is hard to guess: the identifier
+    /// can be checked against a recorded handshake, so an attacker can
+    /// guess offline.
+    pub fn from_passphrase(passphrase: &[u8],
+                           params: &PassphraseParams)
+                           -> Option<NetworkIdentifier> {
+        let mut salt = Vec::with_capacity(PASSPHRASE_SALT_PREFIX.len() + params.salt.len());
+        salt.extend_from_slice(PASSPHRASE_SALT_PREFIX);
+        salt.extend_from_slice(&params.salt);
+        let salt = pwhash::Salt(sha256::hash(&salt).0);
+
+        let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+        match pwhash::derive_key(&mut network_identifier,
+                                 passphrase,
+                                 &salt,
+                                 pwhash::OpsLimit(params.ops_limit),
+                                 pwhash::MemLimit(params.mem_limit)) {
+            Ok(_) => Some(NetworkIdentifier(network_identifier)),
+            Err(()) => None,
+        }
+    }
+
+    /// The bytes of the identifier.
+    pub fn bytes(&self) -> [u8; NETWORK_IDENTIFIER_BYTES] {
+        self.0
+    }
+}
+
+impl Debug for NetworkIdentifier {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_tuple("NetworkIdentifier").field(&Redacted).finish()
+    }
+}
+
+/// A shared, thread-safe handle to an identity that can be replaced at
+/// runtime, e.g. to rotate the longterm keys of a running `Acceptor`.
+///
+/// Replacing the identity only affects handshakes that start afterwards.
+#[derive(Clone)]
+pub struct SharedIdentity(Arc<Mutex<Arc<Identity>>>);
+
+impl SharedIdentity {
+    /// Creates a new `SharedIdentity`, initially holding `identity`.
+    pub fn new(identity: Identity) -> SharedIdentity {
+        SharedIdentity(Arc::new(Mutex::new(Arc::new(identity))))
+    }
+
+    /// Returns the current identity.
+    pub fn load(&self) -> Arc<Identity> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Replaces the identity, and returns the previous one.
+    pub fn store(&self, identity: Identity) -> Arc<Identity> {
+        let mut current = self.0.lock().unwrap();
+        mem::replace(&mut *current, Arc::new(identity))
+    }
+}
+
+impl Debug for SharedIdentity {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_tuple("SharedIdentity").field(&*self.load()).finish()
+    }
+}
@@ -0,0 +1,189 @@
+//! Dial a pub with an invite code.
+//!
+//! An ssb invite code, such as `example.com:8008:@<key>.ed25519~<seed>`,
+//! names a pub together with a seed from which the invited peer derives a
+//! throwaway longterm keypair. The pub accepts handshakes from that keypair
+//! until the invite has been used up, so that the invited peer can ask it to
+//! follow its actual identity.
+//!
+//! `Invite` parses such codes, and performs the client side of the handshake
+//! with the pub under the keypair of the invite. Redeeming the invite over
+//! the resulting connection is left to the application.
+//!
+//! ```rust,ignore
+//! let invite: Invite = code.parse()?;
+//! let (outcome, stream) = await!(invite.connect_tcp(MAIN_NETWORK))?;
+//! // Call the `invite.use` rpc with the actual longterm public key.
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use client::OwningClientHandshaker;
+#[cfg(feature = "tokio")]
+use connect::{ConnectTcp, connect_tcp};
+use crypto::{NETWORK_IDENTIFIER_BYTES, Redacted};
+use encoding::KeyEncoding;
+use identity::Identity;
+use transport::{Transport, Connect, connect};
+
+/// A parsed invite code.
+#[derive(Clone, PartialEq)]
+pub struct Invite {
+    /// The host name or ip address of the pub.
+    pub host: String,
+    /// The port of the pub.
+    pub port: u16,
+    /// The longterm public key of the pub.
+    pub server_longterm_pk: sign::PublicKey,
+    /// The seed of the longterm keypair to perform the handshake with.
+    pub seed: sign::Seed,
+}
+
+/// Everything that can go wrong when parsing an invite code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteError {
+    /// The code did not consist of an address, a key and a seed.
+    Malformed,
+    /// The address did not consist of a host and a valid port.
+    InvalidHost,
+    /// The key of the pub was not a valid ed25519 public key.
+    InvalidKey,
+    /// The seed was not valid base64 encoding of 32 bytes.
+    InvalidSeed,
+}
+
+impl Display for InviteError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Invite error: {}", self.description())
+    }
+}
+
+impl Error for InviteError {
+    fn description(&self) -> &str {
+        match *self {
+            InviteError::Malformed => "malformed invite code",
+            InviteError::InvalidHost => "invalid host or port",
+            InviteError::InvalidKey => "invalid public key",
+            InviteError::InvalidSeed => "invalid seed",
+        }
+    }
+}
+
+impl Invite {
+    /// The identity under which to perform the handshake with the pub, in
+    /// the network with the given identifier.
+    pub fn identity(&self, network_identifier: [u8; NETWORK_IDENTIFIER_BYTES]) -> Identity {
+        let (longterm_pk, longterm_sk) = sign::keypair_from_seed(&self.seed);
+        Identity::new(network_identifier, longterm_pk, longterm_sk)
+    }
+
+    /// Creates a handshaker that performs the client side of a handshake
+    /// with the pub over the `stream`, using fresh ephemeral keys.
+    pub fn handshake<S>(&self,
+                        stream: S,
+                        network_identifier: [u8; NETWORK_IDENTIFIER_BYTES])
+                        -> OwningClientHandshaker<S>
+        where S: AsyncRead + AsyncWrite
+    {
+        let identity = self.identity(network_identifier);
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+        OwningClientHandshaker::new(stream,
+                                    network_identifier,
+                                    identity.longterm_pk().clone(),
+                                    identity.longterm_sk().clone(),
+                                    ephemeral_pk,
+                                    ephemeral_sk,
+                                    self.server_longterm_pk.clone())
+    }
+
+    /// Connects to the host and port of the pub via the `transport`, and
+    /// performs a handshake with it.
+    pub fn connect<T>(&self,
+                      transport: &mut T,
+                      network_identifier: [u8; NETWORK_IDENTIFIER_BYTES])
+                      -> Connect<T::Connecting, T::Stream>
+        where T: Transport<(String, u16)>
+    {
+        connect(transport,
+                &(self.host.clone(), self.port),
+                &self.identity(network_identifier),
+                &self.server_longterm_pk)
+    }
+
+    /// Resolves the host of the pub, connects to it over tcp, and performs a
+    /// handshake with it.
+    #[cfg(feature = "tokio")]
+    pub fn connect_tcp(&self, network_identifier: [u8; NETWORK_IDENTIFIER_BYTES]) -> ConnectTcp {
+        connect_tcp((self.host.as_str(), self.port),
+                    &self.identity(network_identifier),
+                    &self.server_longterm_pk)
+    }
+}
+
+/// Parses codes of the form `host:port:@key.ed25519~seed`, with the key and
+/// the seed in base64.
+impl FromStr for Invite {
+    type Err = InviteError;
+
+    fn from_str(s: &str) -> Result<Invite, InviteError> {
+        let mut parts = s.trim().rsplitn(2, '~');
+        let seed = parts.next().ok_or(InviteError::Malformed)?;
+        let address = parts.next().ok_or(InviteError::Malformed)?;
+
+        let mut parts = address.rsplitn(2, ":@");
+        let key = parts.next().ok_or(InviteError::Malformed)?;
+        let host_port = parts.next().ok_or(InviteError::Malformed)?;
+
+        let mut parts = host_port.rsplitn(2, ':');
+        let port = parts
+            .next()
+            .and_then(|port| port.parse().ok())
+            .ok_or(InviteError::InvalidHost)?;
+        let host = match parts.next() {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => return Err(InviteError::InvalidHost),
+        };
+
+        if !key.ends_with(".ed25519") {
+            return Err(InviteError::InvalidKey);
+        }
+        let key = &key[..key.len() - ".ed25519".len()];
+        let server_longterm_pk = sign::PublicKey::from_base64(key).ok_or(InviteError::InvalidKey)?;
+        let seed = sign::Seed::from_base64(seed).ok_or(InviteError::InvalidSeed)?;
+
+        Ok(Invite {
+               host,
+               port,
+               server_longterm_pk,
+               seed,
+           })
+    }
+}
+
+impl Display for Invite {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+               "{}:{}:@{}.ed25519~{}",
+               self.host,
+               self.port,
+               self.server_longterm_pk.to_base64(),
+               self.seed.to_base64())
+    }
+}
+
+/// The `Debug` output does not show the seed.
+impl Debug for Invite {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Invite")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("server_longterm_pk", &self.server_longterm_pk)
+            .field("seed", &Redacted)
+            .finish()
+    }
+}
@@ -0,0 +1,164 @@
+//! Discover peers on the local network.
+//!
+//! Ssb peers announce themselves on the local network by periodically
+//! broadcasting udp packets to port 8008. Each packet contains the
+//! multiserver address of the peer, e.g.
+//! `net:192.168.1.23:8008~shs:<base64 encoded longterm public key>`, or
+//! several such addresses separated by `;`.
+//!
+//! `Announcement::parse` turns the payload of such a packet into the
+//! longterm public key of the peer and its addresses, and `recv` reads
+//! announcements from a bound socket:
+//!
+//! ```rust,ignore
+//! let socket = UdpSocket::bind(("0.0.0.0", lan::PORT))?;
+//! loop {
+//!     let (announcement, _) = lan::recv(&socket)?;
+//!     if let Some(addr) = announcement.net() {
+//!         connect_tcp(addr, &identity, &announcement.server_longterm_pk);
+//!     }
+//! }
+//! ```
+//!
+//! Anyone on the local network can send announcements, for any key and any
+//! address. This is harmless for the handshake, which authenticates the
+//! server regardless, but announcements should not be trusted otherwise.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::{self, Utf8Error};
+
+use sodiumoxide::crypto::sign;
+
+use multiserver::{self, Address, ParseError};
+
+/// The udp port to which ssb peers broadcast their announcements.
+pub const PORT: u16 = 8008;
+
+/// The announcement of a peer on the local network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    /// The longterm public key of the announced peer.
+    pub server_longterm_pk: sign::PublicKey,
+    /// The addresses under which the peer can be reached. They all have a
+    /// `shs` transform with `server_longterm_pk`.
+    pub addresses: Vec<Address>,
+}
+
+/// Everything that can go wrong when parsing an announcement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnouncementError {
+    /// The packet was not valid utf-8.
+    Utf8(Utf8Error),
+    /// The packet was not a valid multiserver address.
+    Address(ParseError),
+    /// None of the addresses contained a `shs` transform.
+    NoKey,
+    /// The addresses contained `shs` transforms with different keys.
+    ConflictingKeys,
+}
+
+impl Display for AnnouncementError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            AnnouncementError::Utf8(ref err) => write!(f, "Announcement error: {}", err),
+            AnnouncementError::Address(ref err) => write!(f, "Announcement error: {}", err),
+            _ => write!(f, "Announcement error: {}", self.description()),
+        }
+    }
+}
+
+impl Error for AnnouncementError {
+    fn description(&self) -> &str {
+        match *self {
+            AnnouncementError::Utf8(ref err) => err.description(),
+            AnnouncementError::Address(ref err) => err.description(),
+            AnnouncementError::NoKey => "no shs key",
+            AnnouncementError::ConflictingKeys => "conflicting shs keys",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            AnnouncementError::Utf8(ref err) => Some(err),
+            AnnouncementError::Address(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for AnnouncementError {
+    fn from(err: Utf8Error) -> AnnouncementError {
+        AnnouncementError::Utf8(err)
+    }
+}
+
+impl From<ParseError> for AnnouncementError {
+    fn from(err: ParseError) -> AnnouncementError {
+        AnnouncementError::Address(err)
+    }
+}
+
+impl Announcement {
+    /// Parses the payload of an announcement packet.
+    ///
+    /// Addresses without a `shs` transform can not be dialed with a handshake,
+    /// so they are skipped. Trailing whitespace is ignored.
+    pub fn parse(packet: &[u8]) -> Result<Announcement, AnnouncementError> {
+        let addresses = multiserver::parse(str::from_utf8(packet)?.trim_right())?;
+
+        let mut server_longterm_pk = None;
+        let mut dialable = Vec::new();
+        for address in addresses {
+            match (address.shs().cloned(), server_longterm_pk.clone()) {
+                (None, _) => continue,
+                (Some(key), None) => server_longterm_pk = Some(key),
+                (Some(key), Some(first)) => {
+                    if key != first {
+                        return Err(AnnouncementError::ConflictingKeys);
+                    }
+                }
+            }
+            dialable.push(address);
+        }
+
+        match server_longterm_pk {
+            Some(server_longterm_pk) => {
+                Ok(Announcement {
+                       server_longterm_pk,
+                       addresses: dialable,
+                   })
+            }
+            None => Err(AnnouncementError::NoKey),
+        }
+    }
+
+    /// Formats the announcement as the payload of a packet.
+    pub fn to_packet(&self) -> Vec<u8> {
+        multiserver::format(&self.addresses).into_bytes()
+    }
+
+    /// Returns the host and port of the first address with a `net`
+    /// transport, as accepted by `connect::connect_tcp`.
+    pub fn net(&self) -> Option<(&str, u16)> {
+        self.addresses.iter().filter_map(Address::net).next()
+    }
+}
+
+/// Receives packets on the `socket` until one of them contains a valid
+/// announcement, and returns it together with the address it was sent from.
+///
+/// This blocks unless the socket is nonblocking, in which case an error of
+/// kind `WouldBlock` is returned once no more packets are queued.
+pub fn recv(socket: &UdpSocket) -> io::Result<(Announcement, SocketAddr)> {
+    // Announcements are short, and udp truncates longer packets.
+    let mut buf = [0; 1024];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if let Ok(announcement) = Announcement::parse(&buf[..len]) {
+            return Ok((announcement, from));
+        }
+    }
+}
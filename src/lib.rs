@@ -1,24 +1,105 @@
 //! Implementation of the [secret-handshake](https://github.com/auditdrivencrypto/secret-handshake)
-//! protocol version 1.
+//! protocol version 1, see the `v1` module.
 //!
 //! This library uses libsodium internally. In application code, call
 //! [`sodiumoxide::init()`](https://dnaq.github.io/sodiumoxide/sodiumoxide/fn.init.html)
 //! before performing any handshakes.
+//!
+//! Like fused futures, the handshakers return `Pending` when polled again
+//! after they completed, rather than panicking.
 
 #![deny(missing_docs)]
+extern crate base64;
+#[cfg(feature = "box-stream")]
+extern crate box_stream;
 extern crate sodiumoxide;
 extern crate libc;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "mio")]
+extern crate mio;
 extern crate futures_core;
 extern crate futures_io;
+#[cfg(feature = "test-utils")]
+extern crate proptest;
+#[cfg(feature = "serialize-outcome")]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
+pub mod acceptor;
+pub mod audit;
+pub mod auth_only;
+pub mod batch;
+pub mod budget;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod completion;
+pub mod confirm;
+#[cfg(feature = "tokio")]
+pub mod connect;
 pub mod crypto;
+pub mod deadline;
+#[cfg(feature = "tokio")]
+pub mod dialer;
+pub mod encoding;
 pub mod errors;
+pub mod filter;
+pub mod framed;
+pub mod hex;
+pub mod identity;
+pub mod invite;
+pub mod lan;
+pub mod messages;
+pub mod metrics;
+#[cfg(feature = "mio")]
+pub mod mio_handshake;
+pub mod multiserver;
+pub mod nonce;
+pub mod obfuscate;
+pub mod offload;
+#[cfg(feature = "secret-stream")]
+pub mod peer_pool;
+pub mod pool;
+pub mod proxy;
+pub mod rate_limit;
+pub mod replay;
+pub mod retry;
+pub mod room;
+pub mod sans_io;
+#[cfg(feature = "secret-stream")]
+pub mod secret_stream;
+pub mod service;
+pub mod session_cache;
+pub mod stage;
+pub mod stats;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+#[cfg(feature = "session-tickets")]
+pub mod tickets;
+pub mod transcript;
+pub mod transport;
+pub mod typestate;
+#[cfg(feature = "box-stream")]
+pub mod upgrade;
+pub mod v1;
+pub mod version;
+pub mod wire;
 mod client;
 mod server;
+mod trace;
 
 pub use client::*;
 pub use server::*;
-pub use crypto::{Outcome, NETWORK_IDENTIFIER_BYTES};
+pub use crypto::{Outcome, EncryptionParams, DecryptionParams, SessionKeys, Authenticated,
+                 SendHalf, RecvHalf, PeerId, NETWORK_IDENTIFIER_BYTES,
+                 network_identifier_from_name};
+pub use deadline::Deadline;
+pub use identity::{Identity, NetworkIdentifier};
+pub use stage::Stage;
+pub use version::Version;
 
 #[cfg(test)]
 extern crate async_ringbuffer;
@@ -26,6 +107,8 @@ extern crate async_ringbuffer;
 extern crate atm_io_utils;
 #[cfg(test)]
 extern crate futures;
+#[cfg(all(test, feature = "serialize-outcome"))]
+extern crate serde_json;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,91 @@
+//! The four messages of a handshake as typed values.
+//!
+//! Each message wraps a fixed-size byte array. Use `from_slice` to parse a
+//! message from untrusted bytes, and `as_bytes` to serialize it. The
+//! `crypto::Client` and `crypto::Server` can create and verify these types
+//! directly.
+
+use std::fmt::{self, Debug, Formatter};
+
+use crypto::{MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES};
+
+macro_rules! message {
+    ($(#[$attr:meta])* $name:ident, $len:expr) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy)]
+        pub struct $name([u8; $len]);
+
+        impl $name {
+            /// Length of the message in bytes.
+            pub const LEN: usize = $len;
+
+            /// Wraps the given bytes.
+            pub fn from_bytes(bytes: [u8; $len]) -> $name {
+                $name(bytes)
+            }
+
+            /// Copies the message from a slice, returning `None` if it does
+            /// not have the right length.
+            pub fn from_slice(bytes: &[u8]) -> Option<$name> {
+                if bytes.len() != $len {
+                    return None;
+                }
+
+                let mut msg = [0; $len];
+                msg.copy_from_slice(bytes);
+                Some($name(msg))
+            }
+
+            /// The bytes of the message.
+            pub fn as_bytes(&self) -> &[u8; $len] {
+                &self.0
+            }
+
+            /// Mutable access to the bytes of the message, e.g. to corrupt it
+            /// for testing.
+            pub fn as_bytes_mut(&mut self) -> &mut [u8; $len] {
+                &mut self.0
+            }
+
+            /// Unwraps the bytes of the message.
+            pub fn into_bytes(self) -> [u8; $len] {
+                self.0
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &$name) -> bool {
+                self.0[..] == other.0[..]
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+                write!(f, "{}({:?})", stringify!($name), &self.0[..])
+            }
+        }
+    }
+}
+
+message!(
+    /// The hello sent by the client (msg1): its ephemeral public key,
+    /// authenticated with the network identifier.
+    ClientHello, MSG1_BYTES);
+
+message!(
+    /// The hello sent by the server (msg2): its ephemeral public key,
+    /// authenticated with the network identifier.
+    ServerHello, MSG2_BYTES);
+
+message!(
+    /// The authentication sent by the client (msg3): its encrypted longterm
+    /// public key and a signature proving knowledge of the server's longterm
+    /// public key.
+    ClientAuth, MSG3_BYTES);
+
+message!(
+    /// The acknowledgement sent by the server (msg4): an encrypted signature
+    /// proving the server's identity.
+    ServerAck, MSG4_BYTES);
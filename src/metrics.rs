@@ -0,0 +1,109 @@
+//! Counters for monitoring the handshakes performed by a server.
+//!
+//! Attach the same `Metrics` handle to any number of server handshakers via
+//! their `set_metrics` method, and retrieve the aggregated counters with
+//! `Metrics::snapshot`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use errors::FilteringHandshakeError;
+
+/// Shared, thread-safe handle to a set of handshake counters.
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Mutex<MetricsSnapshot>>);
+
+/// The values of all counters of a `Metrics` at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetricsSnapshot {
+    /// Number of handshakes started.
+    pub started: u64,
+    /// Number of handshakes that completed successfully.
+    pub succeeded: u64,
+    /// Number of handshakes that failed due to an io error.
+    pub io_errors: u64,
+    /// Number of handshakes that failed because the client sent an invalid msg1.
+    pub invalid_msg1: u64,
+    /// Number of handshakes that failed because the client sent an invalid msg3.
+    pub invalid_msg3: u64,
+    /// Number of handshakes where the client was rejected by the filter function.
+    pub rejected: u64,
+    /// Number of handshakes where the filter function errored.
+    pub filter_errors: u64,
+    /// Number of handshakes that failed because the client used the server's own key.
+    pub self_connections: u64,
+    /// Number of handshakes that exceeded their resource budget.
+    pub budget_exceeded: u64,
+    /// The summed duration of all successful handshakes.
+    pub total_duration: Duration,
+}
+
+impl MetricsSnapshot {
+    /// Number of handshakes that failed for any reason.
+    pub fn failed(&self) -> u64 {
+        self.io_errors + self.invalid_msg1 + self.invalid_msg3 + self.rejected +
+        self.filter_errors + self.self_connections + self.budget_exceeded
+    }
+
+    /// The mean duration of the successful handshakes, or `None` if there
+    /// were none.
+    pub fn mean_duration(&self) -> Option<Duration> {
+        if self.succeeded == 0 {
+            return None;
+        }
+
+        // Divides seconds and the remaining nanoseconds separately, so that
+        // neither the count nor the total is truncated.
+        let secs = self.total_duration.as_secs();
+        let remainder = (secs % self.succeeded) as f64 * 1e9 +
+                        self.total_duration.subsec_nanos() as f64;
+        Some(Duration::new(secs / self.succeeded,
+                           (remainder / self.succeeded as f64) as u32))
+    }
+}
+
+impl Metrics {
+    /// Creates a new `Metrics` with all counters set to zero.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Returns the current values of all counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        *self.0.lock().unwrap()
+    }
+
+    /// Sets all counters to zero.
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = MetricsSnapshot::default();
+    }
+
+    pub(crate) fn record_start(&self) {
+        self.0.lock().unwrap().started += 1;
+    }
+
+    pub(crate) fn record_success(&self, duration: Duration) {
+        let mut counters = self.0.lock().unwrap();
+        counters.succeeded += 1;
+        counters.total_duration += duration;
+    }
+
+    // `during_msg1` indicates whether a crypto error concerned msg1 or msg3.
+    pub(crate) fn record_failure<E>(&self, err: &FilteringHandshakeError<E>, during_msg1: bool) {
+        let mut counters = self.0.lock().unwrap();
+        match *err {
+            FilteringHandshakeError::IoError(_) => counters.io_errors += 1,
+            FilteringHandshakeError::FilterError(_) => counters.filter_errors += 1,
+            FilteringHandshakeError::CryptoError => {
+                if during_msg1 {
+                    counters.invalid_msg1 += 1;
+                } else {
+                    counters.invalid_msg3 += 1;
+                }
+            }
+            FilteringHandshakeError::Rejected => counters.rejected += 1,
+            FilteringHandshakeError::SelfConnection => counters.self_connections += 1,
+            FilteringHandshakeError::BudgetExceeded => counters.budget_exceeded += 1,
+        }
+    }
+}
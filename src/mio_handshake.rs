@@ -0,0 +1,112 @@
+//! Drive a `HandshakeState` over a non-blocking
+//! [mio](https://crates.io/crates/mio) stream, without any futures executor.
+//!
+//! Register the stream with the interest returned by `interest`, and call
+//! `advance` whenever the stream becomes ready. `advance` reads and writes
+//! until the stream would block or the handshake is done, so it works with
+//! edge-triggered registrations.
+//!
+//! ```rust,ignore
+//! let mut state = HandshakeState::client(&net_id, &pk, &sk, &eph_pk, &eph_sk, &server_pk);
+//! mio_handshake::register(&poll, &stream, TOKEN, &state)?;
+//!
+//! loop {
+//!     poll.poll(&mut events, None)?;
+//!     if mio_handshake::advance(&mut state, &mut stream)? {
+//!         break;
+//!     }
+//!     mio_handshake::reregister(&poll, &stream, TOKEN, &state)?;
+//! }
+//!
+//! let outcome = state.outcome().unwrap();
+//! ```
+//!
+//! This module requires the `mio` feature.
+
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::{Interrupted, UnexpectedEof, WouldBlock, WriteZero};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+
+use errors::HandshakeError;
+use sans_io::HandshakeState;
+
+/// The readiness the handshake currently waits for: readable while it wants
+/// to read, writable while it wants to write, and empty once it is done or
+/// failed.
+pub fn interest(state: &HandshakeState) -> Ready {
+    if state.wants_write() > 0 {
+        Ready::writable()
+    } else if state.wants_read() > 0 {
+        Ready::readable()
+    } else {
+        Ready::empty()
+    }
+}
+
+/// Registers the `stream` with `poll`, edge-triggered, for the current
+/// interest of the handshake.
+pub fn register<E: Evented>(poll: &Poll,
+                            stream: &E,
+                            token: Token,
+                            state: &HandshakeState)
+                            -> io::Result<()> {
+    poll.register(stream, token, interest(state), PollOpt::edge())
+}
+
+/// Updates the registration of the `stream` to the current interest of the
+/// handshake. Call this after every `advance` that did not complete the
+/// handshake.
+pub fn reregister<E: Evented>(poll: &Poll,
+                              stream: &E,
+                              token: Token,
+                              state: &HandshakeState)
+                              -> io::Result<()> {
+    poll.reregister(stream, token, interest(state), PollOpt::edge())
+}
+
+/// Moves handshake messages between the `state` and the non-blocking
+/// `stream` until the stream would block or the handshake is done. Returns
+/// whether the handshake is done, in which case `state.outcome()` yields the
+/// outcome.
+///
+/// Written data is not flushed, so the stream should not buffer writes, as
+/// is the case for `mio::net::TcpStream`.
+pub fn advance<S: Read + Write>(state: &mut HandshakeState,
+                                stream: &mut S)
+                                -> Result<bool, HandshakeError> {
+    loop {
+        if state.is_done() {
+            return Ok(true);
+        }
+
+        if state.wants_write() > 0 {
+            let written = match stream.write(state.outgoing()) {
+                Ok(0) => {
+                    return Err(io::Error::new(WriteZero, "failed to write handshake message")
+                                   .into())
+                }
+                Ok(written) => written,
+                Err(ref err) if err.kind() == WouldBlock => return Ok(false),
+                Err(ref err) if err.kind() == Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            state.advance_write(written);
+        } else if state.wants_read() > 0 {
+            let read = match stream.read(state.incoming()) {
+                Ok(0) => {
+                    return Err(io::Error::new(UnexpectedEof, "failed to read handshake message")
+                                   .into())
+                }
+                Ok(read) => read,
+                Err(ref err) if err.kind() == WouldBlock => return Ok(false),
+                Err(ref err) if err.kind() == Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            state.advance_read(read)?;
+        } else {
+            // A failed state neither reads nor writes.
+            return Err(HandshakeError::CryptoError);
+        }
+    }
+}
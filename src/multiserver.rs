@@ -0,0 +1,326 @@
+//! Parse and format [multiserver](https://github.com/ssbc/multiserver)
+//! addresses, as used by ssb to advertise how to reach a peer.
+//!
+//! A multiserver address consists of one or more addresses separated by `;`.
+//! Each address is a transport protocol followed by any number of transform
+//! protocols, separated by `~`, e.g.
+//! `net:example.com:8008~shs:<base64 encoded longterm public key>`. Each
+//! protocol consists of a name and data segments separated by `:`. The
+//! characters `:`, `~`, `;` and `!` are escaped within data segments by
+//! prefixing them with a `!`.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use sodiumoxide::crypto::sign;
+
+use encoding::KeyEncoding;
+
+/// A single multiserver address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    /// How to establish a connection.
+    pub transport: Transport,
+    /// How to secure the connection once it has been established.
+    pub transforms: Vec<Transform>,
+}
+
+/// A transport protocol of a multiserver address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// A tcp connection, `net:host:port`.
+    Net {
+        /// The host name or ip address to connect to.
+        host: String,
+        /// The port to connect to.
+        port: u16,
+    },
+    /// A tcp connection via tor, `onion:host:port`.
+    Onion {
+        /// The onion address to connect to.
+        host: String,
+        /// The port to connect to.
+        port: u16,
+    },
+    /// Any other transport protocol.
+    Other(Protocol),
+}
+
+/// A transform protocol of a multiserver address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// A secret-handshake with a server, `shs:key`.
+    Shs {
+        /// The longterm public key of the server.
+        server_longterm_pk: sign::PublicKey,
+    },
+    /// Any other transform protocol.
+    Other(Protocol),
+}
+
+/// An uninterpreted protocol of a multiserver address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    /// The name of the protocol.
+    pub name: String,
+    /// The unescaped data segments of the protocol.
+    pub data: Vec<String>,
+}
+
+/// Everything that can go wrong when parsing a multiserver address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// An address or one of its protocols was empty.
+    Empty,
+    /// A `net` or `onion` transport did not consist of a host and a valid port.
+    InvalidHost,
+    /// A `shs` transform did not contain a valid public key.
+    InvalidKey,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Multiserver address error: {}", self.description())
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::Empty => "empty address or protocol",
+            ParseError::InvalidHost => "invalid host or port",
+            ParseError::InvalidKey => "invalid public key",
+        }
+    }
+}
+
+/// Parses a multiserver address consisting of one or more `;`-separated
+/// addresses.
+pub fn parse(addresses: &str) -> Result<Vec<Address>, ParseError> {
+    split_unescaped(addresses, ';')
+        .into_iter()
+        .map(|address| address.parse())
+        .collect()
+}
+
+/// Formats a list of addresses as a single multiserver address.
+pub fn format(addresses: &[Address]) -> String {
+    addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+impl Address {
+    /// Returns the host and port of a `net` transport.
+    pub fn net(&self) -> Option<(&str, u16)> {
+        match self.transport {
+            Transport::Net { ref host, port } => Some((host, port)),
+            _ => None,
+        }
+    }
+
+    /// Returns the host and port of an `onion` transport.
+    pub fn onion(&self) -> Option<(&str, u16)> {
+        match self.transport {
+            Transport::Onion { ref host, port } => Some((host, port)),
+            _ => None,
+        }
+    }
+
+    /// Returns the server longterm public key of the first `shs` transform.
+    pub fn shs(&self) -> Option<&sign::PublicKey> {
+        self.transforms
+            .iter()
+            .filter_map(|transform| match *transform {
+                            Transform::Shs { ref server_longterm_pk } => Some(server_longterm_pk),
+                            _ => None,
+                        })
+            .next()
+    }
+}
+
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Address, ParseError> {
+        let mut protocols = split_unescaped(s, '~').into_iter();
+
+        let transport = match protocols.next() {
+            Some(protocol) => Transport::from_protocol(protocol.parse()?)?,
+            None => return Err(ParseError::Empty),
+        };
+
+        let transforms = protocols
+            .map(|protocol| Transform::from_protocol(protocol.parse()?))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Address {
+               transport,
+               transforms,
+           })
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.transport.to_protocol())?;
+        for transform in self.transforms.iter() {
+            write!(f, "~{}", transform.to_protocol())?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport {
+    fn from_protocol(protocol: Protocol) -> Result<Transport, ParseError> {
+        if protocol.name == "net" {
+            let (host, port) = host_and_port(&protocol.data)?;
+            Ok(Transport::Net { host, port })
+        } else if protocol.name == "onion" {
+            let (host, port) = host_and_port(&protocol.data)?;
+            Ok(Transport::Onion { host, port })
+        } else {
+            Ok(Transport::Other(protocol))
+        }
+    }
+
+    fn to_protocol(&self) -> Protocol {
+        match *self {
+            Transport::Net { ref host, port } => {
+                Protocol {
+                    name: "net".to_string(),
+                    data: host_and_port_data(host, port),
+                }
+            }
+            Transport::Onion { ref host, port } => {
+                Protocol {
+                    name: "onion".to_string(),
+                    data: host_and_port_data(host, port),
+                }
+            }
+            Transport::Other(ref protocol) => protocol.clone(),
+        }
+    }
+}
+
+impl Transform {
+    fn from_protocol(protocol: Protocol) -> Result<Transform, ParseError> {
+        if protocol.name == "shs" {
+            let server_longterm_pk = decode_key(protocol.data.first())?;
+            Ok(Transform::Shs { server_longterm_pk })
+        } else {
+            Ok(Transform::Other(protocol))
+        }
+    }
+
+    fn to_protocol(&self) -> Protocol {
+        match *self {
+            Transform::Shs { ref server_longterm_pk } => {
+                Protocol {
+                    name: "shs".to_string(),
+                    data: vec![server_longterm_pk.to_base64()],
+                }
+            }
+            Transform::Other(ref protocol) => protocol.clone(),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Protocol, ParseError> {
+        let mut segments = split_unescaped(s, ':').into_iter();
+        let name = match segments.next() {
+            Some(ref name) if !name.is_empty() => unescape(name),
+            _ => return Err(ParseError::Empty),
+        };
+
+        Ok(Protocol {
+               name,
+               data: segments.map(|segment| unescape(segment)).collect(),
+           })
+    }
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", escape(&self.name))?;
+        for segment in self.data.iter() {
+            write!(f, ":{}", escape(segment))?;
+        }
+        Ok(())
+    }
+}
+
+fn decode_key(data: Option<&String>) -> Result<sign::PublicKey, ParseError> {
+    let key = data.ok_or(ParseError::InvalidKey)?;
+    sign::PublicKey::from_base64(key).ok_or(ParseError::InvalidKey)
+}
+
+// The port is the last data segment, everything before it is the host (which
+// may contain colons itself if it is an ipv6 address).
+fn host_and_port(data: &[String]) -> Result<(String, u16), ParseError> {
+    match data.split_last() {
+        Some((port, host)) if !host.is_empty() => {
+            let port = port.parse().map_err(|_| ParseError::InvalidHost)?;
+            Ok((host.join(":"), port))
+        }
+        _ => Err(ParseError::InvalidHost),
+    }
+}
+
+fn host_and_port_data(host: &str, port: u16) -> Vec<String> {
+    let mut data: Vec<String> = host.split(':').map(|part| part.to_string()).collect();
+    data.push(port.to_string());
+    data
+}
+
+// Splits at all occurences of `separator` that are not escaped by a `!`.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '!' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ':' || c == '~' || c == ';' || c == '!' {
+            escaped.push('!');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut escaped = false;
+    for c in s.chars() {
+        if !escaped && c == '!' {
+            escaped = true;
+        } else {
+            escaped = false;
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
@@ -0,0 +1,84 @@
+//! Generate the sequence of nonces for encrypting or decrypting a stream of
+//! secretboxes, starting from the initial nonce of an `Outcome`.
+//!
+//! Box-stream (and `secret_stream`) interpret nonces as 24 byte big-endian
+//! numbers, and increment them by one after every secretbox. Each packet
+//! uses two nonces, the first for its header and the second for its body.
+//!
+//! ```rust,ignore
+//! let mut nonces = NonceGen::new(outcome.send().nonce);
+//! let (header_nonce, body_nonce) = nonces.next_packet().expect("nonces exhausted");
+//! ```
+
+use sodiumoxide::crypto::secretbox;
+
+/// Yields consecutive nonces, and never yields the same nonce twice.
+///
+/// The counter wraps around from all `0xff` bytes to all zeroes. Once it
+/// would reach the initial nonce again, the generator is exhausted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceGen {
+    initial: secretbox::Nonce,
+    next: secretbox::Nonce,
+    exhausted: bool,
+}
+
+impl NonceGen {
+    /// Creates a new `NonceGen` whose first nonce is `initial`.
+    pub fn new(initial: secretbox::Nonce) -> NonceGen {
+        NonceGen {
+            initial,
+            next: initial,
+            exhausted: false,
+        }
+    }
+
+    /// The nonce that the next call to `next` will return, or `None` if the
+    /// generator is exhausted.
+    pub fn peek(&self) -> Option<secretbox::Nonce> {
+        if self.exhausted { None } else { Some(self.next) }
+    }
+
+    /// Returns the next nonce, or `None` if the generator is exhausted.
+    pub fn next(&mut self) -> Option<secretbox::Nonce> {
+        if self.exhausted {
+            return None;
+        }
+
+        let nonce = self.next;
+        self.next = increment(nonce);
+        self.exhausted = self.next == self.initial;
+        Some(nonce)
+    }
+
+    /// Returns the header and body nonces of the next box-stream packet, or
+    /// `None` if fewer than two nonces remain. In that case, no nonce is
+    /// consumed.
+    pub fn next_packet(&mut self) -> Option<(secretbox::Nonce, secretbox::Nonce)> {
+        if self.exhausted || increment(self.next) == self.initial {
+            return None;
+        }
+
+        let header_nonce = self.next().unwrap();
+        let body_nonce = self.next().unwrap();
+        Some((header_nonce, body_nonce))
+    }
+
+    /// Returns whether all nonces have been used up.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
+
+/// Interprets the nonce as a big-endian number and increments it by one,
+/// wrapping around on overflow.
+pub fn increment(nonce: secretbox::Nonce) -> secretbox::Nonce {
+    let secretbox::Nonce(mut bytes) = nonce;
+    for byte in bytes.iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+    secretbox::Nonce(bytes)
+}
@@ -0,0 +1,321 @@
+//! Disguise the sizes and timing of the handshake messages.
+//!
+//! The four handshake messages are 64, 64, 112 and 80 bytes long, and are
+//! exchanged in lockstep. Deep packet inspection can flag connections by this
+//! pattern alone. An `Obfuscated` stream, wrapped around the connection for
+//! the duration of the handshake, sends each write as a frame with a random
+//! amount of random padding, and can delay each frame by a random jitter.
+//!
+//! Each frame starts with a four byte header holding the length of the
+//! padding and of the payload, both as big-endian 16 bit integers, followed
+//! by the padding and then the payload. The header is masked with the first
+//! four bytes of HMAC-SHA-256 keyed with the network identifier, over
+//! `"shs1-obfuscation"`, a byte for the sender (`0` for the client, `1` for
+//! the server) and the index of the frame as a big-endian 64 bit integer.
+//! So the header looks random to anyone who does not know the network
+//! identifier.
+//!
+//! Both peers have to obfuscate, an obfuscating peer can not handshake with
+//! one that does not. Peers that do not use this module are unaffected. Once
+//! the handshake is done, unwrap the stream via `into_inner`:
+//!
+//! ```rust,ignore
+//! let stream = Obfuscated::client(stream, network_identifier, Obfuscation::default(), make_delay);
+//! let (outcome, stream) = await!(OwningClientHandshaker::new(stream, ...))?;
+//! let stream = SecretStream::new(&outcome, stream.into_inner());
+//! ```
+//!
+//! Since the padding precedes the payload, a frame has been read completely
+//! once its payload has been, so no data after the handshake is consumed.
+
+use std::cmp::min;
+use std::io;
+use std::io::ErrorKind::{UnexpectedEof, WriteZero};
+use std::time::Duration;
+
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::randombytes::randombytes_into;
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::NETWORK_IDENTIFIER_BYTES;
+use server::random_below;
+
+// Prefix of the input of the HMAC from which the header masks are taken.
+const MASK_LABEL: &'static [u8] = b"shs1-obfuscation";
+const HEADER_BYTES: usize = 4;
+
+/// How an `Obfuscated` stream disguises the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Obfuscation {
+    /// The maximum number of padding bytes per frame. The actual number is
+    /// chosen uniformly at random for each frame.
+    pub max_padding: u16,
+    /// The maximum time to wait before sending each frame. The actual time
+    /// is chosen uniformly at random, in whole milliseconds. `None` sends
+    /// frames right away.
+    pub max_jitter: Option<Duration>,
+}
+
+/// Up to 255 bytes of padding per frame, and no jitter.
+impl Default for Obfuscation {
+    fn default() -> Obfuscation {
+        Obfuscation {
+            max_padding: 255,
+            max_jitter: None,
+        }
+    }
+}
+
+/// Wraps a stream, framing and padding all data written to it, see the
+/// module documentation.
+pub struct Obfuscated<S, MakeDelay, D> {
+    stream: S,
+    key: hmacsha256::Key,
+    client: bool,
+    obfuscation: Obfuscation,
+    make_delay: MakeDelay,
+    // writing
+    frames_sent: u64,
+    delay: Option<D>,
+    frame: Vec<u8>, // the frame currently being written
+    written: usize, // how much of `frame` has been written
+    payload_len: usize, // the length of the payload of `frame`
+    // reading
+    frames_received: u64,
+    header: [u8; HEADER_BYTES],
+    header_read: usize,
+    padding_left: usize, // padding of the current frame that is yet to be skipped
+    payload_left: usize, // payload of the current frame that is yet to be read
+}
+
+impl<S, MakeDelay, D> Obfuscated<S, MakeDelay, D>
+    where MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    /// Wraps the stream of the client of a handshake.
+    ///
+    /// `make_delay` is called to create a delay future of your runtime for
+    /// the jitter of each frame, a delay that errors counts as elapsed.
+    pub fn client(stream: S,
+                  network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                  obfuscation: Obfuscation,
+                  make_delay: MakeDelay)
+                  -> Obfuscated<S, MakeDelay, D> {
+        Obfuscated::new(stream, network_identifier, true, obfuscation, make_delay)
+    }
+
+    /// Wraps the stream of the server of a handshake.
+    ///
+    /// `make_delay` is called to create a delay future of your runtime for
+    /// the jitter of each frame, a delay that errors counts as elapsed.
+    pub fn server(stream: S,
+                  network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                  obfuscation: Obfuscation,
+                  make_delay: MakeDelay)
+                  -> Obfuscated<S, MakeDelay, D> {
+        Obfuscated::new(stream, network_identifier, false, obfuscation, make_delay)
+    }
+
+    fn new(stream: S,
+           network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+           client: bool,
+           obfuscation: Obfuscation,
+           make_delay: MakeDelay)
+           -> Obfuscated<S, MakeDelay, D> {
+        Obfuscated {
+            stream,
+            key: hmacsha256::Key(network_identifier),
+            client,
+            obfuscation,
+            make_delay,
+            frames_sent: 0,
+            delay: None,
+            frame: Vec::new(),
+            written: 0,
+            payload_len: 0,
+            frames_received: 0,
+            header: [0; HEADER_BYTES],
+            header_read: 0,
+            padding_left: 0,
+            payload_left: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `Obfuscated` stream, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // The mask of the header of the frame with the given index, sent by the
+    // client or the server.
+    fn mask(&self, client: bool, index: u64) -> [u8; HEADER_BYTES] {
+        let mut input = Vec::with_capacity(MASK_LABEL.len() + 9);
+        input.extend_from_slice(MASK_LABEL);
+        input.push(if client { 0 } else { 1 });
+        for i in (0..8).rev() {
+            input.push((index >> (8 * i)) as u8);
+        }
+
+        let tag = hmacsha256::authenticate(&input, &self.key);
+        let mut mask = [0; HEADER_BYTES];
+        mask.copy_from_slice(&tag.0[..HEADER_BYTES]);
+        mask
+    }
+
+    // Creates the frame for the given payload, along with the jitter before
+    // sending it.
+    fn start_frame(&mut self, payload: &[u8]) {
+        let padding_len = random_below(self.obfuscation.max_padding as usize + 1);
+        let mask = self.mask(self.client, self.frames_sent);
+        self.frames_sent += 1;
+
+        self.frame.clear();
+        self.frame.push((padding_len >> 8) as u8 ^ mask[0]);
+        self.frame.push(padding_len as u8 ^ mask[1]);
+        self.frame.push((payload.len() >> 8) as u8 ^ mask[2]);
+        self.frame.push(payload.len() as u8 ^ mask[3]);
+        let padding_start = self.frame.len();
+        self.frame.resize(padding_start + padding_len, 0);
+        randombytes_into(&mut self.frame[padding_start..]);
+        self.frame.extend_from_slice(payload);
+        self.written = 0;
+        self.payload_len = payload.len();
+
+        if let Some(max_jitter) = self.obfuscation.max_jitter {
+            let max_millis = max_jitter.as_secs() * 1000 +
+                             (max_jitter.subsec_nanos() / 1000000) as u64;
+            let jitter = random_below(max_millis as usize + 1) as u64;
+            self.delay = Some((self.make_delay)(Duration::from_millis(jitter)));
+        }
+    }
+
+    // Skips the padding of the current frame, and reads and unmasks the
+    // header of the next one once its payload has been read. Returns `false`
+    // on end of file between frames.
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<bool, io::Error>
+        where S: AsyncRead
+    {
+        loop {
+            // The padding precedes the payload.
+            while self.padding_left > 0 {
+                let mut scratch = [0; 256];
+                let len = min(self.padding_left, scratch.len());
+                match self.stream.poll_read(cx, &mut scratch[..len])? {
+                    Ready(0) => return Err(io::Error::new(UnexpectedEof, "truncated frame")),
+                    Ready(read) => self.padding_left -= read,
+                    Pending => return Ok(Pending),
+                }
+            }
+
+            if self.payload_left > 0 {
+                return Ok(Ready(true));
+            }
+
+            while self.header_read < HEADER_BYTES {
+                match self.stream.poll_read(cx, &mut self.header[self.header_read..])? {
+                    Ready(0) if self.header_read == 0 => return Ok(Ready(false)),
+                    Ready(0) => return Err(io::Error::new(UnexpectedEof, "truncated frame")),
+                    Ready(read) => self.header_read += read,
+                    Pending => return Ok(Pending),
+                }
+            }
+
+            let mask = self.mask(!self.client, self.frames_received);
+            self.frames_received += 1;
+            self.header_read = 0;
+            self.padding_left = ((self.header[0] ^ mask[0]) as usize) << 8 |
+                                (self.header[1] ^ mask[1]) as usize;
+            self.payload_left = ((self.header[2] ^ mask[2]) as usize) << 8 |
+                                (self.header[3] ^ mask[3]) as usize;
+        }
+    }
+}
+
+impl<S, MakeDelay, D> AsyncRead for Obfuscated<S, MakeDelay, D>
+    where S: AsyncRead,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(Ready(0));
+        }
+
+        match self.poll_frame(cx)? {
+            Ready(true) => {}
+            Ready(false) => return Ok(Ready(0)),
+            Pending => return Ok(Pending),
+        }
+
+        let len = min(buf.len(), self.payload_left);
+        match self.stream.poll_read(cx, &mut buf[..len])? {
+            Ready(0) => Err(io::Error::new(UnexpectedEof, "truncated frame")),
+            Ready(read) => {
+                self.payload_left -= read;
+                Ok(Ready(read))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+impl<S, MakeDelay, D> AsyncWrite for Obfuscated<S, MakeDelay, D>
+    where S: AsyncWrite,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    // Writes `buf` as a single frame, and only reports it as written once the
+    // whole frame is. The caller retries with the same data until then.
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        if buf.is_empty() {
+            return Ok(Ready(0));
+        }
+
+        if self.frame.is_empty() {
+            let len = min(buf.len(), u16::max_value() as usize);
+            self.start_frame(&buf[..len]);
+        }
+
+        if let Some(mut delay) = self.delay.take() {
+            match delay.poll(cx) {
+                Ok(Pending) => {
+                    self.delay = Some(delay);
+                    return Ok(Pending);
+                }
+                Ok(Ready(())) | Err(_) => {}
+            }
+        }
+
+        while self.written < self.frame.len() {
+            match self.stream.poll_write(cx, &self.frame[self.written..])? {
+                Ready(0) => return Err(io::Error::new(WriteZero, "failed to write frame")),
+                Ready(written) => self.written += written,
+                Pending => return Ok(Pending),
+            }
+        }
+
+        self.frame.clear();
+        Ok(Ready(self.payload_len))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.stream.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.stream.poll_close(cx)
+    }
+}
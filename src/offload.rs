@@ -0,0 +1,213 @@
+//! Move the handshake crypto out of the executor's event loop.
+//!
+//! Verifying msg1 and msg3 and creating the replies takes several scalar
+//! multiplications and signature operations, as does verifying msg2 on the
+//! client side. On a single-threaded executor, this delays all other tasks
+//! for every handshake. An `Offload`, attached via the `set_offload` method
+//! of a handshaker or acceptor, is handed each of these steps as a `Job`
+//! instead.
+//!
+//! A job owns copies of the keys and the handshake state it works on, so the
+//! offload can run it on any thread, e.g. on a thread pool or a blocking
+//! section of the runtime. Meanwhile, the handshaker returns `Pending`, and
+//! its task is woken once the job has run:
+//!
+//! ```rust,ignore
+//! // A single thread that performs the crypto of all handshakes.
+//! let (jobs, queue) = mpsc::channel::<Job>();
+//! thread::spawn(move || for job in queue {
+//!     job.run();
+//! });
+//!
+//! let jobs = Mutex::new(jobs);
+//! server.set_offload(Offload::new(move |job| {
+//!     let _ = jobs.lock().unwrap().send(job);
+//! }));
+//! ```
+//!
+//! If a job is dropped without being run, its handshake fails with an
+//! `IoError`.
+
+use std::fmt::{self, Debug, Formatter};
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use futures_core::Async;
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::{Context, Waker};
+
+use errors::HandshakeError;
+use sans_io::HandshakeState;
+
+/// Runs the crypto steps of handshakes, see the module documentation.
+///
+/// Clones share the same function.
+#[derive(Clone)]
+pub struct Offload(Arc<Fn(Job) + Send + Sync>);
+
+impl Offload {
+    /// Creates an `Offload` from a function that is called with each crypto
+    /// step of a handshake. The function should arrange for the job to be
+    /// run, on any thread.
+    pub fn new<F>(f: F) -> Offload
+        where F: Fn(Job) + Send + Sync + 'static
+    {
+        Offload(Arc::new(f))
+    }
+
+    // Hands `work` to the offload function, and returns a handle to its
+    // result.
+    fn spawn<T, W>(&self, cx: &mut Context, work: W) -> InFlight<T>
+        where T: Send + 'static,
+              W: FnOnce() -> T + Send + 'static
+    {
+        let slot = Arc::new(Mutex::new(Slot {
+                                           result: None,
+                                           dropped: false,
+                                           waker: cx.waker().clone(),
+                                       }));
+        (self.0)(Job(Box::new(Task {
+                                  work: Some(work),
+                                  slot: slot.clone(),
+                              })));
+        InFlight(slot)
+    }
+}
+
+impl Debug for Offload {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Offload").finish()
+    }
+}
+
+/// A crypto step of a handshake, handed to an `Offload`.
+pub struct Job(Box<Run + Send>);
+
+impl Job {
+    /// Performs the step, and wakes the task of its handshake.
+    pub fn run(self) {
+        self.0.run()
+    }
+}
+
+impl Debug for Job {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("Job").finish()
+    }
+}
+
+trait Run {
+    fn run(self: Box<Self>);
+}
+
+// The work of a job, and where to put its result.
+struct Task<T, W> {
+    work: Option<W>,
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T, W: FnOnce() -> T> Run for Task<T, W> {
+    fn run(mut self: Box<Self>) {
+        if let Some(work) = self.work.take() {
+            let result = work();
+            complete(&self.slot, Some(result));
+        }
+    }
+}
+
+// Wakes the handshake of a job that is dropped without being run, so that it
+// can fail instead of waiting forever.
+impl<T, W> Drop for Task<T, W> {
+    fn drop(&mut self) {
+        if self.work.is_some() {
+            complete(&self.slot, None);
+        }
+    }
+}
+
+// The result of a job, shared between the job and its handshaker.
+struct Slot<T> {
+    result: Option<T>,
+    dropped: bool, // whether the job was dropped without being run
+    waker: Waker, // of the task that last polled for the result
+}
+
+// Stores the result of a job, or `None` if it was dropped, and wakes the
+// task waiting for it.
+fn complete<T>(slot: &Mutex<Slot<T>>, result: Option<T>) {
+    let waker = {
+        let mut slot = slot.lock().unwrap();
+        match result {
+            Some(result) => slot.result = Some(result),
+            None => slot.dropped = true,
+        }
+        slot.waker.clone()
+    };
+    waker.wake();
+}
+
+// The handshaker's side of a job.
+struct InFlight<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> InFlight<T> {
+    // Returns the result once the job has run, or `None` if it was dropped
+    // without being run.
+    fn poll(&mut self, cx: &mut Context) -> Async<Option<T>> {
+        let mut slot = self.0.lock().unwrap();
+        if let Some(result) = slot.result.take() {
+            return Ready(Some(result));
+        }
+        if slot.dropped {
+            return Ready(None);
+        }
+
+        slot.waker = cx.waker().clone();
+        Pending
+    }
+}
+
+/// The verification of a message, see `poll_advance_read`.
+pub(crate) struct Verification(InFlight<(HandshakeState<'static>, Result<(), HandshakeError>)>);
+
+// Marks the `received` bytes completing the current incoming message of
+// `state` as read, which verifies the message and prepares the reply. With
+// an `offload`, this happens on a detached copy of the state, which is handed
+// to the offload as a job and tracked in `verification` until it has run.
+pub(crate) fn poll_advance_read(cx: &mut Context,
+                                state: &mut HandshakeState<'static>,
+                                received: usize,
+                                offload: Option<&Offload>,
+                                verification: &mut Option<Verification>)
+                                -> Async<Result<(), HandshakeError>> {
+    let offload = match offload {
+        Some(offload) => offload,
+        None => return Ready(state.advance_read(received)),
+    };
+
+    if verification.is_none() {
+        let mut detached = state.detach();
+        let in_flight = offload.spawn(cx, move || {
+            let result = detached.advance_read(received);
+            (detached, result)
+        });
+        *verification = Some(Verification(in_flight));
+    }
+
+    let polled = match *verification {
+        Some(Verification(ref mut in_flight)) => in_flight.poll(cx),
+        None => unreachable!(),
+    };
+    match polled {
+        Ready(Some((detached, result))) => {
+            *verification = None;
+            state.attach(detached);
+            Ready(result)
+        }
+        Ready(None) => {
+            *verification = None;
+            let err = io::Error::new(ErrorKind::Other, "the offload dropped a crypto step");
+            Ready(Err(err.into()))
+        }
+        Pending => Pending,
+    }
+}
@@ -0,0 +1,364 @@
+//! Maintain at most one authenticated connection per peer.
+//!
+//! A [`PeerPool`](struct.PeerPool.html) connects to peers on request and
+//! hands out the resulting encrypted streams. Requesting a peer the pool is
+//! already connected to yields the existing connection, and concurrent
+//! requests for a peer the pool is still connecting to share that attempt.
+//!
+//! The pool can not dial by itself. It takes a function that starts
+//! connecting to a peer given its longterm public key, e.g. by looking up
+//! its address and calling `connect::connect_tcp`, or by wrapping the dial
+//! in a `retry::Retry`. Failed attempts are repeated up to a configurable
+//! number of times. Connections that fail while reading or writing, or that
+//! the peer closed, are replaced by a new one on the next request.
+//!
+//! ```rust,ignore
+//! let pool = PeerPool::new(move |peer: &sign::PublicKey| {
+//!     connect_tcp(&addresses[peer], &identity, peer)
+//! });
+//! let connection = pool.connect(&peer); // a future of the connection
+//! ```
+//!
+//! All handles to a connection share the same stream, and only the task
+//! that polled it last is woken. Usually, a single task such as an rpc layer
+//! reads from the connection, and writers coordinate through it.
+//!
+//! This module requires the `secret-stream` feature.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind::Other;
+use std::sync::{Arc, Mutex};
+
+use sodiumoxide::crypto::sign;
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::{Context, Waker};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::Outcome;
+use errors::ConnectError;
+use secret_stream::SecretStream;
+
+/// Shared, thread-safe handle to a set of connections, at most one per peer.
+pub struct PeerPool<ConnectFn, Connecting, S>(Arc<Mutex<Inner<ConnectFn, Connecting, S>>>);
+
+struct Inner<ConnectFn, Connecting, S> {
+    connect_fn: ConnectFn,
+    max_attempts: usize,
+    peers: HashMap<[u8; sign::PUBLICKEYBYTES], Peer<Connecting, S>>,
+    failed: HashMap<usize, String>, // errors for the requests that shared a failed attempt
+    next_request: usize,
+}
+
+enum Peer<Connecting, S> {
+    Connecting {
+        attempt: Connecting,
+        attempts: usize,
+        requests: Vec<(usize, Waker)>, // the requests that polled the attempt
+    },
+    Connected(PeerConnection<S>),
+}
+
+impl<ConnectFn, Connecting, S> Clone for PeerPool<ConnectFn, Connecting, S> {
+    fn clone(&self) -> Self {
+        PeerPool(self.0.clone())
+    }
+}
+
+impl<ConnectFn, Connecting, S> PeerPool<ConnectFn, Connecting, S>
+    where ConnectFn: FnMut(&sign::PublicKey) -> Connecting,
+          Connecting: Future<Item = (Outcome, S), Error = ConnectError>,
+          S: AsyncRead + AsyncWrite
+{
+    /// Creates a new, empty `PeerPool`, which calls `connect_fn` to connect
+    /// to a peer and perform a handshake with it.
+    ///
+    /// By default, up to three attempts are made for each request.
+    pub fn new(connect_fn: ConnectFn) -> Self {
+        PeerPool(Arc::new(Mutex::new(Inner {
+                                         connect_fn,
+                                         max_attempts: 3,
+                                         peers: HashMap::new(),
+                                         failed: HashMap::new(),
+                                         next_request: 0,
+                                     })))
+    }
+
+    /// Set the maximum number of attempts to connect to a peer, including
+    /// the first one. A value of zero is treated as one.
+    pub fn set_max_attempts(&self, max_attempts: usize) {
+        self.0.lock().unwrap().max_attempts = max_attempts;
+    }
+
+    /// Returns a future that yields a connection to the peer with the given
+    /// longterm public key, connecting to it unless the pool already holds a
+    /// working connection or attempt.
+    pub fn connect(&self, peer: &sign::PublicKey) -> Connect<ConnectFn, Connecting, S> {
+        let mut inner = self.0.lock().unwrap();
+        let request = inner.next_request;
+        inner.next_request = inner.next_request.wrapping_add(1);
+
+        Connect {
+            pool: self.clone(),
+            peer: peer.clone(),
+            request,
+        }
+    }
+
+    /// Returns the connection to the peer with the given longterm public
+    /// key, if the pool holds a working one.
+    pub fn get(&self, peer: &sign::PublicKey) -> Option<PeerConnection<S>> {
+        match self.0.lock().unwrap().peers.get(&peer.0) {
+            Some(&Peer::Connected(ref connection)) if !connection.is_broken() => {
+                Some(connection.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes the connection to the peer with the given longterm public key
+    /// from the pool and returns it, e.g. to close it. Attempts to connect
+    /// are not affected.
+    pub fn disconnect(&self, peer: &sign::PublicKey) -> Option<PeerConnection<S>> {
+        let mut inner = self.0.lock().unwrap();
+        let connected = match inner.peers.get(&peer.0) {
+            Some(&Peer::Connected(_)) => true,
+            _ => false,
+        };
+        if !connected {
+            return None;
+        }
+
+        match inner.peers.remove(&peer.0) {
+            Some(Peer::Connected(connection)) => Some(connection),
+            _ => None,
+        }
+    }
+}
+
+impl<ConnectFn, Connecting, S> Inner<ConnectFn, Connecting, S>
+    where ConnectFn: FnMut(&sign::PublicKey) -> Connecting,
+          Connecting: Future<Item = (Outcome, S), Error = ConnectError>,
+          S: AsyncRead + AsyncWrite
+{
+    // Polls the attempt to connect to `peer` on behalf of `request`, starting
+    // one if there is neither a working connection nor an attempt.
+    fn poll_peer(&mut self,
+                 peer: &sign::PublicKey,
+                 request: usize,
+                 cx: &mut Context)
+                 -> Poll<PeerConnection<S>, ConnectError> {
+        loop {
+            let start = match self.peers.get(&peer.0) {
+                Some(&Peer::Connected(ref connection)) => {
+                    if !connection.is_broken() {
+                        return Ok(Ready(connection.clone()));
+                    }
+                    true
+                }
+                Some(&Peer::Connecting { .. }) => false,
+                None => true,
+            };
+            if start {
+                let attempt = (self.connect_fn)(peer);
+                self.peers.insert(peer.0,
+                                  Peer::Connecting {
+                                      attempt,
+                                      attempts: 1,
+                                      requests: Vec::new(),
+                                  });
+            }
+
+            let result = match self.peers.get_mut(&peer.0) {
+                Some(&mut Peer::Connecting { ref mut attempt, ref mut requests, .. }) => {
+                    requests.retain(|&(other, _)| other != request);
+                    requests.push((request, cx.waker().clone()));
+                    attempt.poll(cx)
+                }
+                _ => unreachable!(),
+            };
+
+            let err = match result {
+                Ok(Pending) => return Ok(Pending),
+                Ok(Ready((outcome, stream))) => {
+                    let connection = PeerConnection::new(SecretStream::new(&outcome, stream));
+                    if let Some(Peer::Connecting { requests, .. }) =
+                        self.peers.insert(peer.0, Peer::Connected(connection.clone())) {
+                        wake_others(&requests, request);
+                    }
+                    return Ok(Ready(connection));
+                }
+                Err(err) => err,
+            };
+
+            let retry = match self.peers.get(&peer.0) {
+                Some(&Peer::Connecting { attempts, .. }) => attempts < self.max_attempts,
+                _ => unreachable!(),
+            };
+            if retry {
+                let next = (self.connect_fn)(peer);
+                if let Some(&mut Peer::Connecting { ref mut attempt, ref mut attempts, .. }) =
+                    self.peers.get_mut(&peer.0) {
+                    *attempt = next;
+                    *attempts += 1;
+                }
+                continue;
+            }
+
+            // All requests that shared the attempt fail.
+            if let Some(Peer::Connecting { requests, .. }) = self.peers.remove(&peer.0) {
+                for &(other, _) in requests.iter().filter(|&&(other, _)| other != request) {
+                    self.failed.insert(other, err.to_string());
+                }
+                wake_others(&requests, request);
+            }
+            return Err(err);
+        }
+    }
+}
+
+fn wake_others(requests: &[(usize, Waker)], request: usize) {
+    for &(other, ref waker) in requests {
+        if other != request {
+            waker.wake();
+        }
+    }
+}
+
+/// Future that yields a connection of a `PeerPool`, see `PeerPool::connect`.
+///
+/// Dropping it cancels the attempt to connect, unless other requests share
+/// it.
+pub struct Connect<ConnectFn, Connecting, S> {
+    pool: PeerPool<ConnectFn, Connecting, S>,
+    peer: sign::PublicKey,
+    request: usize,
+}
+
+impl<ConnectFn, Connecting, S> Future for Connect<ConnectFn, Connecting, S>
+    where ConnectFn: FnMut(&sign::PublicKey) -> Connecting,
+          Connecting: Future<Item = (Outcome, S), Error = ConnectError>,
+          S: AsyncRead + AsyncWrite
+{
+    type Item = PeerConnection<S>;
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let mut inner = self.pool.0.lock().unwrap();
+
+        if let Some(msg) = inner.failed.remove(&self.request) {
+            return Err(ConnectError::IoError(io::Error::new(Other, msg)));
+        }
+
+        inner.poll_peer(&self.peer, self.request, cx)
+    }
+}
+
+// Hands the attempt over to another request, or cancels it if there is none.
+impl<ConnectFn, Connecting, S> Drop for Connect<ConnectFn, Connecting, S> {
+    fn drop(&mut self) {
+        let mut inner = match self.pool.0.lock() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+        inner.failed.remove(&self.request);
+
+        let abandoned = match inner.peers.get_mut(&self.peer.0) {
+            Some(&mut Peer::Connecting { ref mut requests, .. }) => {
+                let polled = requests.iter().any(|&(other, _)| other == self.request);
+                requests.retain(|&(other, _)| other != self.request);
+                match requests.first() {
+                    Some(&(_, ref waker)) => {
+                        waker.wake();
+                        false
+                    }
+                    None => polled,
+                }
+            }
+            _ => false,
+        };
+        if abandoned {
+            inner.peers.remove(&self.peer.0);
+        }
+    }
+}
+
+/// A handle to a connection of a `PeerPool`, reading and writing through the
+/// encrypted stream shared by all its handles.
+pub struct PeerConnection<S>(Arc<Mutex<Connection<S>>>);
+
+struct Connection<S> {
+    stream: SecretStream<S>,
+    broken: bool, // whether an io error occured, or either side closed the stream
+}
+
+impl<S> Clone for PeerConnection<S> {
+    fn clone(&self) -> Self {
+        PeerConnection(self.0.clone())
+    }
+}
+
+impl<S> PeerConnection<S> {
+    fn new(stream: SecretStream<S>) -> PeerConnection<S> {
+        PeerConnection(Arc::new(Mutex::new(Connection {
+                                               stream,
+                                               broken: false,
+                                           })))
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_longterm_pk(&self) -> sign::PublicKey {
+        self.0.lock().unwrap().stream.peer_longterm_pk().clone()
+    }
+
+    /// Returns whether reading or writing failed, or either side closed the
+    /// connection. The pool replaces broken connections on the next request.
+    pub fn is_broken(&self) -> bool {
+        self.0.lock().unwrap().broken
+    }
+
+    /// Returns whether both handles refer to the same connection.
+    pub fn ptr_eq(&self, other: &PeerConnection<S>) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for PeerConnection<S> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        let mut connection = self.0.lock().unwrap();
+        let result = connection.stream.poll_read(cx, buf);
+        match result {
+            Ok(Ready(0)) if !buf.is_empty() => connection.broken = true,
+            Err(_) => connection.broken = true,
+            _ => {}
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for PeerConnection<S> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        let mut connection = self.0.lock().unwrap();
+        let result = connection.stream.poll_write(cx, buf);
+        if result.is_err() {
+            connection.broken = true;
+        }
+        result
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        let mut connection = self.0.lock().unwrap();
+        let result = connection.stream.poll_flush(cx);
+        if result.is_err() {
+            connection.broken = true;
+        }
+        result
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        let mut connection = self.0.lock().unwrap();
+        connection.broken = true;
+        connection.stream.poll_close(cx)
+    }
+}
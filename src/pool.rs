@@ -0,0 +1,193 @@
+//! Recycle the allocations of server handshakes across connections.
+//!
+//! An `OwningServerHandshakerWithFilter` copies the server's keys and its
+//! ephemeral keypair into a heap allocation, so that the handshaker does not
+//! borrow from its caller. A `HandshakePool` keeps the allocations of
+//! finished handshakes around and hands them to new ones, so that a busy
+//! `Acceptor` does not hit the allocator for every connection.
+//!
+//! Generating the ephemeral keypair of a handshake is on the accept path as
+//! well. An `EphemeralKeyPool` generates keypairs ahead of time on a
+//! background thread, so that bursts of connections do not have to wait for
+//! it.
+//!
+//! Both pools are cheap-to-clone handles around shared state, so the same
+//! pool can be attached to several acceptors.
+
+use std::sync::{Arc, Mutex, Condvar, Weak};
+use std::thread;
+use std::time::Duration;
+
+use sodiumoxide::crypto::box_;
+use sodiumoxide::utils::memzero;
+
+use identity::Identity;
+use server::ServerKeys;
+
+/// Shared, thread-safe handle to a set of recyclable handshake allocations.
+#[derive(Clone)]
+pub struct HandshakePool(Arc<Mutex<Inner>>);
+
+struct Inner {
+    capacity: usize,
+    free: Vec<Box<ServerKeys>>,
+}
+
+impl HandshakePool {
+    /// Creates a new, empty `HandshakePool` which retains up to `capacity`
+    /// allocations of finished handshakes. Any further allocations are freed.
+    pub fn new(capacity: usize) -> HandshakePool {
+        HandshakePool(Arc::new(Mutex::new(Inner {
+                                              capacity,
+                                              free: Vec::with_capacity(capacity),
+                                          })))
+    }
+
+    /// Returns the number of allocations currently available for reuse.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().free.len()
+    }
+
+    /// Returns whether no allocations are currently available for reuse.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of allocations retained by the pool.
+    pub fn capacity(&self) -> usize {
+        self.0.lock().unwrap().capacity
+    }
+
+    // Returns an allocation holding the keys of `identity` and the given
+    // ephemeral keypair, reusing a pooled allocation if there is one.
+    pub(crate) fn take(&self,
+                       identity: &Identity,
+                       ephemeral_pk: box_::PublicKey,
+                       ephemeral_sk: box_::SecretKey)
+                       -> Box<ServerKeys> {
+        let recycled = self.0.lock().unwrap().free.pop();
+        match recycled {
+            Some(mut keys) => {
+                keys.network_identifier = *identity.network_identifier();
+                keys.longterm_pk = identity.longterm_pk().clone();
+                keys.longterm_sk = identity.longterm_sk().clone();
+                keys.ephemeral_pk = ephemeral_pk;
+                keys.ephemeral_sk = ephemeral_sk;
+                keys
+            }
+            None => {
+                Box::new(ServerKeys {
+                             network_identifier: *identity.network_identifier(),
+                             longterm_pk: identity.longterm_pk().clone(),
+                             longterm_sk: identity.longterm_sk().clone(),
+                             ephemeral_pk,
+                             ephemeral_sk,
+                         })
+            }
+        }
+    }
+
+    // Returns an allocation to the pool. Both secret keys are zeroed before
+    // the allocation is pooled, and on drop if the pool is full.
+    pub(crate) fn put(&self, mut keys: Box<ServerKeys>) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.free.len() < inner.capacity {
+            memzero(&mut keys.longterm_sk.0);
+            memzero(&mut keys.ephemeral_sk.0);
+            inner.free.push(keys);
+        }
+    }
+}
+
+/// Shared, thread-safe handle to a set of pre-generated ephemeral keypairs,
+/// replenished by a background thread.
+///
+/// The background thread exits once all handles to the pool have been
+/// dropped.
+#[derive(Clone)]
+pub struct EphemeralKeyPool(Arc<KeyPoolShared>);
+
+struct KeyPoolShared {
+    keypairs: Mutex<Vec<(box_::PublicKey, box_::SecretKey)>>,
+    capacity: usize,
+    // Signalled whenever a keypair is taken from the pool.
+    taken: Condvar,
+}
+
+// How long (in seconds) the background thread sleeps on a full pool before
+// checking whether the pool is still in use.
+const REPLENISH_IDLE_SECS: u64 = 1;
+
+impl EphemeralKeyPool {
+    /// Creates a new `EphemeralKeyPool` holding up to `capacity` keypairs,
+    /// and starts the background thread that fills it.
+    pub fn new(capacity: usize) -> EphemeralKeyPool {
+        let shared = Arc::new(KeyPoolShared {
+                                  keypairs: Mutex::new(Vec::with_capacity(capacity)),
+                                  capacity,
+                                  taken: Condvar::new(),
+                              });
+
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || replenish(weak));
+
+        EphemeralKeyPool(shared)
+    }
+
+    /// Takes a keypair from the pool. If the pool has run dry, a fresh
+    /// keypair is generated on the calling thread instead.
+    pub fn take(&self) -> (box_::PublicKey, box_::SecretKey) {
+        let keypair = self.0.keypairs.lock().unwrap().pop();
+        self.0.taken.notify_one();
+        match keypair {
+            Some(keypair) => keypair,
+            None => box_::gen_keypair(),
+        }
+    }
+
+    /// Returns the number of keypairs currently available.
+    pub fn len(&self) -> usize {
+        self.0.keypairs.lock().unwrap().len()
+    }
+
+    /// Returns whether the pool has currently run dry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of keypairs held by the pool.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity
+    }
+}
+
+// Body of the background thread, fills the pool up to its capacity whenever
+// keypairs are taken. Keypairs are generated without holding the lock.
+fn replenish(weak: Weak<KeyPoolShared>) {
+    loop {
+        let shared = match weak.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        let missing = {
+            let keypairs = shared.keypairs.lock().unwrap();
+            if keypairs.len() < shared.capacity {
+                shared.capacity - keypairs.len()
+            } else {
+                let idle = Duration::from_secs(REPLENISH_IDLE_SECS);
+                let _ = shared.taken.wait_timeout(keypairs, idle).unwrap();
+                continue;
+            }
+        };
+
+        for _ in 0..missing {
+            let keypair = box_::gen_keypair();
+            let mut keypairs = shared.keypairs.lock().unwrap();
+            if keypairs.len() >= shared.capacity {
+                break;
+            }
+            keypairs.push(keypair);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Tunnel connections through proxies before performing a handshake.
+//!
+//! The handshake itself works over any stream, so proxying is a matter of
+//! negotiating a tunnel over the connection to the proxy, and then handing
+//! the connection to a handshaker. The futures in this module perform that
+//! negotiation over an already established connection to the proxy, and
+//! yield it once it tunnels to the target.
+
+use std::io;
+use std::io::ErrorKind::{Other, InvalidInput, WriteZero, UnexpectedEof};
+use std::net::SocketAddr;
+
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+/// A proxy through which to reach a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proxy {
+    /// A SOCKS5 proxy not requiring authentication, such as the SOCKS port
+    /// of a tor daemon. The target host name is resolved by the proxy, so
+    /// onion addresses can be reached.
+    Socks5(SocketAddr),
+    /// An HTTP proxy supporting the `CONNECT` method.
+    HttpConnect(SocketAddr),
+}
+
+impl Proxy {
+    /// The address of the proxy itself.
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            Proxy::Socks5(addr) |
+            Proxy::HttpConnect(addr) => addr,
+        }
+    }
+
+    /// Negotiates a tunnel to `host:port` over the `stream` connected to
+    /// this proxy.
+    pub fn tunnel<S>(&self, stream: S, host: &str, port: u16) -> Tunnel<S>
+        where S: AsyncRead + AsyncWrite
+    {
+        match *self {
+            Proxy::Socks5(_) => Tunnel(TunnelInner::Socks5(Socks5Connect::new(stream, host, port))),
+            Proxy::HttpConnect(_) => {
+                Tunnel(TunnelInner::HttpConnect(HttpConnect::new(stream, host, port)))
+            }
+        }
+    }
+}
+
+/// Future that negotiates a tunnel through a `Proxy`, see `Proxy::tunnel`.
+pub struct Tunnel<S>(TunnelInner<S>);
+
+enum TunnelInner<S> {
+    Socks5(Socks5Connect<S>),
+    HttpConnect(HttpConnect<S>),
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Tunnel<S> {
+    type Item = S;
+    type Error = io::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.0 {
+            TunnelInner::Socks5(ref mut connect) => connect.poll(cx),
+            TunnelInner::HttpConnect(ref mut connect) => connect.poll(cx),
+        }
+    }
+}
+
+/// Future that asks a SOCKS5 proxy to connect to a target host, and yields
+/// the stream to the proxy once it is connected.
+pub struct Socks5Connect<S> {
+    stream: Option<S>,
+    state: Socks5State,
+    buf: Vec<u8>, // the message currently being written or read
+    offset: usize, // offset into `buf` at which to read/write
+    request: Vec<u8>,
+    error: Option<io::Error>, // set if the request could not be encoded
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Socks5State {
+    WriteGreeting,
+    ReadMethod,
+    WriteRequest,
+    ReadReplyHead,
+    ReadReplyTail,
+}
+
+impl<S: AsyncRead + AsyncWrite> Socks5Connect<S> {
+    /// Creates a new `Socks5Connect`, requesting a connection to
+    /// `host:port` over the `stream` connected to the proxy.
+    ///
+    /// The host is sent as a domain name, the proxy resolves it.
+    pub fn new(stream: S, host: &str, port: u16) -> Socks5Connect<S> {
+        let mut request = vec![5, 1, 0, 3, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.push((port >> 8) as u8);
+        request.push(port as u8);
+
+        Socks5Connect {
+            stream: Some(stream),
+            state: Socks5State::WriteGreeting,
+            buf: vec![5, 1, 0], // version 5, one method, no authentication
+            offset: 0,
+            request,
+            error: if host.is_empty() || host.len() > 255 {
+                Some(io::Error::new(InvalidInput, "invalid socks5 target host"))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Socks5Connect<S> {
+    type Item = S;
+    type Error = io::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let polled = self.poll_negotiation(cx);
+        if polled.is_err() {
+            // Drop the stream, so that polling again does not resume the
+            // failed negotiation.
+            self.stream = None;
+        }
+        polled
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Socks5Connect<S> {
+    fn poll_negotiation(&mut self, cx: &mut Context) -> Poll<S, io::Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        loop {
+            {
+                let stream = match self.stream.as_mut() {
+                    Some(stream) => stream,
+                    // The negotiation has already completed, stay in that terminal state.
+                    None => return Ok(Pending),
+                };
+                let done = match self.state {
+                    Socks5State::WriteGreeting |
+                    Socks5State::WriteRequest => {
+                        poll_write_all(stream, cx, &self.buf, &mut self.offset)?
+                    }
+                    _ => poll_read_exact(stream, cx, &mut self.buf, &mut self.offset)?,
+                };
+                if let Pending = done {
+                    return Ok(Pending);
+                }
+            }
+
+            self.offset = 0;
+            match self.state {
+                Socks5State::WriteGreeting => {
+                    self.state = Socks5State::ReadMethod;
+                    self.buf = vec![0; 2];
+                }
+                Socks5State::ReadMethod => {
+                    if self.buf != [5, 0] {
+                        return Err(io::Error::new(Other,
+                                                  "socks5 proxy requires authentication"));
+                    }
+                    self.state = Socks5State::WriteRequest;
+                    self.buf = self.request.clone();
+                }
+                Socks5State::WriteRequest => {
+                    self.state = Socks5State::ReadReplyHead;
+                    // version, reply code, reserved, address type, first address byte
+                    self.buf = vec![0; 5];
+                }
+                Socks5State::ReadReplyHead => {
+                    if self.buf[0] != 5 || self.buf[1] != 0 {
+                        return Err(io::Error::new(Other, "socks5 proxy failed to connect"));
+                    }
+                    // remaining address bytes and the port
+                    let remaining = match self.buf[3] {
+                        1 => 3 + 2,
+                        3 => self.buf[4] as usize + 2,
+                        4 => 15 + 2,
+                        _ => return Err(io::Error::new(Other, "invalid socks5 reply")),
+                    };
+                    self.state = Socks5State::ReadReplyTail;
+                    self.buf = vec![0; remaining];
+                }
+                Socks5State::ReadReplyTail => return Ok(Ready(self.stream.take().unwrap())),
+            }
+        }
+    }
+}
+
+/// Future that asks an HTTP proxy to tunnel to a target host via the
+/// `CONNECT` method, and yields the stream to the proxy once the tunnel is
+/// established.
+pub struct HttpConnect<S> {
+    stream: Option<S>,
+    request: Vec<u8>,
+    written: usize, // number of request bytes written so far
+    response: Vec<u8>, // the response header read so far
+}
+
+// Upper bound on the size of the response header, to not buffer arbitrary
+// amounts of data from a misbehaving proxy.
+const MAX_RESPONSE_BYTES: usize = 8192;
+
+impl<S: AsyncRead + AsyncWrite> HttpConnect<S> {
+    /// Creates a new `HttpConnect`, requesting a tunnel to `host:port` over
+    /// the `stream` connected to the proxy.
+    pub fn new(stream: S, host: &str, port: u16) -> HttpConnect<S> {
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+
+        HttpConnect {
+            stream: Some(stream),
+            request: request.into_bytes(),
+            written: 0,
+            response: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for HttpConnect<S> {
+    type Item = S;
+    type Error = io::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let polled = self.poll_negotiation(cx);
+        if polled.is_err() {
+            // Drop the stream, so that polling again does not resume the
+            // failed negotiation.
+            self.stream = None;
+        }
+        polled
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> HttpConnect<S> {
+    fn poll_negotiation(&mut self, cx: &mut Context) -> Poll<S, io::Error> {
+        {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                // The negotiation has already completed, stay in that terminal state.
+                None => return Ok(Pending),
+            };
+
+            if let Pending = poll_write_all(stream, cx, &self.request, &mut self.written)? {
+                return Ok(Pending);
+            }
+
+            // Read byte by byte, so that no data following the response
+            // header is consumed.
+            while !self.response.ends_with(b"\r\n\r\n") {
+                if self.response.len() >= MAX_RESPONSE_BYTES {
+                    return Err(io::Error::new(Other, "http proxy response too long"));
+                }
+
+                let mut byte = [0u8; 1];
+                let mut offset = 0;
+                if let Pending = poll_read_exact(stream, cx, &mut byte, &mut offset)? {
+                    return Ok(Pending);
+                }
+                self.response.push(byte[0]);
+            }
+        }
+
+        // The status line has the form `HTTP/1.x 200 Reason`.
+        let accepted = {
+            let mut status_line = self.response.split(|byte| *byte == b' ');
+            status_line.next().map_or(false, |version| version.starts_with(b"HTTP/1.")) &&
+            status_line.next().map_or(false, |code| code.len() == 3 && code[0] == b'2')
+        };
+
+        if accepted {
+            Ok(Ready(self.stream.take().unwrap()))
+        } else {
+            Err(io::Error::new(Other, "http proxy refused to connect"))
+        }
+    }
+}
+
+// Writes all of `buf[*offset..]`, advancing `offset`.
+fn poll_write_all<S: AsyncWrite>(stream: &mut S,
+                                  cx: &mut Context,
+                                  buf: &[u8],
+                                  offset: &mut usize)
+                                  -> Poll<(), io::Error> {
+    while *offset < buf.len() {
+        match stream.poll_write(cx, &buf[*offset..])? {
+            Ready(0) => return Err(io::Error::new(WriteZero, "failed to write to proxy")),
+            Ready(written) => *offset += written,
+            Pending => return Ok(Pending),
+        }
+    }
+    stream.poll_flush(cx)
+}
+
+// Fills all of `buf[*offset..]`, advancing `offset`.
+fn poll_read_exact<S: AsyncRead>(stream: &mut S,
+                                 cx: &mut Context,
+                                 buf: &mut [u8],
+                                 offset: &mut usize)
+                                 -> Poll<(), io::Error> {
+    while *offset < buf.len() {
+        match stream.poll_read(cx, &mut buf[*offset..])? {
+            Ready(0) => return Err(io::Error::new(UnexpectedEof, "proxy closed the connection")),
+            Ready(read) => *offset += read,
+            Pending => return Ok(Pending),
+        }
+    }
+    Ok(Ready(()))
+}
@@ -0,0 +1,312 @@
+//! Temporarily refuse handshakes from peers that repeatedly fail authentication.
+//!
+//! A `RateLimiter` counts handshake failures per source address, and per
+//! claimed client longterm public key where one is known. Once a source
+//! reaches the configured number of failures within the observation window,
+//! it is refused for the ban duration.
+//!
+//! With `set_escalation`, the limiter adapts to persistent offenders: every
+//! further ban of the same source doubles the ban duration, up to a maximum.
+//! Past bans are forgiven one at a time, for every decay period without a
+//! new ban, so that a source returns to the base ban duration eventually.
+//!
+//! An `Acceptor` feeds its handshakes into a limiter attached via
+//! `Acceptor::set_rate_limiter`, and refuses banned addresses and keys on its
+//! own. It records failed authentication against the address only, and the
+//! rejection of a client's verified key (by the filter function, the
+//! admission controller or the limiter itself) against the address and the
+//! key. The key claimed in a msg3 that fails to verify is never recorded:
+//! anyone who knows the network identifier can claim any key there, and
+//! could get other clients banned that way. With raw handshakers, call
+//! `admission` from an admission hook and compose the filter function with
+//! `filter` instead.
+//!
+//! The limiter keeps at most `max_records` records of addresses and as many
+//! of keys, about 100 bytes each (see `set_max_records`). Records are
+//! forgotten once they are stale, which the limiter checks at least once
+//! per window while failures are recorded, and up to once per second while
+//! a table is full.
+//! While a table is full of records that are not stale, new sources are
+//! not tracked.
+//!
+//! The limiter is a cheap-to-clone handle around shared state, so the same
+//! limiter can be consulted before constructing a handshaker, from within a
+//! filter function, and when recording the result of a handshake.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+use futures_core::Never;
+use futures_core::future::{FutureResult, ok};
+
+use acceptor::Admission;
+
+/// The default maximum number of records of addresses, and of keys.
+pub const DEFAULT_MAX_RECORDS: usize = 65536;
+
+// How often (in seconds) at most the tables are pruned because they are full.
+const FULL_PRUNE_INTERVAL_SECS: u64 = 1;
+
+/// Shared, thread-safe handle to the failure records of a set of peers.
+#[derive(Clone)]
+pub struct RateLimiter(Arc<Mutex<Inner>>);
+
+struct Inner {
+    policy: Policy,
+    addrs: HashMap<IpAddr, Record>,
+    keys: HashMap<[u8; sign::PUBLICKEYBYTES], Record>,
+    max_records: usize, // per table
+    last_prune: Instant,
+}
+
+impl Inner {
+    // Forgets all stale records.
+    fn prune(&mut self, now: Instant) {
+        let policy = self.policy;
+        self.addrs.retain(|_, record| !record.is_stale(now, &policy));
+        self.keys.retain(|_, record| !record.is_stale(now, &policy));
+        self.last_prune = now;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Policy {
+    max_failures: u32,
+    window: Duration,
+    ban_duration: Duration,
+    escalation: Option<(Duration, Duration)>, // maximum ban duration and decay period
+}
+
+// The failures of a single source.
+struct Record {
+    failures: u32,
+    first_failure: Instant,
+    banned_until: Option<Instant>,
+    bans: u32, // past bans that have not been forgiven as of `last_ban`
+    last_ban: Instant,
+}
+
+impl Record {
+    fn new(now: Instant) -> Record {
+        Record {
+            failures: 0,
+            first_failure: now,
+            banned_until: None,
+            bans: 0,
+            last_ban: now,
+        }
+    }
+
+    // The number of past bans that have not been forgiven at `now`.
+    fn bans_at(&self, now: Instant, policy: &Policy) -> u32 {
+        match policy.escalation {
+            Some((_, decay)) => {
+                let forgiven = duration_ratio(now.duration_since(self.last_ban), decay);
+                self.bans.saturating_sub(forgiven)
+            }
+            None => 0,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        match self.banned_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+
+    // Whether the record can be forgotten without losing information.
+    fn is_stale(&self, now: Instant, policy: &Policy) -> bool {
+        self.is_window_over(now, policy) && self.bans_at(now, policy) == 0
+    }
+
+    fn is_window_over(&self, now: Instant, policy: &Policy) -> bool {
+        !self.is_banned(now) && now.duration_since(self.first_failure) >= policy.window
+    }
+
+    fn fail(&mut self, now: Instant, policy: &Policy) {
+        if self.is_window_over(now, policy) {
+            self.failures = 0;
+            self.first_failure = now;
+            self.banned_until = None;
+        }
+
+        self.failures += 1;
+        if self.failures >= policy.max_failures {
+            let previous_bans = self.bans_at(now, policy);
+            self.banned_until = Some(now + ban_duration(previous_bans, policy));
+            self.bans = previous_bans + 1;
+            self.last_ban = now;
+        }
+    }
+}
+
+// The duration of a ban of a source with the given number of unforgiven
+// previous bans.
+fn ban_duration(previous_bans: u32, policy: &Policy) -> Duration {
+    match policy.escalation {
+        Some((max_ban_duration, _)) => {
+            let mut duration = policy.ban_duration;
+            for _ in 0..previous_bans {
+                if duration >= max_ban_duration {
+                    break;
+                }
+                duration *= 2;
+            }
+            if duration > max_ban_duration {
+                max_ban_duration
+            } else {
+                duration
+            }
+        }
+        None => policy.ban_duration,
+    }
+}
+
+// How many times `period` fits into `elapsed`, at millisecond precision.
+fn duration_ratio(elapsed: Duration, period: Duration) -> u32 {
+    let elapsed = elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64;
+    let period = period.as_secs() * 1000 + (period.subsec_nanos() / 1000000) as u64;
+    if period == 0 {
+        return u32::max_value();
+    }
+
+    let ratio = elapsed / period;
+    if ratio > u32::max_value() as u64 {
+        u32::max_value()
+    } else {
+        ratio as u32
+    }
+}
+
+impl RateLimiter {
+    /// Creates a new `RateLimiter` which refuses a source for `ban_duration`
+    /// once it failed `max_failures` handshakes within `window`.
+    pub fn new(max_failures: u32, window: Duration, ban_duration: Duration) -> RateLimiter {
+        RateLimiter(Arc::new(Mutex::new(Inner {
+                                            policy: Policy {
+                                                max_failures,
+                                                window,
+                                                ban_duration,
+                                                escalation: None,
+                                            },
+                                            addrs: HashMap::new(),
+                                            keys: HashMap::new(),
+                                            max_records: DEFAULT_MAX_RECORDS,
+                                            last_prune: Instant::now(),
+                                        })))
+    }
+
+    /// Escalate the bans of repeat offenders: each ban of a source doubles
+    /// the duration of its previous ban, up to `max_ban_duration`. One
+    /// previous ban is forgiven for every `decay` that passes without a new
+    /// ban of the source.
+    pub fn set_escalation(&self, max_ban_duration: Duration, decay: Duration) {
+        self.0.lock().unwrap().policy.escalation = Some((max_ban_duration, decay));
+    }
+
+    /// Keep at most `max` records of addresses, and at most `max` records of
+    /// keys. Defaults to `DEFAULT_MAX_RECORDS`.
+    pub fn set_max_records(&self, max: usize) {
+        self.0.lock().unwrap().max_records = max;
+    }
+
+    /// An admission decision for use with `Acceptor::set_admission`, which
+    /// rejects connections from addresses that are currently refused.
+    pub fn admission(&self, addr: &SocketAddr) -> Admission {
+        if self.is_addr_allowed(&addr.ip()) {
+            Admission::Accept
+        } else {
+            Admission::Reject
+        }
+    }
+
+    /// Returns whether a new handshake from the given address should be
+    /// attempted at all.
+    pub fn is_addr_allowed(&self, addr: &IpAddr) -> bool {
+        let inner = self.0.lock().unwrap();
+        match inner.addrs.get(addr) {
+            Some(record) => !record.is_banned(Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Returns whether a client claiming the given longterm public key should
+    /// be allowed to complete a handshake.
+    pub fn is_key_allowed(&self, client_longterm_pk: &sign::PublicKey) -> bool {
+        let inner = self.0.lock().unwrap();
+        match inner.keys.get(&client_longterm_pk.0) {
+            Some(record) => !record.is_banned(Instant::now()),
+            None => true,
+        }
+    }
+
+    /// A filter function for use with `ServerHandshakerWithFilter`, which
+    /// rejects clients whose longterm public key is currently refused.
+    pub fn filter(&self, client_longterm_pk: &sign::PublicKey) -> FutureResult<bool, Never> {
+        ok(self.is_key_allowed(client_longterm_pk))
+    }
+
+    /// Records a failed handshake from the given address, and from the given
+    /// client longterm public key if it is known.
+    pub fn record_failure(&self, addr: IpAddr, client_longterm_pk: Option<&sign::PublicKey>) {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        let policy = inner.policy;
+
+        // Whether a new record would not fit into its table.
+        let new_addr = !inner.addrs.contains_key(&addr);
+        let new_key = client_longterm_pk.map_or(false, |pk| !inner.keys.contains_key(&pk.0));
+        let full = (new_addr && inner.addrs.len() >= inner.max_records) ||
+                   (new_key && inner.keys.len() >= inner.max_records);
+
+        let since_prune = now.duration_since(inner.last_prune);
+        if since_prune >= policy.window ||
+           (full && since_prune >= Duration::from_secs(FULL_PRUNE_INTERVAL_SECS)) {
+            inner.prune(now);
+        }
+
+        let max_records = inner.max_records;
+        fail(&mut inner.addrs, addr, max_records, now, &policy);
+        if let Some(pk) = client_longterm_pk {
+            fail(&mut inner.keys, pk.0, max_records, now, &policy);
+        }
+    }
+
+    /// Records a successful handshake, forgetting all previous failures of
+    /// the address and the client longterm public key.
+    pub fn record_success(&self, addr: &IpAddr, client_longterm_pk: &sign::PublicKey) {
+        let mut inner = self.0.lock().unwrap();
+        inner.addrs.remove(addr);
+        inner.keys.remove(&client_longterm_pk.0);
+    }
+
+    /// Forgets all records whose window elapsed, whose ban expired, and
+    /// whose previous bans have all been forgiven.
+    ///
+    /// Stale records are also reset lazily and pruned while failures are
+    /// recorded, so calling this is only needed to release memory early.
+    pub fn prune(&self) {
+        self.0.lock().unwrap().prune(Instant::now());
+    }
+}
+
+// Records a failure of the source `id` in `records`, unless it is not
+// tracked yet and `records` is full.
+fn fail<K: Hash + Eq>(records: &mut HashMap<K, Record>,
+                      id: K,
+                      max_records: usize,
+                      now: Instant,
+                      policy: &Policy) {
+    if !records.contains_key(&id) && records.len() >= max_records {
+        return;
+    }
+    records
+        .entry(id)
+        .or_insert_with(|| Record::new(now))
+        .fail(now, policy);
+}
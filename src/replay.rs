@@ -0,0 +1,125 @@
+//! Reject replayed client hellos.
+//!
+//! Every client hello (msg1) carries a fresh ephemeral public key, so an
+//! honest client never sends the same msg1 twice. An attacker replaying a
+//! captured msg1 can not complete the handshake, as it lacks the ephemeral
+//! secret key, but it does make the server send msg2 and run its crypto. A
+//! `ReplayCache` remembers the ephemeral keys of recently verified hellos,
+//! and servers with a cache attached via `set_replay_cache` fail with a
+//! `CryptoError` on an exact replay, right after verifying msg1 and before
+//! reaching the filter function.
+//!
+//! The cache is bounded: it holds up to `capacity` keys, each for at most
+//! `window`. When it is full, the oldest key is evicted early, so choose the
+//! capacity according to the expected rate of handshakes times the window.
+//! Only hellos that passed verification are remembered, so random garbage
+//! can not evict entries. On a network with a known network identifier,
+//! such as the main ssb network, anyone can create valid hellos though, and
+//! flush the cache by sending `capacity` of them within the window. A
+//! captured hello can be replayed successfully after that.
+//!
+//! The cache does not know where hellos come from. To keep a single source
+//! from flushing it, limit the number of verified hellos per address, e.g.
+//! in `AdmissionController::msg1_verified` of an `Acceptor`, which is
+//! consulted at the same point of the handshake.
+//!
+//! `ReplayCache::hits` counts the rejected replays. They are also counted as
+//! invalid msg1 by `metrics::Metrics`.
+//!
+//! The cache is a cheap-to-clone handle around shared state, share it
+//! between all server handshakers of a listener.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::box_;
+
+/// Shared, thread-safe handle to a set of recently seen client hellos.
+#[derive(Clone)]
+pub struct ReplayCache(Arc<Mutex<Inner>>);
+
+struct Inner {
+    capacity: usize,
+    window: Duration,
+    seen: HashSet<[u8; box_::PUBLICKEYBYTES]>,
+    // the keys in `seen`, oldest first
+    order: VecDeque<([u8; box_::PUBLICKEYBYTES], Instant)>,
+    hits: u64,
+}
+
+impl Inner {
+    // Forgets all keys seen before `now - window`.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(key, seen_at)) = self.order.front() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&key);
+        }
+    }
+}
+
+impl ReplayCache {
+    /// Creates a new, empty `ReplayCache` which remembers up to `capacity`
+    /// client hellos, each for at most `window`.
+    pub fn new(capacity: usize, window: Duration) -> ReplayCache {
+        ReplayCache(Arc::new(Mutex::new(Inner {
+                                            capacity,
+                                            window,
+                                            seen: HashSet::new(),
+                                            order: VecDeque::new(),
+                                            hits: 0,
+                                        })))
+    }
+
+    /// The number of replays that have been detected.
+    pub fn hits(&self) -> u64 {
+        self.0.lock().unwrap().hits
+    }
+
+    /// The number of client hellos currently remembered.
+    pub fn len(&self) -> usize {
+        let mut inner = self.0.lock().unwrap();
+        inner.expire(Instant::now());
+        inner.order.len()
+    }
+
+    /// Returns whether no client hellos are currently remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets all client hellos, but keeps the number of hits.
+    pub fn clear(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.seen.clear();
+        inner.order.clear();
+    }
+
+    // Remembers the ephemeral public key of a verified client hello, and
+    // returns `false` if it has been seen within the window.
+    pub(crate) fn observe(&self, client_ephemeral_pk: &[u8; box_::PUBLICKEYBYTES]) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        let now = Instant::now();
+        inner.expire(now);
+
+        if inner.seen.contains(client_ephemeral_pk) {
+            inner.hits += 1;
+            return false;
+        }
+
+        if inner.capacity == 0 {
+            return true;
+        }
+        if inner.order.len() >= inner.capacity {
+            if let Some((oldest, _)) = inner.order.pop_front() {
+                inner.seen.remove(&oldest);
+            }
+        }
+        inner.seen.insert(*client_ephemeral_pk);
+        inner.order.push_back((*client_ephemeral_pk, now));
+        true
+    }
+}
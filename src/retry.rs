@@ -0,0 +1,203 @@
+//! Retry handshakes that fail due to transient io errors.
+//!
+//! A [`Retry`](struct.Retry.html) repeatedly dials a server via a function
+//! supplied by the caller and performs the client side of a handshake over
+//! the resulting connection, each time with fresh ephemeral keys. Attempts
+//! that fail with an io error of kind `Interrupted`, `ConnectionReset`,
+//! `ConnectionAborted` or `TimedOut` are retried after a jittered,
+//! exponentially growing delay. All other failures, in particular crypto
+//! errors, are reported immediately.
+
+use std::cmp::min;
+use std::io;
+use std::io::ErrorKind::{Interrupted, ConnectionReset, ConnectionAborted, TimedOut};
+use std::mem::replace;
+use std::time::Duration;
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use client::OwningClientHandshaker;
+use crypto::Outcome;
+use errors::{ConnectError, HandshakeError};
+use identity::Identity;
+use server::random_below;
+
+/// Future that dials a server and performs a handshake, retrying on
+/// transient io errors.
+pub struct Retry<DialFn, Dialing, S, MakeDelay, D> {
+    dial_fn: DialFn,
+    make_delay: MakeDelay,
+    identity: Identity,
+    server_longterm_pk: sign::PublicKey,
+    max_attempts: usize,
+    attempt: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    state: State<Dialing, S, D>,
+}
+
+enum State<Dialing, S, D> {
+    Idle,
+    Dialing(Dialing),
+    Handshaking(OwningClientHandshaker<S>),
+    Waiting(D),
+    Done,
+}
+
+impl<DialFn, Dialing, S, MakeDelay, D> Retry<DialFn, Dialing, S, MakeDelay, D>
+    where DialFn: FnMut() -> Dialing,
+          Dialing: Future<Item = S, Error = io::Error>,
+          S: AsyncRead + AsyncWrite,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    /// Creates a new `Retry`, which calls `dial_fn` to establish each
+    /// connection, and then performs a handshake with the server with the
+    /// given longterm public key. `make_delay` is called to wait between
+    /// attempts, a delay that errors counts as elapsed.
+    ///
+    /// By default, at most three attempts are made, with a backoff of
+    /// around 100 milliseconds after the first one, doubling after each
+    /// further attempt, up to ten seconds. Each delay is randomly shortened
+    /// by up to half its length.
+    pub fn new(dial_fn: DialFn,
+               identity: &Identity,
+               server_longterm_pk: &sign::PublicKey,
+               make_delay: MakeDelay)
+               -> Retry<DialFn, Dialing, S, MakeDelay, D> {
+        Retry {
+            dial_fn,
+            make_delay,
+            identity: identity.clone(),
+            server_longterm_pk: server_longterm_pk.clone(),
+            max_attempts: 3,
+            attempt: 0,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            state: State::Idle,
+        }
+    }
+
+    /// Set the maximum number of attempts, including the first one. A value
+    /// of zero is treated as one.
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Set the delay after the first failed attempt, and the maximum the
+    /// doubling delay can grow to.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.backoff = initial;
+        self.max_backoff = max;
+    }
+
+    fn handshake(&self, stream: S) -> OwningClientHandshaker<S> {
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+        OwningClientHandshaker::new(stream,
+                                    *self.identity.network_identifier(),
+                                    self.identity.longterm_pk().clone(),
+                                    self.identity.longterm_sk().clone(),
+                                    ephemeral_pk,
+                                    ephemeral_sk,
+                                    self.server_longterm_pk.clone())
+    }
+
+    // Either schedules the next attempt, or returns the error if it is not
+    // transient or there are no attempts left.
+    fn fail(&mut self, err: ConnectError) -> Result<(), ConnectError> {
+        if !is_transient(&err) || self.attempt >= self.max_attempts {
+            self.state = State::Done;
+            return Err(err);
+        }
+
+        let millis = self.backoff.as_secs() * 1000 + (self.backoff.subsec_nanos() / 1000000) as u64;
+        let jitter = Duration::from_millis(random_below((millis / 2) as usize + 1) as u64);
+        self.state = State::Waiting((self.make_delay)(self.backoff - jitter));
+        self.backoff = min(self.backoff * 2, self.max_backoff);
+        Ok(())
+    }
+}
+
+/// Future implementation to asynchronously dial and drive handshakes.
+impl<DialFn, Dialing, S, MakeDelay, D> Future for Retry<DialFn, Dialing, S, MakeDelay, D>
+    where DialFn: FnMut() -> Dialing,
+          Dialing: Future<Item = S, Error = io::Error>,
+          S: AsyncRead + AsyncWrite,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    type Item = (Outcome, S);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match replace(&mut self.state, State::Idle) {
+                State::Idle => {
+                    self.attempt += 1;
+                    self.state = State::Dialing((self.dial_fn)());
+                }
+
+                State::Dialing(mut dialing) => {
+                    match dialing.poll(cx) {
+                        Ok(Ready(stream)) => self.state = State::Handshaking(self.handshake(stream)),
+                        Ok(Pending) => {
+                            self.state = State::Dialing(dialing);
+                            return Ok(Pending);
+                        }
+                        Err(err) => self.fail(ConnectError::IoError(err))?,
+                    }
+                }
+
+                State::Handshaking(mut handshaker) => {
+                    match handshaker.poll(cx) {
+                        Ok(Ready(connection)) => {
+                            self.state = State::Done;
+                            return Ok(Ready(connection));
+                        }
+                        Ok(Pending) => {
+                            self.state = State::Handshaking(handshaker);
+                            return Ok(Pending);
+                        }
+                        Err((err, _)) => self.fail(ConnectError::Handshake(err))?,
+                    }
+                }
+
+                State::Waiting(mut delay) => {
+                    match delay.poll(cx) {
+                        Ok(Pending) => {
+                            self.state = State::Waiting(delay);
+                            return Ok(Pending);
+                        }
+                        Ok(Ready(())) | Err(_) => {}
+                    }
+                }
+
+                // Already completed, stay in that terminal state.
+                State::Done => {
+                    self.state = State::Done;
+                    return Ok(Pending);
+                }
+            }
+        }
+    }
+}
+
+// Whether an attempt that failed with `err` may succeed when retried.
+fn is_transient(err: &ConnectError) -> bool {
+    let io_err = match *err {
+        ConnectError::IoError(ref err) => err,
+        ConnectError::Handshake(HandshakeError::IoError(ref err)) => err,
+        ConnectError::Handshake(_) => return false,
+        ConnectError::ConnectTimedOut |
+        ConnectError::HandshakeTimedOut => return true,
+    };
+
+    match io_err.kind() {
+        Interrupted | ConnectionReset | ConnectionAborted | TimedOut => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,48 @@
+//! Perform handshakes over tunnels, e.g. through an ssb room server.
+//!
+//! A room relays connections between its members: a client first performs a
+//! handshake with the room, then opens a tunnel through it, and finally
+//! performs a second handshake with the target peer over that tunnel. The
+//! handshakers work over any stream, so the inner handshake only needs the
+//! tunnel to implement `AsyncRead` and `AsyncWrite`. The helpers in this
+//! module generate fresh ephemeral keys for the inner handshake, so that it
+//! shares no state with the outer one.
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use client::OwningClientHandshaker;
+use identity::Identity;
+use server::OwningServerHandshaker;
+
+/// Performs the client side of a handshake with the target peer over an
+/// established `tunnel`.
+pub fn connect_through<S>(tunnel: S,
+                          identity: &Identity,
+                          target_longterm_pk: &sign::PublicKey)
+                          -> OwningClientHandshaker<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    OwningClientHandshaker::new(tunnel,
+                                *identity.network_identifier(),
+                                identity.longterm_pk().clone(),
+                                identity.longterm_sk().clone(),
+                                ephemeral_pk,
+                                ephemeral_sk,
+                                target_longterm_pk.clone())
+}
+
+/// Performs the server side of a handshake with a peer that connected over
+/// an established `tunnel`.
+pub fn accept_through<S>(tunnel: S, identity: &Identity) -> OwningServerHandshaker<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    OwningServerHandshaker::new(tunnel,
+                                *identity.network_identifier(),
+                                identity.longterm_pk().clone(),
+                                identity.longterm_sk().clone(),
+                                ephemeral_pk,
+                                ephemeral_sk)
+}
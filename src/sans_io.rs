@@ -0,0 +1,749 @@
+//! An io-free state machine for driving a handshake.
+//!
+//! A `HandshakeState` performs no io itself. The caller asks it whether it
+//! `wants_write` or `wants_read`, moves the bytes of the next message between
+//! it and the transport via `write_message` and `read_message`, and collects
+//! the `outcome` once the handshake `is_done`. This allows driving the
+//! protocol from mio event loops, custom schedulers or other runtimes.
+//!
+//! A server state can accept clients using alternative network identifiers
+//! or addressing alternative longterm keypairs, see
+//! `set_alternative_network_identifiers` and
+//! `set_alternative_longterm_keypairs`.
+//!
+//! All handshakers of this crate are adapters over a `HandshakeState`. The
+//! server handshakers add filtering clients, decoys and tarpitting on top.
+
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::mem::uninitialized;
+
+use sodiumoxide::crypto::{box_, sign};
+use sodiumoxide::utils::memzero;
+
+use crypto::*;
+use errors::HandshakeError;
+use stage::Stage;
+use trace::{self, Side};
+
+/// The state of one side of a handshake, independent of any io.
+///
+/// The `Debug` output shows the side and progress of the handshake, but not
+/// the buffered message data.
+pub struct HandshakeState<'a> {
+    core: Core,
+    copies: Option<Box<KeyCopies>>, // the keys of a detached state
+    keys: Keys,
+    step: Step,
+    data: [u8; MSG3_BYTES], // the message currently being written or read
+    offset: usize, // offset into the data array at which to read/write
+    network_identifier: usize, // 0 for the primary one, i + 1 for alternative i
+    longterm_keypair: usize, // 0 for the primary one, i + 1 for alternative i
+    _keys: PhantomData<&'a u8>,
+}
+
+// The locations of the keys of a state. The core points at these, or, for a
+// server, at the alternatives chosen by the client.
+#[derive(Clone, Copy)]
+struct Keys {
+    network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: *const sign::PublicKey,
+    longterm_sk: *const sign::SecretKey,
+    ephemeral_pk: *const box_::PublicKey,
+    ephemeral_sk: *const box_::SecretKey,
+    // The server's longterm public key for a client, unused by a server.
+    server_longterm_pk: *const sign::PublicKey,
+    // Only used by a server.
+    alternative_network_identifiers: *const [[u8; NETWORK_IDENTIFIER_BYTES]],
+    alternative_longterm_keypairs: *const [(sign::PublicKey, sign::SecretKey)],
+}
+
+// Copies of all keys of a state, owned by a detached state.
+struct KeyCopies {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: sign::PublicKey,
+    longterm_sk: sign::SecretKey,
+    ephemeral_pk: box_::PublicKey,
+    ephemeral_sk: box_::SecretKey,
+    server_longterm_pk: sign::PublicKey,
+    alternative_network_identifiers: Vec<[u8; NETWORK_IDENTIFIER_BYTES]>,
+    alternative_longterm_keypairs: Vec<(sign::PublicKey, sign::SecretKey)>,
+}
+
+enum Core {
+    Client(Client),
+    Server(Server),
+}
+
+impl Core {
+    // A copy of the core, pointing at the same keys.
+    fn duplicate(&self) -> Core {
+        match *self {
+            Core::Client(ref client) => Core::Client(client.duplicate()),
+            Core::Server(ref server) => Core::Server(server.duplicate()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Step {
+    WriteMsg1,
+    ReadMsg1,
+    WriteMsg2,
+    ReadMsg2,
+    WriteMsg3,
+    ReadMsg3,
+    WriteMsg4,
+    ReadMsg4,
+    Done,
+    Failed(u8), // the number of the message that failed verification
+}
+
+impl Step {
+    // The number of the message this step writes or reads.
+    fn msg(&self) -> u8 {
+        match *self {
+            Step::WriteMsg1 | Step::ReadMsg1 => 1,
+            Step::WriteMsg2 | Step::ReadMsg2 => 2,
+            Step::WriteMsg3 | Step::ReadMsg3 => 3,
+            Step::Failed(msg) => msg,
+            _ => 4,
+        }
+    }
+
+    // Whether the given message has been verified successfully.
+    fn verified(&self, msg: u8) -> bool {
+        match *self {
+            Step::Done => true,
+            Step::Failed(failed) => failed > msg,
+            step => step.msg() > msg,
+        }
+    }
+}
+
+impl<'a> HandshakeState<'a> {
+    /// Creates the state of a client connecting to a server with known public
+    /// key and app key. The client starts by writing msg1.
+    pub fn client(network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+                  client_longterm_pk: &'a sign::PublicKey,
+                  client_longterm_sk: &'a sign::SecretKey,
+                  client_ephemeral_pk: &'a box_::PublicKey,
+                  client_ephemeral_sk: &'a box_::SecretKey,
+                  server_longterm_pk: &'a sign::PublicKey)
+                  -> HandshakeState<'a> {
+        unsafe {
+            HandshakeState::client_unchecked(network_identifier,
+                                             client_longterm_pk,
+                                             client_longterm_sk,
+                                             client_ephemeral_pk,
+                                             client_ephemeral_sk,
+                                             server_longterm_pk)
+        }
+    }
+
+    /// Creates the state of a server accepting a connection from a client
+    /// which knows the server's public key and uses the right app key. The
+    /// server starts by reading msg1.
+    pub fn server(network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+                  server_longterm_pk: &'a sign::PublicKey,
+                  server_longterm_sk: &'a sign::SecretKey,
+                  server_ephemeral_pk: &'a box_::PublicKey,
+                  server_ephemeral_sk: &'a box_::SecretKey)
+                  -> HandshakeState<'a> {
+        unsafe {
+            HandshakeState::server_unchecked(network_identifier,
+                                             server_longterm_pk,
+                                             server_longterm_sk,
+                                             server_ephemeral_pk,
+                                             server_ephemeral_sk)
+        }
+    }
+
+    /// Also accept clients using any of the `network_identifiers`. The
+    /// network identifier passed to `server` is tried first, the others in
+    /// order. This has no effect on a client, and must be set before msg1
+    /// is read.
+    ///
+    /// The network identifier the client used is reported by
+    /// `Outcome::network_identifier`.
+    pub fn set_alternative_network_identifiers(&mut self,
+                                               network_identifiers: &'a [[u8; NETWORK_IDENTIFIER_BYTES]]) {
+        self.keys.alternative_network_identifiers = network_identifiers;
+    }
+
+    /// Also accept clients addressing any of the `longterm_keypairs`. The
+    /// longterm keys passed to `server` are tried first, the others in
+    /// order. This has no effect on a client, and must be set before msg3 is
+    /// read.
+    ///
+    /// The identity the client addressed is reported by
+    /// `Outcome::local_longterm_pk`.
+    pub fn set_alternative_longterm_keypairs(&mut self,
+                                             longterm_keypairs: &'a [(sign::PublicKey,
+                                                                      sign::SecretKey)]) {
+        self.keys.alternative_longterm_keypairs = longterm_keypairs;
+    }
+
+    // Creates the state of a server without tying it to the lifetime of the
+    // keys, which must outlive the state.
+    pub(crate) unsafe fn server_unchecked(network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
+                                          server_longterm_pk: *const sign::PublicKey,
+                                          server_longterm_sk: *const sign::SecretKey,
+                                          server_ephemeral_pk: *const box_::PublicKey,
+                                          server_ephemeral_sk: *const box_::SecretKey)
+                                          -> HandshakeState<'a> {
+        trace::started(Side::Server);
+        let keys = Keys {
+            network_identifier,
+            longterm_pk: server_longterm_pk,
+            longterm_sk: server_longterm_sk,
+            ephemeral_pk: server_ephemeral_pk,
+            ephemeral_sk: server_ephemeral_sk,
+            server_longterm_pk,
+            alternative_network_identifiers: &[],
+            alternative_longterm_keypairs: &[],
+        };
+        HandshakeState {
+            core: Core::Server(Server::new(network_identifier,
+                                           &(*server_longterm_pk).0,
+                                           &(*server_longterm_sk).0,
+                                           &(*server_ephemeral_pk).0,
+                                           &(*server_ephemeral_sk).0)),
+            copies: None,
+            keys,
+            step: Step::ReadMsg1,
+            data: [0; MSG3_BYTES],
+            offset: 0,
+            network_identifier: 0,
+            longterm_keypair: 0,
+            _keys: PhantomData,
+        }
+    }
+
+    // Creates the state of a client without tying it to the lifetime of the
+    // keys. The keys must outlive the state, or be re-pointed via
+    // `set_client_keys` before each use.
+    pub(crate) unsafe fn client_unchecked(network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
+                                          client_longterm_pk: *const sign::PublicKey,
+                                          client_longterm_sk: *const sign::SecretKey,
+                                          client_ephemeral_pk: *const box_::PublicKey,
+                                          client_ephemeral_sk: *const box_::SecretKey,
+                                          server_longterm_pk: *const sign::PublicKey)
+                                          -> HandshakeState<'a> {
+        trace::started(Side::Client);
+        let keys = Keys {
+            network_identifier,
+            longterm_pk: client_longterm_pk,
+            longterm_sk: client_longterm_sk,
+            ephemeral_pk: client_ephemeral_pk,
+            ephemeral_sk: client_ephemeral_sk,
+            server_longterm_pk,
+            alternative_network_identifiers: &[],
+            alternative_longterm_keypairs: &[],
+        };
+        let mut state = HandshakeState {
+            core: Core::Client(Client::new(network_identifier,
+                                           &(*client_longterm_pk).0,
+                                           &(*client_longterm_sk).0,
+                                           &(*client_ephemeral_pk).0,
+                                           &(*client_ephemeral_sk).0,
+                                           &(*server_longterm_pk).0)),
+            copies: None,
+            keys,
+            step: Step::WriteMsg1,
+            data: [0; MSG3_BYTES],
+            offset: 0,
+            network_identifier: 0,
+            longterm_keypair: 0,
+            _keys: PhantomData,
+        };
+
+        if let Core::Client(ref mut client) = state.core {
+            client.create_msg1(&mut *(&mut state.data as *mut [u8; MSG3_BYTES] as
+                                      *mut [u8; MSG1_BYTES]));
+        }
+
+        state
+    }
+
+    // Points a client core at the current location of its keys, which must
+    // have the same values as those it was created with. Only the server
+    // longterm public key may differ, and only until msg2 is verified.
+    pub(crate) unsafe fn set_client_keys(&mut self,
+                                         network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
+                                         client_longterm_pk: *const sign::PublicKey,
+                                         client_longterm_sk: *const sign::SecretKey,
+                                         client_ephemeral_pk: *const box_::PublicKey,
+                                         client_ephemeral_sk: *const box_::SecretKey,
+                                         server_longterm_pk: *const sign::PublicKey) {
+        self.keys.network_identifier = network_identifier;
+        self.keys.longterm_pk = client_longterm_pk;
+        self.keys.longterm_sk = client_longterm_sk;
+        self.keys.ephemeral_pk = client_ephemeral_pk;
+        self.keys.ephemeral_sk = client_ephemeral_sk;
+        self.keys.server_longterm_pk = server_longterm_pk;
+        self.point_core();
+    }
+
+    // Creates a copy of the state that owns copies of all keys, so that it
+    // can be advanced on another thread. Its progress is taken back via
+    // `attach`.
+    pub(crate) fn detach(&self) -> HandshakeState<'static> {
+        let copies = unsafe {
+            Box::new(KeyCopies {
+                         network_identifier: *self.keys.network_identifier,
+                         longterm_pk: (*self.keys.longterm_pk).clone(),
+                         longterm_sk: (*self.keys.longterm_sk).clone(),
+                         ephemeral_pk: (*self.keys.ephemeral_pk).clone(),
+                         ephemeral_sk: (*self.keys.ephemeral_sk).clone(),
+                         server_longterm_pk: (*self.keys.server_longterm_pk).clone(),
+                         alternative_network_identifiers:
+                             (*self.keys.alternative_network_identifiers).to_vec(),
+                         alternative_longterm_keypairs:
+                             (*self.keys.alternative_longterm_keypairs).to_vec(),
+                     })
+        };
+        // The copies live on the heap, so moving the box does not move them.
+        let keys = Keys {
+            network_identifier: &copies.network_identifier,
+            longterm_pk: &copies.longterm_pk,
+            longterm_sk: &copies.longterm_sk,
+            ephemeral_pk: &copies.ephemeral_pk,
+            ephemeral_sk: &copies.ephemeral_sk,
+            server_longterm_pk: &copies.server_longterm_pk,
+            alternative_network_identifiers: &copies.alternative_network_identifiers[..],
+            alternative_longterm_keypairs: &copies.alternative_longterm_keypairs[..],
+        };
+
+        let mut detached = HandshakeState {
+            core: self.core.duplicate(),
+            copies: Some(copies),
+            keys,
+            step: self.step,
+            data: self.data,
+            offset: self.offset,
+            network_identifier: self.network_identifier,
+            longterm_keypair: self.longterm_keypair,
+            _keys: PhantomData,
+        };
+        detached.point_core();
+        detached
+    }
+
+    // Takes over the progress of a state created by `detach`.
+    pub(crate) fn attach(&mut self, detached: HandshakeState) {
+        self.core = detached.core.duplicate();
+        self.step = detached.step;
+        self.data = detached.data;
+        self.offset = detached.offset;
+        self.network_identifier = detached.network_identifier;
+        self.longterm_keypair = detached.longterm_keypair;
+        self.point_core();
+    }
+
+    // Points the core at the keys, and for a server at the alternatives
+    // chosen by the client so far.
+    fn point_core(&mut self) {
+        let keys = self.keys;
+        unsafe {
+            match self.core {
+                Core::Client(ref mut client) => {
+                    client.set_inputs(keys.network_identifier,
+                                      &(*keys.longterm_pk).0,
+                                      &(*keys.longterm_sk).0,
+                                      &(*keys.ephemeral_pk).0,
+                                      &(*keys.ephemeral_sk).0,
+                                      &(*keys.server_longterm_pk).0)
+                }
+                Core::Server(ref mut server) => {
+                    let network_identifier = match self.network_identifier {
+                        0 => keys.network_identifier,
+                        i => &(*keys.alternative_network_identifiers)[i - 1] as *const _,
+                    };
+                    let (longterm_pk, longterm_sk) = match self.longterm_keypair {
+                        0 => (&(*keys.longterm_pk).0, &(*keys.longterm_sk).0),
+                        i => {
+                            let &(ref pk, ref sk) = &(*keys.alternative_longterm_keypairs)[i - 1];
+                            (&pk.0, &sk.0)
+                        }
+                    };
+                    server.set_inputs(network_identifier,
+                                      longterm_pk,
+                                      longterm_sk,
+                                      &(*keys.ephemeral_pk).0,
+                                      &(*keys.ephemeral_sk).0)
+                }
+            }
+        }
+    }
+
+    // Returns whether this is a client connecting to its own longterm public
+    // key.
+    pub(crate) fn connects_to_self(&self) -> bool {
+        match self.core {
+            Core::Client(ref client) => client.connects_to_self(),
+            Core::Server(_) => false,
+        }
+    }
+
+    // The ephemeral public key of the client, once a server verified msg1.
+    pub(crate) fn client_ephemeral_pk(&self) -> Option<box_::PublicKey> {
+        match self.core {
+            Core::Server(ref server) if self.step.verified(1) => {
+                Some(box_::PublicKey(unsafe { server.client_ephemeral_pub() }))
+            }
+            _ => None,
+        }
+    }
+
+    // The longterm public key of the client, once a server verified msg3.
+    pub(crate) fn client_longterm_pk(&self) -> Option<sign::PublicKey> {
+        match self.core {
+            Core::Server(ref server) if self.step.verified(3) => {
+                Some(sign::PublicKey(unsafe { server.client_longterm_pub() }))
+            }
+            _ => None,
+        }
+    }
+
+    // Returns whether this is a server that verified msg3 of a client using
+    // the server's own longterm public key.
+    pub(crate) fn accepts_self(&self) -> bool {
+        match self.core {
+            Core::Server(ref server) if self.step.verified(3) => unsafe { server.accepts_self() },
+            _ => false,
+        }
+    }
+
+    // The progress of the handshake, for the `Debug` output of handshakers.
+    pub(crate) fn progress(&self) -> &Debug {
+        &self.step
+    }
+
+    /// Returns the number of bytes of the current message that still need to
+    /// be written to the peer, or `0` if the state is not waiting to write.
+    pub fn wants_write(&self) -> usize {
+        match self.step {
+            Step::WriteMsg1 => MSG1_BYTES - self.offset,
+            Step::WriteMsg2 => MSG2_BYTES - self.offset,
+            Step::WriteMsg3 => MSG3_BYTES - self.offset,
+            Step::WriteMsg4 => MSG4_BYTES - self.offset,
+            _ => 0,
+        }
+    }
+
+    /// Returns the number of bytes of the current message that still need to
+    /// be read from the peer, or `0` if the state is not waiting to read.
+    pub fn wants_read(&self) -> usize {
+        match self.step {
+            Step::ReadMsg1 => MSG1_BYTES - self.offset,
+            Step::ReadMsg2 => MSG2_BYTES - self.offset,
+            Step::ReadMsg3 => MSG3_BYTES - self.offset,
+            Step::ReadMsg4 => MSG4_BYTES - self.offset,
+            _ => 0,
+        }
+    }
+
+    // The stage of an unfinished handshake.
+    pub(crate) fn stage(&self) -> Stage {
+        match self.step.msg() {
+            1 => Stage::Msg1,
+            2 => Stage::Msg2,
+            3 => Stage::Msg3,
+            _ => Stage::Msg4,
+        }
+    }
+
+    /// Returns whether the handshake has completed successfully.
+    pub fn is_done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    /// Copies as much of the current outgoing message into `buf` as fits, and
+    /// returns the number of bytes copied. These bytes must be written to the
+    /// peer, in order.
+    pub fn write_message(&mut self, buf: &mut [u8]) -> usize {
+        let len = {
+            let outgoing = self.outgoing();
+            let len = if buf.len() < outgoing.len() {
+                buf.len()
+            } else {
+                outgoing.len()
+            };
+            buf[..len].copy_from_slice(&outgoing[..len]);
+            len
+        };
+        self.advance_write(len);
+        len
+    }
+
+    /// Consumes as many bytes of `buf` as belong to the current incoming
+    /// message, and returns their number. Once a message is complete, it is
+    /// verified.
+    ///
+    /// After an error, the state neither wants to read nor write anymore.
+    pub fn read_message(&mut self, buf: &[u8]) -> Result<usize, HandshakeError> {
+        let len = {
+            let incoming = self.incoming();
+            let len = if buf.len() < incoming.len() {
+                buf.len()
+            } else {
+                incoming.len()
+            };
+            incoming[..len].copy_from_slice(&buf[..len]);
+            len
+        };
+        self.advance_read(len)?;
+        Ok(len)
+    }
+
+    /// Returns the outcome of the handshake, or `None` if it has not
+    /// completed yet.
+    pub fn outcome(&mut self) -> Option<Outcome> {
+        if self.step != Step::Done {
+            return None;
+        }
+
+        let mut outcome = unsafe { uninitialized() };
+        match self.core {
+            Core::Client(ref mut client) => client.outcome(&mut outcome),
+            Core::Server(ref mut server) => server.outcome(&mut outcome),
+        }
+        trace::outcome_computed(self.side(), &outcome.peer_longterm_pk());
+        Some(outcome)
+    }
+
+    /// Performs the rest of the handshake over a blocking reader and writer,
+    /// e.g. stdin and stdout, or both halves of a `std::net::TcpStream`. The
+    /// writer is flushed after every message.
+    pub fn run_blocking<R: Read, W: Write>(&mut self,
+                                           reader: &mut R,
+                                           writer: &mut W)
+                                           -> Result<Outcome, HandshakeError> {
+        let mut buf = [0; MSG3_BYTES];
+        while !self.is_done() {
+            if self.wants_write() > 0 {
+                let len = self.write_message(&mut buf);
+                writer.write_all(&buf[..len])?;
+                writer.flush()?;
+            } else if self.wants_read() > 0 {
+                let len = self.wants_read();
+                reader.read_exact(&mut buf[..len])?;
+                self.read_message(&buf[..len])?;
+            } else {
+                // A failed state neither reads nor writes.
+                return Err(HandshakeError::CryptoError);
+            }
+        }
+
+        Ok(self.outcome().unwrap())
+    }
+
+    // Which side of the handshake this state performs.
+    fn side(&self) -> Side {
+        match self.core {
+            Core::Client(_) => Side::Client,
+            Core::Server(_) => Side::Server,
+        }
+    }
+
+    // The remaining bytes of the current outgoing message.
+    pub(crate) fn outgoing(&self) -> &[u8] {
+        let end = self.offset + self.wants_write();
+        &self.data[self.offset..end]
+    }
+
+    // Marks `written` bytes of the current outgoing message as written.
+    pub(crate) fn advance_write(&mut self, written: usize) {
+        if written == 0 || self.wants_write() == 0 {
+            return;
+        }
+
+        self.offset += written;
+        if self.wants_write() == 0 {
+            trace::msg_sent(self.side(), self.step.msg());
+            self.offset = 0;
+            self.step = match self.step {
+                Step::WriteMsg1 => Step::ReadMsg2,
+                Step::WriteMsg2 => Step::ReadMsg3,
+                Step::WriteMsg3 => Step::ReadMsg4,
+                _ => Step::Done,
+            };
+        }
+    }
+
+    // The buffer for the remaining bytes of the current incoming message.
+    pub(crate) fn incoming(&mut self) -> &mut [u8] {
+        let end = self.offset + self.wants_read();
+        &mut self.data[self.offset..end]
+    }
+
+    // Marks `read` bytes of the current incoming message as read, verifies
+    // the message if it is complete, and prepares the reply.
+    pub(crate) fn advance_read(&mut self, read: usize) -> Result<(), HandshakeError> {
+        if read == 0 || self.wants_read() == 0 {
+            return Ok(());
+        }
+
+        self.offset += read;
+        if self.wants_read() > 0 {
+            return Ok(());
+        }
+        self.offset = 0;
+
+        let (side, msg) = (self.side(), self.step.msg());
+        let (valid, next) = match self.step {
+            Step::ReadMsg1 => (self.verify_msg1(), Step::WriteMsg2),
+            Step::ReadMsg2 => (self.verify_msg2(), Step::WriteMsg3),
+            Step::ReadMsg3 => (self.verify_msg3(), Step::WriteMsg4),
+            Step::ReadMsg4 => (self.verify_msg4(), Step::Done),
+            _ => unreachable!(),
+        };
+        trace::msg_verified(side, msg, valid);
+
+        if valid {
+            self.step = next;
+            Ok(())
+        } else {
+            self.step = Step::Failed(msg);
+            Err(HandshakeError::CryptoError)
+        }
+    }
+
+    // Verifies msg1 against the primary network identifier, then against the
+    // alternative ones, and writes msg2 into the data buffer. Leaves the core
+    // pointed at the first matching network identifier.
+    fn verify_msg1(&mut self) -> bool {
+        let candidates = 1 + unsafe { (*self.keys.alternative_network_identifiers).len() };
+        for candidate in 0..candidates {
+            self.network_identifier = candidate;
+            self.point_core();
+
+            let data = &mut self.data;
+            if let Core::Server(ref mut server) = self.core {
+                if server.verify_msg1(unsafe { as_msg::<[u8; MSG1_BYTES]>(data) }) {
+                    server.create_msg2(unsafe { as_msg_mut::<[u8; MSG2_BYTES]>(data) });
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    // Verifies msg2 and writes msg3 into the data buffer.
+    fn verify_msg2(&mut self) -> bool {
+        let data = &mut self.data;
+        match self.core {
+            Core::Client(ref mut client) => {
+                if client.verify_msg2(unsafe { as_msg::<[u8; MSG2_BYTES]>(data) }) {
+                    client.create_msg3(data);
+                    true
+                } else {
+                    false
+                }
+            }
+            Core::Server(_) => unreachable!(),
+        }
+    }
+
+    // Verifies msg3 against the primary longterm keys, then against the
+    // alternative ones, and writes msg4 into the data buffer. Leaves the core
+    // pointed at the first matching longterm keys.
+    fn verify_msg3(&mut self) -> bool {
+        let alternatives = unsafe { &*self.keys.alternative_longterm_keypairs };
+        // Verifying updates the core even if it fails, so the alternatives
+        // start from copies made before.
+        let candidates: Vec<Server> = match self.core {
+            Core::Server(ref server) => {
+                alternatives
+                    .iter()
+                    .map(|&(ref pk, ref sk)| server.with_longterm_keys(&pk.0, &sk.0))
+                    .collect()
+            }
+            Core::Client(_) => unreachable!(),
+        };
+
+        let data = &mut self.data;
+        if let Core::Server(ref mut server) = self.core {
+            if server.verify_msg3(data) {
+                server.create_msg4(unsafe { as_msg_mut::<[u8; MSG4_BYTES]>(data) });
+                return true;
+            }
+        }
+
+        for (i, mut candidate) in candidates.into_iter().enumerate() {
+            if candidate.verify_msg3(data) {
+                candidate.create_msg4(unsafe { as_msg_mut::<[u8; MSG4_BYTES]>(data) });
+                self.core = Core::Server(candidate);
+                self.longterm_keypair = i + 1;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Verifies msg4.
+    fn verify_msg4(&mut self) -> bool {
+        match self.core {
+            Core::Client(ref mut client) => {
+                client.verify_msg4(unsafe { as_msg::<[u8; MSG4_BYTES]>(&self.data) })
+            }
+            Core::Server(_) => unreachable!(),
+        }
+    }
+}
+
+impl<'a> Debug for HandshakeState<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        let side = match self.core {
+            Core::Client(_) => "client",
+            Core::Server(_) => "server",
+        };
+        f.debug_struct("HandshakeState")
+            .field("side", &side)
+            .field("step", &self.step)
+            .field("offset", &self.offset)
+            .field("data", &Redacted)
+            .finish()
+    }
+}
+
+// Zero buffered handshake data on dropping.
+impl<'a> Drop for HandshakeState<'a> {
+    fn drop(&mut self) {
+        memzero(&mut self.data);
+    }
+}
+
+// A state owns all memory it writes to. Its raw pointers, in `keys` and in
+// the core, are only ever read through. They point to:
+//
+// - `network_identifier`, `longterm_pk`, `longterm_sk`, `ephemeral_pk`,
+//   `ephemeral_sk` and `server_longterm_pk`: byte arrays that the public
+//   constructors borrow for `'a`. With `client_unchecked` and
+//   `server_unchecked`, they are owned by the handshaker holding the state
+//   instead, which moves together with it and points the state at their
+//   new location before using it again.
+// - `alternative_network_identifiers` and `alternative_longterm_keypairs`:
+//   slices of byte arrays, borrowed for `'a` by the public setters, or
+//   owned by the server handshaker holding the state.
+//
+// In a state created by `detach`, all of them point into `copies`, which
+// the state owns.
+//
+// Sending a state to another thread is thus like sending shared references
+// to byte arrays, and sharing it only allows reading them.
+unsafe impl<'a> Send for HandshakeState<'a> {}
+unsafe impl<'a> Sync for HandshakeState<'a> {}
+
+// Views the start of the data buffer as a shorter message.
+unsafe fn as_msg<M>(data: &[u8; MSG3_BYTES]) -> &M {
+    &*(data as *const [u8; MSG3_BYTES] as *const M)
+}
+
+unsafe fn as_msg_mut<M>(data: &mut [u8; MSG3_BYTES]) -> &mut M {
+    &mut *(data as *mut [u8; MSG3_BYTES] as *mut M)
+}
@@ -0,0 +1,539 @@
+//! An encrypted duplex stream, using the
+//! [box-stream](https://github.com/dominictarr/pull-box-stream) protocol of
+//! ssb.
+//!
+//! Data is sent in packets of at most 4096 bytes. Each packet consists of an
+//! encrypted header, containing the length and authenticator of the body,
+//! followed by the encrypted body. Closing the stream sends a final header of
+//! all zeroes, which tells the peer that no more data follows. Since the
+//! headers are authenticated as well, a peer can distinguish a clean end of
+//! the stream from a truncated connection.
+//!
+//! The futures returned by `connect` and `accept` yield the encrypted stream,
+//! their `handoff` method passes it on to an rpc layer right away.
+//!
+//! `rekey_client` and `rekey_server` run a fresh handshake over an
+//! established stream and switch it to the resulting keys, so that
+//! long-lived connections can rotate their keys periodically.
+//!
+//! This module requires the `secret-stream` feature.
+
+use std::cmp::min;
+use std::io;
+use std::io::ErrorKind::{InvalidData, WriteZero};
+use std::mem::replace;
+
+use sodiumoxide::crypto::{box_, secretbox, sign};
+use sodiumoxide::utils::memzero;
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use client::OwningClientHandshaker;
+use crypto::Outcome;
+use errors::HandshakeError;
+use identity::Identity;
+use nonce::NonceGen;
+use server::OwningServerHandshaker;
+
+/// The maximum number of bytes in the body of a packet.
+pub const MAX_PACKET_BYTES: usize = 4096;
+// Length of a plaintext header: the body length and the body authenticator.
+const HEADER_BYTES: usize = 2 + secretbox::MACBYTES;
+// Length of an encrypted header.
+const BOXED_HEADER_BYTES: usize = HEADER_BYTES + secretbox::MACBYTES;
+
+/// Wraps a stream, encrypting all writes and decrypting all reads.
+pub struct SecretStream<S> {
+    stream: S,
+    encryption_key: secretbox::Key,
+    encryption_nonces: NonceGen,
+    decryption_key: secretbox::Key,
+    decryption_nonces: NonceGen,
+    peer_longterm_pk: sign::PublicKey,
+    // the encrypted packet currently being written, and how much of it was written
+    out: Vec<u8>,
+    out_offset: usize,
+    sent_goodbye: bool,
+    // the encrypted header or body currently being read, and how much of it was read
+    in_header: [u8; BOXED_HEADER_BYTES],
+    in_body: Vec<u8>,
+    in_offset: usize,
+    in_body_mac: Option<secretbox::Tag>, // `Some` while reading a body
+    // decrypted data that has not been read yet
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+    received_goodbye: bool,
+}
+
+impl<S> SecretStream<S> {
+    /// Creates a new `SecretStream` over the `stream` over which a handshake
+    /// resulted in the given `outcome`.
+    pub fn new(outcome: &Outcome, stream: S) -> SecretStream<S> {
+        SecretStream {
+            stream,
+            encryption_key: outcome.send().key,
+            encryption_nonces: NonceGen::new(outcome.send().nonce),
+            decryption_key: outcome.recv().key,
+            decryption_nonces: NonceGen::new(outcome.recv().nonce),
+            peer_longterm_pk: outcome.peer_longterm_pk(),
+            out: Vec::new(),
+            out_offset: 0,
+            sent_goodbye: false,
+            in_header: [0; BOXED_HEADER_BYTES],
+            in_body: Vec::new(),
+            in_offset: 0,
+            in_body_mac: None,
+            plaintext: Vec::new(),
+            plaintext_offset: 0,
+            received_goodbye: false,
+        }
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_longterm_pk(&self) -> &sign::PublicKey {
+        &self.peer_longterm_pk
+    }
+
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the underlying stream.
+    ///
+    /// Reading from or writing to it directly corrupts the encrypted stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    // Switches to the keys and nonces of `outcome`. Fails if the stream is in
+    // the middle of a packet or holds unread plaintext, as the peer would not
+    // switch at the same position.
+    fn swap_keys(&mut self, outcome: &Outcome) -> bool {
+        if self.out_offset < self.out.len() || self.in_offset != 0 ||
+           self.in_body_mac.is_some() || self.plaintext_offset < self.plaintext.len() {
+            return false;
+        }
+
+        self.encryption_key = outcome.send().key;
+        self.encryption_nonces = NonceGen::new(outcome.send().nonce);
+        self.decryption_key = outcome.recv().key;
+        self.decryption_nonces = NonceGen::new(outcome.recv().nonce);
+        true
+    }
+
+    // Encrypts a packet with the given body into `self.out`.
+    fn seal_packet(&mut self, body: &[u8]) -> io::Result<()> {
+        let (header_nonce, body_nonce) = self.encryption_nonces
+            .next_packet()
+            .ok_or_else(nonces_exhausted)?;
+
+        let mut boxed_body = body.to_vec();
+        let secretbox::Tag(body_mac) =
+            secretbox::seal_detached(&mut boxed_body, &body_nonce, &self.encryption_key);
+
+        let mut header = [0u8; HEADER_BYTES];
+        header[0] = (body.len() >> 8) as u8;
+        header[1] = body.len() as u8;
+        header[2..].copy_from_slice(&body_mac);
+
+        self.out = secretbox::seal(&header, &header_nonce, &self.encryption_key);
+        self.out.extend_from_slice(&boxed_body);
+        self.out_offset = 0;
+        memzero(&mut header);
+        Ok(())
+    }
+
+    // Encrypts the final header of all zeroes into `self.out`.
+    fn seal_goodbye(&mut self) -> io::Result<()> {
+        let nonce = self.encryption_nonces.next().ok_or_else(nonces_exhausted)?;
+        self.out = secretbox::seal(&[0; HEADER_BYTES], &nonce, &self.encryption_key);
+        self.out_offset = 0;
+        self.sent_goodbye = true;
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite> SecretStream<S> {
+    // Writes the remainder of `self.out` to the underlying stream.
+    fn poll_write_out(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        while self.out_offset < self.out.len() {
+            match self.stream.poll_write(cx, &self.out[self.out_offset..])? {
+                Ready(0) => return Err(io::Error::new(WriteZero, "failed to write packet")),
+                Ready(written) => self.out_offset += written,
+                Pending => return Ok(Pending),
+            }
+        }
+        Ok(Ready(()))
+    }
+}
+
+impl<S: AsyncRead> SecretStream<S> {
+    // Reads and decrypts the next packet into `self.plaintext`, or sets
+    // `self.received_goodbye`.
+    fn poll_read_packet(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        loop {
+            match self.in_body_mac.clone() {
+                None => {
+                    while self.in_offset < BOXED_HEADER_BYTES {
+                        match self.stream
+                                  .poll_read(cx, &mut self.in_header[self.in_offset..])? {
+                            Ready(0) => {
+                                return Err(io::Error::new(InvalidData,
+                                                          "stream ended without a goodbye"))
+                            }
+                            Ready(read) => self.in_offset += read,
+                            Pending => return Ok(Pending),
+                        }
+                    }
+
+                    let header_nonce = self.decryption_nonces
+                        .next()
+                        .ok_or_else(nonces_exhausted)?;
+                    let header = secretbox::open(&self.in_header,
+                                                 &header_nonce,
+                                                 &self.decryption_key)
+                            .map_err(|_| {
+                                         io::Error::new(InvalidData,
+                                                        "failed to decrypt packet header")
+                                     })?;
+
+                    self.in_offset = 0;
+                    if header.iter().all(|byte| *byte == 0) {
+                        self.received_goodbye = true;
+                        return Ok(Ready(()));
+                    }
+
+                    let len = ((header[0] as usize) << 8) | (header[1] as usize);
+                    if len > MAX_PACKET_BYTES {
+                        return Err(io::Error::new(InvalidData, "packet too long"));
+                    }
+                    self.in_body = vec![0; len];
+                    self.in_body_mac = secretbox::Tag::from_slice(&header[2..]);
+                }
+
+                Some(body_mac) => {
+                    while self.in_offset < self.in_body.len() {
+                        match self.stream
+                                  .poll_read(cx, &mut self.in_body[self.in_offset..])? {
+                            Ready(0) => {
+                                return Err(io::Error::new(InvalidData,
+                                                          "stream ended within a packet"))
+                            }
+                            Ready(read) => self.in_offset += read,
+                            Pending => return Ok(Pending),
+                        }
+                    }
+
+                    let body_nonce = self.decryption_nonces
+                        .next()
+                        .ok_or_else(nonces_exhausted)?;
+                    secretbox::open_detached(&mut self.in_body,
+                                             &body_mac,
+                                             &body_nonce,
+                                             &self.decryption_key)
+                            .map_err(|_| {
+                                         io::Error::new(InvalidData,
+                                                        "failed to decrypt packet body")
+                                     })?;
+
+                    self.in_offset = 0;
+                    self.in_body_mac = None;
+                    self.plaintext = replace(&mut self.in_body, Vec::new());
+                    self.plaintext_offset = 0;
+                    return Ok(Ready(()));
+                }
+            }
+        }
+    }
+}
+
+// Zero buffered plaintext on dropping.
+impl<S> Drop for SecretStream<S> {
+    fn drop(&mut self) {
+        memzero(&mut self.plaintext);
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for SecretStream<S> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        while self.plaintext_offset >= self.plaintext.len() {
+            if self.received_goodbye {
+                return Ok(Ready(0));
+            }
+            if let Pending = self.poll_read_packet(cx)? {
+                return Ok(Pending);
+            }
+        }
+
+        let available = &self.plaintext[self.plaintext_offset..];
+        let read = min(buf.len(), available.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.plaintext_offset += read;
+        Ok(Ready(read))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for SecretStream<S> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        if let Pending = self.poll_write_out(cx)? {
+            return Ok(Pending);
+        }
+        if self.sent_goodbye {
+            return Err(io::Error::new(WriteZero, "wrote to a closed secret stream"));
+        }
+        if buf.is_empty() {
+            return Ok(Ready(0));
+        }
+
+        let len = min(buf.len(), MAX_PACKET_BYTES);
+        self.seal_packet(&buf[..len])?;
+        Ok(Ready(len))
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        if let Pending = self.poll_write_out(cx)? {
+            return Ok(Pending);
+        }
+        self.stream.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        if let Pending = self.poll_write_out(cx)? {
+            return Ok(Pending);
+        }
+        if !self.sent_goodbye {
+            self.seal_goodbye()?;
+            if let Pending = self.poll_write_out(cx)? {
+                return Ok(Pending);
+            }
+        }
+        self.stream.poll_close(cx)
+    }
+}
+
+/// Performs the client side of a handshake over the `stream`, and wraps it in
+/// a `SecretStream` on success.
+pub fn connect<S>(stream: S,
+                  identity: &Identity,
+                  server_longterm_pk: &sign::PublicKey)
+                  -> Connect<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    Connect(OwningClientHandshaker::new(stream,
+                                        *identity.network_identifier(),
+                                        identity.longterm_pk().clone(),
+                                        identity.longterm_sk().clone(),
+                                        ephemeral_pk,
+                                        ephemeral_sk,
+                                        server_longterm_pk.clone()))
+}
+
+/// Future that performs the client side of a handshake and yields a
+/// `SecretStream`, see `connect`.
+pub struct Connect<S>(OwningClientHandshaker<S>);
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S: AsyncRead + AsyncWrite> Future for Connect<S> {
+    type Item = SecretStream<S>;
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll(cx)? {
+            Ready((outcome, stream)) => Ok(Ready(SecretStream::new(&outcome, stream))),
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Performs the server side of a handshake over the `stream`, and wraps it in
+/// a `SecretStream` on success.
+pub fn accept<S>(stream: S, identity: &Identity) -> Accept<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    Accept(OwningServerHandshaker::new(stream,
+                                       *identity.network_identifier(),
+                                       identity.longterm_pk().clone(),
+                                       identity.longterm_sk().clone(),
+                                       ephemeral_pk,
+                                       ephemeral_sk))
+}
+
+/// Future that performs the server side of a handshake and yields a
+/// `SecretStream`, see `accept`.
+pub struct Accept<S>(OwningServerHandshaker<S>);
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S: AsyncRead + AsyncWrite> Future for Accept<S> {
+    type Item = SecretStream<S>;
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll(cx)? {
+            Ready((outcome, stream)) => Ok(Ready(SecretStream::new(&outcome, stream))),
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Performs the client side of a fresh handshake with the peer of an
+/// established `SecretStream`, over the stream itself, and switches the
+/// stream to the new keys on success.
+///
+/// Neither side may send any other data while rekeying: the application
+/// must agree with the peer on when to rekey, e.g. via its rpc protocol, and
+/// the peer has to call `rekey_server` at the same time. Both sides must use
+/// the same identities as in the original handshake.
+pub fn rekey_client<S>(stream: SecretStream<S>, identity: &Identity) -> Rekey<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let peer_longterm_pk = stream.peer_longterm_pk().clone();
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let handshaker = OwningClientHandshaker::new(stream,
+                                                 *identity.network_identifier(),
+                                                 identity.longterm_pk().clone(),
+                                                 identity.longterm_sk().clone(),
+                                                 ephemeral_pk,
+                                                 ephemeral_sk,
+                                                 peer_longterm_pk.clone());
+    Rekey {
+        handshake: RekeyHandshake::Client(handshaker),
+        peer_longterm_pk,
+    }
+}
+
+/// Performs the server side of a fresh handshake with the peer of an
+/// established `SecretStream`, see `rekey_client`.
+///
+/// The handshake fails with a `CryptoError` if the client authenticates with
+/// a different longterm key than in the original handshake.
+pub fn rekey_server<S>(stream: SecretStream<S>, identity: &Identity) -> Rekey<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let peer_longterm_pk = stream.peer_longterm_pk().clone();
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    let handshaker = OwningServerHandshaker::new(stream,
+                                                 *identity.network_identifier(),
+                                                 identity.longterm_pk().clone(),
+                                                 identity.longterm_sk().clone(),
+                                                 ephemeral_pk,
+                                                 ephemeral_sk);
+    Rekey {
+        handshake: RekeyHandshake::Server(handshaker),
+        peer_longterm_pk,
+    }
+}
+
+/// Future that rekeys a `SecretStream`, see `rekey_client` and
+/// `rekey_server`.
+///
+/// Yields the stream using the new keys. On failure, the stream is returned
+/// with the old keys, but it should be closed: the peer may already have
+/// switched to the new ones.
+pub struct Rekey<S> {
+    handshake: RekeyHandshake<S>,
+    peer_longterm_pk: sign::PublicKey,
+}
+
+enum RekeyHandshake<S> {
+    Client(OwningClientHandshaker<SecretStream<S>>),
+    Server(OwningServerHandshaker<SecretStream<S>>),
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S: AsyncRead + AsyncWrite> Future for Rekey<S> {
+    type Item = SecretStream<S>;
+    type Error = (HandshakeError, SecretStream<S>);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let polled = match self.handshake {
+            RekeyHandshake::Client(ref mut handshaker) => handshaker.poll(cx)?,
+            RekeyHandshake::Server(ref mut handshaker) => handshaker.poll(cx)?,
+        };
+
+        match polled {
+            Ready((outcome, mut stream)) => {
+                if outcome.peer_longterm_pk() != self.peer_longterm_pk {
+                    return Err((HandshakeError::CryptoError, stream));
+                }
+                if !stream.swap_keys(&outcome) {
+                    let err = io::Error::new(InvalidData, "received data while rekeying");
+                    return Err((HandshakeError::IoError(err), stream));
+                }
+                Ok(Ready(stream))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+/// Hands the `SecretStream` resulting from a `Connect` or `Accept` to an rpc
+/// layer, e.g. packet-stream or muxrpc, see `Connect::handoff` and
+/// `Accept::handoff`.
+///
+/// Yields whatever `make_rpc` returns, together with the longterm public key
+/// of the peer.
+pub struct Handoff<F, MakeRpc> {
+    handshake: F,
+    make_rpc: Option<MakeRpc>,
+}
+
+impl<S> Connect<S> {
+    /// Once the handshake completed, passes the `SecretStream` to `make_rpc`.
+    pub fn handoff<MakeRpc, Rpc>(self, make_rpc: MakeRpc) -> Handoff<Connect<S>, MakeRpc>
+        where MakeRpc: FnOnce(SecretStream<S>) -> Rpc
+    {
+        Handoff {
+            handshake: self,
+            make_rpc: Some(make_rpc),
+        }
+    }
+}
+
+impl<S> Accept<S> {
+    /// Once the handshake completed, passes the `SecretStream` to `make_rpc`.
+    pub fn handoff<MakeRpc, Rpc>(self, make_rpc: MakeRpc) -> Handoff<Accept<S>, MakeRpc>
+        where MakeRpc: FnOnce(SecretStream<S>) -> Rpc
+    {
+        Handoff {
+            handshake: self,
+            make_rpc: Some(make_rpc),
+        }
+    }
+}
+
+impl<F, S, MakeRpc, Rpc> Future for Handoff<F, MakeRpc>
+    where F: Future<Item = SecretStream<S>>,
+          MakeRpc: FnOnce(SecretStream<S>) -> Rpc
+{
+    type Item = (Rpc, sign::PublicKey);
+    type Error = F::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let make_rpc = match self.make_rpc.take() {
+            Some(make_rpc) => make_rpc,
+            // The handoff has already completed or failed, stay in that terminal state.
+            None => return Ok(Pending),
+        };
+
+        match self.handshake.poll(cx)? {
+            Ready(stream) => {
+                let peer_longterm_pk = stream.peer_longterm_pk().clone();
+                Ok(Ready((make_rpc(stream), peer_longterm_pk)))
+            }
+            Pending => {
+                self.make_rpc = Some(make_rpc);
+                Ok(Pending)
+            }
+        }
+    }
+}
+
+// The error for a stream that used up all of its nonces.
+fn nonces_exhausted() -> io::Error {
+    io::Error::new(InvalidData, "secret stream exhausted its nonces")
+}
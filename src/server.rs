@@ -4,18 +4,29 @@ use std::{error, io, fmt};
 use std::error::Error;
 use std::io::ErrorKind::{WriteZero, UnexpectedEof};
 use std::marker::PhantomData;
-use std::mem::uninitialized;
+use std::time::Instant;
 
 use sodiumoxide::crypto::{box_, sign};
-use sodiumoxide::utils::memzero;
+use sodiumoxide::randombytes::randombytes_into;
 use futures_core::{Poll, Future, Never};
 use futures_core::Async::{Ready, Pending};
 use futures_core::task::Context;
 use futures_core::future::{FutureResult, ok};
 use futures_io::{AsyncRead, AsyncWrite};
 
+use budget::{Budget, BudgetTracker};
 use crypto::*;
 use errors::*;
+use metrics::Metrics;
+use offload::{self, Offload, Verification};
+use replay::ReplayCache;
+use sans_io::HandshakeState;
+use stage::Stage;
+use stats::HandshakeTimer;
+use trace::{self, Reason, Side};
+
+// Panic message for accessing the stream after the handshake returned it.
+const TERMINATED: &str = "The handshake already terminated and returned the stream";
 
 /// Performs the server side of a handshake.
 pub struct ServerHandshaker<'a, S>(ServerHandshakerWithFilter<'a,
@@ -43,6 +54,119 @@ impl<'a, S: AsyncRead + AsyncWrite> ServerHandshaker<'a, S> {
                                                          &server_ephemeral_pk,
                                                          &server_ephemeral_sk))
     }
+
+    /// Read and discard a random number of up to `max_discard` bytes before
+    /// failing on an invalid msg1, instead of closing the connection
+    /// immediately. This makes the server harder to fingerprint by scanners.
+    ///
+    /// A peer that sends nothing more holds the handshake in the tarpit
+    /// forever, so only use this on a stream wrapped in a `Deadline`.
+    ///
+    /// Defaults to `0`, which fails immediately.
+    pub fn set_tarpit(&mut self, max_discard: usize) {
+        self.0.set_tarpit(max_discard)
+    }
+
+    /// Reply to an invalid msg1 with random data shaped like msg2 before
+    /// failing (and tarpitting, if enabled), instead of closing the
+    /// connection without a reply. Scanners then can not tell a server that
+    /// uses another network identifier from a random service.
+    ///
+    /// Defaults to `false`.
+    pub fn set_decoy(&mut self, decoy: bool) {
+        self.0.set_decoy(decoy)
+    }
+
+    /// Fail with a `SelfConnection` error if the client uses the server's
+    /// own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.set_reject_self_connection(reject)
+    }
+
+    /// Fail with a `CryptoError` if the client hello is an exact replay of
+    /// one recently seen by the given `cache`, see `replay::ReplayCache`.
+    pub fn set_replay_cache(&mut self, cache: ReplayCache) {
+        self.0.set_replay_cache(cache)
+    }
+
+    /// Count this handshake and its result in the given `metrics`. The
+    /// duration of the handshake is measured from this call on.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.0.set_metrics(metrics)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(self) -> Option<(S, Stage)> {
+        self.0.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.0.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.0.get_mut()
+    }
+
+    /// Also accept clients using any of the `network_identifiers`, e.g. to
+    /// bridge several networks on the same port. The network identifier
+    /// passed to `new` is tried first, the others in order.
+    ///
+    /// The network identifier the client used is reported by
+    /// `Outcome::network_identifier`.
+    pub fn set_alternative_network_identifiers(&mut self,
+                                               network_identifiers: &'a [[u8; NETWORK_IDENTIFIER_BYTES]]) {
+        self.0.set_alternative_network_identifiers(network_identifiers)
+    }
+
+    /// Also accept clients addressing any of the `longterm_keypairs`, e.g.
+    /// during key rotation. The longterm keys passed to `new` are tried
+    /// first, the others in order.
+    ///
+    /// The identity the client addressed is reported by
+    /// `Outcome::local_longterm_pk`.
+    pub fn set_alternative_longterm_keypairs(&mut self,
+                                             longterm_keypairs: &'a [(sign::PublicKey,
+                                                                      sign::SecretKey)]) {
+        self.0.set_alternative_longterm_keypairs(longterm_keypairs)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -59,6 +183,8 @@ impl<'a, S: AsyncRead + AsyncWrite> Future for ServerHandshaker<'a, S> {
                     FilteringHandshakeError::FilterError(_) => unreachable!(),
                     FilteringHandshakeError::CryptoError => HandshakeError::CryptoError,
                     FilteringHandshakeError::Rejected => unreachable!(),
+                    FilteringHandshakeError::SelfConnection => HandshakeError::SelfConnection,
+                    FilteringHandshakeError::BudgetExceeded => HandshakeError::BudgetExceeded,
                 };
 
                 Err((new_err, stream))
@@ -67,6 +193,13 @@ impl<'a, S: AsyncRead + AsyncWrite> Future for ServerHandshaker<'a, S> {
     }
 }
 
+/// Shows the progress of the handshake, but no keys or message data.
+impl<'a, S> fmt::Debug for ServerHandshaker<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        (self.0).0.fmt_as("ServerHandshaker", f)
+    }
+}
+
 /// Performs the server side of a handshake. This copies the keys so that it isn't constrainted by
 /// their lifetime.
 pub struct OwningServerHandshaker<S>(OwningServerHandshakerWithFilter<S,
@@ -94,6 +227,119 @@ impl<S: AsyncRead + AsyncWrite> OwningServerHandshaker<S> {
                                                                      server_ephemeral_pk,
                                                                      server_ephemeral_sk))
     }
+
+    /// Read and discard a random number of up to `max_discard` bytes before
+    /// failing on an invalid msg1, instead of closing the connection
+    /// immediately. This makes the server harder to fingerprint by scanners.
+    ///
+    /// A peer that sends nothing more holds the handshake in the tarpit
+    /// forever, so only use this on a stream wrapped in a `Deadline`.
+    ///
+    /// Defaults to `0`, which fails immediately.
+    pub fn set_tarpit(&mut self, max_discard: usize) {
+        self.0.set_tarpit(max_discard)
+    }
+
+    /// Reply to an invalid msg1 with random data shaped like msg2 before
+    /// failing (and tarpitting, if enabled), instead of closing the
+    /// connection without a reply. Scanners then can not tell a server that
+    /// uses another network identifier from a random service.
+    ///
+    /// Defaults to `false`.
+    pub fn set_decoy(&mut self, decoy: bool) {
+        self.0.set_decoy(decoy)
+    }
+
+    /// Fail with a `SelfConnection` error if the client uses the server's
+    /// own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.set_reject_self_connection(reject)
+    }
+
+    /// Fail with a `CryptoError` if the client hello is an exact replay of
+    /// one recently seen by the given `cache`, see `replay::ReplayCache`.
+    pub fn set_replay_cache(&mut self, cache: ReplayCache) {
+        self.0.set_replay_cache(cache)
+    }
+
+    /// Count this handshake and its result in the given `metrics`. The
+    /// duration of the handshake is measured from this call on.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.0.set_metrics(metrics)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(self) -> Option<(S, Stage)> {
+        self.0.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.0.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.0.get_mut()
+    }
+
+    /// Also accept clients using any of the `network_identifiers`, e.g. to
+    /// bridge several networks on the same port. The network identifier
+    /// passed to `new` is tried first, the others in order.
+    ///
+    /// The network identifier the client used is reported by
+    /// `Outcome::network_identifier`.
+    pub fn set_alternative_network_identifiers(&mut self,
+                                               network_identifiers: Vec<[u8; NETWORK_IDENTIFIER_BYTES]>) {
+        self.0.set_alternative_network_identifiers(network_identifiers)
+    }
+
+    /// Also accept clients addressing any of the `longterm_keypairs`, e.g.
+    /// during key rotation. The longterm keys passed to `new` are tried
+    /// first, the others in order.
+    ///
+    /// The identity the client addressed is reported by
+    /// `Outcome::local_longterm_pk`.
+    pub fn set_alternative_longterm_keypairs(&mut self,
+                                             longterm_keypairs: Vec<(sign::PublicKey,
+                                                                     sign::SecretKey)>) {
+        self.0.set_alternative_longterm_keypairs(longterm_keypairs)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -110,6 +356,8 @@ impl<S: AsyncRead + AsyncWrite> Future for OwningServerHandshaker<S> {
                     FilteringHandshakeError::FilterError(_) => unreachable!(),
                     FilteringHandshakeError::CryptoError => HandshakeError::CryptoError,
                     FilteringHandshakeError::Rejected => unreachable!(),
+                    FilteringHandshakeError::SelfConnection => HandshakeError::SelfConnection,
+                    FilteringHandshakeError::BudgetExceeded => HandshakeError::BudgetExceeded,
                 };
 
                 Err((new_err, stream))
@@ -118,7 +366,14 @@ impl<S: AsyncRead + AsyncWrite> Future for OwningServerHandshaker<S> {
     }
 }
 
-fn const_async_true(_: &sign::PublicKey) -> FutureResult<bool, Never> {
+/// Shows the progress of the handshake, but no keys or message data.
+impl<S> fmt::Debug for OwningServerHandshaker<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        (self.0).inner.fmt_as("OwningServerHandshaker", f)
+    }
+}
+
+pub(crate) fn const_async_true(_: &sign::PublicKey) -> FutureResult<bool, Never> {
     ok(true)
 }
 
@@ -155,6 +410,119 @@ impl<'a, S, FilterFn, AsyncBool> ServerHandshakerWithFilter<'a, S, FilterFn, Asy
                                                                          server_ephemeral_sk),
                                    PhantomData)
     }
+
+    /// Read and discard a random number of up to `max_discard` bytes before
+    /// failing on an invalid msg1, instead of closing the connection
+    /// immediately. This makes the server harder to fingerprint by scanners.
+    ///
+    /// A peer that sends nothing more holds the handshake in the tarpit
+    /// forever, so only use this on a stream wrapped in a `Deadline`.
+    ///
+    /// Defaults to `0`, which fails immediately.
+    pub fn set_tarpit(&mut self, max_discard: usize) {
+        self.0.set_tarpit(max_discard)
+    }
+
+    /// Reply to an invalid msg1 with random data shaped like msg2 before
+    /// failing (and tarpitting, if enabled), instead of closing the
+    /// connection without a reply. Scanners then can not tell a server that
+    /// uses another network identifier from a random service.
+    ///
+    /// Defaults to `false`.
+    pub fn set_decoy(&mut self, decoy: bool) {
+        self.0.set_decoy(decoy)
+    }
+
+    /// Fail with a `SelfConnection` error if the client uses the server's
+    /// own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.0.set_reject_self_connection(reject)
+    }
+
+    /// Fail with a `CryptoError` if the client hello is an exact replay of
+    /// one recently seen by the given `cache`, see `replay::ReplayCache`.
+    pub fn set_replay_cache(&mut self, cache: ReplayCache) {
+        self.0.set_replay_cache(cache)
+    }
+
+    /// Count this handshake and its result in the given `metrics`. The
+    /// duration of the handshake is measured from this call on.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.0.set_metrics(metrics)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.0.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.0.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.0.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        self.0.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.0.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.0.get_mut()
+    }
+
+    /// Also accept clients using any of the `network_identifiers`, e.g. to
+    /// bridge several networks on the same port. The network identifier
+    /// passed to `new` is tried first, the others in order.
+    ///
+    /// The network identifier the client used is reported by
+    /// `Outcome::network_identifier`.
+    pub fn set_alternative_network_identifiers(&mut self,
+                                               network_identifiers: &'a [[u8; NETWORK_IDENTIFIER_BYTES]]) {
+        self.0.set_alternative_network_identifiers(network_identifiers)
+    }
+
+    /// Also accept clients addressing any of the `longterm_keypairs`, e.g.
+    /// during key rotation. The longterm keys passed to `new` are tried
+    /// first, the others in order.
+    ///
+    /// The identity the client addressed is reported by
+    /// `Outcome::local_longterm_pk`.
+    pub fn set_alternative_longterm_keypairs(&mut self,
+                                             longterm_keypairs: &'a [(sign::PublicKey,
+                                                                      sign::SecretKey)]) {
+        self.0.set_alternative_longterm_keypairs(longterm_keypairs)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -171,18 +539,39 @@ impl<'a, S, FilterFn, AsyncBool> Future for ServerHandshakerWithFilter<'a, S, Fi
     }
 }
 
+/// Shows the progress of the handshake, but no keys or message data.
+impl<'a, S, FilterFn, AsyncBool> fmt::Debug for ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_as("ServerHandshakerWithFilter", f)
+    }
+}
+
 /// Performs the server side of a handshake. Allows filtering clients based on
 /// their longterm public key. This copies the keys so that it isn't constrainted by
 /// their lifetime.
+///
+/// Unlike those of an `OwningClientHandshaker`, the keys are kept in a single
+/// heap allocation: an `Acceptor` returns it to its `HandshakePool` once the
+/// handshake is done, and takes the next handshaker's from there, so that a
+/// busy server does not allocate for each connection.
 pub struct OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-    network_identifier: Box<[u8; NETWORK_IDENTIFIER_BYTES]>,
-    server_longterm_pk: Box<sign::PublicKey>,
-    server_longterm_sk: Box<sign::SecretKey>,
-    server_ephemeral_pk: Box<box_::PublicKey>,
-    server_ephemeral_sk: Box<box_::SecretKey>,
+    keys: Box<ServerKeys>,
+    alternative_network_identifiers: Vec<[u8; NETWORK_IDENTIFIER_BYTES]>,
+    alternative_longterm_keypairs: Vec<(sign::PublicKey, sign::SecretKey)>,
     inner: UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>,
 }
 
+// The keys an `OwningServerHandshakerWithFilter` borrows from, kept in a
+// single allocation so that it can be recycled by a `HandshakePool`. Storing
+// them inline, like the client does, would leave the pool nothing to recycle.
+pub(crate) struct ServerKeys {
+    pub(crate) network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    pub(crate) longterm_pk: sign::PublicKey,
+    pub(crate) longterm_sk: sign::SecretKey,
+    pub(crate) ephemeral_pk: box_::PublicKey,
+    pub(crate) ephemeral_sk: box_::SecretKey,
+}
+
 impl<S, FilterFn, AsyncBool> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>
     where S: AsyncRead + AsyncWrite,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
@@ -203,27 +592,179 @@ impl<S, FilterFn, AsyncBool> OwningServerHandshakerWithFilter<S, FilterFn, Async
                server_ephemeral_pk: box_::PublicKey,
                server_ephemeral_sk: box_::SecretKey)
                -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-        let network_identifier = Box::new(network_identifier.clone());
-        let server_longterm_pk = Box::new(server_longterm_pk.clone());
-        let server_longterm_sk = Box::new(server_longterm_sk.clone());
-        let server_ephemeral_pk = Box::new(server_ephemeral_pk.clone());
-        let server_ephemeral_sk = Box::new(server_ephemeral_sk.clone());
+        let keys = Box::new(ServerKeys {
+                                network_identifier,
+                                longterm_pk: server_longterm_pk,
+                                longterm_sk: server_longterm_sk,
+                                ephemeral_pk: server_ephemeral_pk,
+                                ephemeral_sk: server_ephemeral_sk,
+                            });
+        OwningServerHandshakerWithFilter::from_keys(stream, filter_fn, keys)
+    }
 
+    // Creates a handshaker that borrows from the given `keys`, which may have
+    // been recycled from an earlier handshaker.
+    pub(crate) fn from_keys(stream: S,
+                            filter_fn: FilterFn,
+                            keys: Box<ServerKeys>)
+                            -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
         OwningServerHandshakerWithFilter {
             inner: UnsafeServerHandshakerWithFilter::new(stream,
                                                          filter_fn,
-                                                         network_identifier.as_ref(),
-                                                         server_longterm_pk.as_ref(),
-                                                         server_longterm_sk.as_ref(),
-                                                         server_ephemeral_pk.as_ref(),
-                                                         server_ephemeral_sk.as_ref()),
-            network_identifier,
-            server_longterm_pk,
-            server_longterm_sk,
-            server_ephemeral_pk,
-            server_ephemeral_sk,
+                                                         &keys.network_identifier,
+                                                         &keys.longterm_pk,
+                                                         &keys.longterm_sk,
+                                                         &keys.ephemeral_pk,
+                                                         &keys.ephemeral_sk),
+            keys,
+            alternative_network_identifiers: Vec::new(),
+            alternative_longterm_keypairs: Vec::new(),
         }
     }
+
+    // Drops the handshaker (and its stream, if it is still held) and returns
+    // the allocation holding its keys, for reuse by `from_keys`.
+    pub(crate) fn into_keys(self) -> Box<ServerKeys> {
+        let OwningServerHandshakerWithFilter { keys, inner, .. } = self;
+        drop(inner);
+        keys
+    }
+
+    // The longterm public key of the client, once its authentication has been
+    // verified.
+    pub(crate) fn client_longterm_pk(&self) -> Option<sign::PublicKey> {
+        self.inner.client_longterm_pk()
+    }
+
+    // The longterm public key the handshaker was created with.
+    pub(crate) fn server_longterm_pk(&self) -> sign::PublicKey {
+        self.keys.longterm_pk
+    }
+
+    // Calls `checkpoint` at each `Checkpoint` of the handshake, and fails the
+    // handshake with a `Rejected` error if it returns `false`.
+    pub(crate) fn set_checkpoint(&mut self,
+                                 checkpoint: Box<FnMut(Checkpoint) -> bool + Send + Sync>) {
+        self.inner.set_checkpoint(checkpoint)
+    }
+
+    /// Read and discard a random number of up to `max_discard` bytes before
+    /// failing on an invalid msg1, instead of closing the connection
+    /// immediately. This makes the server harder to fingerprint by scanners.
+    ///
+    /// A peer that sends nothing more holds the handshake in the tarpit
+    /// forever, so only use this on a stream wrapped in a `Deadline`.
+    ///
+    /// Defaults to `0`, which fails immediately.
+    pub fn set_tarpit(&mut self, max_discard: usize) {
+        self.inner.set_tarpit(max_discard)
+    }
+
+    /// Reply to an invalid msg1 with random data shaped like msg2 before
+    /// failing (and tarpitting, if enabled), instead of closing the
+    /// connection without a reply. Scanners then can not tell a server that
+    /// uses another network identifier from a random service.
+    ///
+    /// Defaults to `false`.
+    pub fn set_decoy(&mut self, decoy: bool) {
+        self.inner.set_decoy(decoy)
+    }
+
+    /// Fail with a `SelfConnection` error if the client uses the server's
+    /// own longterm public key.
+    ///
+    /// Defaults to `false`, which allows self connections.
+    pub fn set_reject_self_connection(&mut self, reject: bool) {
+        self.inner.set_reject_self_connection(reject)
+    }
+
+    /// Fail with a `CryptoError` if the client hello is an exact replay of
+    /// one recently seen by the given `cache`, see `replay::ReplayCache`.
+    pub fn set_replay_cache(&mut self, cache: ReplayCache) {
+        self.inner.set_replay_cache(cache)
+    }
+
+    /// Count this handshake and its result in the given `metrics`. The
+    /// duration of the handshake is measured from this call on.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.inner.set_metrics(metrics)
+    }
+
+    /// Record the timing of this handshake in the given `timer`. The
+    /// handshake is timed from this call on.
+    pub fn set_timer(&mut self, timer: HandshakeTimer) {
+        self.inner.set_timer(timer)
+    }
+
+    /// Fail with a `BudgetExceeded` error once the handshake exceeds the
+    /// given `budget`. The time budget starts with this call.
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.inner.set_budget(budget)
+    }
+
+    /// Run the crypto of verifying and creating handshake messages through
+    /// the given `offload`.
+    pub fn set_offload(&mut self, offload: Offload) {
+        self.inner.set_offload(offload)
+    }
+
+    /// Stops the handshake and returns the stream together with the stage
+    /// the handshake was in, or `None` if the handshake already completed or
+    /// failed.
+    pub fn abort(mut self) -> Option<(S, Stage)> {
+        self.inner.abort()
+    }
+
+    /// Returns the stream, dropping the handshaker.
+    ///
+    /// Panics if the handshake already completed or failed, as the stream has
+    /// been returned then.
+    pub fn into_inner(self) -> S {
+        self.abort().map(|(stream, _)| stream).expect(TERMINATED)
+    }
+
+    /// Returns a reference to the stream.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_ref(&self) -> &S {
+        self.inner.get_ref()
+    }
+
+    /// Returns a mutable reference to the stream. Reading from or writing to
+    /// it breaks the handshake.
+    ///
+    /// Panics if the handshake already completed or failed.
+    pub fn get_mut(&mut self) -> &mut S {
+        self.inner.get_mut()
+    }
+
+    /// Also accept clients using any of the `network_identifiers`, e.g. to
+    /// bridge several networks on the same port. The network identifier
+    /// passed to `new` is tried first, the others in order.
+    ///
+    /// The network identifier the client used is reported by
+    /// `Outcome::network_identifier`.
+    pub fn set_alternative_network_identifiers(&mut self,
+                                               network_identifiers: Vec<[u8; NETWORK_IDENTIFIER_BYTES]>) {
+        self.alternative_network_identifiers = network_identifiers;
+        self.inner
+            .set_alternative_network_identifiers(self.alternative_network_identifiers
+                                                     .as_slice());
+    }
+
+    /// Also accept clients addressing any of the `longterm_keypairs`, e.g.
+    /// during key rotation. The longterm keys passed to `new` are tried
+    /// first, the others in order.
+    ///
+    /// The identity the client addressed is reported by
+    /// `Outcome::local_longterm_pk`.
+    pub fn set_alternative_longterm_keypairs(&mut self,
+                                             longterm_keypairs: Vec<(sign::PublicKey,
+                                                                     sign::SecretKey)>) {
+        self.alternative_longterm_keypairs = longterm_keypairs;
+        self.inner
+            .set_alternative_longterm_keypairs(self.alternative_longterm_keypairs.as_slice());
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
@@ -240,21 +781,87 @@ impl<S, FilterFn, AsyncBool> Future for OwningServerHandshakerWithFilter<S, Filt
     }
 }
 
-// Performs the server side of a handshake. Allows filtering clients based on
-// their longterm public key.
+/// Shows the progress of the handshake, but no keys or message data.
+impl<S, FilterFn, AsyncBool> fmt::Debug for OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        self.inner.fmt_as("OwningServerHandshakerWithFilter", f)
+    }
+}
+
+// Performs the server side of a handshake, as an adapter over a
+// `HandshakeState`. Allows filtering clients based on their longterm public
+// key.
 struct UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
     stream: Option<S>,
     filter: Option<FilterStuff<FilterFn, AsyncBool>>,
-    server: Server,
-    state: State,
-    data: [u8; MSG3_BYTES], // used to hold and cache the results of `server.create_server_challenge` and `server.create_server_ack`, and any data read from the client
-    offset: usize, // offset into the data array at which to read/write
+    state: HandshakeState<'static>,
+    phase: Phase,
+    flushing: bool, // whether the last message has been written but not flushed yet
+    received: usize, // bytes completing the current incoming message, not yet verified
+    msg1_accepted: bool, // whether msg1 passed verification, the replay cache and the checkpoint
+    scratch: [u8; MSG2_BYTES], // holds the decoy, and receives the bytes discarded by the tarpit
+    offset: usize, // offset into the decoy at which to write
+    tarpit: usize, // maximum number of bytes to discard after an invalid msg1
+    discard: usize, // number of bytes left to discard before failing
+    decoy: bool, // whether to reply to an invalid msg1 with random data
+    reject_self_connection: bool,
+    replay_cache: Option<ReplayCache>,
+    metrics: Option<(Metrics, Instant)>,
+    timer: Option<HandshakeTimer>,
+    budget: Option<BudgetTracker>,
+    offload: Option<Offload>,
+    verification: Option<Verification>, // of the received message, by the offload
+    checkpoint: Option<Box<FnMut(Checkpoint) -> bool + Send + Sync>>,
 }
 
-// Zero buffered handshake data on dropping.
-impl<S, FilterFn, AsyncBool> Drop for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-    fn drop(&mut self) {
-        memzero(&mut self.data);
+impl<S, FilterFn, AsyncBool> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+    // Formats the progress of the handshake under the name of a public
+    // handshaker type.
+    fn fmt_as(&self, name: &str, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let state: &fmt::Debug = match self.phase {
+            Handshake => self.state.progress(),
+            ref phase => phase,
+        };
+        f.debug_struct(name)
+            .field("state", state)
+            .field("flushing", &self.flushing)
+            .field("filtering", &self.phase.is_filtering())
+            .field("has_stream", &self.stream.is_some())
+            .finish()
+    }
+
+    // Takes the stream, unless the handshake has already terminated.
+    fn abort(&mut self) -> Option<(S, Stage)> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return None,
+        };
+
+        if let Some(ref timer) = self.timer {
+            timer.finish(false);
+        }
+
+        let stage = match self.phase {
+            WriteDecoy | FlushDecoy | Tarpit => Stage::Msg1,
+            FilterClient => Stage::Filtering,
+            // While flushing, the state already waits for the reply to the
+            // flushed message.
+            Handshake => {
+                match (self.flushing, self.state.stage()) {
+                    (true, Stage::Msg3) => Stage::Msg2,
+                    (_, stage) => stage,
+                }
+            }
+        };
+        Some((stream, stage))
+    }
+
+    fn get_ref(&self) -> &S {
+        self.stream.as_ref().expect(TERMINATED)
+    }
+
+    fn get_mut(&mut self) -> &mut S {
+        self.stream.as_mut().expect(TERMINATED)
     }
 }
 
@@ -278,139 +885,340 @@ impl<S, FilterFn, AsyncBool> UnsafeServerHandshakerWithFilter<S, FilterFn, Async
                server_ephemeral_pk: *const box_::PublicKey,
                server_ephemeral_sk: *const box_::SecretKey)
                -> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-        unsafe {
-            UnsafeServerHandshakerWithFilter {
-                stream: Some(stream),
-                filter: Some(FilterFun(filter_fn)),
-                server: Server::new(network_identifier,
-                                    &(*server_longterm_pk).0,
-                                    &(*server_longterm_sk).0,
-                                    &(*server_ephemeral_pk).0,
-                                    &(*server_ephemeral_sk).0),
-                state: ReadMsg1,
-                data: [0; MSG3_BYTES],
-                offset: 0,
-            }
+        UnsafeServerHandshakerWithFilter {
+            stream: Some(stream),
+            filter: Some(FilterFun(filter_fn)),
+            state: unsafe {
+                HandshakeState::server_unchecked(network_identifier,
+                                                 server_longterm_pk,
+                                                 server_longterm_sk,
+                                                 server_ephemeral_pk,
+                                                 server_ephemeral_sk)
+            },
+            phase: Handshake,
+            flushing: false,
+            received: 0,
+            msg1_accepted: false,
+            scratch: [0; MSG2_BYTES],
+            offset: 0,
+            tarpit: 0,
+            discard: 0,
+            decoy: false,
+            reject_self_connection: false,
+            replay_cache: None,
+            metrics: None,
+            timer: None,
+            budget: None,
+            offload: None,
+            verification: None,
+            checkpoint: None,
+        }
+    }
+
+    fn set_tarpit(&mut self, max_discard: usize) {
+        self.tarpit = max_discard;
+    }
+
+    fn set_decoy(&mut self, decoy: bool) {
+        self.decoy = decoy;
+    }
+
+    // Enters the tarpit after an invalid msg1, returns `false` if tarpitting
+    // is disabled.
+    fn enter_tarpit(&mut self) -> bool {
+        if self.tarpit == 0 {
+            return false;
         }
+
+        self.discard = random_below(self.tarpit) + 1;
+        self.phase = Tarpit;
+        true
+    }
+
+    fn set_reject_self_connection(&mut self, reject: bool) {
+        self.reject_self_connection = reject;
+    }
+
+    fn set_replay_cache(&mut self, cache: ReplayCache) {
+        self.replay_cache = Some(cache);
+    }
+
+    // Returns whether the verified msg1 has not been seen recently by the
+    // replay cache, if any.
+    fn is_fresh_msg1(&self) -> bool {
+        match (self.replay_cache.as_ref(), self.state.client_ephemeral_pk()) {
+            (Some(cache), Some(client_ephemeral_pk)) => cache.observe(&client_ephemeral_pk.0),
+            _ => true,
+        }
+    }
+
+    fn set_metrics(&mut self, metrics: Metrics) {
+        metrics.record_start();
+        self.metrics = Some((metrics, Instant::now()));
+    }
+
+    fn set_timer(&mut self, timer: HandshakeTimer) {
+        timer.start();
+        self.timer = Some(timer);
+    }
+
+    fn set_budget(&mut self, budget: Budget) {
+        self.budget = Some(BudgetTracker::new(budget));
+    }
+
+    fn set_offload(&mut self, offload: Offload) {
+        self.offload = Some(offload);
+    }
+
+    fn set_checkpoint(&mut self, checkpoint: Box<FnMut(Checkpoint) -> bool + Send + Sync>) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    // Returns whether the handshake may continue past the given checkpoint.
+    fn pass(&mut self, checkpoint: Checkpoint) -> bool {
+        match self.checkpoint {
+            Some(ref mut f) => f(checkpoint),
+            None => true,
+        }
+    }
+
+    // Records that the current message has been completely read or written.
+    fn message_completed(&self) {
+        if let Some(ref timer) = self.timer {
+            timer.message_completed();
+        }
+    }
+
+    // Records a read that returned data, and returns whether the handshake
+    // is still within its budget.
+    fn record_read(&mut self) -> bool {
+        match self.budget {
+            Some(ref mut budget) => budget.record_read(),
+            None => true,
+        }
+    }
+
+    // Records that the current message has been completely read.
+    fn message_received(&mut self) {
+        self.message_completed();
+        if let Some(ref mut budget) = self.budget {
+            budget.message_received();
+        }
+    }
+
+    fn has_time_left(&self) -> bool {
+        match self.budget {
+            Some(ref budget) => budget.has_time_left(),
+            None => true,
+        }
+    }
+
+    fn client_longterm_pk(&self) -> Option<sign::PublicKey> {
+        self.state.client_longterm_pk()
+    }
+
+    fn set_alternative_network_identifiers(&mut self,
+                                           network_identifiers: *const [[u8; NETWORK_IDENTIFIER_BYTES]]) {
+        self.state
+            .set_alternative_network_identifiers(unsafe { &*network_identifiers })
+    }
+
+    fn set_alternative_longterm_keypairs(&mut self,
+                                         longterm_keypairs: *const [(sign::PublicKey,
+                                                                     sign::SecretKey)]) {
+        self.state
+            .set_alternative_longterm_keypairs(unsafe { &*longterm_keypairs })
     }
 }
 
-/// Future implementation to asynchronously drive a handshake.
-impl<S, FilterFn, AsyncBool> Future for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>
+impl<S, FilterFn, AsyncBool> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>
     where S: AsyncRead + AsyncWrite,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
           AsyncBool: Future<Item = bool>
 {
-    type Item = (Outcome, S);
-    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+    // Drives the handshake state machine.
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<(Outcome, S), (FilteringHandshakeError<AsyncBool::Error>, S)> {
+        loop {
+            let mut stream = match self.stream.take() {
+                Some(stream) => stream,
+                // The handshake has already completed, stay in that terminal state.
+                None => return Ok(Pending),
+            };
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        let mut stream = self.stream
-            .take()
-            .expect("Polled ServerHandshaker after completion");
-
-        match self.state {
-            ReadMsg1 => {
-                while self.offset < MSG1_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG1_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((io::Error::new(UnexpectedEof, "failed to read msg1")
-                                                .into(),
-                                            stream));
+            if !self.has_time_left() {
+                return Err((FilteringHandshakeError::BudgetExceeded, stream));
+            }
+
+            match self.phase {
+                Handshake => {}
+
+                Tarpit => {
+                    while self.discard > 0 {
+                        let len = if self.discard < MSG2_BYTES {
+                            self.discard
+                        } else {
+                            MSG2_BYTES
+                        };
+
+                        match stream.poll_read(cx, &mut self.scratch[..len]) {
+                            Ok(Ready(read)) => {
+                                if read == 0 || !self.record_read() {
+                                    break;
+                                }
+                                self.discard -= read;
                             }
-                            self.offset += read;
+                            Ok(Pending) => {
+                                self.stream = Some(stream);
+                                return Ok(Pending);
+                            }
+                            Err(_) => break,
                         }
+                    }
+
+                    return Err((FilteringHandshakeError::CryptoError, stream));
+                }
+
+                // Failures while sending the decoy are reported as the
+                // invalid msg1 that caused it.
+                WriteDecoy => {
+                    while self.offset < MSG2_BYTES {
+                        match stream.poll_write(cx, &self.scratch[self.offset..]) {
+                            Ok(Ready(written)) if written > 0 => self.offset += written,
+                            Ok(Pending) => {
+                                self.stream = Some(stream);
+                                return Ok(Pending);
+                            }
+                            Ok(Ready(_)) | Err(_) => {
+                                return Err((FilteringHandshakeError::CryptoError, stream))
+                            }
+                        }
+                    }
+
+                    self.stream = Some(stream);
+                    self.phase = FlushDecoy;
+                    continue;
+                }
+
+                FlushDecoy => {
+                    match stream.poll_flush(cx) {
+                        Ok(Ready(())) => {}
                         Ok(Pending) => {
                             self.stream = Some(stream);
                             return Ok(Pending);
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        Err(_) => return Err((FilteringHandshakeError::CryptoError, stream)),
                     }
-                }
 
-                if !self.server
-                        .verify_msg1(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG1_BYTES])
-                                     }) {
-                    return Err((FilteringHandshakeError::CryptoError, stream));
+                    if !self.enter_tarpit() {
+                        return Err((FilteringHandshakeError::CryptoError, stream));
+                    }
+                    self.stream = Some(stream);
+                    continue;
                 }
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = WriteMsg2;
-                self.server
-                    .create_msg2(unsafe {
-                                     &mut *(&mut self.data as *mut [u8; MSG3_BYTES] as
-                                            *mut [u8; MSG2_BYTES])
-                                 });
-                return self.poll(cx);
-            }
+                FilterClient => {
+                    let mut filter_future =
+                        match self.filter
+                                  .take()
+                                  .expect("Attempted to poll ServerHandshaker after completion") {
+                            FilterFun(_) => unreachable!(),
+                            FilterFuture(f) => f,
+                        };
 
-            WriteMsg2 => {
-                while self.offset < MSG2_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG2_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((io::Error::new(WriteZero, "failed to write msg2")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += written;
-                        }
+                    match filter_future.poll(cx) {
+                        Err(err) => return Err((FilteringHandshakeError::FilterError(err), stream)),
                         Ok(Pending) => {
+                            self.filter = Some(FilterFuture(filter_future));
                             self.stream = Some(stream);
                             return Ok(Pending);
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        Ok(Ready(is_authorized)) => {
+                            let client_longterm_pk = self.state
+                                .client_longterm_pk()
+                                .expect("filtering a client before verifying msg3");
+                            trace::filter_decision(Side::Server,
+                                                   &client_longterm_pk,
+                                                   is_authorized);
+                            if !is_authorized {
+                                return Err((FilteringHandshakeError::Rejected, stream));
+                            }
+
+                            // The state holds msg4 already.
+                            self.stream = Some(stream);
+                            self.phase = Handshake;
+                            continue;
+                        }
                     }
                 }
-
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg2;
-                return self.poll(cx);
             }
 
-            FlushMsg2 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
-                    Ok(Pending) => {
+            if self.received > 0 {
+                let received = self.received;
+                let stage = self.state.stage();
+                let verified = match offload::poll_advance_read(cx,
+                                                                &mut self.state,
+                                                                received,
+                                                                self.offload.as_ref(),
+                                                                &mut self.verification) {
+                    Ready(Ok(())) => true,
+                    Ready(Err(HandshakeError::IoError(e))) => {
+                        return Err((FilteringHandshakeError::IoError(e), stream))
+                    }
+                    Ready(Err(_)) => false,
+                    Pending => {
                         self.stream = Some(stream);
                         return Ok(Pending);
                     }
-                    Err(e) => return Err((e.into(), stream)),
-                }
-
-                self.stream = Some(stream);
-                self.state = ReadMsg3;
-                return self.poll(cx);
-            }
+                };
+                self.received = 0;
 
-            ReadMsg3 => {
-                while self.offset < MSG3_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG3_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((io::Error::new(UnexpectedEof, "failed to read msg3")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += read;
-                        }
-                        Ok(Pending) => {
+                if stage == Stage::Msg1 {
+                    if !verified {
+                        if self.decoy {
+                            randombytes_into(&mut self.scratch);
                             self.stream = Some(stream);
-                            return Ok(Pending);
+                            self.offset = 0;
+                            self.phase = WriteDecoy;
+                            continue;
+                        }
+
+                        if !self.enter_tarpit() {
+                            return Err((FilteringHandshakeError::CryptoError, stream));
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        self.stream = Some(stream);
+                        continue;
+                    }
+
+                    if !self.is_fresh_msg1() {
+                        return Err((FilteringHandshakeError::CryptoError, stream));
                     }
+
+                    if !self.pass(Checkpoint::Msg1Verified) {
+                        return Err((FilteringHandshakeError::Rejected, stream));
+                    }
+
+                    self.msg1_accepted = true;
+                    self.stream = Some(stream);
+                    continue;
                 }
 
-                if !self.server.verify_msg3(&self.data) {
+                if !verified {
                     return Err((FilteringHandshakeError::CryptoError, stream));
                 }
 
+                if self.reject_self_connection && self.state.accepts_self() {
+                    return Err((FilteringHandshakeError::SelfConnection, stream));
+                }
+
+                let client_longterm_pk = self.state
+                    .client_longterm_pk()
+                    .expect("verified msg3 without a client key");
+                if !self.pass(Checkpoint::ClientKeyRevealed(&client_longterm_pk)) {
+                    return Err((FilteringHandshakeError::Rejected, stream));
+                }
+
                 let filter_fn =
                     match self.filter
                               .take()
@@ -419,79 +1227,70 @@ impl<S, FilterFn, AsyncBool> Future for UnsafeServerHandshakerWithFilter<S, Filt
                         FilterFuture(_) => unreachable!(),
                     };
 
-                self.filter =
-                    Some(FilterFuture(filter_fn(&sign::PublicKey(unsafe {
-                                                 self.server.client_longterm_pub()
-                                             }))));
-
+                self.filter = Some(FilterFuture(filter_fn(&client_longterm_pk)));
                 self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FilterClient;
-                return self.poll(cx);
+                self.phase = FilterClient;
+                continue;
             }
 
-            FilterClient => {
-                let mut filter_future =
-                    match self.filter
-                              .take()
-                              .expect("Attempted to poll ServerHandshaker after completion") {
-                        FilterFun(_) => unreachable!(),
-                        FilterFuture(f) => f,
-                    };
-
-                match filter_future.poll(cx) {
-                    Err(err) => return Err((FilteringHandshakeError::FilterError(err), stream)),
+            if self.flushing {
+                match stream.poll_flush(cx) {
+                    Ok(Ready(())) => {}
                     Ok(Pending) => {
-                        self.filter = Some(FilterFuture(filter_future));
                         self.stream = Some(stream);
                         return Ok(Pending);
                     }
-                    Ok(Ready(is_authorized)) => {
-                        if !is_authorized {
-                            return Err((FilteringHandshakeError::Rejected, stream));
-                        }
-
-                        self.stream = Some(stream);
-                        self.state = WriteMsg4;
-                        self.server
-                            .create_msg4(unsafe {
-                                             &mut *(&mut self.data as *mut [u8; MSG3_BYTES] as
-                                                    *mut [u8; MSG4_BYTES])
-                                         });
-
-                        return self.poll(cx);
-                    }
+                    Err(e) => return Err((e.into(), stream)),
                 }
+
+                self.stream = Some(stream);
+                self.flushing = false;
+                self.message_completed();
+                continue;
             }
 
-            WriteMsg4 => {
-                while self.offset < MSG4_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG4_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((io::Error::new(WriteZero, "failed to write msg4")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += written;
+            if self.state.wants_write() > 0 {
+                match stream.poll_write(cx, self.state.outgoing()) {
+                    Ok(Ready(written)) => {
+                        if written == 0 {
+                            let err = io::Error::new(WriteZero,
+                                                     "failed to write handshake message");
+                            return Err((err.into(), stream));
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
+                        self.state.advance_write(written);
+                        self.flushing = self.state.wants_write() == 0;
                     }
+                    Ok(Pending) => {
+                        self.stream = Some(stream);
+                        return Ok(Pending);
+                    }
+                    Err(e) => return Err((e.into(), stream)),
                 }
 
                 self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg4;
-                return self.poll(cx);
+                continue;
             }
 
-            FlushMsg4 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
+            if self.state.wants_read() > 0 {
+                match stream.poll_read(cx, self.state.incoming()) {
+                    Ok(Ready(read)) => {
+                        if read == 0 {
+                            let err = io::Error::new(UnexpectedEof,
+                                                     "failed to read handshake message");
+                            return Err((err.into(), stream));
+                        }
+                        if !self.record_read() {
+                            return Err((FilteringHandshakeError::BudgetExceeded, stream));
+                        }
+                        // Completing a message is verified on the next
+                        // iteration, via the offload.
+                        if read == self.state.wants_read() {
+                            self.received = read;
+                            self.message_received();
+                        } else if self.state.advance_read(read).is_err() {
+                            return Err((FilteringHandshakeError::CryptoError, stream));
+                        }
+                    }
                     Ok(Pending) => {
                         self.stream = Some(stream);
                         return Ok(Pending);
@@ -499,11 +1298,51 @@ impl<S, FilterFn, AsyncBool> Future for UnsafeServerHandshakerWithFilter<S, Filt
                     Err(e) => return Err((e.into(), stream)),
                 }
 
-                let mut outcome = unsafe { uninitialized() };
-                self.server.outcome(&mut outcome);
-                return Ok(Ready((outcome, stream)));
+                self.stream = Some(stream);
+                continue;
+            }
+
+            let outcome = self.state
+                .outcome()
+                .expect("server handshake state neither reads, writes nor is done");
+            return Ok(Ready((outcome, stream)));
+        }
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S, FilterFn, AsyncBool> Future for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: Future<Item = bool>
+{
+    type Item = (Outcome, S);
+    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_handshake(cx);
+
+        if let Err((ref err, _)) = result {
+            trace::failed(Side::Server, &Reason(err));
+        }
+
+        if let Some(ref timer) = self.timer {
+            match result {
+                Ok(Pending) => {}
+                Ok(Ready(_)) => timer.finish(true),
+                Err(_) => timer.finish(false),
             }
         }
+
+        if let Some((ref metrics, started)) = self.metrics {
+            match result {
+                Ok(Pending) => {}
+                Ok(Ready(_)) => metrics.record_success(started.elapsed()),
+                Err((ref err, _)) => metrics.record_failure(err, !self.msg1_accepted),
+            }
+        }
+
+        result
     }
 }
 
@@ -550,17 +1389,49 @@ impl<FilterErr: error::Error> error::Error for ServerHandshakeError<FilterErr> {
     }
 }
 
-// State for the future state machine.
-enum State {
-    ReadMsg1,
-    WriteMsg2,
-    FlushMsg2,
-    ReadMsg3,
+// Returns a uniformly random number in `0..upper_bound`.
+pub(crate) fn random_below(upper_bound: usize) -> usize {
+    let upper_bound = upper_bound as u64;
+    // The random numbers below `2^64 % upper_bound` would make the
+    // remainders up to that value more likely, so they are rejected.
+    let threshold = upper_bound.wrapping_neg() % upper_bound;
+    let mut bytes = [0u8; 8];
+    loop {
+        randombytes_into(&mut bytes);
+        let random = bytes.iter().fold(0u64, |acc, byte| (acc << 8) | (*byte as u64));
+        if random >= threshold {
+            return (random % upper_bound) as usize;
+        }
+    }
+}
+
+// A point of a server handshake at which an acceptor's admission controller
+// or rate limiter may end it.
+pub(crate) enum Checkpoint<'a> {
+    Msg1Verified,
+    ClientKeyRevealed(&'a sign::PublicKey),
+}
+
+// What a server handshaker does besides exchanging the messages of its
+// `HandshakeState`.
+#[derive(Debug)]
+enum Phase {
+    Handshake,
+    WriteDecoy,
+    FlushDecoy,
+    Tarpit,
     FilterClient,
-    WriteMsg4,
-    FlushMsg4,
 }
-use server::State::*;
+use server::Phase::*;
+
+impl Phase {
+    fn is_filtering(&self) -> bool {
+        match *self {
+            FilterClient => true,
+            _ => false,
+        }
+    }
+}
 
 enum FilterStuff<FilterFn, AsyncBool> {
     FilterFun(FilterFn),
@@ -0,0 +1,74 @@
+//! A service-style interface for accepting handshakes.
+//!
+//! A [`HandshakeService`](struct.HandshakeService.html) turns accepted io
+//! objects into futures resolving to authenticated connections, following the
+//! `poll_ready`/`call` shape of `tower::Service`. This allows slotting the
+//! handshake into middleware stacks built around that interface.
+//!
+//! The `tower` crate itself is built on futures 0.1, whereas this crate uses
+//! futures 0.2, so `HandshakeService` can not implement `tower::Service`
+//! directly. Stacks based on futures 0.1 need to wrap it in a compatibility
+//! shim of their executor.
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_core::{Poll, Future, Never};
+use futures_core::Async::Ready;
+use futures_core::task::Context;
+use futures_core::future::FutureResult;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use identity::Identity;
+use server::{OwningServerHandshakerWithFilter, const_async_true};
+
+/// Performs the server side of a handshake on each io object it is called
+/// with.
+#[derive(Clone)]
+pub struct HandshakeService<FilterFn> {
+    identity: Identity,
+    filter_fn: FilterFn,
+}
+
+impl HandshakeService<fn(&sign::PublicKey) -> FutureResult<bool, Never>> {
+    /// Creates a new `HandshakeService`, accepting any client that uses the
+    /// right network identifier and knows the server's longterm public key.
+    pub fn new(identity: Identity) -> Self {
+        HandshakeService::with_filter(identity, const_async_true)
+    }
+}
+
+impl<FilterFn> HandshakeService<FilterFn> {
+    /// Creates a new `HandshakeService`, accepting clients that use the
+    /// right network identifier, know the server's longterm public key, and
+    /// pass the `filter_fn` (see `ServerHandshakerWithFilter`).
+    pub fn with_filter(identity: Identity, filter_fn: FilterFn) -> Self {
+        HandshakeService {
+            identity,
+            filter_fn,
+        }
+    }
+
+    /// Returns whether the service is ready to accept a new io object, which
+    /// it always is.
+    pub fn poll_ready(&mut self, _cx: &mut Context) -> Poll<(), Never> {
+        Ok(Ready(()))
+    }
+
+    /// Returns a future that performs the server side of a handshake over
+    /// `io`, using fresh ephemeral keys.
+    pub fn call<S, AsyncBool>(&mut self,
+                              io: S)
+                              -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>
+        where S: AsyncRead + AsyncWrite,
+              FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool + Clone,
+              AsyncBool: Future<Item = bool>
+    {
+        let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+        OwningServerHandshakerWithFilter::new(io,
+                                              self.filter_fn.clone(),
+                                              *self.identity.network_identifier(),
+                                              self.identity.longterm_pk().clone(),
+                                              self.identity.longterm_sk().clone(),
+                                              ephemeral_pk,
+                                              ephemeral_sk)
+    }
+}
@@ -0,0 +1,281 @@
+//! Remember recent sessions per peer, and deduplicate simultaneous dials.
+//!
+//! A `SessionCache` maps longterm public keys of peers to the outcomes of
+//! recent handshakes with them. Before dialing a peer, call
+//! `SessionCache::begin`: it returns the cached session if there is a fresh
+//! one, a future for the result of a handshake with that peer that is
+//! already under way, or a `HandshakeSlot` that the caller fills with the
+//! outcome of its own handshake. Applications decide for themselves whether
+//! to reuse a session, to wait for the in-flight handshake, or to perform a
+//! redundant one anyway.
+//!
+//! Sessions are shared as `Arc<Outcome>`, the keys are zeroed once the last
+//! reference to a session is dropped. Note that sharing an outcome means
+//! sharing its nonces: only one connection should ever use a session's keys.
+//!
+//! The cache is a cheap-to-clone handle around shared state.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+use futures_core::{Future, Poll, Never};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::{Context, Waker};
+
+use crypto::Outcome;
+
+/// Shared, thread-safe handle to a set of recent sessions.
+#[derive(Clone)]
+pub struct SessionCache(Arc<Mutex<Inner>>);
+
+struct Inner {
+    capacity: usize,
+    ttl: Duration,
+    sessions: HashMap<[u8; sign::PUBLICKEYBYTES], Entry>,
+    dials: HashMap<[u8; sign::PUBLICKEYBYTES], Arc<Mutex<Dial>>>,
+}
+
+struct Entry {
+    outcome: Arc<Outcome>,
+    established: Instant,
+}
+
+impl Entry {
+    fn is_fresh(&self, now: Instant, ttl: Duration) -> bool {
+        now.duration_since(self.established) < ttl
+    }
+}
+
+// A handshake that is currently under way.
+struct Dial {
+    // `Some` once the handshake completed, `Some(None)` if it failed.
+    result: Option<Option<Arc<Outcome>>>,
+    waiters: Vec<Waker>,
+}
+
+impl Dial {
+    fn finish(&mut self, outcome: Option<Arc<Outcome>>) {
+        self.result = Some(outcome);
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// What to do about a handshake with some peer, see `SessionCache::begin`.
+pub enum Begin {
+    /// There is a fresh session with the peer.
+    Cached(Arc<Outcome>),
+    /// A handshake with the peer is already under way, the future resolves
+    /// to its result.
+    InProgress(PendingSession),
+    /// There is neither a session nor a handshake in progress. The caller
+    /// should perform the handshake and report its outcome to the slot.
+    Start(HandshakeSlot),
+}
+
+impl Inner {
+    fn get(&mut self, key: &[u8; sign::PUBLICKEYBYTES]) -> Option<Arc<Outcome>> {
+        let now = Instant::now();
+        let ttl = self.ttl;
+
+        let fresh = match self.sessions.get(key) {
+            Some(entry) => entry.is_fresh(now, ttl),
+            None => return None,
+        };
+
+        if fresh {
+            self.sessions.get(key).map(|entry| entry.outcome.clone())
+        } else {
+            self.sessions.remove(key);
+            None
+        }
+    }
+
+    fn insert(&mut self, key: [u8; sign::PUBLICKEYBYTES], outcome: Arc<Outcome>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if !self.sessions.contains_key(&key) && self.sessions.len() >= self.capacity {
+            let oldest = self.sessions
+                .iter()
+                .min_by_key(|&(_, entry)| entry.established)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+
+        self.sessions
+            .insert(key,
+                    Entry {
+                        outcome,
+                        established: Instant::now(),
+                    });
+    }
+}
+
+impl SessionCache {
+    /// Creates a new, empty `SessionCache` which holds up to `capacity`
+    /// sessions, each for at most `ttl`. When the cache is full, the oldest
+    /// session is evicted.
+    pub fn new(capacity: usize, ttl: Duration) -> SessionCache {
+        SessionCache(Arc::new(Mutex::new(Inner {
+                                             capacity,
+                                             ttl,
+                                             sessions: HashMap::new(),
+                                             dials: HashMap::new(),
+                                         })))
+    }
+
+    /// Returns the session with the given peer, if there is a fresh one.
+    pub fn get(&self, peer: &sign::PublicKey) -> Option<Arc<Outcome>> {
+        self.0.lock().unwrap().get(&peer.0)
+    }
+
+    /// Stores the outcome of a handshake, keyed by the longterm public key of
+    /// the peer, replacing any previous session with that peer.
+    pub fn insert(&self, outcome: Outcome) -> Arc<Outcome> {
+        let key = outcome.peer_longterm_pk().0;
+        let outcome = Arc::new(outcome);
+        self.0.lock().unwrap().insert(key, outcome.clone());
+        outcome
+    }
+
+    /// Forgets the session with the given peer, e.g. after its connection
+    /// broke.
+    pub fn remove(&self, peer: &sign::PublicKey) {
+        self.0.lock().unwrap().sessions.remove(&peer.0);
+    }
+
+    /// Looks up the session with the given peer, or the handshake with it
+    /// that is currently under way. If there is neither, the caller is
+    /// registered as performing the handshake, and later calls return
+    /// `Begin::InProgress` until the returned slot is completed or dropped.
+    pub fn begin(&self, peer: &sign::PublicKey) -> Begin {
+        let mut inner = self.0.lock().unwrap();
+
+        if let Some(outcome) = inner.get(&peer.0) {
+            return Begin::Cached(outcome);
+        }
+
+        if let Some(dial) = inner.dials.get(&peer.0) {
+            return Begin::InProgress(PendingSession(dial.clone()));
+        }
+
+        let dial = Arc::new(Mutex::new(Dial {
+                                           result: None,
+                                           waiters: Vec::new(),
+                                       }));
+        inner.dials.insert(peer.0, dial.clone());
+
+        Begin::Start(HandshakeSlot {
+                         cache: self.clone(),
+                         peer: peer.0,
+                         dial,
+                         finished: false,
+                     })
+    }
+
+    /// Returns the number of cached sessions, including expired ones that
+    /// have not been pruned yet.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().sessions.len()
+    }
+
+    /// Returns whether no sessions are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets all expired sessions.
+    ///
+    /// Expired sessions are also removed lazily when looked up, so calling
+    /// this is only needed to release their keys early.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        let ttl = inner.ttl;
+        inner.sessions.retain(|_, entry| entry.is_fresh(now, ttl));
+    }
+}
+
+/// The right to perform the handshake with a peer, obtained from
+/// `SessionCache::begin`.
+///
+/// Dropping the slot without completing it marks the handshake as failed:
+/// pending sessions resolve to `None`, and the next call to `begin` returns a
+/// new slot.
+pub struct HandshakeSlot {
+    cache: SessionCache,
+    peer: [u8; sign::PUBLICKEYBYTES],
+    dial: Arc<Mutex<Dial>>,
+    finished: bool,
+}
+
+impl HandshakeSlot {
+    /// The longterm public key of the peer this slot is for.
+    pub fn peer(&self) -> sign::PublicKey {
+        sign::PublicKey(self.peer)
+    }
+
+    /// Stores the outcome of the handshake in the cache, and hands it to all
+    /// pending sessions waiting for it.
+    ///
+    /// Panics if the outcome is not that of a handshake with the peer of this
+    /// slot, the pending sessions then resolve to `None`.
+    pub fn complete(mut self, outcome: Outcome) -> Arc<Outcome> {
+        assert!(outcome.peer_longterm_pk() == self.peer(),
+                "completed a handshake slot with the outcome of another peer");
+        let outcome = Arc::new(outcome);
+        self.finish(Some(outcome.clone()));
+        outcome
+    }
+
+    fn finish(&mut self, outcome: Option<Arc<Outcome>>) {
+        self.finished = true;
+        {
+            let mut inner = self.cache.0.lock().unwrap();
+            inner.dials.remove(&self.peer);
+            if let Some(ref outcome) = outcome {
+                inner.insert(self.peer, outcome.clone());
+            }
+        }
+        self.dial.lock().unwrap().finish(outcome);
+    }
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish(None);
+        }
+    }
+}
+
+/// Future for the result of a handshake performed by someone else, see
+/// `Begin::InProgress`.
+///
+/// Resolves to the session, or to `None` if the handshake failed.
+pub struct PendingSession(Arc<Mutex<Dial>>);
+
+impl Future for PendingSession {
+    type Item = Option<Arc<Outcome>>;
+    type Error = Never;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let mut dial = self.0.lock().unwrap();
+        if let Some(ref outcome) = dial.result {
+            return Ok(Ready(outcome.clone()));
+        }
+
+        // Polling again from the same task must not register it again.
+        if !dial.waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+            dial.waiters.push(cx.waker().clone());
+        }
+        Ok(Pending)
+    }
+}
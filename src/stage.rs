@@ -0,0 +1,33 @@
+//! How far an aborted handshake got.
+//!
+//! All handshakers have an `abort` method, which stops the handshake and
+//! gives back the stream, e.g. to close it politely once the user cancelled
+//! the connection attempt. Together with the stream, it reports the `Stage`
+//! the handshake was in. Parts of the message of that stage may already have
+//! been written or read.
+//!
+//! ```rust,ignore
+//! match handshaker.abort() {
+//!     Some((stream, Stage::Msg1)) => close_quietly(stream),
+//!     Some((stream, _)) => close_with_goodbye(stream),
+//!     None => {} // the handshake already completed or failed
+//! }
+//! ```
+
+/// The message a handshake was exchanging, or whether it was filtering the
+/// peer, when it was aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Exchanging msg1, the client's hello. For servers, this includes
+    /// sending a decoy and discarding data after an invalid msg1.
+    Msg1,
+    /// Exchanging msg2, the server's hello.
+    Msg2,
+    /// Exchanging msg3, the client's authentication.
+    Msg3,
+    /// Exchanging msg4, the server's acknowledgement.
+    Msg4,
+    /// All messages the handshaker has to read were read, and the filter
+    /// function decides whether to accept the peer.
+    Filtering,
+}
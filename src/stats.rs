@@ -0,0 +1,102 @@
+//! Timing information about individual handshakes.
+//!
+//! Attach a `HandshakeTimer` to a handshaker via its `set_timer` method.
+//! While the handshake runs and after it completed or failed,
+//! `HandshakeTimer::stats` tells when it started, when each of its messages
+//! completed, and how long it took in total. Peers that stall between
+//! messages, e.g. to tie up server resources, show up as large gaps.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared, thread-safe handle to the timing of a single handshake.
+#[derive(Clone)]
+pub struct HandshakeTimer(Arc<Mutex<HandshakeStats>>);
+
+/// The timing of a handshake at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeStats {
+    /// When the handshake started.
+    pub started: Instant,
+    /// When each of the four messages was completely written (and flushed)
+    /// or read, `None` for messages that have not completed yet.
+    pub messages: [Option<Instant>; 4],
+    /// When the handshake completed or failed, or `None` if it is still
+    /// running.
+    pub finished: Option<Instant>,
+    /// Whether the handshake completed successfully.
+    pub succeeded: bool,
+}
+
+impl HandshakeStats {
+    /// The total duration of the handshake, or `None` if it is still running.
+    pub fn duration(&self) -> Option<Duration> {
+        self.finished.map(|finished| finished.duration_since(self.started))
+    }
+
+    /// The time since the handshake started, up to when it finished.
+    pub fn elapsed(&self) -> Duration {
+        match self.finished {
+            Some(finished) => finished.duration_since(self.started),
+            None => self.started.elapsed(),
+        }
+    }
+
+    /// The time from the start of the handshake until message `msg` (1 to
+    /// 4) completed, or `None` if it has not completed.
+    pub fn until_message(&self, msg: usize) -> Option<Duration> {
+        assert!(msg >= 1 && msg <= 4, "there are only four handshake messages");
+        self.messages[msg - 1].map(|completed| completed.duration_since(self.started))
+    }
+
+    /// The number of messages that have completed.
+    pub fn completed_messages(&self) -> usize {
+        self.messages.iter().filter(|msg| msg.is_some()).count()
+    }
+}
+
+impl HandshakeTimer {
+    /// Creates a new `HandshakeTimer`. The handshake is timed from when the
+    /// timer is attached to a handshaker.
+    pub fn new() -> HandshakeTimer {
+        HandshakeTimer(Arc::new(Mutex::new(HandshakeStats {
+                                               started: Instant::now(),
+                                               messages: [None; 4],
+                                               finished: None,
+                                               succeeded: false,
+                                           })))
+    }
+
+    /// Returns the current timing of the handshake.
+    pub fn stats(&self) -> HandshakeStats {
+        *self.0.lock().unwrap()
+    }
+
+    pub(crate) fn start(&self) {
+        let mut stats = self.0.lock().unwrap();
+        stats.started = Instant::now();
+        stats.messages = [None; 4];
+        stats.finished = None;
+        stats.succeeded = false;
+    }
+
+    // Messages complete in order, so this fills the first empty slot.
+    pub(crate) fn message_completed(&self) {
+        let mut stats = self.0.lock().unwrap();
+        if let Some(slot) = stats.messages.iter_mut().find(|msg| msg.is_none()) {
+            *slot = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn finish(&self, succeeded: bool) {
+        let mut stats = self.0.lock().unwrap();
+        stats.finished = Some(Instant::now());
+        stats.succeeded = succeeded;
+    }
+}
+
+impl Default for HandshakeTimer {
+    fn default() -> HandshakeTimer {
+        HandshakeTimer::new()
+    }
+}
@@ -87,6 +87,52 @@ static SERVER_MSGS: [u8; MSG2_BYTES + MSG4_BYTES] = [
     72,114,92,105,109,48,17,14,25,150,242,50,148,70,49,25,222,254,255,124,194,144,84,114,190,148,252,189,159,132,157,173,92,14,247,198,87,232,141,83,84,79,226,43,194,95,14,8,138,233,96,40,126,153,205,36,95,203,200,202,221,118,126,99,47,216,209,219,3,133,240,216,166,182,182,226,215,116,177,66 // end msg4
 ];
 
+// Polls `future` once, and returns whether it is pending. Used to check that
+// futures stay pending when polled after they completed.
+fn is_pending<F: Future>(future: &mut F) -> bool {
+    use futures::future::poll_fn;
+
+    block_on(poll_fn(|cx| {
+                         let pending = match future.poll(cx) {
+                             Ok(Async::Pending) => true,
+                             _ => false,
+                         };
+                         Ok::<_, ()>(Async::Ready(pending))
+                     }))
+            .unwrap()
+}
+
+// Performs a successful handshake, and one that the server fails as the
+// client uses the wrong network identifier. Used to check the events emitted
+// about handshakes.
+#[cfg(any(feature = "tracing", feature = "log"))]
+fn traced_handshakes() {
+    for app in [APP, [0; auth::KEYBYTES]].iter() {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           app,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+        let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+        // Drop the streams of failed handshakes, so that the peer sees the
+        // connection closing.
+        let client = client.map(|_| ()).map_err(|(err, _)| err);
+        let server = server.map(|_| ()).map_err(|(err, _)| err);
+        let _ = block_on(client.then(|r| ok::<_, ()>(r)).join(server.then(|r| ok::<_, ()>(r))));
+    }
+}
+
 #[test]
 // A client and a server can perform a handshake.
 fn success() {
@@ -118,18 +164,4158 @@ fn success() {
 
     let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
 
-    assert_eq!(client_outcome.encryption_key(),
-               server_outcome.decryption_key());
-    assert_eq!(client_outcome.encryption_nonce(),
-               server_outcome.decryption_nonce());
-    assert_eq!(client_outcome.decryption_key(),
-               server_outcome.encryption_key());
-    assert_eq!(client_outcome.decryption_nonce(),
-               server_outcome.encryption_nonce());
+    assert_eq!(client_outcome.send().key,
+               server_outcome.recv().key);
+    assert_eq!(client_outcome.send().nonce,
+               server_outcome.recv().nonce);
+    assert_eq!(client_outcome.recv().key,
+               server_outcome.send().key);
+    assert_eq!(client_outcome.recv().nonce,
+               server_outcome.send().nonce);
 
     assert_eq!(client_outcome.peer_longterm_pk(), server_longterm_pk);
     assert_eq!(server_outcome.peer_longterm_pk(), client_longterm_pk);
 }
+
+#[test]
+// A rate limiter refuses an address and key after too many failures.
+fn rate_limiter_bans_repeated_failures() {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use rate_limit::RateLimiter;
+
+    let limiter = RateLimiter::new(2, Duration::from_secs(60), Duration::from_secs(60));
+    let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    limiter.record_failure(addr, Some(&CLIENT_PUB));
+    assert!(limiter.is_addr_allowed(&addr));
+    assert!(limiter.is_key_allowed(&CLIENT_PUB));
+
+    limiter.record_failure(addr, Some(&CLIENT_PUB));
+    assert!(!limiter.is_addr_allowed(&addr));
+    assert!(!limiter.is_key_allowed(&CLIENT_PUB));
+    assert!(limiter.is_key_allowed(&SERVER_PUB));
+}
+
+#[test]
+// A rate limiter does not track new sources while its tables are full of
+// records that are not stale.
+fn rate_limiter_max_records() {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use rate_limit::RateLimiter;
+
+    let limiter = RateLimiter::new(1, Duration::from_secs(60), Duration::from_secs(60));
+    limiter.set_max_records(1);
+    let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    limiter.record_failure(first, Some(&CLIENT_PUB));
+    limiter.record_failure(second, Some(&SERVER_PUB));
+    assert!(!limiter.is_addr_allowed(&first));
+    assert!(!limiter.is_key_allowed(&CLIENT_PUB));
+    assert!(limiter.is_addr_allowed(&second));
+    assert!(limiter.is_key_allowed(&SERVER_PUB));
+}
+
+#[test]
+// A server fails with a TimedOut io error once its deadline elapsed.
+fn deadline_elapsed() {
+    let (_writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, _reader_b) = ring_buffer(2);
+
+    let server_duplex = Deadline::new(Duplex::new(reader_a, writer_b), ok::<(), ()>(()));
+
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    match block_on(server).err().unwrap().0 {
+        errors::HandshakeError::IoError(e) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        _ => panic!("expected a timeout"),
+    }
+}
+
+#[test]
+// A server accepts clients using one of its alternative network identifiers.
+fn alternative_network_identifier() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let other_app = [42; NETWORK_IDENTIFIER_BYTES];
+    let alternatives = [[7; NETWORK_IDENTIFIER_BYTES], APP];
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &other_app,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+    server.set_alternative_network_identifiers(&alternatives);
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+
+    assert_eq!(client_outcome.network_identifier(), APP);
+    assert_eq!(server_outcome.network_identifier(), APP);
+    assert_eq!(server_outcome.peer_longterm_pk(), CLIENT_PUB);
+}
+
+#[test]
+// A server accepts clients addressing one of its alternative longterm
+// keypairs, and rejects clients addressing any other key.
+fn alternative_longterm_keypair() {
+    let (old_pk, old_sk) = sign::gen_keypair();
+    let (other_pk, other_sk) = sign::gen_keypair();
+    let alternatives = [(other_pk, other_sk), (SERVER_PUB, SERVER_SEC)];
+
+    fn handshake(alternatives: &[(sign::PublicKey, sign::SecretKey)],
+                 old_pk: &sign::PublicKey,
+                 old_sk: &sign::SecretKey,
+                 server_pk: &sign::PublicKey)
+                 -> (Result<Outcome, errors::HandshakeError>,
+                     Result<Outcome, errors::HandshakeError>) {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           server_pk);
+        let mut server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                               &APP,
+                                               old_pk,
+                                               old_sk,
+                                               &SERVER_EPH_PUB,
+                                               &SERVER_EPH_SEC);
+        server.set_alternative_longterm_keypairs(alternatives);
+
+        block_on(client.then(without_stream).join(server.then(without_stream))).unwrap()
+    }
+
+    // Dropping the stream lets the peer of a failed handshake fail as well.
+    fn without_stream<S>(result: Result<(Outcome, S), (errors::HandshakeError, S)>)
+                         -> FutureResult<Result<Outcome, errors::HandshakeError>, ()> {
+        ok(result.map(|(outcome, _)| outcome).map_err(|(err, _)| err))
+    }
+
+    let (client_result, server_result) = handshake(&alternatives, &old_pk, &old_sk, &SERVER_PUB);
+    let server_outcome = server_result.ok().unwrap();
+    assert_eq!(server_outcome.local_longterm_pk(), SERVER_PUB);
+    assert_eq!(server_outcome.peer_longterm_pk(), CLIENT_PUB);
+    assert_eq!(client_result.ok().unwrap().peer_longterm_pk(), SERVER_PUB);
+
+    let (wrong_pk, _) = sign::gen_keypair();
+    let (client_result, server_result) = handshake(&alternatives, &old_pk, &old_sk, &wrong_pk);
+    assert!(client_result.is_err());
+    match server_result {
+        Err(errors::HandshakeError::CryptoError) => {}
+        _ => panic!("expected the server to reject the client"),
+    }
+}
+
+#[test]
+// A client rejecting self connections fails when connecting to its own key.
+fn client_rejects_self_connection() {
+    let (_writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, _reader_b) = ring_buffer(2);
+
+    let mut client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &CLIENT_PUB);
+    client.set_reject_self_connection(true);
+
+    match block_on(client).err().unwrap().0 {
+        errors::HandshakeError::SelfConnection => {}
+        _ => panic!("expected a self connection error"),
+    }
+}
+
+#[test]
+// Multiserver addresses can be parsed and formatted.
+fn multiserver_roundtrip() {
+    use multiserver::{self, Transport, Transform};
+
+    let address = "net:example.com:8008~shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=;ws:foo!:bar";
+    let parsed = multiserver::parse(address).unwrap();
+
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(parsed[0].net(), Some(("example.com", 8008)));
+    assert_eq!(parsed[0].shs(), Some(&SERVER_PUB));
+    assert_eq!(parsed[0].transforms,
+               vec![Transform::Shs { server_longterm_pk: SERVER_PUB }]);
+    match parsed[1].transport {
+        Transport::Other(ref protocol) => assert_eq!(protocol.data, vec!["foo:bar".to_string()]),
+        _ => panic!("expected an unknown transport"),
+    }
+
+    assert_eq!(multiserver::format(&parsed), address);
+}
+
+#[test]
+// A filtering client rejects a correctly authenticated server.
+fn client_filter_rejects_server() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshakerWithFilter::new(client_duplex,
+                                                 |_: &sign::PublicKey| ok::<bool, ()>(false),
+                                                 &APP,
+                                                 &CLIENT_PUB,
+                                                 &CLIENT_SEC,
+                                                 &CLIENT_EPH_PUB,
+                                                 &CLIENT_EPH_SEC,
+                                                 &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (client_result, server_result) = block_on(client.then(|result| ok::<_, ()>(result))
+                                                      .join(server.then(|result| {
+                                                                            ok::<_, ()>(result)
+                                                                        })))
+            .ok()
+            .unwrap();
+
+    match client_result {
+        Err((errors::FilteringHandshakeError::Rejected, _)) => {}
+        _ => panic!("expected the server to be rejected"),
+    }
+    assert!(server_result.is_ok());
+}
+
+#[test]
+// A retrying client redials after an interrupted connection attempt, and stays
+// pending once it completed or failed.
+fn retry_after_interrupted() {
+    use retry::Retry;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let mut client_duplex = Some(Duplex::new(reader_a, writer_b));
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut attempts = 0;
+    let dial = move || {
+        attempts += 1;
+        if attempts == 1 {
+            err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+        } else {
+            ok(client_duplex.take().unwrap())
+        }
+    };
+
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let mut client = Retry::new(dial, &identity, &SERVER_PUB, |_| ok::<(), ()>(()));
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (client_result, server_result) = block_on((&mut client)
+                                                      .then(|result| ok::<_, ()>(result))
+                                                      .join(server.then(|result| {
+                                                                            ok::<_, ()>(result)
+                                                                        })))
+            .ok()
+            .unwrap();
+
+    assert_eq!(client_result.ok().unwrap().0.peer_longterm_pk(), SERVER_PUB);
+    assert!(server_result.is_ok());
+    // Polling again does not dial again, which would find no stream.
+    assert!(is_pending(&mut client));
+
+    // A non-transient error is final, polling again does not dial again.
+    let mut attempts = 0;
+    {
+        let dial = || {
+            attempts += 1;
+            err::<Duplex<Reader, Writer>, _>(io::Error::new(io::ErrorKind::NotFound, "not found"))
+        };
+        let mut client = Retry::new(dial, &identity, &SERVER_PUB, |_| ok::<(), ()>(()));
+        assert!(block_on(&mut client).is_err());
+        assert!(is_pending(&mut client));
+    }
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+// A SOCKS5 negotiation requests the target host by name, and stays pending
+// once it completed or failed.
+fn socks5_connect() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use proxy::Socks5Connect;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let proxy_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = Socks5Connect::new(client_duplex, "example.onion", 8008);
+    let proxy = proxy_duplex
+        .read_exact([0u8; 3])
+        .and_then(|(stream, greeting)| {
+                      assert_eq!(greeting, [5, 1, 0]);
+                      stream.write_all([5u8, 0])
+                  })
+        .and_then(|(stream, _)| stream.read_exact([0u8; 20]))
+        .and_then(|(stream, request)| {
+                      assert_eq!(&request[..5], &[5, 1, 0, 3, 13]);
+                      assert_eq!(&request[5..18], b"example.onion");
+                      assert_eq!(&request[18..], &[0x1f, 0x48]);
+                      stream.write_all([5u8, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x48])
+                  });
+
+    assert!(block_on((&mut client).join(proxy)).is_ok());
+    assert!(is_pending(&mut client));
+
+    let (writer, reader) = ring_buffer(2);
+    let mut client = Socks5Connect::new(Duplex::new(reader, writer), "", 8008);
+    assert!(block_on(&mut client).is_err());
+    assert!(is_pending(&mut client));
+}
+
+#[test]
+// An HTTP CONNECT negotiation consumes exactly the response header, and stays
+// pending once it completed or failed.
+fn http_connect() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use proxy::HttpConnect;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let proxy_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut connecting = HttpConnect::new(client_duplex, "example.com", 8008);
+    let ((_, after_header), request) = {
+        let client = (&mut connecting).and_then(|stream| stream.read_exact([0u8; 2]));
+        let proxy = proxy_duplex
+            .read_exact([0u8; 61])
+            .and_then(|(stream, request)| {
+                stream
+                    .write_all(&b"HTTP/1.1 200 Connection established\r\n\r\nhi"[..])
+                    .map(move |_| request)
+            });
+        block_on(client.join(proxy)).ok().unwrap()
+    };
+    assert_eq!(&request[..],
+               &b"CONNECT example.com:8008 HTTP/1.1\r\nHost: example.com:8008\r\n\r\n"[..]);
+    assert_eq!(&after_header, b"hi");
+    assert!(is_pending(&mut connecting));
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let mut connecting = HttpConnect::new(Duplex::new(reader_a, writer_b), "example.com", 8008);
+    let proxy = Duplex::new(reader_b, writer_a)
+        .read_exact([0u8; 61])
+        .and_then(|(stream, _)| stream.write_all(&b"HTTP/1.1 403 Forbidden\r\n\r\n"[..]));
+    match block_on((&mut connecting).join(proxy)) {
+        Err(ref err) if err.kind() == io::ErrorKind::Other => {}
+        _ => panic!("the proxy did not refuse"),
+    }
+    assert!(is_pending(&mut connecting));
+}
+
+#[test]
+// A handshake can be performed over the stream of a completed handshake.
+fn nested_handshake() {
+    use room::{connect_through, accept_through};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let room_duplex = Duplex::new(reader_b, writer_a);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let room_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+    let (target_longterm_pk, target_longterm_sk) = sign::gen_keypair();
+    let target_identity = Identity::new(APP, target_longterm_pk.clone(), target_longterm_sk);
+
+    let outer = connect_through(client_duplex, &client_identity, &SERVER_PUB)
+        .join(accept_through(room_duplex, &room_identity));
+    let ((_, client_tunnel), (_, target_tunnel)) = block_on(outer).ok().unwrap();
+
+    // The room forwards the tunnel to the target, which accepts the inner handshake.
+    let inner = connect_through(client_tunnel, &client_identity, &target_longterm_pk)
+        .join(accept_through(target_tunnel, &target_identity));
+    let ((client_outcome, _), (target_outcome, _)) = block_on(inner).ok().unwrap();
+
+    assert_eq!(client_outcome.peer_longterm_pk(), target_longterm_pk);
+    assert_eq!(target_outcome.peer_longterm_pk(), CLIENT_PUB);
+    assert_eq!(client_outcome.send().key,
+               target_outcome.recv().key);
+}
+
+#[test]
+// An inner handshake fails if the tunnel leads to a peer other than the target.
+fn nested_handshake_wrong_target() {
+    use room::{connect_through, accept_through};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let room_duplex = Duplex::new(reader_b, writer_a);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let room_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+    let (target_longterm_pk, _) = sign::gen_keypair();
+
+    let outer = connect_through(client_duplex, &client_identity, &SERVER_PUB)
+        .join(accept_through(room_duplex, &room_identity));
+    let ((_, client_tunnel), (_, room_tunnel)) = block_on(outer).ok().unwrap();
+
+    // The room answers the inner handshake itself, pretending to be the target.
+    let inner = connect_through(client_tunnel, &client_identity, &target_longterm_pk)
+        .then(|result| ok::<_, ()>(result))
+        .join(accept_through(room_tunnel, &room_identity).then(|result| {
+            // Drop the stream, so that the client does not wait for msg4 forever.
+            ok::<_, ()>(result.map(|_| ()).map_err(|(err, _)| err))
+        }));
+    let (client_result, room_result) = block_on(inner).ok().unwrap();
+
+    assert!(client_result.is_err());
+    assert!(room_result.is_err());
+}
+
+#[test]
+#[cfg(feature = "secret-stream")]
+// Data written to a secret stream can be read by the peer, until the goodbye.
+fn secret_stream_roundtrip() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use secret_stream::{connect, accept};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let server_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+
+    let (client, server) = block_on(connect(client_duplex, &client_identity, &SERVER_PUB)
+                                        .join(accept(server_duplex, &server_identity)))
+            .ok()
+            .unwrap();
+    assert_eq!(server.peer_longterm_pk(), &CLIENT_PUB);
+
+    let data = vec![42u8; 5000];
+    let write = client
+        .write_all(data.clone())
+        .and_then(|(client, _)| client.close());
+    let read = server.read_to_end(Vec::new());
+
+    let (_, (_, received)) = block_on(write.join(read)).ok().unwrap();
+    assert_eq!(received, data);
+}
+
+#[test]
+#[cfg(feature = "secret-stream")]
+// A handoff passes the encrypted stream to the rpc layer, and stays pending
+// when polled again.
+fn secret_stream_handoff() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use secret_stream::{connect, accept};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let server_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+
+    let mut client = connect(Duplex::new(reader_a, writer_b), &client_identity, &SERVER_PUB)
+        .handoff(|stream| {
+                     stream
+                         .write_all(vec![1u8, 2, 3])
+                         .and_then(|(stream, _)| stream.flush())
+                 });
+    let mut server = accept(Duplex::new(reader_b, writer_a), &server_identity)
+        .handoff(|stream| stream.read_exact([0u8; 3]));
+
+    let ((client_rpc, client_peer), (server_rpc, server_peer)) =
+        block_on((&mut client).join(&mut server)).ok().unwrap();
+    assert_eq!(client_peer, SERVER_PUB);
+    assert_eq!(server_peer, CLIENT_PUB);
+    assert!(is_pending(&mut client));
+    assert!(is_pending(&mut server));
+
+    let (_, (_, received)) = block_on(client_rpc.join(server_rpc)).ok().unwrap();
+    assert_eq!(received, [1, 2, 3]);
+}
+
+#[test]
+#[cfg(feature = "capi")]
+// A client and a server can perform a handshake via the C api.
+fn capi_handshake() {
+    use capi::*;
+
+    let mut msg1 = [0u8; MSG1_BYTES];
+    let mut msg2 = [0u8; MSG2_BYTES];
+    let mut msg3 = [0u8; MSG3_BYTES];
+    let mut msg4 = [0u8; MSG4_BYTES];
+    let mut client_outcome: ShsOutcome = unsafe { ::std::mem::zeroed() };
+    let mut server_outcome: ShsOutcome = unsafe { ::std::mem::zeroed() };
+
+    unsafe {
+        let client = shs_client_new(APP.as_ptr(),
+                                    CLIENT_PUB.0.as_ptr(),
+                                    CLIENT_SEC.0.as_ptr(),
+                                    CLIENT_EPH_PUB.0.as_ptr(),
+                                    CLIENT_EPH_SEC.0.as_ptr(),
+                                    SERVER_PUB.0.as_ptr());
+        let server = shs_server_new(APP.as_ptr(),
+                                    SERVER_PUB.0.as_ptr(),
+                                    SERVER_SEC.0.as_ptr(),
+                                    SERVER_EPH_PUB.0.as_ptr(),
+                                    SERVER_EPH_SEC.0.as_ptr());
+
+        shs_client_create_msg1(client, msg1.as_mut_ptr());
+        assert!(shs_server_verify_msg1(server, msg1.as_ptr()) != 0);
+        shs_server_create_msg2(server, msg2.as_mut_ptr());
+        assert!(shs_client_verify_msg2(client, msg2.as_ptr()) != 0);
+        shs_client_create_msg3(client, msg3.as_mut_ptr());
+        assert!(shs_server_verify_msg3(server, msg3.as_ptr()) != 0);
+        shs_server_create_msg4(server, msg4.as_mut_ptr());
+        assert!(shs_client_verify_msg4(client, msg4.as_ptr()) != 0);
+
+        shs_client_outcome(client, &mut client_outcome);
+        shs_server_outcome(server, &mut server_outcome);
+        shs_client_free(client);
+        shs_server_free(server);
+    }
+
+    assert_eq!(&msg1[..], &CLIENT_MSGS[..MSG1_BYTES]);
+    assert_eq!(client_outcome.send_params().key.0, EXP_CLIENT_ENC_KEY.0);
+    assert_eq!(server_outcome.send_params().key.0, EXP_SERVER_ENC_KEY.0);
+    assert_eq!(client_outcome.peer_longterm_pk, EXP_SERVER_PUB.0);
+    assert_eq!(server_outcome.peer_longterm_pk, EXP_CLIENT_PUB.0);
+}
+
+#[test]
+// Polling a handshaker after completion neither panics nor completes again.
+fn poll_after_completion() {
+    use futures::future::poll_fn;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    assert!(block_on((&mut client).join(&mut server)).is_ok());
+
+    let pending = block_on(poll_fn(|cx| {
+        let client_pending = match client.poll(cx) {
+            Ok(Async::Pending) => true,
+            _ => false,
+        };
+        let server_pending = match server.poll(cx) {
+            Ok(Async::Pending) => true,
+            _ => false,
+        };
+        Ok::<_, ()>(Async::Ready(client_pending && server_pending))
+    }));
+    assert!(pending.unwrap());
+}
+
+#[test]
+// An acceptor returns the allocations of finished handshakes to its pool.
+fn acceptor_recycles_into_pool() {
+    use acceptor::Acceptor;
+    use pool::HandshakePool;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let addr = "127.0.0.1:8008".parse().unwrap();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let pool = HandshakePool::new(4);
+
+    let mut acceptor = Acceptor::new(incoming, identity);
+    acceptor.set_pool(pool.clone());
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+
+    let (client_result, accepted) =
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(acceptor.next().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_ok());
+    match accepted {
+        Ok((Some(_), _)) => {}
+        _ => panic!("no connection accepted"),
+    }
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+// An acceptor clears the failures of the address of a successful handshake,
+// and rejects clients whose key its rate limiter refuses.
+fn acceptor_rate_limiter() {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use acceptor::Acceptor;
+    use rate_limit::RateLimiter;
+
+    fn handshake(limiter: &RateLimiter, addr: SocketAddr) -> (bool, bool) {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client_duplex = Duplex::new(reader_a, writer_b);
+        let server_duplex = Duplex::new(reader_b, writer_a);
+
+        let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+        let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+        let mut acceptor = Acceptor::new(incoming, identity);
+        acceptor.set_rate_limiter(limiter.clone());
+
+        let client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+
+        let (client_result, accepted) =
+            block_on(client
+                         .then(|r| ok::<_, ()>(r))
+                         .join(acceptor.next().then(|r| ok::<_, ()>(r))))
+                    .unwrap();
+        let accepted = match accepted {
+            Ok((Some(_), _)) => true,
+            Ok((None, _)) => false,
+            Err(_) => panic!("the acceptor failed"),
+        };
+        (client_result.is_ok(), accepted)
+    }
+
+    let limiter = RateLimiter::new(2, Duration::from_secs(60), Duration::from_secs(60));
+    let addr: SocketAddr = "127.0.0.1:8008".parse().unwrap();
+
+    limiter.record_failure(addr.ip(), None);
+    assert_eq!(handshake(&limiter, addr), (true, true));
+    limiter.record_failure(addr.ip(), None);
+    assert!(limiter.is_addr_allowed(&addr.ip()));
+
+    let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    limiter.record_failure(other, Some(&CLIENT_PUB));
+    limiter.record_failure(other, Some(&CLIENT_PUB));
+    assert_eq!(handshake(&limiter, addr), (false, false));
+}
+
+#[test]
+// Keypairs are still handed out once the ephemeral key pool has run dry.
+fn ephemeral_key_pool_take() {
+    use pool::EphemeralKeyPool;
+
+    let pool = EphemeralKeyPool::new(2);
+    let (pk_a, _) = pool.take();
+    let (pk_b, _) = pool.take();
+    let (pk_c, _) = pool.take();
+    assert!(pk_a != pk_b && pk_b != pk_c && pk_a != pk_c);
+    assert!(pool.len() <= pool.capacity());
+}
+
+#[test]
+// The borrowing and consuming outcome accessors agree with the copying ones.
+fn outcome_key_accessors() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), _) = block_on(client.join(server)).ok().unwrap();
+
+    assert_eq!(client_outcome.send_params().key.0, EXP_CLIENT_ENC_KEY.0);
+    assert_eq!(client_outcome.send_params().nonce.0, EXP_CLIENT_ENC_NONCE.0);
+    assert_eq!(client_outcome.recv_params().key.0, EXP_CLIENT_DEC_KEY.0);
+    assert_eq!(client_outcome.recv_params().nonce.0, EXP_CLIENT_DEC_NONCE.0);
+
+    let keys = client_outcome.into_keys();
+    assert_eq!(keys.send.key, EXP_CLIENT_ENC_KEY);
+    assert_eq!(keys.send.nonce, EXP_CLIENT_ENC_NONCE);
+    assert_eq!(keys.recv.key, EXP_CLIENT_DEC_KEY);
+    assert_eq!(keys.recv.nonce, EXP_CLIENT_DEC_NONCE);
+}
+
+#[test]
+// The type-state api produces the expected outcomes.
+fn typestate_handshake() {
+    use typestate::{ClientState, ServerState};
+
+    let mut msg1 = [0; MSG1_BYTES];
+    let mut msg2 = [0; MSG2_BYTES];
+    let mut msg3 = [0; MSG3_BYTES];
+    let mut msg4 = [0; MSG4_BYTES];
+
+    let client = ClientState::new(APP,
+                                  CLIENT_PUB,
+                                  CLIENT_SEC.clone(),
+                                  CLIENT_EPH_PUB,
+                                  CLIENT_EPH_SEC.clone(),
+                                  SERVER_PUB);
+    let server = ServerState::new(APP,
+                                  SERVER_PUB,
+                                  SERVER_SEC.clone(),
+                                  SERVER_EPH_PUB,
+                                  SERVER_EPH_SEC.clone());
+
+    let client = client.send_hello(&mut msg1);
+    let server = server.receive_hello(&msg1).unwrap();
+    let server = server.send_hello(&mut msg2);
+    let client = client.receive_hello(&msg2).unwrap().send_auth(&mut msg3);
+    let server = server.receive_auth(&msg3).unwrap();
+    assert_eq!(server.client_longterm_pk(), CLIENT_PUB);
+    let server_outcome = server.send_ack(&mut msg4);
+    let client_outcome = client.receive_ack(&msg4).unwrap();
+
+    assert_eq!(client_outcome.send().key, EXP_CLIENT_ENC_KEY);
+    assert_eq!(client_outcome.recv().key, EXP_CLIENT_DEC_KEY);
+    assert_eq!(server_outcome.send().key, EXP_SERVER_ENC_KEY);
+    assert_eq!(server_outcome.recv().key, EXP_SERVER_DEC_KEY);
+}
+
+#[test]
+// Two handshake states can be driven against each other without any io,
+// moving the messages in small chunks.
+fn sans_io_handshake() {
+    use sans_io::HandshakeState;
+
+    let mut client = HandshakeState::client(&APP,
+                                            &CLIENT_PUB,
+                                            &CLIENT_SEC,
+                                            &CLIENT_EPH_PUB,
+                                            &CLIENT_EPH_SEC,
+                                            &SERVER_PUB);
+    let mut server = HandshakeState::server(&APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC);
+
+    let mut buf = [0u8; 7];
+    while !(client.is_done() && server.is_done()) {
+        let (from, to) = if client.wants_write() > 0 {
+            (&mut client, &mut server)
+        } else {
+            (&mut server, &mut client)
+        };
+        let len = from.write_message(&mut buf);
+        assert!(len > 0);
+        assert_eq!(to.read_message(&buf[..len]).unwrap(), len);
+    }
+
+    let client_outcome = client.outcome().unwrap();
+    let server_outcome = server.outcome().unwrap();
+    assert_eq!(client_outcome.send().key, EXP_CLIENT_ENC_KEY);
+    assert_eq!(client_outcome.recv().key, EXP_CLIENT_DEC_KEY);
+    assert_eq!(server_outcome.send().key, EXP_SERVER_ENC_KEY);
+    assert_eq!(server_outcome.recv().key, EXP_SERVER_DEC_KEY);
+}
+
+#[test]
+// The crypto layer creates and verifies typed messages, and the messages
+// survive a roundtrip through their byte representation.
+fn typed_messages() {
+    use messages::{ClientHello, ServerHello, ClientAuth, ServerAck};
+
+    let mut client = Client::new(&APP,
+                                 &CLIENT_PUB.0,
+                                 &CLIENT_SEC.0,
+                                 &CLIENT_EPH_PUB.0,
+                                 &CLIENT_EPH_SEC.0,
+                                 &SERVER_PUB.0);
+    let mut server = Server::new(&APP,
+                                 &SERVER_PUB.0,
+                                 &SERVER_SEC.0,
+                                 &SERVER_EPH_PUB.0,
+                                 &SERVER_EPH_SEC.0);
+
+    let hello = client.client_hello();
+    let parsed = ClientHello::from_slice(&hello.as_bytes()[..]).unwrap();
+    assert_eq!(parsed, hello);
+    assert!(ClientHello::from_slice(&hello.as_bytes()[1..]).is_none());
+    assert!(server.verify_client_hello(&parsed));
+
+    let hello = ServerHello::from_slice(&server.server_hello().as_bytes()[..]).unwrap();
+    assert!(client.verify_server_hello(&hello));
+
+    let auth = client.client_auth();
+    assert_eq!(auth.as_bytes().len(), ClientAuth::LEN);
+    let corrupted = {
+        let mut corrupted = auth;
+        corrupted.as_bytes_mut()[0] ^= 1;
+        corrupted
+    };
+    assert!(corrupted != auth);
+    assert!(server.verify_client_auth(&auth));
+
+    let ack = ServerAck::from_bytes(server.server_ack().into_bytes());
+    assert!(client.verify_server_ack(&ack));
+}
+
+#[test]
+// The transcript explainer accepts a valid transcript and pinpoints
+// corrupted messages.
+fn explain_transcript() {
+    use transcript::{explain, Verdict};
+
+    let mut client_crypto = Client::new(&APP,
+                                        &CLIENT_PUB.0,
+                                        &CLIENT_SEC.0,
+                                        &CLIENT_EPH_PUB.0,
+                                        &CLIENT_EPH_SEC.0,
+                                        &SERVER_PUB.0);
+    let mut server_crypto = Server::new(&APP,
+                                        &SERVER_PUB.0,
+                                        &SERVER_SEC.0,
+                                        &SERVER_EPH_PUB.0,
+                                        &SERVER_EPH_SEC.0);
+
+    let msg1 = client_crypto.client_hello();
+    assert!(server_crypto.verify_client_hello(&msg1));
+    let msg2 = server_crypto.server_hello();
+    assert!(client_crypto.verify_server_hello(&msg2));
+    let msg3 = client_crypto.client_auth();
+    assert!(server_crypto.verify_client_auth(&msg3));
+    let msg4 = server_crypto.server_ack();
+
+    let mut client_to_server = msg1.as_bytes().to_vec();
+    client_to_server.extend_from_slice(&msg3.as_bytes()[..]);
+    let mut server_to_client = msg2.as_bytes().to_vec();
+    server_to_client.extend_from_slice(&msg4.as_bytes()[..]);
+
+    let client = Identity::new(APP, CLIENT_PUB, CLIENT_SEC.clone());
+    let server = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+
+    let explanation = explain(&client,
+                              &CLIENT_EPH_PUB,
+                              &CLIENT_EPH_SEC,
+                              &server,
+                              &SERVER_EPH_PUB,
+                              &SERVER_EPH_SEC,
+                              &client_to_server,
+                              &server_to_client);
+    assert!(explanation.is_valid());
+
+    client_to_server[MSG1_BYTES + 3] ^= 1;
+    let explanation = explain(&client,
+                              &CLIENT_EPH_PUB,
+                              &CLIENT_EPH_SEC,
+                              &server,
+                              &SERVER_EPH_PUB,
+                              &SERVER_EPH_SEC,
+                              &client_to_server,
+                              &server_to_client[..MSG2_BYTES]);
+    assert_eq!(explanation.msg2, Verdict::Valid);
+    assert_eq!(explanation.msg3, Verdict::Invalid);
+    assert_eq!(explanation.msg4, Verdict::NotChecked);
+
+    let other_network = Identity::new([0; NETWORK_IDENTIFIER_BYTES],
+                                      SERVER_PUB,
+                                      SERVER_SEC.clone());
+    let explanation = explain(&client,
+                              &CLIENT_EPH_PUB,
+                              &CLIENT_EPH_SEC,
+                              &other_network,
+                              &SERVER_EPH_PUB,
+                              &SERVER_EPH_SEC,
+                              &client_to_server,
+                              &server_to_client);
+    assert_eq!(explanation.msg1, Verdict::InvalidHmac);
+}
+
+#[test]
+#[cfg(feature = "test-utils")]
+// Corrupted client hellos are rejected by the server.
+fn corrupted_msg1_rejected() {
+    use proptest::test_runner::TestRunner;
+    use test_utils::corrupted;
+
+    let mut client = Client::new(&APP,
+                                 &CLIENT_PUB.0,
+                                 &CLIENT_SEC.0,
+                                 &CLIENT_EPH_PUB.0,
+                                 &CLIENT_EPH_SEC.0,
+                                 &SERVER_PUB.0);
+    let msg1 = client.client_hello();
+
+    let mut runner = TestRunner::default();
+    runner
+        .run(&corrupted(msg1.as_bytes()), |msg1| {
+            let mut server = Server::new(&APP,
+                                         &SERVER_PUB.0,
+                                         &SERVER_SEC.0,
+                                         &SERVER_EPH_PUB.0,
+                                         &SERVER_EPH_SEC.0);
+            let msg1 = messages::ClientHello::from_slice(&msg1).unwrap();
+            assert!(!server.verify_client_hello(&msg1));
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+#[cfg(feature = "test-utils")]
+// The fixture handshake yields matching keys for both sides.
+fn fixture_handshake_pair() {
+    use test_utils::{client_identity, server_identity, seeded_identity, handshake_pair};
+
+    let (client, server) = handshake_pair(&client_identity(), &server_identity()).unwrap();
+    assert_eq!(client.send().key, EXP_CLIENT_ENC_KEY);
+    assert_eq!(client.recv().nonce, EXP_CLIENT_DEC_NONCE);
+    assert_eq!(server.send().key, EXP_SERVER_ENC_KEY);
+    assert_eq!(server.recv().nonce, EXP_SERVER_DEC_NONCE);
+
+    assert!(handshake_pair(&seeded_identity(1), &seeded_identity(2)).is_ok());
+}
+
+#[test]
+// Timers record the completion of all messages of a handshake.
+fn handshake_timers() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    let client_timer = stats::HandshakeTimer::new();
+    let server_timer = stats::HandshakeTimer::new();
+    client.set_timer(client_timer.clone());
+    server.set_timer(server_timer.clone());
+    assert_eq!(server_timer.stats().completed_messages(), 0);
+    assert_eq!(server_timer.stats().duration(), None);
+
+    assert!(block_on(client.join(server)).is_ok());
+
+    for stats in &[client_timer.stats(), server_timer.stats()] {
+        assert!(stats.succeeded);
+        assert_eq!(stats.completed_messages(), 4);
+        assert!(stats.until_message(1) <= stats.until_message(4));
+        assert!(stats.until_message(4) <= stats.duration());
+    }
+}
+
+#[test]
+// The acceptor sends an audit event for a rejected client.
+fn acceptor_audit_rejected() {
+    use acceptor::Acceptor;
+    use audit::{self, AuditResult, FailureReason};
+
+    fn reject(_: &sign::PublicKey) -> FutureResult<bool, ()> {
+        ok(false)
+    }
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let addr = "127.0.0.1:8008".parse().unwrap();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let (sender, events) = audit::channel(4);
+
+    let mut acceptor = Acceptor::with_filter(incoming, reject, identity);
+    acceptor.set_audit(sender);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+
+    let (client_result, accepted) =
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(acceptor.collect().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_err());
+    assert_eq!(accepted.unwrap().len(), 0);
+
+    let events = block_on(events.collect()).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].peer, Some(CLIENT_PUB));
+    assert_eq!(events[0].addr, addr);
+    assert_eq!(events[0].result,
+               AuditResult::Failed(FailureReason::Rejected));
+    assert!(events[0].started <= events[0].finished);
+}
+
+#[test]
+// Outcomes contain the ephemeral keys used by both parties.
+fn outcome_ephemeral_keys() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+
+    assert_eq!(client_outcome.local_ephemeral_pk(), CLIENT_EPH_PUB);
+    assert_eq!(client_outcome.peer_ephemeral_pk(), SERVER_EPH_PUB);
+    assert_eq!(server_outcome.local_ephemeral_pk(), SERVER_EPH_PUB);
+    assert_eq!(server_outcome.peer_ephemeral_pk(), CLIENT_EPH_PUB);
+}
+
+#[test]
+// Both peers derive the same subkeys, which depend on the label.
+fn outcome_derive_key() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+
+    let mut client_key = [0u8; 100];
+    let mut server_key = [0u8; 100];
+    client_outcome.derive_key(b"file transfer", &mut client_key);
+    server_outcome.derive_key(b"file transfer", &mut server_key);
+    assert_eq!(&client_key[..], &server_key[..]);
+
+    let mut other_key = [0u8; 100];
+    client_outcome.derive_key(b"side channel", &mut other_key);
+    assert!(&client_key[..] != &other_key[..]);
+
+    // Shorter outputs are prefixes of longer ones.
+    let mut short_key = [0u8; 16];
+    client_outcome.derive_key(b"file transfer", &mut short_key);
+    assert_eq!(&short_key[..], &client_key[..16]);
+}
+
+#[test]
+// Debug output of outcomes and identities does not contain secrets.
+fn debug_redacts_secrets() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), _) = block_on(client.join(server)).ok().unwrap();
+
+    let debug = format!("{:?}", client_outcome);
+    assert!(debug.contains(&format!("{:?}", SERVER_PUB.0)));
+    assert!(!debug.contains(&format!("{:?}", EXP_CLIENT_ENC_KEY.0)));
+    assert!(!debug.contains(&format!("{:?}", EXP_CLIENT_DEC_KEY.0)));
+    assert!(!debug.contains(&format!("{:?}", APP)));
+    let debug = format!("{:?}", client_outcome.into_keys());
+    assert!(!debug.contains(&format!("{:?}", EXP_CLIENT_ENC_KEY.0)));
+
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let debug = format!("{:?}", identity);
+    assert!(!debug.contains(&format!("{:?}", &SERVER_SEC.0[..])));
+    assert!(!debug.contains(&format!("{:?}", APP)));
+}
+
+#[test]
+#[cfg(feature = "serialize-outcome")]
+// An outcome survives a serialization round trip, and truncated input is rejected.
+fn outcome_serde_roundtrip() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((outcome, _), _) = block_on(client.join(server)).ok().unwrap();
+
+    let json = serde_json::to_string(&outcome).unwrap();
+    let restored: Outcome = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(restored.send(), outcome.send());
+    assert_eq!(restored.recv(), outcome.recv());
+    assert_eq!(restored.peer_longterm_pk(), outcome.peer_longterm_pk());
+    assert_eq!(restored.local_ephemeral_pk(), outcome.local_ephemeral_pk());
+    assert_eq!(restored.peer_ephemeral_pk(), outcome.peer_ephemeral_pk());
+
+    assert!(serde_json::from_str::<Outcome>("[1,2,3]").is_err());
+}
+
+#[test]
+// A session cache deduplicates dials to the same peer.
+fn session_cache_dedup() {
+    use std::time::Duration;
+    use session_cache::{SessionCache, Begin};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let cache = SessionCache::new(4, Duration::from_secs(60));
+
+    // An abandoned dial resolves pending sessions to `None`.
+    let slot = match cache.begin(&SERVER_PUB) {
+        Begin::Start(slot) => slot,
+        _ => panic!("expected to start a handshake"),
+    };
+    let pending = match cache.begin(&SERVER_PUB) {
+        Begin::InProgress(pending) => pending,
+        _ => panic!("expected a handshake in progress"),
+    };
+    drop(slot);
+    assert!(block_on(pending).unwrap().is_none());
+
+    let slot = match cache.begin(&SERVER_PUB) {
+        Begin::Start(slot) => slot,
+        _ => panic!("expected to start a handshake"),
+    };
+    let pending = match cache.begin(&SERVER_PUB) {
+        Begin::InProgress(pending) => pending,
+        _ => panic!("expected a handshake in progress"),
+    };
+
+    let ((outcome, _), _) = block_on(client.join(server)).ok().unwrap();
+    let session = slot.complete(outcome);
+
+    let waited = block_on(pending).unwrap().unwrap();
+    assert_eq!(waited.send(), session.send());
+
+    match cache.begin(&SERVER_PUB) {
+        Begin::Cached(cached) => assert_eq!(cached.recv(), session.recv()),
+        _ => panic!("expected a cached session"),
+    }
+    assert_eq!(cache.len(), 1);
+
+    cache.remove(&SERVER_PUB);
+    assert!(cache.get(&SERVER_PUB).is_none());
+}
+
+#[test]
+#[cfg(feature = "secret-stream")]
+// After rekeying, data still flows over a secret stream.
+fn secret_stream_rekey() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use secret_stream::{connect, accept, rekey_client, rekey_server};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let server_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+
+    let (client, server) = block_on(connect(client_duplex, &client_identity, &SERVER_PUB)
+                                        .join(accept(server_duplex, &server_identity)))
+            .ok()
+            .unwrap();
+
+    let (client, server) = block_on(rekey_client(client, &client_identity)
+                                        .join(rekey_server(server, &server_identity)))
+            .ok()
+            .unwrap();
+    assert_eq!(server.peer_longterm_pk(), &CLIENT_PUB);
+
+    let data = vec![42u8; 5000];
+    let write = client
+        .write_all(data.clone())
+        .and_then(|(client, _)| client.close());
+    let read = server.read_to_end(Vec::new());
+
+    let (_, (_, received)) = block_on(write.join(read)).ok().unwrap();
+    assert_eq!(received, data);
+}
+
+#[test]
+// Nonces are incremented as big-endian numbers, with carry and wrap-around.
+fn nonce_gen_increments() {
+    use nonce::NonceGen;
+
+    let mut initial = [0u8; secretbox::NONCEBYTES];
+    initial[secretbox::NONCEBYTES - 1] = 0xfe;
+    let mut nonces = NonceGen::new(secretbox::Nonce(initial));
+
+    let (header_nonce, body_nonce) = nonces.next_packet().unwrap();
+    assert_eq!(header_nonce.0[secretbox::NONCEBYTES - 1], 0xfe);
+    assert_eq!(body_nonce.0[secretbox::NONCEBYTES - 1], 0xff);
+
+    let carried = nonces.next().unwrap();
+    assert_eq!(carried.0[secretbox::NONCEBYTES - 2], 1);
+    assert_eq!(carried.0[secretbox::NONCEBYTES - 1], 0);
+
+    let mut nonces = NonceGen::new(secretbox::Nonce([0xff; secretbox::NONCEBYTES]));
+    assert_eq!(nonces.next().unwrap().0, [0xff; secretbox::NONCEBYTES]);
+    assert_eq!(nonces.peek().unwrap().0, [0; secretbox::NONCEBYTES]);
+    assert!(!nonces.is_exhausted());
+}
+
+#[test]
+#[cfg(feature = "mio")]
+// Handshake states can be driven over non-blocking streams.
+fn mio_handshake_nonblocking() {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use mio_handshake::advance;
+    use sans_io::HandshakeState;
+
+    // One direction of an in-memory connection, with room for 16 bytes.
+    type Pipe = Rc<RefCell<VecDeque<u8>>>;
+
+    struct NonBlocking(Pipe, Pipe);
+
+    impl Read for NonBlocking {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut incoming = self.0.borrow_mut();
+            if incoming.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let mut read = 0;
+            while read < buf.len() {
+                match incoming.pop_front() {
+                    Some(byte) => buf[read] = byte,
+                    None => break,
+                }
+                read += 1;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for NonBlocking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut outgoing = self.1.borrow_mut();
+            let written = buf.len().min(16 - outgoing.len());
+            if written == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            outgoing.extend(&buf[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let a: Pipe = Rc::new(RefCell::new(VecDeque::new()));
+    let b: Pipe = Rc::new(RefCell::new(VecDeque::new()));
+    let mut client_stream = NonBlocking(a.clone(), b.clone());
+    let mut server_stream = NonBlocking(b, a);
+
+    let mut client = HandshakeState::client(&APP,
+                                            &CLIENT_PUB,
+                                            &CLIENT_SEC,
+                                            &CLIENT_EPH_PUB,
+                                            &CLIENT_EPH_SEC,
+                                            &SERVER_PUB);
+    let mut server = HandshakeState::server(&APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC);
+
+    let mut client_done = false;
+    let mut server_done = false;
+    while !(client_done && server_done) {
+        client_done = advance(&mut client, &mut client_stream).unwrap();
+        server_done = advance(&mut server, &mut server_stream).unwrap();
+    }
+
+    let client_outcome = client.outcome().unwrap();
+    let server_outcome = server.outcome().unwrap();
+    assert_eq!(client_outcome.send().key, EXP_CLIENT_ENC_KEY);
+    assert_eq!(server_outcome.send().key, EXP_SERVER_ENC_KEY);
+}
+
+#[test]
+// Keys survive a hex round trip, and malformed hex is rejected.
+fn hex_keys() {
+    let encoded = hex::encode(&SERVER_PUB.0);
+    assert_eq!(encoded.len(), 2 * sign::PUBLICKEYBYTES);
+    assert_eq!(hex::decode_longterm_pk(&encoded), Some(SERVER_PUB));
+    assert_eq!(hex::decode_longterm_pk(&encoded.to_uppercase()), Some(SERVER_PUB));
+    assert_eq!(hex::decode_network_identifier(&hex::encode(&APP)), Some(APP));
+
+    assert_eq!(hex::decode("0"), None);
+    assert_eq!(hex::decode("zz"), None);
+    assert_eq!(hex::decode_longterm_pk(&encoded[2..]), None);
+    assert_eq!(hex::decode_network_identifier(&encoded[..10]), None);
+}
+
+#[test]
+// Keys survive hex and base64 round trips, and only exact encodings decode.
+fn key_encodings() {
+    use encoding::KeyEncoding;
+
+    assert_eq!(sign::PublicKey::from_hex(&SERVER_PUB.to_hex()), Some(SERVER_PUB));
+    assert_eq!(sign::PublicKey::from_base64(&SERVER_PUB.to_base64()), Some(SERVER_PUB));
+    assert_eq!(SERVER_PUB.to_base64(), ::base64::encode(&SERVER_PUB.0));
+    assert_eq!(sign::SecretKey::from_base64(&SERVER_SEC.to_base64()).unwrap().0[..],
+               SERVER_SEC.0[..]);
+    assert_eq!(box_::PublicKey::from_hex(&CLIENT_EPH_PUB.to_hex()), Some(CLIENT_EPH_PUB));
+    assert_eq!(<[u8; NETWORK_IDENTIFIER_BYTES]>::from_base64(&APP.to_base64()), Some(APP));
+    let main_network = "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=";
+    assert_eq!(<[u8; NETWORK_IDENTIFIER_BYTES]>::from_base64(main_network),
+               Some([0xd4, 0xa1, 0xcb, 0x88, 0xa6, 0x6f, 0x02, 0xf8, 0xdb, 0x63, 0x5c, 0xe2,
+                     0x64, 0x41, 0xcc, 0x5d, 0xac, 0x1b, 0x08, 0x42, 0x0c, 0xea, 0xac, 0x23,
+                     0x08, 0x39, 0xb7, 0x55, 0x84, 0x5a, 0x9f, 0xfb]));
+
+    let encoded = SERVER_PUB.to_base64();
+    // Unpadded, truncated, or with bits set beyond the key.
+    assert_eq!(sign::PublicKey::from_base64(encoded.trim_right_matches('=')), None);
+    assert_eq!(sign::PublicKey::from_base64(&encoded[4..]), None);
+    assert_eq!(sign::PublicKey::from_base64(&format!("{}B=", &encoded[..42])), None);
+    assert_eq!(sign::PublicKey::from_base64(&encoded.replace('=', "!")), None);
+    assert_eq!(sign::PublicKey::from_hex(&SERVER_PUB.to_hex()[2..]), None);
+}
+
+#[test]
+// Identities can be declared as statics.
+fn static_identity() {
+    static IDENTITY: Identity = Identity::new([7; NETWORK_IDENTIFIER_BYTES],
+                                              sign::PublicKey([1; sign::PUBLICKEYBYTES]),
+                                              sign::SecretKey([2; sign::SECRETKEYBYTES]));
+
+    assert_eq!(IDENTITY.network_identifier(), &[7; NETWORK_IDENTIFIER_BYTES]);
+    assert_eq!(IDENTITY.longterm_pk(), &sign::PublicKey([1; sign::PUBLICKEYBYTES]));
+}
+
+#[test]
+// Handshakers can be debug printed without revealing keys.
+fn debug_handshakers() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let debug = format!("{:?}", client);
+    assert!(debug.starts_with("ClientHandshaker {"));
+    assert!(debug.contains("has_stream: true"));
+    assert!(!debug.contains(&format!("{:?}", &CLIENT_SEC.0[..])));
+
+    let debug = format!("{:?}", server);
+    assert!(debug.starts_with("ServerHandshaker { state: ReadMsg1"));
+    assert!(!debug.contains(&format!("{:?}", SERVER_EPH_SEC.0)));
+}
+
+#[test]
+// Aborting returns the stream of in-flight handshakes, and nothing once they
+// terminated.
+fn abort_handshakers() {
+    use futures::future::poll_fn;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    // The client fills the ring buffer with the start of msg1, the server
+    // reads it and waits for more.
+    block_on(poll_fn(|cx| {
+                         assert!(client.poll(cx).unwrap().is_pending());
+                         assert!(server.poll(cx).unwrap().is_pending());
+                         Ok::<_, ()>(Async::Ready(()))
+                     }))
+            .unwrap();
+
+    let (_, client_stage) = client.abort().unwrap();
+    let (_, server_stage) = server.abort().unwrap();
+    assert_eq!(client_stage, Stage::Msg1);
+    assert_eq!(server_stage, Stage::Msg1);
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    assert!(block_on((&mut client).join(&mut server)).is_ok());
+    assert!(client.abort().is_none());
+    assert!(server.abort().is_none());
+}
+
+#[test]
+// The streams recovered from unpolled handshakers can be used for another
+// handshake.
+fn into_inner_handshakers() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut client = ClientHandshakerWithFilter::new(client_duplex,
+                                                     |_: &sign::PublicKey| ok::<bool, ()>(true),
+                                                     &APP,
+                                                     &CLIENT_PUB,
+                                                     &CLIENT_SEC,
+                                                     &CLIENT_EPH_PUB,
+                                                     &CLIENT_EPH_SEC,
+                                                     &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    let client_ptr = client.get_ref() as *const _;
+    assert_eq!(client.get_mut() as *mut _ as *const _, client_ptr);
+    let server_ptr = server.get_ref() as *const _;
+    assert_eq!(server.get_mut() as *mut _ as *const _, server_ptr);
+
+    let client = ClientHandshaker::new(client.into_inner(),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server.into_inner(),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+    assert_eq!(client_outcome.send_params().key.0, EXP_CLIENT_ENC_KEY.0);
+    assert_eq!(server_outcome.send_params().key.0, EXP_SERVER_ENC_KEY.0);
+}
+
+#[test]
+// An acceptor with all handshake slots taken and a full queue stops
+// accepting, and resumes once a handshake fails.
+fn acceptor_backpressure() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use futures::future::poll_fn;
+    use acceptor::Acceptor;
+
+    let addr = "127.0.0.1:8008".parse().unwrap();
+    let mut clients = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..3 {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+        clients.push(Duplex::new(reader_a, writer_b));
+        servers.push((Duplex::new(reader_b, writer_a), addr));
+    }
+
+    let accepted = Rc::new(Cell::new(0));
+    let counter = accepted.clone();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(servers)
+        .inspect(move |_| counter.set(counter.get() + 1));
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+
+    let mut acceptor = Acceptor::new(incoming, identity);
+    acceptor.set_max_handshakes(1);
+    acceptor.set_max_queued(1);
+
+    let mut poll_acceptor = || {
+        block_on(poll_fn(|cx| {
+                             assert!(acceptor.poll_next(cx).unwrap().is_pending());
+                             Ok::<_, ()>(Async::Ready(()))
+                         }))
+                .unwrap()
+    };
+
+    poll_acceptor();
+    assert_eq!(accepted.get(), 2);
+
+    // The first handshake fails as its client disconnects.
+    clients.remove(0);
+    poll_acceptor();
+    assert_eq!(accepted.get(), 3);
+}
+
+#[test]
+// A server fails once the client needs too many reads for a message.
+fn server_budget_exceeded() {
+    use budget::Budget;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+
+    // The ring buffer delivers msg1 in 32 reads.
+    let mut budget = Budget::unlimited();
+    budget.max_reads_per_message = Some(8);
+    server.set_budget(budget);
+
+    // Drop the streams of failed handshakes, so that the peer sees the
+    // connection closing.
+    let client = client.map(|_| ()).map_err(|(err, _)| err);
+    let server = server.map(|_| ()).map_err(|(err, _)| err);
+
+    let (client_result, server_result) = block_on(client.then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+
+    match server_result {
+        Err(errors::HandshakeError::BudgetExceeded) => {}
+        _ => panic!("expected the budget to be exceeded"),
+    }
+    assert!(client_result.is_err());
+}
+
+#[test]
+// The admission hook rejects connections before the handshake, and delayed
+// connections are handshaked once their delay elapsed.
+fn acceptor_admission() {
+    use std::net::SocketAddr;
+    use acceptor::{Acceptor, Admission};
+    use audit::{self, AuditResult};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let (_, unused_reader) = ring_buffer(2);
+    let (unused_writer, _) = ring_buffer(2);
+    let denied_duplex = Duplex::new(unused_reader, unused_writer);
+
+    let allowed: SocketAddr = "127.0.0.1:8008".parse().unwrap();
+    let denied: SocketAddr = "10.0.0.1:8008".parse().unwrap();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(denied_duplex, denied),
+                                                                 (server_duplex, allowed)]);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let (sender, events) = audit::channel(4);
+
+    let mut acceptor = Acceptor::new(incoming, identity);
+    acceptor.set_audit(sender);
+    acceptor.set_admission(move |addr: &SocketAddr| if *addr == denied {
+                               Admission::Reject
+                           } else {
+                               Admission::Delay(Box::new(ok(())))
+                           });
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+
+    let (client_result, accepted) =
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(acceptor.collect().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_ok());
+    assert_eq!(accepted.unwrap().len(), 1);
+
+    let events = block_on(events.collect()).unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].addr, denied);
+    assert_eq!(events[0].result, AuditResult::NotAdmitted);
+    assert_eq!(events[1].addr, allowed);
+    assert_eq!(events[1].result, AuditResult::Accepted);
+}
+
+#[test]
+// Repeated bans of the same address last longer with escalation.
+fn rate_limiter_escalates_bans() {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use acceptor::Admission;
+    use rate_limit::RateLimiter;
+
+    let limiter = RateLimiter::new(1, Duration::from_secs(60), Duration::from_millis(100));
+    limiter.set_escalation(Duration::from_secs(60), Duration::from_secs(60));
+    let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    limiter.record_failure(addr, None);
+    match limiter.admission(&SocketAddr::new(addr, 8008)) {
+        Admission::Reject => {}
+        _ => panic!("expected the address to be rejected"),
+    }
+    sleep(Duration::from_millis(150));
+    assert!(limiter.is_addr_allowed(&addr));
+
+    // The second ban lasts 200 milliseconds.
+    limiter.record_failure(addr, None);
+    sleep(Duration::from_millis(150));
+    assert!(!limiter.is_addr_allowed(&addr));
+}
+
+#[test]
+// A decoy server replies to msg1 of another network with data that the
+// client can not tell from an invalid msg2.
+fn server_decoy() {
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let other_network = [0u8; NETWORK_IDENTIFIER_BYTES];
+    let client = ClientHandshaker::new(client_duplex,
+                                       &other_network,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+    server.set_decoy(true);
+
+    let (client_result, server_result) = block_on(client.then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+
+    match client_result {
+        Err((errors::HandshakeError::CryptoError, _)) => {}
+        _ => panic!("expected the decoy to be rejected as an invalid msg2"),
+    }
+    match server_result {
+        Err((errors::HandshakeError::CryptoError, _)) => {}
+        _ => panic!("expected the server to fail on the invalid msg1"),
+    }
+}
+
+#[test]
+// Connections accepted after replacing the identity of an acceptor use the
+// new identity.
+fn acceptor_identity_rotation() {
+    use acceptor::Acceptor;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let addr = "127.0.0.1:8008".parse().unwrap();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+
+    let acceptor = Acceptor::new(incoming, identity);
+    let (new_pk, new_sk) = sign::gen_keypair();
+    let old = acceptor.identity_handle().store(Identity::new(APP, new_pk, new_sk));
+    assert_eq!(old.longterm_pk(), &SERVER_PUB);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &new_pk);
+
+    let (client_result, accepted) =
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(acceptor.collect().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_ok());
+    assert_eq!(accepted.unwrap().len(), 1);
+}
+
+#[test]
+// A shared key filter decides by the key set it holds at the time.
+fn shared_key_filter_replace() {
+    use filter::{KeySet, SharedKeyFilter};
+
+    let keys = SharedKeyFilter::new(KeySet::allow(vec![CLIENT_PUB]));
+    let filter = keys.clone();
+    assert!(block_on(filter.filter(&CLIENT_PUB)).unwrap());
+    assert!(!block_on(filter.filter(&SERVER_PUB)).unwrap());
+
+    let previous = keys.replace(KeySet::deny(vec![CLIENT_PUB]));
+    assert_eq!(*previous, KeySet::allow(vec![CLIENT_PUB]));
+    assert!(!block_on(filter.filter(&CLIENT_PUB)).unwrap());
+    assert!(block_on(filter.filter(&SERVER_PUB)).unwrap());
+}
+
+#[test]
+// Handshakes complete when their crypto steps are run as jobs on other
+// threads.
+fn offload_crypto() {
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, ThreadId};
+    use offload::Offload;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    // Runs every job on a new thread, and records the threads.
+    fn threaded_offload(threads: Arc<Mutex<Vec<ThreadId>>>) -> Offload {
+        Offload::new(move |job| {
+            let threads = threads.clone();
+            thread::spawn(move || {
+                                threads.lock().unwrap().push(thread::current().id());
+                                job.run();
+                            });
+        })
+    }
+
+    let client_threads = Arc::new(Mutex::new(Vec::new()));
+    let server_threads = Arc::new(Mutex::new(Vec::new()));
+
+    let mut client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+    client.set_offload(threaded_offload(client_threads.clone()));
+    server.set_offload(threaded_offload(server_threads.clone()));
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+    assert_eq!(client_outcome.send_params().key.0, EXP_CLIENT_ENC_KEY.0);
+    assert_eq!(server_outcome.send_params().key.0, EXP_SERVER_ENC_KEY.0);
+
+    // The client verifies msg2 and msg4, the server verifies msg1 and msg3.
+    let client_threads = client_threads.lock().unwrap();
+    let server_threads = server_threads.lock().unwrap();
+    assert_eq!(client_threads.len(), 2);
+    assert_eq!(server_threads.len(), 2);
+    let current = thread::current().id();
+    assert!(client_threads.iter().chain(server_threads.iter()).all(|id| *id != current));
+}
+
+#[test]
+// A handshake fails with an io error if the offload drops a job instead of
+// running it.
+fn offload_drops_job() {
+    use offload::Offload;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let mut server = ServerHandshaker::new(server_duplex,
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+    server.set_offload(Offload::new(|job| drop(job)));
+
+    match block_on(client.select(server)) {
+        Err(((errors::HandshakeError::IoError(_), _), _)) => {}
+        _ => panic!("expected the server to fail with an io error"),
+    }
+}
+
+#[test]
+// An admission controller is consulted once msg1 is verified and once the
+// client revealed its key, and can reject the client at that point.
+fn acceptor_admission_controller() {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use acceptor::{Acceptor, AdmissionController};
+    use audit::{self, AuditResult, FailureReason};
+
+    #[derive(Clone, Default)]
+    struct Denylist(Arc<Mutex<Vec<&'static str>>>);
+
+    impl AdmissionController for Denylist {
+        fn msg1_verified(&mut self, _: &SocketAddr) -> bool {
+            self.0.lock().unwrap().push("msg1");
+            true
+        }
+
+        fn client_key_revealed(&mut self, _: &SocketAddr, pk: &sign::PublicKey) -> bool {
+            self.0.lock().unwrap().push("key");
+            *pk != CLIENT_PUB
+        }
+    }
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let addr: SocketAddr = "127.0.0.1:8008".parse().unwrap();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let (sender, events) = audit::channel(4);
+    let controller = Denylist::default();
+
+    let mut acceptor = Acceptor::new(incoming, identity);
+    acceptor.set_audit(sender);
+    acceptor.set_admission_controller(controller.clone());
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+
+    let (client_result, accepted) =
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(acceptor.collect().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_err());
+    assert_eq!(accepted.unwrap().len(), 0);
+    assert_eq!(*controller.0.lock().unwrap(), vec!["msg1", "key"]);
+
+    let events = block_on(events.collect()).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].result, AuditResult::Failed(FailureReason::Rejected));
+}
+
+#[test]
+#[cfg(feature = "secret-stream")]
+// Concurrent requests for the same peer share a single connection.
+fn peer_pool_deduplicates() {
+    use std::cell::RefCell;
+    use errors::ConnectError;
+    use peer_pool::PeerPool;
+    use secret_stream::accept;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let server_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+
+    let duplexes = RefCell::new(vec![client_duplex]);
+    let pool = PeerPool::new(|peer: &sign::PublicKey| {
+        OwningClientHandshaker::new(duplexes.borrow_mut().pop().unwrap(),
+                                    *client_identity.network_identifier(),
+                                    client_identity.longterm_pk().clone(),
+                                    client_identity.longterm_sk().clone(),
+                                    CLIENT_EPH_PUB.clone(),
+                                    CLIENT_EPH_SEC.clone(),
+                                    peer.clone())
+                .map_err(|(err, _)| ConnectError::Handshake(err))
+    });
+    assert!(pool.get(&SERVER_PUB).is_none());
+
+    let ((first, second), _) = block_on(pool.connect(&SERVER_PUB)
+                                            .join(pool.connect(&SERVER_PUB))
+                                            .join(accept(server_duplex, &server_identity)
+                                                      .map_err(|(err, _)| {
+                                                                   ConnectError::Handshake(err)
+                                                               })))
+            .ok()
+            .unwrap();
+    assert!(first.ptr_eq(&second));
+    assert_eq!(first.peer_longterm_pk(), SERVER_PUB);
+    assert!(pool.get(&SERVER_PUB).unwrap().ptr_eq(&first));
+    assert!(duplexes.borrow().is_empty());
+
+    assert!(pool.disconnect(&SERVER_PUB).unwrap().ptr_eq(&first));
+    assert!(pool.get(&SERVER_PUB).is_none());
+}
+
+#[test]
+// A batch dial yields a result for each target, including unreachable ones.
+fn batch_dial_yields_each_target() {
+    use batch::BatchDial;
+    use errors::ConnectError;
+    use futures::Never;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let mut client_duplex = Some(Duplex::new(reader_a, writer_b));
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let dial = move |addr: &usize| if *addr == 0 {
+        err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+    } else {
+        ok(client_duplex.take().unwrap())
+    };
+
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let targets = vec![(0, SERVER_PUB.clone()), (1, SERVER_PUB.clone())];
+    let batch = BatchDial::new(targets, &identity, 1, dial);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (results, server_result) =
+        block_on(batch.collect().join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+
+    assert_eq!(results.len(), 2);
+    match results[0] {
+        (0, _, Err(ConnectError::IoError(ref err))) => {
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused)
+        }
+        _ => panic!("the unreachable target did not fail"),
+    }
+    match results[1] {
+        (1, _, Ok((ref outcome, _))) => assert_eq!(outcome.peer_longterm_pk(), SERVER_PUB),
+        _ => panic!("the reachable target failed"),
+    }
+    assert!(server_result.is_ok());
+}
+
+#[test]
+// Any function from addresses to streams can serve as a transport. The
+// connection stays pending once it completed or failed.
+fn connect_via_transport() {
+    use futures::Never;
+    use std::net::SocketAddr;
+    use proxy::Proxy;
+    use transport::{connect, Proxied, Transport};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let mut client_duplex = Some(Duplex::new(reader_a, writer_b));
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let mut dialed = Vec::new();
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let mut client = {
+        let mut transport = |addr: &str| {
+            dialed.push(addr.to_string());
+            ok::<_, io::Error>(client_duplex.take().unwrap())
+        };
+        connect(&mut transport, "memory", &identity, &SERVER_PUB)
+    };
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (client_result, server_result) =
+        block_on((&mut client)
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+
+    assert_eq!(client_result.ok().unwrap().0.peer_longterm_pk(), SERVER_PUB);
+    assert!(server_result.is_ok());
+    assert!(is_pending(&mut client));
+    assert_eq!(dialed, vec!["memory".to_string()]);
+
+    fn refuse<A: ?Sized>(_: &A) -> FutureResult<Duplex<Reader, Writer>, io::Error> {
+        err(io::Error::new(io::ErrorKind::ConnectionRefused, "unreachable"))
+    }
+
+    let mut transport = refuse::<str>;
+    let mut client = connect(&mut transport, "memory", &identity, &SERVER_PUB);
+    match block_on(&mut client) {
+        Err(errors::ConnectError::IoError(_)) => {}
+        _ => panic!("the connection did not fail"),
+    }
+    assert!(is_pending(&mut client));
+
+    let proxy = Proxy::Socks5("127.0.0.1:9050".parse().unwrap());
+    let mut proxied = Proxied::new(refuse::<SocketAddr>, proxy);
+    let mut tunneling = proxied.connect(&("example.onion".to_string(), 8008));
+    assert!(block_on(&mut tunneling).is_err());
+    assert!(is_pending(&mut tunneling));
+}
+
+#[test]
+#[cfg(feature = "test-utils")]
+// A handshake completes over a shaped stream, which waits before each transfer.
+fn shaped_stream_handshake() {
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use test_utils::{Shaped, Shaping};
+
+    let (writer_a, reader_a) = ring_buffer(64);
+    let (writer_b, reader_b) = ring_buffer(64);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let delays = RefCell::new(Vec::new());
+    let mut shaping = Shaping::default();
+    shaping.latency = Duration::from_millis(10);
+    shaping.bytes_per_second = Some(1000);
+    shaping.max_chunk = Some(16);
+    let client_stream = Shaped::new(client_duplex, shaping, |duration| {
+        delays.borrow_mut().push(duration);
+        ok::<(), ()>(())
+    });
+
+    let client = ClientHandshaker::new(client_stream,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (client_result, server_result) = block_on(client.then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    assert!(client_result.is_ok());
+    assert!(server_result.is_ok());
+
+    // Each write of the client transfers at most 16 bytes, which take 16
+    // milliseconds at the bandwidth limit.
+    let delays = delays.borrow();
+    assert_eq!(delays[0], Duration::from_millis(10));
+    assert!(delays.contains(&Duration::from_millis(26)));
+}
+
+#[test]
+// A lan announcement yields the key and the dialable addresses of the peer.
+fn lan_announcement() {
+    use lan::{Announcement, AnnouncementError};
+
+    let packet = b"net:192.168.1.23:8008~shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=;ws:foo\n";
+    let announcement = Announcement::parse(packet).unwrap();
+    assert_eq!(announcement.server_longterm_pk, SERVER_PUB);
+    assert_eq!(announcement.addresses.len(), 1);
+    assert_eq!(announcement.net(), Some(("192.168.1.23", 8008)));
+    assert_eq!(Announcement::parse(&announcement.to_packet()).unwrap(), announcement);
+
+    assert_eq!(Announcement::parse(b"net:192.168.1.23:8008"),
+               Err(AnnouncementError::NoKey));
+    let conflicting = format!("net:a:1~shs:{};net:b:1~shs:{}",
+                              ::base64::encode(&SERVER_PUB.0),
+                              ::base64::encode(&CLIENT_PUB.0));
+    assert_eq!(Announcement::parse(conflicting.as_bytes()),
+               Err(AnnouncementError::ConflictingKeys));
+}
+
+#[test]
+// An invite code yields a handshake under the keypair of its seed.
+fn invite_handshake() {
+    use invite::{Invite, InviteError};
+
+    let code = format!("example.com:8008:@{}.ed25519~{}",
+                       ::base64::encode(&SERVER_PUB.0),
+                       ::base64::encode(&[7u8; 32]));
+    let invite: Invite = code.parse().unwrap();
+    assert_eq!(invite.host, "example.com");
+    assert_eq!(invite.port, 8008);
+    assert_eq!(invite.server_longterm_pk, SERVER_PUB);
+    assert_eq!(invite.to_string(), code);
+    assert_eq!("example.com:8008".parse::<Invite>(), Err(InviteError::Malformed));
+    let short_seed = format!("example.com:8008:@{}.ed25519~{}",
+                             ::base64::encode(&SERVER_PUB.0),
+                             ::base64::encode(&[7u8; 31]));
+    assert_eq!(short_seed.parse::<Invite>(), Err(InviteError::InvalidSeed));
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let client = invite.handshake(client_duplex, APP);
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let (client_result, server_result) = block_on(client.then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    assert!(client_result.is_ok());
+    assert_eq!(server_result.ok().unwrap().0.peer_longterm_pk(),
+               *invite.identity(APP).longterm_pk());
+}
+
+#[test]
+// Network identifiers derived from names are prefixed hashes of the names.
+fn network_identifier_from_names() {
+    use sodiumoxide::crypto::hash::sha256;
+
+    let network_identifier = network_identifier_from_name(b"my private network");
+    assert_eq!(network_identifier,
+               sha256::hash(b"shs1-network-name:my private network").0);
+    assert!(network_identifier != network_identifier_from_name(b"my other network"));
+
+    let identity = Identity::with_network_name(b"my private network",
+                                               CLIENT_PUB.clone(),
+                                               CLIENT_SEC.clone());
+    assert_eq!(*identity.network_identifier(), network_identifier);
+}
+
+#[test]
+// Network identifiers derived from a passphrase depend on the passphrase and the salt.
+fn network_identifier_from_passphrase() {
+    use identity::PassphraseParams;
+
+    let mut params = PassphraseParams::interactive();
+    let first = NetworkIdentifier::from_passphrase(b"correct horse", &params).unwrap();
+    assert_eq!(NetworkIdentifier::from_passphrase(b"correct horse", &params),
+               Some(first));
+    assert!(NetworkIdentifier::from_passphrase(b"battery staple", &params) != Some(first));
+
+    params.salt = b"my private network".to_vec();
+    assert!(NetworkIdentifier::from_passphrase(b"correct horse", &params) != Some(first));
+}
+
+#[test]
+// Key confirmation succeeds with matching outcomes, and detects mismatched ones.
+// Either way, it stays pending when polled again.
+fn key_confirmation() {
+    use confirm::confirm;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let ((client_outcome, _), (server_outcome, _)) =
+        block_on(client.map_err(|(err, _)| err).join(server.map_err(|(err, _)| err))).unwrap();
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let mut client_confirm = confirm(Duplex::new(reader_a, writer_b), &client_outcome);
+    let mut server_confirm = confirm(Duplex::new(reader_b, writer_a), &server_outcome);
+    let (client_result, server_result) =
+        block_on((&mut client_confirm)
+                     .then(|r| ok::<_, ()>(r))
+                     .join((&mut server_confirm).then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    assert!(client_result.is_ok());
+    assert!(server_result.is_ok());
+    assert!(is_pending(&mut client_confirm));
+    assert!(is_pending(&mut server_confirm));
+
+    // The server confirms with the outcome of the client.
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let mut client_confirm = confirm(Duplex::new(reader_a, writer_b), &client_outcome);
+    let (client_result, server_result) =
+        block_on((&mut client_confirm)
+                     .then(|r| ok::<_, ()>(r))
+                     .join(confirm(Duplex::new(reader_b, writer_a), &client_outcome)
+                               .then(|r| ok::<_, ()>(r))))
+                .unwrap();
+    match (client_result, server_result) {
+        (Err((errors::HandshakeError::CryptoError, _)),
+         Err((errors::HandshakeError::CryptoError, _))) => {}
+        _ => panic!("expected both confirmations to fail"),
+    }
+    assert!(is_pending(&mut client_confirm));
+}
+
+#[test]
+// An obfuscated handshake succeeds, and leaves the streams at the end of the
+// last frame.
+fn obfuscated_handshake() {
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use obfuscate::{Obfuscated, Obfuscation};
+
+    let (writer_a, reader_a) = ring_buffer(64);
+    let (writer_b, reader_b) = ring_buffer(64);
+
+    let obfuscation = Obfuscation {
+        max_padding: 300,
+        max_jitter: Some(Duration::from_millis(50)),
+    };
+    let delays = RefCell::new(Vec::new());
+    let client_stream = Obfuscated::client(Duplex::new(reader_a, writer_b),
+                                           APP,
+                                           obfuscation,
+                                           |duration| {
+                                               delays.borrow_mut().push(duration);
+                                               ok::<(), ()>(())
+                                           });
+    let server_stream = Obfuscated::server(Duplex::new(reader_b, writer_a),
+                                           APP,
+                                           obfuscation,
+                                           |_| ok::<(), ()>(()));
+
+    let client = ClientHandshaker::new(client_stream,
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(server_stream,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let ((client_outcome, client_stream), (server_outcome, server_stream)) =
+        block_on(client.map_err(|(err, _)| err).join(server.map_err(|(err, _)| err))).unwrap();
+    assert_eq!(client_outcome.send_params().key.0,
+               server_outcome.recv_params().key.0);
+
+    // The client sends two frames, each after a jitter of at most 50 ms.
+    {
+        let delays = delays.borrow();
+        assert_eq!(delays.len(), 2);
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_millis(50)));
+    }
+
+    let client_stream = client_stream.into_inner();
+    let server_stream = server_stream.into_inner();
+    let (_, written) = block_on(client_stream.write_all(vec![1, 2, 3])).unwrap();
+    let (_, read) = block_on(server_stream.read_exact(vec![0u8; 3])).unwrap();
+    assert_eq!(written, read);
+}
+
+#[test]
+// The fields of the messages are labelled in order, and cut off at the end of
+// the bytes.
+fn wire_annotation() {
+    use stage::Stage;
+    use wire::{annotate, Direction, FieldKind};
+
+    let fields = annotate(Direction::ClientToServer, &[0; 180]);
+    let layout: Vec<_> = fields
+        .iter()
+        .map(|field| (field.message, field.kind, field.range.clone()))
+        .collect();
+    assert_eq!(layout,
+               vec![(Stage::Msg1, FieldKind::Hmac, 0..32),
+                    (Stage::Msg1, FieldKind::EphemeralPk, 32..64),
+                    (Stage::Msg3, FieldKind::Mac, 64..80),
+                    (Stage::Msg3, FieldKind::EncryptedSignature, 80..144),
+                    (Stage::Msg3, FieldKind::EncryptedLongtermPk, 144..176)]);
+
+    let fields = annotate(Direction::ServerToClient, &[0; 70]);
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[2].message, Stage::Msg4);
+    assert_eq!(fields[2].kind, FieldKind::Mac);
+    assert_eq!(fields[2].range, 64..70);
+
+    assert!(annotate(Direction::ServerToClient, &[]).is_empty());
+}
+
+#[test]
+// An authentication-only handshake yields the keys of both peers.
+fn auth_only_handshake() {
+    use auth_only::auth_only;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let ((client_authenticated, _), (server_authenticated, _)) =
+        block_on(auth_only(client)
+                     .map_err(|(err, _)| err)
+                     .join(auth_only(server).map_err(|(err, _)| err)))
+                .unwrap();
+
+    assert_eq!(client_authenticated,
+               Authenticated {
+                   peer_longterm_pk: SERVER_PUB,
+                   local_longterm_pk: CLIENT_PUB,
+               });
+    assert_eq!(server_authenticated.peer_longterm_pk, CLIENT_PUB);
+    assert_eq!(server_authenticated.local_longterm_pk, SERVER_PUB);
+}
+
+#[test]
+#[cfg(feature = "session-tickets")]
+// A client resumes a session with a ticket issued after a full handshake, and
+// both peers derive matching keys.
+fn session_ticket_resumption() {
+    use std::time::Duration;
+    use futures::future::poll_fn;
+    use tickets::{accept_resumption, resume, ClientTicket, TicketKey};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let ((client_outcome, _), (server_outcome, _)) =
+        block_on(client.map_err(|(err, _)| err).join(server.map_err(|(err, _)| err))).unwrap();
+
+    let ticket_key = TicketKey::generate();
+    let identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+    let ticket = ClientTicket::new(ticket_key.issue(&server_outcome, Duration::from_secs(60)),
+                                   &client_outcome);
+    assert_eq!(ticket.server_longterm_pk(), &SERVER_PUB);
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let mut resuming = resume(Duplex::new(reader_a, writer_b), &ticket);
+    let mut accepting = accept_resumption(Duplex::new(reader_b, writer_a), &ticket_key, &identity);
+    let ((client_outcome, _), (server_outcome, _)) =
+        block_on((&mut resuming)
+                     .map_err(|(err, _)| err)
+                     .join((&mut accepting).map_err(|(err, _)| err)))
+                .unwrap();
+    assert_eq!(client_outcome.send_params().key.0,
+               server_outcome.recv_params().key.0);
+    assert_eq!(client_outcome.recv_params().key.0,
+               server_outcome.send_params().key.0);
+    assert_eq!(client_outcome.send_params().nonce.0,
+               server_outcome.recv_params().nonce.0);
+    assert_eq!(server_outcome.peer_longterm_pk(), CLIENT_PUB);
+    assert_eq!(client_outcome.peer_longterm_pk(), SERVER_PUB);
+
+    // Polling after completion neither panics nor completes again.
+    let pending = block_on(poll_fn(|cx| {
+        let resuming_pending = match resuming.poll(cx) {
+            Ok(Async::Pending) => true,
+            _ => false,
+        };
+        let accepting_pending = match accepting.poll(cx) {
+            Ok(Async::Pending) => true,
+            _ => false,
+        };
+        Ok::<_, ()>(Async::Ready(resuming_pending && accepting_pending))
+    }));
+    assert!(pending.unwrap());
+
+    // A server with another ticket key rejects the ticket.
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    match block_on(resume(Duplex::new(reader_a, writer_b), &ticket)
+                       .select(accept_resumption(Duplex::new(reader_b, writer_a),
+                                                 &TicketKey::generate(),
+                                                 &identity))) {
+        Err(((errors::HandshakeError::CryptoError, _), _)) => {}
+        _ => panic!("expected the server to reject the ticket"),
+    }
+}
+
+#[test]
+// A client resolves the server key from an alias after receiving msg2, and
+// fails if the alias is unknown.
+fn client_resolves_server_key() {
+    use std::cell::Cell;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let resolved = Cell::new(false);
+    let client = OwningClientHandshakerWithResolver::new(Duplex::new(reader_a, writer_b),
+                                                         "pub",
+                                                         |alias: &&str| {
+                                                             assert_eq!(*alias, "pub");
+                                                             resolved.set(true);
+                                                             ok::<_, ()>(Some(SERVER_PUB.clone()))
+                                                         },
+                                                         APP,
+                                                         CLIENT_PUB.clone(),
+                                                         CLIENT_SEC.clone(),
+                                                         CLIENT_EPH_PUB.clone(),
+                                                         CLIENT_EPH_SEC.clone());
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let (client_result, server_result) = block_on(client.then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    let (client_outcome, _) = client_result.ok().unwrap();
+    let (server_outcome, _) = server_result.ok().unwrap();
+    assert!(resolved.get());
+    assert_eq!(client_outcome.peer_longterm_pk(), SERVER_PUB);
+    assert_eq!(server_outcome.peer_longterm_pk(), CLIENT_PUB);
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = OwningClientHandshakerWithResolver::new(Duplex::new(reader_a, writer_b),
+                                                         "unknown",
+                                                         |_: &&str| ok::<_, ()>(None),
+                                                         APP,
+                                                         CLIENT_PUB.clone(),
+                                                         CLIENT_SEC.clone(),
+                                                         CLIENT_EPH_PUB.clone(),
+                                                         CLIENT_EPH_SEC.clone());
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    // Dropping the stream of the client ends the handshake of the server.
+    let (client_result, server_result) = block_on(client.map_err(|(err, _)| err)
+                                                      .then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    match client_result {
+        Err(errors::FilteringHandshakeError::Rejected) => {}
+        _ => panic!("expected the client to reject the unknown alias"),
+    }
+    assert!(server_result.is_err());
+}
+
+#[test]
+// A server with a replay cache rejects a client hello it has already seen.
+fn replay_cache_rejects_replayed_msg1() {
+    use std::time::Duration;
+    use replay::ReplayCache;
+
+    let cache = ReplayCache::new(16, Duration::from_secs(60));
+
+    for replay in vec![false, true] {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+        let mut server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                               &APP,
+                                               &SERVER_PUB,
+                                               &SERVER_SEC,
+                                               &SERVER_EPH_PUB,
+                                               &SERVER_EPH_SEC);
+        server.set_replay_cache(cache.clone());
+
+        // Dropping the stream of a failed server ends the handshake of the
+        // client.
+        let (client_result, server_result) =
+            block_on(client.map_err(|(err, _)| err)
+                         .then(|r| ok::<_, ()>(r))
+                         .join(server.map_err(|(err, _)| err).then(|r| ok::<_, ()>(r))))
+                    .unwrap();
+        if replay {
+            assert!(client_result.is_err());
+            match server_result {
+                Err(errors::HandshakeError::CryptoError) => {}
+                _ => panic!("expected the replayed msg1 to be rejected"),
+            }
+        } else {
+            assert!(client_result.is_ok());
+            assert!(server_result.is_ok());
+        }
+    }
+
+    assert_eq!(cache.hits(), 1);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+// An outcome splits into halves holding the keys of each direction.
+fn split_outcome() {
+    let (client_reader, server_writer) = ring_buffer(2);
+    let (server_reader, client_writer) = ring_buffer(2);
+    let client_duplex = Duplex::new(client_reader, client_writer);
+    let server_duplex = Duplex::new(server_reader, server_writer);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP.clone(),
+                                       &CLIENT_PUB.clone(),
+                                       &CLIENT_SEC.clone(),
+                                       &CLIENT_EPH_PUB.clone(),
+                                       &CLIENT_EPH_SEC.clone(),
+                                       &SERVER_PUB.clone());
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP.clone(),
+                                       &SERVER_PUB.clone(),
+                                       &SERVER_SEC.clone(),
+                                       &SERVER_EPH_PUB.clone(),
+                                       &SERVER_EPH_SEC.clone());
+
+    let ((client_outcome, _), (server_outcome, _)) =
+        block_on(client.join(server)).ok().unwrap();
+
+    let (client_send, client_recv) = client_outcome.split();
+    let (server_send, server_recv) = server_outcome.split();
+
+    assert_eq!(client_send.peer_longterm_pk(), SERVER_PUB.clone());
+    assert_eq!(client_recv.peer_longterm_pk(), SERVER_PUB.clone());
+    assert_eq!(server_send.peer_longterm_pk(), CLIENT_PUB.clone());
+
+    assert_eq!(client_send.params().key, server_recv.params().key);
+    assert_eq!(client_send.params().nonce, server_recv.params().nonce);
+
+    let params = server_send.into_params();
+    assert_eq!(params.key, client_recv.params().key);
+    assert_eq!(params.nonce, client_recv.params().nonce);
+}
+
+#[test]
+// The peer id of an outcome is the ssb feed id of the peer's longterm key.
+fn outcome_peer_id() {
+    let (client_reader, server_writer) = ring_buffer(2);
+    let (server_reader, client_writer) = ring_buffer(2);
+    let client_duplex = Duplex::new(client_reader, client_writer);
+    let server_duplex = Duplex::new(server_reader, server_writer);
+
+    let client = ClientHandshaker::new(client_duplex,
+                                       &APP.clone(),
+                                       &CLIENT_PUB.clone(),
+                                       &CLIENT_SEC.clone(),
+                                       &CLIENT_EPH_PUB.clone(),
+                                       &CLIENT_EPH_SEC.clone(),
+                                       &SERVER_PUB.clone());
+    let server = ServerHandshaker::new(server_duplex,
+                                       &APP.clone(),
+                                       &SERVER_PUB.clone(),
+                                       &SERVER_SEC.clone(),
+                                       &SERVER_EPH_PUB.clone(),
+                                       &SERVER_EPH_SEC.clone());
+
+    let (_, (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+
+    assert_eq!(server_outcome.peer_id().longterm_pk(), CLIENT_PUB.clone());
+    assert_eq!(server_outcome.peer_id_string(),
+               "@4aJJiEl3XlTQZul4Fy7h9cZPsACX0EaSbxdeZRnAHiM=.ed25519");
+}
+
+#[test]
+// A server state accepts a client using an alternative network identifier
+// and addressing an alternative longterm keypair.
+fn sans_io_alternative_identities() {
+    use sans_io::HandshakeState;
+
+    let other_app = [7u8; NETWORK_IDENTIFIER_BYTES];
+    let (old_pk, old_sk) = sign::gen_keypair();
+    let network_identifiers = [APP];
+    let longterm_keypairs = [(SERVER_PUB.clone(), SERVER_SEC.clone())];
+
+    let mut client = HandshakeState::client(&APP,
+                                            &CLIENT_PUB,
+                                            &CLIENT_SEC,
+                                            &CLIENT_EPH_PUB,
+                                            &CLIENT_EPH_SEC,
+                                            &SERVER_PUB);
+    let mut server = HandshakeState::server(&other_app,
+                                            &old_pk,
+                                            &old_sk,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC);
+    server.set_alternative_network_identifiers(&network_identifiers);
+    server.set_alternative_longterm_keypairs(&longterm_keypairs);
+
+    let mut buf = [0u8; MSG3_BYTES];
+    while !(client.is_done() && server.is_done()) {
+        let (from, to) = if client.wants_write() > 0 {
+            (&mut client, &mut server)
+        } else {
+            (&mut server, &mut client)
+        };
+        let len = from.write_message(&mut buf);
+        assert_eq!(to.read_message(&buf[..len]).unwrap(), len);
+    }
+
+    let server_outcome = server.outcome().unwrap();
+    assert_eq!(server_outcome.network_identifier(), APP);
+    assert_eq!(server_outcome.local_longterm_pk(), SERVER_PUB.clone());
+    assert_eq!(server_outcome.send().key, EXP_SERVER_ENC_KEY);
+    assert_eq!(client.outcome().unwrap().peer_longterm_pk(), SERVER_PUB.clone());
+}
+
+#[test]
+// A completion stream accepts written data right away, and resubmits the
+// rest of partial writes until flushed.
+fn completion_stream_partial_writes() {
+    use std::sync::{Arc, Mutex};
+    use futures::future::poll_fn;
+    use futures::task::Context;
+    use completion::{CompletionIo, CompletionStream};
+
+    // Writes at most 10 bytes per operation, each completing on its second
+    // poll.
+    struct MockIo(Arc<Mutex<Vec<u8>>>);
+
+    struct MockWrite {
+        buf: Option<Vec<u8>>,
+        written: Arc<Mutex<Vec<u8>>>,
+        polled: bool,
+    }
+
+    impl Future for MockWrite {
+        type Item = (Vec<u8>, usize);
+        type Error = io::Error;
+
+        fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+            if !self.polled {
+                self.polled = true;
+                cx.waker().wake();
+                return Ok(Async::Pending);
+            }
+
+            let buf = self.buf.take().unwrap();
+            let written = if buf.len() < 10 { buf.len() } else { 10 };
+            self.written.lock().unwrap().extend_from_slice(&buf[..written]);
+            Ok(Async::Ready((buf, written)))
+        }
+    }
+
+    impl CompletionIo for MockIo {
+        type Read = FutureResult<(Vec<u8>, usize), io::Error>;
+        type Write = MockWrite;
+
+        fn read(&self, buf: Vec<u8>) -> Self::Read {
+            ok((buf, 0))
+        }
+
+        fn write(&self, buf: Vec<u8>) -> MockWrite {
+            MockWrite {
+                buf: Some(buf),
+                written: self.0.clone(),
+                polled: false,
+            }
+        }
+    }
+
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let mut stream = CompletionStream::new(MockIo(written.clone()));
+    let data: Vec<u8> = (0..64).collect();
+
+    assert_eq!(block_on(poll_fn(|cx| stream.poll_write(cx, &data))).unwrap(), 64);
+    let mut stream = match stream.into_inner() {
+        Ok(_) => panic!("returned the connection during a write"),
+        Err(stream) => stream,
+    };
+
+    block_on(poll_fn(|cx| stream.poll_flush(cx))).unwrap();
+    assert_eq!(*written.lock().unwrap(), data);
+    assert!(stream.into_inner().is_ok());
+}
+
+#[test]
+// A tarpitting server discards bytes after an invalid msg1, and fails only
+// once it discarded them.
+fn tarpit_discards_before_failing() {
+    use futures::future::poll_fn;
+
+    let (writer_a, reader_a) = ring_buffer(128);
+    let (writer_b, _reader_b) = ring_buffer(128);
+
+    let mut server = ServerHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           &APP,
+                                           &SERVER_PUB,
+                                           &SERVER_SEC,
+                                           &SERVER_EPH_PUB,
+                                           &SERVER_EPH_SEC);
+    // With a maximum of one byte, the tarpit always discards exactly one.
+    server.set_tarpit(1);
+
+    let (writer_a, _) = block_on(writer_a.write_all([0u8; MSG1_BYTES])).ok().unwrap();
+    let pending = block_on(poll_fn(|cx| {
+                                       Ok::<_, ()>(Async::Ready(match server.poll(cx) {
+                                                                    Ok(Async::Pending) => true,
+                                                                    _ => false,
+                                                                }))
+                                   }));
+    assert!(pending.unwrap());
+
+    block_on(writer_a.write_all([0u8; 1])).ok().unwrap();
+    match block_on(server) {
+        Err((errors::HandshakeError::CryptoError, _)) => {}
+        _ => panic!("expected the server to fail with a crypto error"),
+    }
+}
+
+#[test]
+// A filter timeout decides by its `OnTimeout` once the delay resolved, but
+// the filter's decision wins if it arrives first.
+fn filter_timeout() {
+    use futures::future::empty;
+    use errors::FilterTimeoutError;
+    use filter::{FilterTimeout, OnTimeout};
+
+    let timed_out = FilterTimeout::new(empty::<bool, ()>(), ok::<(), ()>(()), OnTimeout::Reject);
+    match block_on(timed_out) {
+        Err(FilterTimeoutError::TimedOut) => {}
+        _ => panic!("expected the filter to time out"),
+    }
+    let timed_out = FilterTimeout::new(empty::<bool, ()>(), ok::<(), ()>(()), OnTimeout::Accept);
+    assert_eq!(block_on(timed_out).ok(), Some(true));
+
+    let decided = FilterTimeout::new(ok::<bool, ()>(false), empty::<(), ()>(), OnTimeout::Accept);
+    assert_eq!(block_on(decided).ok(), Some(false));
+    // The decision wins even if the delay resolved at the same time.
+    let decided = FilterTimeout::new(ok::<bool, ()>(true), ok::<(), ()>(()), OnTimeout::Reject);
+    assert_eq!(block_on(decided).ok(), Some(true));
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// While the attempt over the first address family stalls, a connection over
+// the second one is established, and the stalled attempt is abandoned.
+fn connect_happy_eyeballs() {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use futures::Never;
+    use futures::task::Context;
+    use tokio::net::{TcpListener, TcpStream};
+    use connect::{Dialing, connect_tcp};
+
+    // A connection attempt that never completes, and records being dropped.
+    struct Stalled(Arc<AtomicBool>);
+
+    impl Future for Stalled {
+        type Item = TcpStream;
+        type Error = io::Error;
+
+        fn poll(&mut self, _: &mut Context) -> Poll<Self::Item, Self::Error> {
+            Ok(Async::Pending)
+        }
+    }
+
+    impl Drop for Stalled {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn elapsed(_: Duration) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let ipv4 = listener.local_addr().unwrap();
+    let ipv6: SocketAddr = "[::1]:8008".parse().unwrap();
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+    let identity = Identity::new(APP, CLIENT_PUB, CLIENT_SEC);
+
+    let mut connect = connect_tcp(&[ipv6, ipv4][..], &identity, &SERVER_PUB);
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let stalled = abandoned.clone();
+    connect.set_dial(move |addr: &SocketAddr| if addr.is_ipv6() {
+                         Box::new(Stalled(stalled.clone())) as Dialing
+                     } else {
+                         Box::new(TcpStream::connect(addr))
+                     });
+    connect.set_attempt_delay(Duration::from_secs(0), elapsed);
+
+    let (client_result, server_result) =
+        block_on(connect
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    assert_eq!(client_result.ok().unwrap().0.peer_longterm_pk(), SERVER_PUB);
+    assert!(server_result.is_ok());
+    assert!(abandoned.load(Ordering::SeqCst));
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// The connect timeout bounds resolving and connecting, the handshake timeout
+// bounds the handshake over an established connection.
+fn connect_timeouts() {
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use futures::future::empty;
+    use connect::{Resolve, Resolving, connect_tcp, connect_tcp_with};
+    use errors::ConnectError;
+
+    struct Stalled;
+
+    impl Resolve for Stalled {
+        fn resolve(&self, _: &str, _: u16) -> Resolving {
+            Box::new(empty())
+        }
+    }
+
+    fn elapsed(_: Duration) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(ok(()))
+    }
+
+    fn never(_: Duration) -> Box<Future<Item = (), Error = ()> + Send> {
+        Box::new(empty())
+    }
+
+    let identity = Identity::new(APP, CLIENT_PUB, CLIENT_SEC);
+
+    let mut connect = connect_tcp_with("example.com", 8008, &Stalled, &identity, &SERVER_PUB);
+    connect.set_connect_timeout(Duration::from_secs(1), elapsed);
+    connect.set_handshake_timeout(Duration::from_secs(1), never);
+    match block_on(connect) {
+        Err(ConnectError::ConnectTimedOut) => {}
+        _ => panic!("expected connecting to time out"),
+    }
+
+    // The listener accepts the connection, but never replies.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut connect = connect_tcp(listener.local_addr().unwrap(), &identity, &SERVER_PUB);
+    connect.set_connect_timeout(Duration::from_secs(1), never);
+    connect.set_handshake_timeout(Duration::from_secs(1), elapsed);
+    match block_on(connect) {
+        Err(ConnectError::HandshakeTimedOut) => {}
+        _ => panic!("expected the handshake to time out"),
+    }
+}
+
+#[test]
+#[cfg(all(feature = "tokio", unix))]
+// A client connects to a server via a unix domain socket, and stays pending
+// once it completed or failed.
+fn connect_via_unix_socket() {
+    use std::{env, fs, process};
+    use futures::Never;
+    use tokio::net::unix::UnixListener;
+    use connect::connect_unix;
+
+    let dir = env::temp_dir().join(format!("shs-connect-unix-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("socket");
+    let _ = fs::remove_file(&path);
+
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+    let mut client = connect_unix(&path, &identity, &SERVER_PUB);
+
+    let (client_result, server_result) =
+        block_on((&mut client)
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    assert_eq!(client_result.ok().unwrap().0.peer_longterm_pk(), SERVER_PUB);
+    assert_eq!(server_result.ok().unwrap().0.peer_longterm_pk(), CLIENT_PUB);
+    assert!(is_pending(&mut client));
+
+    let mut client = connect_unix(dir.join("missing"), &identity, &SERVER_PUB);
+    match block_on(&mut client) {
+        Err(errors::ConnectError::IoError(_)) => {}
+        _ => panic!("connecting to a missing socket did not fail"),
+    }
+    assert!(is_pending(&mut client));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+// An acceptor records the rejected keys of clients with its rate limiter, and
+// refuses the key once it is banned, from any address.
+fn acceptor_rate_limiter_bans_rejected_keys() {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use futures::Never;
+    use acceptor::Acceptor;
+    use rate_limit::RateLimiter;
+
+    fn reject(_: &sign::PublicKey) -> FutureResult<bool, Never> {
+        ok(false)
+    }
+
+    fn accept(_: &sign::PublicKey) -> FutureResult<bool, Never> {
+        ok(true)
+    }
+
+    fn handshake(limiter: &RateLimiter,
+                 filter: fn(&sign::PublicKey) -> FutureResult<bool, Never>,
+                 addr: &str)
+                 -> bool {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client_duplex = Duplex::new(reader_a, writer_b);
+        let server_duplex = Duplex::new(reader_b, writer_a);
+
+        let addr: SocketAddr = addr.parse().unwrap();
+        let incoming = futures::stream::iter_ok::<_, io::Error>(vec![(server_duplex, addr)]);
+        let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+        let mut acceptor = Acceptor::with_filter(incoming, filter, identity);
+        acceptor.set_rate_limiter(limiter.clone());
+
+        let client = ClientHandshaker::new(client_duplex,
+                                           &APP,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           &SERVER_PUB);
+
+        let (client_result, _) = block_on(client
+                                              .then(|r| ok::<_, ()>(r))
+                                              .join(acceptor.next().then(|r| ok::<_, ()>(r))))
+                .unwrap();
+        client_result.is_ok()
+    }
+
+    let limiter = RateLimiter::new(2, Duration::from_secs(60), Duration::from_secs(60));
+
+    // Each address fails once, the key twice.
+    assert!(!handshake(&limiter, reject, "10.0.0.1:8008"));
+    assert!(limiter.is_key_allowed(&CLIENT_PUB));
+    assert!(!handshake(&limiter, reject, "10.0.0.2:8008"));
+    assert!(!limiter.is_key_allowed(&CLIENT_PUB));
+    assert!("10.0.0.1".parse().map(|ip| limiter.is_addr_allowed(&ip)).unwrap());
+
+    // The banned key is refused even by a filter that accepts it.
+    assert!(!handshake(&limiter, accept, "10.0.0.3:8008"));
+}
+
+#[test]
+// Metrics count the successful and failed handshakes of a server, and the
+// mean duration of the successful ones.
+fn metrics_counters() {
+    use std::time::Duration;
+    use metrics::{Metrics, MetricsSnapshot};
+
+    fn handshake(metrics: &Metrics, app: &[u8; auth::KEYBYTES], server_pk: &sign::PublicKey) {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+
+        let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                           app,
+                                           &CLIENT_PUB,
+                                           &CLIENT_SEC,
+                                           &CLIENT_EPH_PUB,
+                                           &CLIENT_EPH_SEC,
+                                           server_pk);
+        let mut server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                               &APP,
+                                               &SERVER_PUB,
+                                               &SERVER_SEC,
+                                               &SERVER_EPH_PUB,
+                                               &SERVER_EPH_SEC);
+        server.set_metrics(metrics.clone());
+
+        let _ = block_on(client.then(|r| ok::<_, ()>(r)).join(server.then(|r| ok::<_, ()>(r))));
+    }
+
+    let metrics = Metrics::new();
+    assert_eq!(metrics.snapshot().mean_duration(), None);
+
+    handshake(&metrics, &APP, &SERVER_PUB);
+    handshake(&metrics, &[0; auth::KEYBYTES], &SERVER_PUB);
+    let (other_pk, _) = sign::gen_keypair();
+    handshake(&metrics, &APP, &other_pk);
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.started, 3);
+    assert_eq!(snapshot.succeeded, 1);
+    assert_eq!(snapshot.invalid_msg1, 1);
+    assert_eq!(snapshot.invalid_msg3, 1);
+    assert_eq!(snapshot.failed(), 2);
+    assert_eq!(snapshot.mean_duration(), Some(snapshot.total_duration));
+
+    metrics.reset();
+    assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+
+    // The mean neither truncates nor divides by zero for large counts.
+    let snapshot = MetricsSnapshot {
+        succeeded: 1 << 32,
+        total_duration: Duration::from_secs(3 << 32),
+        ..MetricsSnapshot::default()
+    };
+    assert_eq!(snapshot.mean_duration(), Some(Duration::from_secs(3)));
+    let snapshot = MetricsSnapshot {
+        succeeded: 3,
+        total_duration: Duration::from_secs(1),
+        ..MetricsSnapshot::default()
+    };
+    assert_eq!(snapshot.mean_duration(), Some(Duration::new(0, 333333333)));
+}
+
+#[test]
+// After a graceful shutdown, an acceptor stops taking new connections, but
+// lets the in-flight handshakes finish before it terminates.
+fn acceptor_graceful_shutdown() {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use futures::future::poll_fn;
+    use acceptor::Acceptor;
+
+    let addr = "127.0.0.1:8008".parse().unwrap();
+    let mut clients = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+        clients.push(Duplex::new(reader_a, writer_b));
+        servers.push((Duplex::new(reader_b, writer_a), addr));
+    }
+
+    // Yields the connections pushed to the queue, pending while it is empty.
+    let queue = Rc::new(RefCell::new(VecDeque::new()));
+    let connections = queue.clone();
+    let incoming = futures::stream::poll_fn(move |_| -> Poll<Option<_>, io::Error> {
+        match connections.borrow_mut().pop_front() {
+            Some(connection) => Ok(Async::Ready(Some(connection))),
+            None => Ok(Async::Pending),
+        }
+    });
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+
+    let mut acceptor = Acceptor::new(incoming, identity);
+    let handle = acceptor.shutdown_handle();
+
+    // Start the handshake on the first connection.
+    queue.borrow_mut().push_back(servers.remove(0));
+    {
+        let mut accepting = poll_fn(|cx| acceptor.poll_next(cx));
+        assert!(is_pending(&mut accepting));
+    }
+    assert!(queue.borrow().is_empty());
+
+    handle.shutdown();
+    queue.borrow_mut().push_back(servers.remove(0));
+    let mut completion = handle.completion();
+    assert!(is_pending(&mut completion));
+    assert!(!handle.is_complete());
+
+    let client = ClientHandshaker::new(clients.remove(0),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let (client_result, accepted) = {
+        let accepting = poll_fn(|cx| acceptor.poll_next(cx));
+        block_on(client
+                     .then(|r| ok::<_, ()>(r))
+                     .join(accepting.then(|r| ok::<_, ()>(r))))
+                .unwrap()
+    };
+    assert!(client_result.is_ok());
+    let (outcome, _, _) = accepted.unwrap().unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), CLIENT_PUB);
+
+    // The connection that arrived after the shutdown was never taken.
+    assert_eq!(queue.borrow().len(), 1);
+    let terminated = block_on(poll_fn(|cx| acceptor.poll_next(cx))).unwrap();
+    assert!(terminated.is_none());
+    assert_eq!(queue.borrow().len(), 1);
+
+    assert!(handle.is_complete());
+    block_on(completion).unwrap();
+}
+
+#[test]
+// A shutdown completion that is polled repeatedly resolves once the acceptor
+// is gone.
+fn acceptor_completion_repoll() {
+    use std::net::SocketAddr;
+    use acceptor::Acceptor;
+
+    let connections: Vec<(Duplex<Reader, Writer>, SocketAddr)> = Vec::new();
+    let incoming = futures::stream::iter_ok::<_, io::Error>(connections);
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let acceptor = Acceptor::new(incoming, identity);
+
+    let mut completion = acceptor.shutdown_handle().completion();
+    for _ in 0..3 {
+        assert!(is_pending(&mut completion));
+    }
+
+    drop(acceptor);
+    block_on(completion).unwrap();
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// A dialer moves on from an address that refuses connections, and performs
+// the handshake over the next one.
+fn dialer_skips_unreachable_address() {
+    use std::time::Duration;
+    use futures::Never;
+    use tokio::net::TcpListener;
+    use dialer::Dialer;
+    use multiserver;
+
+    fn elapsed(_: Duration) -> FutureResult<(), ()> {
+        ok(())
+    }
+
+    // Nothing listens on the port once the listener is dropped.
+    let refused = ::std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+
+    let shs = "shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=";
+    let addresses = multiserver::parse(&format!("net:127.0.0.1:{}~{};net:127.0.0.1:{}~{}",
+                                                refused,
+                                                shs,
+                                                port,
+                                                shs))
+            .unwrap();
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let mut dialer = Dialer::new(identity, addresses.clone(), elapsed);
+    dialer.set_rounds(1);
+
+    let (client_result, server_result) =
+        block_on(dialer
+                     .dial()
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    let (outcome, _, address) = client_result.ok().unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), SERVER_PUB);
+    assert_eq!(address, addresses[1]);
+    assert_eq!(server_result.ok().unwrap().0.peer_longterm_pk(), CLIENT_PUB);
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// The event handler of a dialer sees each step of each attempt, in order.
+fn dialer_events() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use futures::Never;
+    use tokio::net::TcpListener;
+    use dialer::{DialEvent, Dialer};
+    use multiserver;
+
+    fn elapsed(_: Duration) -> FutureResult<(), ()> {
+        ok(())
+    }
+
+    // Nothing listens on the port once the listener is dropped.
+    let refused = ::std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let live = listener.local_addr().unwrap();
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+
+    let shs = "shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=";
+    let addresses = multiserver::parse(&format!("net:{}~{};net:{}~{}", refused, shs, live, shs))
+        .unwrap();
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let mut dialer = Dialer::new(identity, addresses.clone(), elapsed);
+    dialer.set_rounds(1);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    dialer.set_event_handler(move |event| {
+        let event = match event {
+            DialEvent::Connecting { address, round } => {
+                format!("connecting {} in round {}", address, round)
+            }
+            DialEvent::Resolved { address, addrs } => {
+                format!("resolved {} to {:?}", address, addrs)
+            }
+            DialEvent::Handshaking { address, addr } => {
+                format!("handshaking {} at {:?}", address, addr)
+            }
+            DialEvent::Failed { address, .. } => format!("failed {}", address),
+            DialEvent::Succeeded { address } => format!("succeeded {}", address),
+        };
+        recorded.lock().unwrap().push(event);
+    });
+
+    let (client_result, server_result) =
+        block_on(dialer
+                     .dial()
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    assert!(client_result.is_ok());
+    assert!(server_result.is_ok());
+
+    assert_eq!(*events.lock().unwrap(),
+               vec![format!("connecting {} in round 1", addresses[0]),
+                    format!("resolved {} to {:?}", addresses[0], [refused]),
+                    format!("failed {}", addresses[0]),
+                    format!("connecting {} in round 1", addresses[1]),
+                    format!("resolved {} to {:?}", addresses[1], [live]),
+                    format!("handshaking {} at {:?}", addresses[1], Some(live)),
+                    format!("succeeded {}", addresses[1])]);
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// A dialer resolves host names via its custom resolver, which fails for
+// names it does not know.
+fn dialer_resolver() {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use futures::Never;
+    use futures::future::result;
+    use tokio::net::TcpListener;
+    use connect::{Resolve, Resolving};
+    use dialer::Dialer;
+    use multiserver;
+
+    // Maps a single made-up name to the listener.
+    struct Stub(SocketAddr);
+
+    impl Resolve for Stub {
+        fn resolve(&self, host: &str, port: u16) -> Resolving {
+            Box::new(result(if host == "peer.invalid" && port == 8008 {
+                                Ok(vec![self.0])
+                            } else {
+                                Err(io::Error::new(io::ErrorKind::NotFound, "unknown host"))
+                            }))
+        }
+    }
+
+    fn elapsed(_: Duration) -> FutureResult<(), ()> {
+        ok(())
+    }
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let stub = Stub(listener.local_addr().unwrap());
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+
+    let shs = "shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=";
+    let addresses = format!("net:unknown.invalid:8008~{};net:peer.invalid:8008~{}", shs, shs);
+    let addresses = multiserver::parse(&addresses).unwrap();
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let mut dialer = Dialer::new(identity, addresses.clone(), elapsed);
+    dialer.set_rounds(1);
+    dialer.set_resolver(stub);
+
+    let (client_result, server_result) =
+        block_on(dialer
+                     .dial()
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    let (outcome, _, address) = client_result.ok().unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), SERVER_PUB);
+    assert_eq!(address, addresses[1]);
+    assert_eq!(server_result.ok().unwrap().0.peer_longterm_pk(), CLIENT_PUB);
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// A pinned connection tries the server keys in order until one of them
+// authenticates, and fails with the handshake error if none does.
+fn connect_tcp_pinned_keys() {
+    use futures::Never;
+    use tokio::net::TcpListener;
+    use connect::connect_tcp_pinned;
+    use errors::ConnectError;
+
+    // Returns the result of the client, and whether each handshake of the
+    // server succeeded.
+    fn pinned(server_longterm_pks: &[sign::PublicKey])
+              -> (Result<sign::PublicKey, ConnectError>, Vec<bool>) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = listener
+            .incoming()
+            .take(server_longterm_pks.len() as u64)
+            .and_then(|stream| {
+                          ServerHandshaker::new(stream,
+                                                &APP,
+                                                &SERVER_PUB,
+                                                &SERVER_SEC,
+                                                &SERVER_EPH_PUB,
+                                                &SERVER_EPH_SEC)
+                                  .then(|result| ok::<_, io::Error>(result.is_ok()))
+                      })
+            .collect();
+
+        let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+        let client = connect_tcp_pinned(addr, &identity, server_longterm_pks)
+            .map(|(outcome, _)| outcome.peer_longterm_pk());
+
+        let (client_result, server_result) =
+            block_on(client
+                         .then(|result| ok::<_, Never>(result))
+                         .join(server.then(|result| ok::<_, Never>(result))))
+                .ok()
+                .unwrap();
+        (client_result, server_result.unwrap())
+    }
+
+    let (other_pk, _) = sign::gen_keypair();
+    let (another_pk, _) = sign::gen_keypair();
+
+    let (client_result, handshakes) = pinned(&[other_pk, SERVER_PUB]);
+    assert_eq!(client_result.ok().unwrap(), SERVER_PUB);
+    assert_eq!(handshakes, vec![false, true]);
+
+    let (client_result, handshakes) = pinned(&[other_pk, another_pk]);
+    match client_result {
+        Err(ConnectError::Handshake(_)) => {}
+        _ => panic!("expected the handshake with the last key to fail"),
+    }
+    assert_eq!(handshakes, vec![false, false]);
+}
+
+#[test]
+#[cfg(feature = "box-stream")]
+// Data written to an upgraded connection is read back by the upgraded
+// connection of the peer.
+fn upgrade_box_stream_roundtrip() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use upgrade::upgrade;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, client_stream), (server_outcome, server_stream)) =
+        block_on(client.join(server)).ok().unwrap();
+    let client = upgrade(&client_outcome, client_stream);
+    let server = upgrade(&server_outcome, server_stream);
+
+    let sending = client
+        .write_all(b"hello box-stream".to_vec())
+        .and_then(|(client, _)| client.flush());
+    let receiving = server.read_exact([0u8; 16]);
+    let (_, (_, received)) = block_on(sending.join(receiving)).ok().unwrap();
+    assert_eq!(&received, b"hello box-stream");
+}
+
+#[test]
+#[cfg(feature = "box-stream")]
+// The box-stream keys and nonces a client encrypts with are those the server
+// decrypts with, and vice versa.
+fn outcome_key_nonce() {
+    use box_stream::KeyNonce;
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+    let (client_send, client_recv): (KeyNonce, KeyNonce) = (&client_outcome).into();
+    let (server_send, server_recv): (KeyNonce, KeyNonce) = (&server_outcome).into();
+
+    assert_eq!(client_send.key.0, EXP_CLIENT_ENC_KEY.0);
+    assert_eq!(client_send.nonce.0, EXP_CLIENT_ENC_NONCE.0);
+    assert_eq!(client_send.key.0, server_recv.key.0);
+    assert_eq!(client_send.nonce.0, server_recv.nonce.0);
+    assert_eq!(server_send.key.0, client_recv.key.0);
+    assert_eq!(server_send.nonce.0, client_recv.nonce.0);
+
+    assert_eq!(client_outcome.encryption_key_nonce().key.0, client_send.key.0);
+    assert_eq!(client_outcome.decryption_key_nonce().nonce.0, client_recv.nonce.0);
+}
+
+#[test]
+#[cfg(all(feature = "tokio", feature = "secret-stream"))]
+// A client and a server exchange data over encrypted streams obtained via
+// `connect_and_encrypt` and `accept_and_encrypt` over local tcp.
+fn connect_and_accept_encrypted() {
+    use futures::Never;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use connect::{accept_and_encrypt, connect_and_encrypt};
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_identity = Identity::new(APP, SERVER_PUB.clone(), SERVER_SEC.clone());
+    let server = accept_and_encrypt(listener, &server_identity)
+        .next()
+        .map_err(|(err, _)| err)
+        .and_then(|(accepted, _)| {
+                      let (stream, client_pk, _) = accepted.unwrap();
+                      stream.read_exact([0u8; 5]).map(move |(stream, received)| {
+                                                          (stream, client_pk, received)
+                                                      })
+                  })
+        .and_then(|(stream, client_pk, received)| {
+                      stream
+                          .write_all(b"world".to_vec())
+                          .and_then(|(stream, _)| stream.flush())
+                          .map(move |_| (client_pk, received))
+                  });
+
+    let client_identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let client = connect_and_encrypt(addr, &client_identity, &SERVER_PUB)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+        .and_then(|(stream, server_pk)| {
+                      stream
+                          .write_all(b"hello".to_vec())
+                          .and_then(|(stream, _)| stream.flush())
+                          .and_then(|stream| stream.read_exact([0u8; 5]))
+                          .map(move |(_, received)| (server_pk, received))
+                  });
+
+    let (client_result, server_result) =
+        block_on(client
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    let (server_pk, client_received) = client_result.unwrap();
+    let (client_pk, server_received) = server_result.unwrap();
+    assert_eq!(server_pk, SERVER_PUB);
+    assert_eq!(client_pk, CLIENT_PUB);
+    assert_eq!(&client_received, b"world");
+    assert_eq!(&server_received, b"hello");
+}
+
+#[test]
+// A handshake service is always ready, performs a handshake on each io object
+// it is called with, and rejects the clients its filter rejects.
+fn handshake_service() {
+    use futures::Never;
+    use futures::future::poll_fn;
+    use errors::FilteringHandshakeError;
+    use service::HandshakeService;
+
+    fn reject(_: &sign::PublicKey) -> FutureResult<bool, Never> {
+        ok(false)
+    }
+
+    fn client(stream: Duplex<Reader, Writer>) -> ClientHandshaker<'static, Duplex<Reader, Writer>> {
+        ClientHandshaker::new(stream,
+                              &APP,
+                              &CLIENT_PUB,
+                              &CLIENT_SEC,
+                              &CLIENT_EPH_PUB,
+                              &CLIENT_EPH_SEC,
+                              &SERVER_PUB)
+    }
+
+    let identity = Identity::new(APP, SERVER_PUB, SERVER_SEC.clone());
+    let mut service = HandshakeService::new(identity.clone());
+    block_on(poll_fn(|cx| service.poll_ready(cx))).unwrap();
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let server = service.call(Duplex::new(reader_b, writer_a));
+    let ((_, _), (outcome, _)) = block_on(client(Duplex::new(reader_a, writer_b))
+                                              .map_err(|_| ())
+                                              .join(server.map_err(|_| ())))
+            .unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), CLIENT_PUB);
+
+    let mut service = HandshakeService::with_filter(identity, reject);
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    // Drop the stream of the failed handshake, so that the client sees the
+    // connection closing.
+    let server = service
+        .call(Duplex::new(reader_b, writer_a))
+        .map_err(|(err, _)| err);
+    let (_, server_result) = block_on(client(Duplex::new(reader_a, writer_b))
+                                          .then(|r| ok::<_, ()>(r))
+                                          .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    match server_result {
+        Err(FilteringHandshakeError::Rejected) => {}
+        _ => panic!("expected the filter to reject the client"),
+    }
+}
+
+#[test]
+// A server over a frame transport reassembles messages split across frames,
+// and fails on frames that do not add up to the messages.
+fn framed_handshake() {
+    use std::collections::VecDeque;
+    use futures::task::Context;
+    use framed::{FrameTransport, Framed};
+
+    // Delivers the given frames, then reports the transport as closed.
+    struct Frames {
+        incoming: VecDeque<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl FrameTransport for Frames {
+        fn poll_send(&mut self, _: &mut Context, frame: &[u8]) -> Poll<(), io::Error> {
+            self.sent.extend_from_slice(frame);
+            Ok(Async::Ready(()))
+        }
+
+        fn poll_flush(&mut self, _: &mut Context) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn poll_recv(&mut self, _: &mut Context) -> Poll<Option<Vec<u8>>, io::Error> {
+            Ok(Async::Ready(self.incoming.pop_front()))
+        }
+    }
+
+    fn handshake(frames: Vec<Vec<u8>>)
+                 -> Result<Framed<Frames>, (errors::HandshakeError, Framed<Frames>)> {
+        let transport = Framed::new(Frames {
+                                        incoming: frames.into_iter().collect(),
+                                        sent: Vec::new(),
+                                    });
+        block_on(ServerHandshaker::new(transport,
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC))
+                .map(|(_, transport)| transport)
+    }
+
+    let (msg1, msg3) = CLIENT_MSGS.split_at(MSG1_BYTES);
+
+    let transport = handshake(vec![msg1[..10].to_vec(),
+                                   msg1[10..].to_vec(),
+                                   msg3[..50].to_vec(),
+                                   msg3[50..51].to_vec(),
+                                   msg3[51..].to_vec()])
+            .ok()
+            .unwrap();
+    assert_eq!(&transport.get_ref().sent[..], &SERVER_MSGS[..]);
+
+    // The surplus of an oversized frame is read as the start of msg3.
+    let mut oversized = msg1.to_vec();
+    oversized.extend_from_slice(&[0; 8]);
+    match handshake(vec![oversized, msg3.to_vec()]) {
+        Err((errors::HandshakeError::CryptoError, transport)) => {
+            assert_eq!(&transport.get_ref().sent[..], &SERVER_MSGS[..MSG2_BYTES]);
+        }
+        _ => panic!("expected the oversized frame to corrupt msg3"),
+    }
+
+    match handshake(vec![msg1[..32].to_vec()]) {
+        Err((errors::HandshakeError::IoError(ref err), ref transport)) => {
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(transport.get_ref().sent.is_empty());
+        }
+        _ => panic!("expected the short frame to end the handshake"),
+    }
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+// A negotiated connection performs a version 1 handshake with an `shs`
+// address, and refuses an address that only offers unknown versions.
+fn connect_negotiated_versions() {
+    use futures::Never;
+    use tokio::net::TcpListener;
+    use connect::connect_negotiated;
+    use errors::ConnectError;
+    use version::Version;
+
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = listener
+        .incoming()
+        .next()
+        .map_err(|(err, _)| errors::HandshakeError::IoError(err))
+        .and_then(|(stream, _)| {
+                      ServerHandshaker::new(stream.unwrap(),
+                                            &APP,
+                                            &SERVER_PUB,
+                                            &SERVER_SEC,
+                                            &SERVER_EPH_PUB,
+                                            &SERVER_EPH_SEC)
+                              .map_err(|(err, _)| err)
+                  });
+
+    let identity = Identity::new(APP, CLIENT_PUB.clone(), CLIENT_SEC.clone());
+    let address = format!("net:{}~shs:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=", addr)
+        .parse()
+        .unwrap();
+    let (client_result, server_result) =
+        block_on(connect_negotiated(&address, &identity)
+                     .then(|result| ok::<_, Never>(result))
+                     .join(server.then(|result| ok::<_, Never>(result))))
+            .ok()
+            .unwrap();
+    let (outcome, _, version) = client_result.ok().unwrap();
+    assert_eq!(version, Version::V1);
+    assert_eq!(outcome.peer_longterm_pk(), SERVER_PUB);
+    assert!(server_result.is_ok());
+
+    let address = format!("net:{}~shs2:Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=", addr)
+        .parse()
+        .unwrap();
+    match block_on(connect_negotiated(&address, &identity)) {
+        Err(ConnectError::IoError(ref err)) => {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(err.to_string().contains("shs2"));
+        }
+        _ => panic!("expected the unknown version to be refused"),
+    }
+}
+
+#[test]
+// Versions are identified by the names of multiserver transforms, and are
+// displayed by their full name.
+fn version_names() {
+    use version::Version;
+
+    assert_eq!(Version::from_multiserver_name("shs"), Some(Version::V1));
+    assert_eq!(Version::from_multiserver_name("shs2"), None);
+    assert_eq!(Version::from_multiserver_name(""), None);
+    assert_eq!(Version::from_multiserver_name(Version::V1.multiserver_name()),
+               Some(Version::V1));
+
+    assert_eq!(Version::V1.to_string(), "secret-handshake v1");
+    assert_eq!(Version::V1.msg_bytes(),
+               [MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES]);
+}
+
+#[test]
+#[cfg(feature = "tracing")]
+// With the `tracing` feature, handshakes emit events about their progress,
+// with the peer keys base64 encoded.
+fn trace_tracing_events() {
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::{Event, Id, Metadata, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Record};
+
+    // Records the fields of each event as `name=value` strings.
+    struct Capture(Arc<Mutex<Vec<Vec<String>>>>);
+
+    struct Fields(Vec<String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    tracing::subscriber::with_default(Capture(events.clone()), traced_handshakes);
+
+    let events = events.lock().unwrap();
+    let emitted = |fields: &[&str]| {
+        events
+            .iter()
+            .any(|event| fields.iter().all(|field| event.contains(&field.to_string())))
+    };
+    let server_pk = format!("peer={}", ::base64::encode(&SERVER_PUB.0));
+
+    assert!(emitted(&["message=handshake started", "side=\"client\""]));
+    assert!(emitted(&["message=handshake started", "side=\"server\""]));
+    assert!(emitted(&["message=handshake message sent", "side=\"client\"", "msg=1"]));
+    assert!(emitted(&["message=handshake message verified", "side=\"server\"", "msg=3"]));
+    assert!(emitted(&["message=handshake completed", "side=\"client\"", &server_pk[..]]));
+    assert!(emitted(&["message=invalid handshake message", "side=\"server\"", "msg=1"]));
+    assert!(emitted(&["message=handshake failed",
+                      "side=\"server\"",
+                      "reason=invalid handshake message"]));
+}
+
+#[test]
+#[cfg(feature = "log")]
+// With the `log` feature, handshakes log their start, their outcome with an
+// abbreviated peer key, and why they failed.
+fn trace_log_records() {
+    use std::sync::{Arc, Mutex};
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    // Records all records as `level message` strings.
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    // The logger is global, so other tests may log as well.
+    let records = Arc::new(Mutex::new(Vec::new()));
+    log::set_logger(Box::leak(Box::new(Capture(records.clone())))).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
+    traced_handshakes();
+
+    let records = records.lock().unwrap();
+    let server_pk = ::base64::encode(&SERVER_PUB.0);
+    let expected = [format!("{} client handshake started", Level::Debug),
+                    format!("{} server handshake started", Level::Debug),
+                    format!("{} client handshake with @{}... completed",
+                            Level::Debug,
+                            &server_pk[..8]),
+                    format!("{} server handshake failed: invalid handshake message",
+                            Level::Warn)];
+    for record in expected.iter() {
+        assert!(records.contains(record), "missing log record: {}", record);
+    }
+}
+
+#[test]
+// A handshake slot refuses the outcome of a handshake with another peer, and
+// a pending session that is polled repeatedly resolves once the slot is
+// completed.
+fn session_cache_slot_peer() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Duration;
+    use session_cache::{SessionCache, Begin};
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+
+    let client = ClientHandshaker::new(Duplex::new(reader_a, writer_b),
+                                       &APP,
+                                       &CLIENT_PUB,
+                                       &CLIENT_SEC,
+                                       &CLIENT_EPH_PUB,
+                                       &CLIENT_EPH_SEC,
+                                       &SERVER_PUB);
+    let server = ServerHandshaker::new(Duplex::new(reader_b, writer_a),
+                                       &APP,
+                                       &SERVER_PUB,
+                                       &SERVER_SEC,
+                                       &SERVER_EPH_PUB,
+                                       &SERVER_EPH_SEC);
+    let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server)).ok().unwrap();
+
+    let cache = SessionCache::new(4, Duration::from_secs(60));
+
+    // The server outcome is that of a handshake with the client.
+    let slot = match cache.begin(&SERVER_PUB) {
+        Begin::Start(slot) => slot,
+        _ => panic!("expected to start a handshake"),
+    };
+    let pending = match cache.begin(&SERVER_PUB) {
+        Begin::InProgress(pending) => pending,
+        _ => panic!("expected a handshake in progress"),
+    };
+    assert!(panic::catch_unwind(AssertUnwindSafe(|| slot.complete(server_outcome))).is_err());
+    assert!(block_on(pending).unwrap().is_none());
+    assert!(cache.get(&SERVER_PUB).is_none());
+    assert!(cache.get(&CLIENT_PUB).is_none());
+
+    let slot = match cache.begin(&SERVER_PUB) {
+        Begin::Start(slot) => slot,
+        _ => panic!("expected to start a handshake"),
+    };
+    let mut pending = match cache.begin(&SERVER_PUB) {
+        Begin::InProgress(pending) => pending,
+        _ => panic!("expected a handshake in progress"),
+    };
+    for _ in 0..3 {
+        assert!(is_pending(&mut pending));
+    }
+    let session = slot.complete(client_outcome);
+    let waited = block_on(pending).unwrap().unwrap();
+    assert_eq!(waited.peer_longterm_pk(), SERVER_PUB);
+    assert_eq!(waited.send(), session.send());
+}
+
+#[test]
+// The keys of `shs` transforms are decoded strictly, like via `KeyEncoding`.
+fn multiserver_strict_keys() {
+    use multiserver::{self, ParseError};
+
+    let parse = |key: &str| multiserver::parse(&format!("net:example.com:8008~shs:{}", key));
+
+    assert!(parse("Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY=").is_ok());
+    // Sets a bit beyond the last byte of the key.
+    assert_eq!(parse("Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUZ="),
+               Err(ParseError::InvalidKey));
+    assert_eq!(parse("Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2AUY"),
+               Err(ParseError::InvalidKey));
+    assert_eq!(parse("Kr5xmRD4u8OjybvMVu5ClzRzoAT0AQxMqoFCDMo2"),
+               Err(ParseError::InvalidKey));
+}
+
 //
 // // A client handles partial reads/writes and WouldBlock errors on the underlying stream.
 // quickcheck! {
@@ -148,10 +4334,10 @@ fn success() {
 //                                                  &SERVER_PUB);
 //
 //           let outcome = client.wait().unwrap().0.unwrap();
-//           assert_eq!(outcome.encryption_key(), EXP_CLIENT_ENC_KEY);
-//           assert_eq!(outcome.encryption_nonce(), EXP_CLIENT_ENC_NONCE);
-//           assert_eq!(outcome.decryption_key(), EXP_CLIENT_DEC_KEY);
-//           assert_eq!(outcome.decryption_nonce(), EXP_CLIENT_DEC_NONCE);
+//           assert_eq!(outcome.send().key, EXP_CLIENT_ENC_KEY);
+//           assert_eq!(outcome.send().nonce, EXP_CLIENT_ENC_NONCE);
+//           assert_eq!(outcome.recv().key, EXP_CLIENT_DEC_KEY);
+//           assert_eq!(outcome.recv().nonce, EXP_CLIENT_DEC_NONCE);
 //           assert_eq!(outcome.peer_longterm_pk(), EXP_SERVER_PUB);
 //           return true;
 //       }
@@ -282,10 +4468,10 @@ fn success() {
 //                                                &SERVER_EPH_SEC);
 //
 //            let outcome = server.wait().unwrap().0.unwrap();
-//            assert_eq!(outcome.encryption_key(), EXP_SERVER_ENC_KEY);
-//            assert_eq!(outcome.encryption_nonce(), EXP_SERVER_ENC_NONCE);
-//            assert_eq!(outcome.decryption_key(), EXP_SERVER_DEC_KEY);
-//            assert_eq!(outcome.decryption_nonce(), EXP_SERVER_DEC_NONCE);
+//            assert_eq!(outcome.send().key, EXP_SERVER_ENC_KEY);
+//            assert_eq!(outcome.send().nonce, EXP_SERVER_ENC_NONCE);
+//            assert_eq!(outcome.recv().key, EXP_SERVER_DEC_KEY);
+//            assert_eq!(outcome.recv().nonce, EXP_SERVER_DEC_NONCE);
 //            assert_eq!(outcome.peer_longterm_pk(), EXP_CLIENT_PUB);
 //            return true;
 //         }
@@ -414,10 +4600,10 @@ fn success() {
 //                                                  &SERVER_EPH_SEC);
 //
 //     let outcome = server.wait().unwrap().0.unwrap();
-//     assert_eq!(outcome.encryption_key(), EXP_SERVER_ENC_KEY);
-//     assert_eq!(outcome.encryption_nonce(), EXP_SERVER_ENC_NONCE);
-//     assert_eq!(outcome.decryption_key(), EXP_SERVER_DEC_KEY);
-//     assert_eq!(outcome.decryption_nonce(), EXP_SERVER_DEC_NONCE);
+//     assert_eq!(outcome.send().key, EXP_SERVER_ENC_KEY);
+//     assert_eq!(outcome.send().nonce, EXP_SERVER_ENC_NONCE);
+//     assert_eq!(outcome.recv().key, EXP_SERVER_DEC_KEY);
+//     assert_eq!(outcome.recv().nonce, EXP_SERVER_DEC_NONCE);
 //     assert_eq!(outcome.peer_longterm_pk(), EXP_CLIENT_PUB);
 // }
 //
@@ -0,0 +1,393 @@
+//! Utilities for testing code built on top of this crate.
+//!
+//! The fixtures are a fixed set of keys for a client and a server, with
+//! `handshake_pair` to run a complete handshake between two identities in
+//! memory.
+//!
+//! The strategies generate keys and network identifiers for
+//! [proptest](https://crates.io/crates/proptest), as well as corruptions of
+//! valid handshake messages, so that applications can property-test their own
+//! accept and connect code paths.
+//!
+//! A `Shaped` stream simulates a slow link, by delaying and splitting up the
+//! reads and writes of the stream it wraps. This allows testing timeouts,
+//! deadlines and budgets without a real network:
+//!
+//! ```rust,ignore
+//! let mut shaping = Shaping::default();
+//! shaping.latency = Duration::from_millis(200);
+//! shaping.bytes_per_second = Some(64);
+//! let stream = Shaped::new(stream, shaping, |duration| Delay::new(Instant::now() + duration));
+//! ```
+//!
+//! This module requires the `test-utils` feature.
+
+use std::cmp::{min, max};
+use std::io;
+use std::time::Duration;
+
+use futures_core::{Poll, Future};
+use futures_core::Async;
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+use proptest::arbitrary::{any, Arbitrary};
+use proptest::collection::vec;
+use proptest::strategy::{BoxedStrategy, Strategy};
+use sodiumoxide::crypto::{box_, sign, scalarmult};
+
+use crypto::{Outcome, NETWORK_IDENTIFIER_BYTES};
+use errors::HandshakeError;
+use identity::Identity;
+use messages::{ClientHello, ServerHello, ClientAuth, ServerAck};
+use sans_io::HandshakeState;
+
+/// The network identifier of the fixtures.
+pub static NETWORK_IDENTIFIER: [u8; NETWORK_IDENTIFIER_BYTES] =
+    [111, 97, 159, 86, 19, 13, 53, 115, 66, 209, 32, 84, 255, 140, 143, 85, 157, 74, 32, 154, 156,
+     90, 29, 185, 141, 19, 184, 255, 104, 107, 124, 198];
+
+/// The longterm public key of the fixture client.
+pub static CLIENT_LONGTERM_PK: sign::PublicKey =
+    sign::PublicKey([225, 162, 73, 136, 73, 119, 94, 84, 208, 102, 233, 120, 23, 46, 225, 245,
+                     198, 79, 176, 0, 151, 208, 70, 146, 111, 23, 94, 101, 25, 192, 30, 35]);
+/// The longterm secret key of the fixture client.
+pub static CLIENT_LONGTERM_SK: sign::SecretKey =
+    sign::SecretKey([243, 168, 6, 50, 44, 78, 192, 183, 210, 241, 189, 36, 183, 154, 132, 119,
+                     115, 84, 47, 151, 32, 32, 26, 237, 64, 180, 69, 20, 95, 133, 92, 176, 225,
+                     162, 73, 136, 73, 119, 94, 84, 208, 102, 233, 120, 23, 46, 225, 245, 198,
+                     79, 176, 0, 151, 208, 70, 146, 111, 23, 94, 101, 25, 192, 30, 35]);
+/// The ephemeral public key of the fixture client.
+pub static CLIENT_EPHEMERAL_PK: box_::PublicKey =
+    box_::PublicKey([79, 79, 77, 238, 254, 215, 129, 197, 235, 41, 185, 208, 47, 32, 146, 37,
+                     255, 237, 208, 215, 182, 92, 201, 106, 85, 86, 157, 41, 53, 165, 177, 32]);
+/// The ephemeral secret key of the fixture client.
+pub static CLIENT_EPHEMERAL_SK: box_::SecretKey =
+    box_::SecretKey([80, 169, 55, 157, 134, 142, 219, 152, 125, 240, 174, 209, 225, 109, 46, 188,
+                     97, 224, 193, 187, 198, 58, 226, 193, 24, 235, 213, 214, 49, 55, 213, 104]);
+
+/// The longterm public key of the fixture server.
+pub static SERVER_LONGTERM_PK: sign::PublicKey =
+    sign::PublicKey([42, 190, 113, 153, 16, 248, 187, 195, 163, 201, 187, 204, 86, 238, 66, 151,
+                     52, 115, 160, 4, 244, 1, 12, 76, 170, 129, 66, 12, 202, 54, 1, 70]);
+/// The longterm secret key of the fixture server.
+pub static SERVER_LONGTERM_SK: sign::SecretKey =
+    sign::SecretKey([118, 98, 17, 77, 86, 116, 58, 146, 99, 84, 198, 164, 35, 220, 73, 213, 246,
+                     224, 242, 230, 175, 116, 71, 218, 56, 37, 212, 66, 163, 14, 74, 209, 42,
+                     190, 113, 153, 16, 248, 187, 195, 163, 201, 187, 204, 86, 238, 66, 151, 52,
+                     115, 160, 4, 244, 1, 12, 76, 170, 129, 66, 12, 202, 54, 1, 70]);
+/// The ephemeral public key of the fixture server.
+pub static SERVER_EPHEMERAL_PK: box_::PublicKey =
+    box_::PublicKey([166, 12, 63, 218, 235, 136, 61, 99, 232, 142, 165, 147, 88, 93, 79, 177, 23,
+                     148, 129, 57, 179, 24, 192, 174, 90, 62, 40, 83, 51, 9, 97, 82]);
+/// The ephemeral secret key of the fixture server.
+pub static SERVER_EPHEMERAL_SK: box_::SecretKey =
+    box_::SecretKey([176, 248, 210, 185, 226, 76, 162, 153, 239, 144, 57, 206, 218, 97, 2, 215,
+                     155, 5, 223, 189, 22, 28, 137, 85, 228, 233, 93, 79, 217, 203, 63, 125]);
+
+/// The identity of the fixture client.
+pub fn client_identity() -> Identity {
+    Identity::new(NETWORK_IDENTIFIER,
+                  CLIENT_LONGTERM_PK,
+                  CLIENT_LONGTERM_SK.clone())
+}
+
+/// The identity of the fixture server.
+pub fn server_identity() -> Identity {
+    Identity::new(NETWORK_IDENTIFIER,
+                  SERVER_LONGTERM_PK,
+                  SERVER_LONGTERM_SK.clone())
+}
+
+/// Creates an identity in the fixture network whose longterm keys are derived
+/// from `seed`, so that tests can create any number of distinct but
+/// reproducible identities.
+pub fn seeded_identity(seed: u8) -> Identity {
+    let (pk, sk) = sign::keypair_from_seed(&sign::Seed([seed; sign::SEEDBYTES]));
+    Identity::new(NETWORK_IDENTIFIER, pk, sk)
+}
+
+/// Runs a complete handshake between `client` and `server` in memory, using
+/// the fixture ephemeral keys, and returns the outcomes of the client and the
+/// server.
+pub fn handshake_pair(client: &Identity,
+                      server: &Identity)
+                      -> Result<(Outcome, Outcome), HandshakeError> {
+    let mut client_state = HandshakeState::client(client.network_identifier(),
+                                                  client.longterm_pk(),
+                                                  client.longterm_sk(),
+                                                  &CLIENT_EPHEMERAL_PK,
+                                                  &CLIENT_EPHEMERAL_SK,
+                                                  server.longterm_pk());
+    let mut server_state = HandshakeState::server(server.network_identifier(),
+                                                  server.longterm_pk(),
+                                                  server.longterm_sk(),
+                                                  &SERVER_EPHEMERAL_PK,
+                                                  &SERVER_EPHEMERAL_SK);
+
+    let mut buf = [0; 128];
+    while !client_state.is_done() {
+        if client_state.wants_write() > 0 {
+            let len = client_state.write_message(&mut buf);
+            server_state.read_message(&buf[..len])?;
+        } else if server_state.wants_write() > 0 {
+            let len = server_state.write_message(&mut buf);
+            client_state.read_message(&buf[..len])?;
+        } else {
+            // A failed state neither reads nor writes.
+            return Err(HandshakeError::CryptoError);
+        }
+    }
+
+    Ok((client_state.outcome().unwrap(), server_state.outcome().unwrap()))
+}
+
+/// Generates arbitrary network identifiers.
+pub fn network_identifier() -> BoxedStrategy<[u8; NETWORK_IDENTIFIER_BYTES]> {
+    any::<[u8; NETWORK_IDENTIFIER_BYTES]>().boxed()
+}
+
+/// Generates longterm keypairs from arbitrary seeds.
+pub fn longterm_keypair() -> BoxedStrategy<(sign::PublicKey, sign::SecretKey)> {
+    any::<[u8; sign::SEEDBYTES]>()
+        .prop_map(|seed| sign::keypair_from_seed(&sign::Seed(seed)))
+        .boxed()
+}
+
+/// Generates ephemeral keypairs from arbitrary secret keys.
+pub fn ephemeral_keypair() -> BoxedStrategy<(box_::PublicKey, box_::SecretKey)> {
+    any::<[u8; box_::SECRETKEYBYTES]>()
+        .prop_map(|sk| {
+                      let pk = scalarmult::scalarmult_base(&scalarmult::Scalar(sk));
+                      (box_::PublicKey(pk.0), box_::SecretKey(sk))
+                  })
+        .boxed()
+}
+
+/// Generates identities with arbitrary network identifiers and longterm keys.
+pub fn identity() -> BoxedStrategy<Identity> {
+    (network_identifier(), longterm_keypair())
+        .prop_map(|(network_identifier, (pk, sk))| Identity::new(network_identifier, pk, sk))
+        .boxed()
+}
+
+/// Generates identities with arbitrary longterm keys, using the given network
+/// identifier.
+pub fn identity_in(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES]) -> BoxedStrategy<Identity> {
+    longterm_keypair()
+        .prop_map(move |(pk, sk)| Identity::new(network_identifier, pk, sk))
+        .boxed()
+}
+
+/// Generates corruptions of the given valid message: copies in which at
+/// least one byte differs.
+pub fn corrupted(message: &[u8]) -> BoxedStrategy<Vec<u8>> {
+    let message = message.to_vec();
+    let original = message.clone();
+    let len = message.len();
+    vec((0..len, 1..256u16), 1..4)
+        .prop_map(move |flips| {
+                      let mut corrupted = message.clone();
+                      for (index, mask) in flips {
+                          corrupted[index] ^= mask as u8;
+                      }
+                      corrupted
+                  })
+        .prop_filter("flips cancelled out", move |corrupted| *corrupted != original)
+        .boxed()
+}
+
+macro_rules! arbitrary_message {
+    ($name:ident) => {
+        /// Arbitrary bytes of the right length, almost certainly invalid.
+        impl Arbitrary for $name {
+            type Parameters = ();
+            type Strategy = BoxedStrategy<$name>;
+
+            fn arbitrary_with(_: ()) -> BoxedStrategy<$name> {
+                vec(any::<u8>(), $name::LEN)
+                    .prop_map(|bytes| $name::from_slice(&bytes).unwrap())
+                    .boxed()
+            }
+        }
+    }
+}
+
+arbitrary_message!(ClientHello);
+arbitrary_message!(ServerHello);
+arbitrary_message!(ClientAuth);
+arbitrary_message!(ServerAck);
+
+/// How a `Shaped` stream slows down the stream it wraps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Shaping {
+    /// How long each read and each write waits before it is performed.
+    pub latency: Duration,
+    /// After a read or write transferred some bytes, the next one in the same
+    /// direction additionally waits for as long as transferring them takes at
+    /// this rate. `None` and zero mean unlimited.
+    pub bytes_per_second: Option<u64>,
+    /// The maximum number of bytes transferred by a single read or write.
+    /// `None` means unlimited, zero is treated as one.
+    pub max_chunk: Option<usize>,
+}
+
+/// Wraps a stream, delaying and splitting up its reads and writes according
+/// to a `Shaping`.
+pub struct Shaped<S, MakeDelay, D> {
+    stream: S,
+    shaping: Shaping,
+    make_delay: MakeDelay,
+    read: Throttle<D>,
+    write: Throttle<D>,
+}
+
+impl<S, MakeDelay, D> Shaped<S, MakeDelay, D>
+    where MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    /// Creates a new `Shaped` stream. `make_delay` is called to create a
+    /// delay future of your runtime for each wait, a delay that errors counts
+    /// as elapsed.
+    pub fn new(stream: S, shaping: Shaping, make_delay: MakeDelay) -> Shaped<S, MakeDelay, D> {
+        Shaped {
+            stream,
+            shaping,
+            make_delay,
+            read: Throttle::new(),
+            write: Throttle::new(),
+        }
+    }
+
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the underlying stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the `Shaped` stream, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    // The number of bytes of a buffer of length `len` to transfer at once.
+    fn chunk(&self, len: usize) -> usize {
+        match self.shaping.max_chunk {
+            Some(max_chunk) => min(len, max(max_chunk, 1)),
+            None => len,
+        }
+    }
+}
+
+impl<S, MakeDelay, D> AsyncRead for Shaped<S, MakeDelay, D>
+    where S: AsyncRead,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        if let Pending = self.read.poll(cx, &self.shaping, &mut self.make_delay) {
+            return Ok(Pending);
+        }
+
+        let len = self.chunk(buf.len());
+        match self.stream.poll_read(cx, &mut buf[..len])? {
+            Ready(read) => {
+                self.read.transferred(read, &self.shaping);
+                Ok(Ready(read))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+}
+
+impl<S, MakeDelay, D> AsyncWrite for Shaped<S, MakeDelay, D>
+    where S: AsyncWrite,
+          MakeDelay: FnMut(Duration) -> D,
+          D: Future<Item = ()>
+{
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        if let Pending = self.write.poll(cx, &self.shaping, &mut self.make_delay) {
+            return Ok(Pending);
+        }
+
+        let len = self.chunk(buf.len());
+        match self.stream.poll_write(cx, &buf[..len])? {
+            Ready(written) => {
+                self.write.transferred(written, &self.shaping);
+                Ok(Ready(written))
+            }
+            Pending => Ok(Pending),
+        }
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.stream.poll_flush(cx)
+    }
+
+    fn poll_close(&mut self, cx: &mut Context) -> Poll<(), io::Error> {
+        self.stream.poll_close(cx)
+    }
+}
+
+// The wait before the next read or write in one direction of a `Shaped`
+// stream.
+struct Throttle<D> {
+    delay: Option<D>,
+    started: bool, // whether the wait for the next transfer has been started
+    backlog: Duration, // the time the previous transfer takes at the bandwidth limit
+}
+
+impl<D: Future<Item = ()>> Throttle<D> {
+    fn new() -> Throttle<D> {
+        Throttle {
+            delay: None,
+            started: false,
+            backlog: Duration::from_secs(0),
+        }
+    }
+
+    // Returns `Ready` once the next transfer may be performed.
+    fn poll<MakeDelay>(&mut self,
+                       cx: &mut Context,
+                       shaping: &Shaping,
+                       make_delay: &mut MakeDelay)
+                       -> Async<()>
+        where MakeDelay: FnMut(Duration) -> D
+    {
+        if !self.started {
+            self.started = true;
+            let wait = shaping.latency + self.backlog;
+            if wait > Duration::from_secs(0) {
+                self.delay = Some(make_delay(wait));
+            }
+        }
+
+        if let Some(mut delay) = self.delay.take() {
+            match delay.poll(cx) {
+                Ok(Pending) => {
+                    self.delay = Some(delay);
+                    return Pending;
+                }
+                Ok(Ready(())) | Err(_) => {}
+            }
+        }
+
+        Ready(())
+    }
+
+    // Records a transfer of `len` bytes, so the next one waits again.
+    fn transferred(&mut self, len: usize, shaping: &Shaping) {
+        self.started = false;
+        self.backlog = match shaping.bytes_per_second {
+            Some(rate) if rate > 0 => {
+                let nanos = len as u64 * 1_000_000_000 / rate;
+                Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+            }
+            _ => Duration::from_secs(0),
+        };
+    }
+}
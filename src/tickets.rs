@@ -0,0 +1,598 @@
+//! Experimental resumption of sessions via tickets.
+//!
+//! **This is not part of the secret-handshake protocol.** Other
+//! implementations do not support it, and the format may change between
+//! releases of this crate. Only use it when both peers run this crate with
+//! the `session-tickets` feature.
+//!
+//! After a full handshake, the server may issue a ticket to the client, e.g.
+//! over the encrypted connection. The ticket is opaque to the client: it is
+//! encrypted under a `TicketKey` that only the server knows, and contains the
+//! longterm public keys of both peers, the network identifier, an expiry
+//! time and a resumption secret derived from the outcome of the handshake.
+//! The client derives the same secret from its own outcome, and stores it
+//! together with the ticket in a `ClientTicket`.
+//!
+//! When reconnecting, the client sends a single message consisting of the
+//! ticket, a fresh ephemeral public key, and an hmac over both keyed with the
+//! resumption secret. The server decrypts the ticket, checks the hmac, and
+//! replies with its own fresh ephemeral public key and an hmac keyed with the
+//! new session secret. This takes a single round trip instead of two, and
+//! skips all signatures.
+//!
+//! The new session secret is an hmac of the Diffie-Hellman shared secret of
+//! the two ephemeral keys, keyed with the resumption secret, so resumed
+//! sessions are forward secret as well. The keys and nonces of the session
+//! are derived from it in the same way a full handshake derives them from
+//! its shared secret.
+//!
+//! Unlike a full handshake, the server does not learn whether the client is
+//! live before replying: a recorded resumption message can be replayed, but
+//! without the ephemeral secret key of the client the reply is useless.
+//! A server can not distinguish a resumption message from a full handshake
+//! by its first bytes, so accept resumptions on a separate listener, or
+//! negotiate them on the application level.
+//!
+//! ```rust,ignore
+//! // server, after a full handshake
+//! let ticket = ticket_key.issue(&outcome, Duration::from_secs(24 * 60 * 60));
+//! send_over_box_stream(ticket);
+//!
+//! // client, after the full handshake
+//! let ticket = ClientTicket::new(received_ticket, &outcome);
+//! // later
+//! let (outcome, stream) = await!(resume(stream, &ticket))?;
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
+use std::io::ErrorKind::{UnexpectedEof, WriteZero};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::{auth, box_, scalarmult, secretbox, sign};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::utils::memzero;
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::{Outcome, EncryptionParams, DecryptionParams, NETWORK_IDENTIFIER_BYTES, Redacted};
+use errors::HandshakeError;
+use identity::Identity;
+
+// The label from which the resumption secret is derived via
+// `Outcome::derive_key`.
+const RESUMPTION_LABEL: &'static [u8] = b"shs1-ticket-resumption";
+// Prefix of the input of the hmac that yields the session secret.
+const SESSION_LABEL: &'static [u8] = b"shs1-resume";
+// The input of the hmac with which the server proves knowledge of the
+// session secret.
+const ACCEPT_LABEL: &'static [u8] = b"shs1-resume-accept";
+
+const SECRET_BYTES: usize = 32;
+// client longterm pk, server longterm pk, network identifier, resumption
+// secret, and the expiry as big-endian seconds since the unix epoch
+const TICKET_PLAINTEXT_BYTES: usize = 2 * sign::PUBLICKEYBYTES + NETWORK_IDENTIFIER_BYTES +
+                                      SECRET_BYTES + 8;
+
+/// The length of a ticket in bytes.
+pub const TICKET_BYTES: usize = secretbox::NONCEBYTES + secretbox::MACBYTES +
+                                TICKET_PLAINTEXT_BYTES;
+/// The length of the message with which the client resumes a session.
+pub const RESUME_BYTES: usize = TICKET_BYTES + box_::PUBLICKEYBYTES + hmacsha256::TAGBYTES;
+/// The length of the reply of the server to a resumption message.
+pub const ACCEPT_BYTES: usize = box_::PUBLICKEYBYTES + hmacsha256::TAGBYTES;
+
+/// Everything that can go wrong when the server checks a resumption message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TicketError {
+    /// The ticket was not issued under this ticket key, or the message was
+    /// not authenticated with the secret of the ticket.
+    Invalid,
+    /// The ticket was issued by a server with a different longterm public
+    /// key, or for a different network.
+    WrongServer,
+    /// The ticket has expired.
+    Expired,
+}
+
+impl Display for TicketError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Ticket error: {}", self.description())
+    }
+}
+
+impl Error for TicketError {
+    fn description(&self) -> &str {
+        match *self {
+            TicketError::Invalid => "invalid ticket",
+            TicketError::WrongServer => "ticket for a different server",
+            TicketError::Expired => "expired ticket",
+        }
+    }
+}
+
+fn resumption_secret(outcome: &Outcome) -> [u8; SECRET_BYTES] {
+    let mut secret = [0; SECRET_BYTES];
+    outcome.derive_key(RESUMPTION_LABEL, &mut secret);
+    secret
+}
+
+/// The key with which a server encrypts its tickets.
+///
+/// All servers that should accept a ticket need the key it was issued
+/// under. Rotating the key invalidates all tickets issued so far. The key is
+/// zeroed when dropped, and the `Debug` output does not show it.
+#[derive(Clone)]
+pub struct TicketKey(secretbox::Key);
+
+impl Debug for TicketKey {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_tuple("TicketKey").field(&Redacted).finish()
+    }
+}
+
+impl TicketKey {
+    /// Generates a random ticket key.
+    pub fn generate() -> TicketKey {
+        TicketKey(secretbox::gen_key())
+    }
+
+    /// Creates a ticket key from its bytes, e.g. to share a key between
+    /// several servers.
+    pub fn from_bytes(bytes: [u8; secretbox::KEYBYTES]) -> TicketKey {
+        TicketKey(secretbox::Key(bytes))
+    }
+
+    /// Issues a ticket to the client of a full handshake, given the outcome
+    /// of the server. The ticket can be used for `lifetime` from now on.
+    pub fn issue(&self, outcome: &Outcome, lifetime: Duration) -> [u8; TICKET_BYTES] {
+        let expiry = SystemTime::now() + lifetime;
+        let expiry = expiry.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        let mut plaintext = [0; TICKET_PLAINTEXT_BYTES];
+        {
+            let client_longterm_pk = outcome.peer_longterm_pk();
+            let server_longterm_pk = outcome.local_longterm_pk();
+            let network_identifier = outcome.network_identifier();
+            let mut secret = resumption_secret(outcome);
+            {
+                let fields: [&[u8]; 4] = [&client_longterm_pk.0,
+                                          &server_longterm_pk.0,
+                                          &network_identifier,
+                                          &secret];
+                let mut offset = 0;
+                for field in fields.iter() {
+                    plaintext[offset..offset + field.len()].copy_from_slice(field);
+                    offset += field.len();
+                }
+                for i in 0..8 {
+                    plaintext[offset + i] = (expiry >> (8 * (7 - i))) as u8;
+                }
+            }
+            memzero(&mut secret);
+        }
+
+        let nonce = secretbox::gen_nonce();
+        let mut ticket = [0; TICKET_BYTES];
+        ticket[..secretbox::NONCEBYTES].copy_from_slice(&nonce.0);
+        ticket[secretbox::NONCEBYTES..].copy_from_slice(&secretbox::seal(&plaintext,
+                                                                         &nonce,
+                                                                         &self.0));
+        memzero(&mut plaintext);
+        ticket
+    }
+
+    /// Checks a resumption message sent by a client, and computes the reply
+    /// and the outcome of the resumed session, using a fresh ephemeral
+    /// keypair. `now` is the time against which the expiry of the ticket is
+    /// checked.
+    pub fn accept(&self,
+                  identity: &Identity,
+                  msg: &[u8; RESUME_BYTES],
+                  now: SystemTime)
+                  -> Result<([u8; ACCEPT_BYTES], Outcome), TicketError> {
+        let (ticket, rest) = msg.split_at(TICKET_BYTES);
+        let (client_ephemeral_pk, tag) = rest.split_at(box_::PUBLICKEYBYTES);
+
+        let nonce = secretbox::Nonce::from_slice(&ticket[..secretbox::NONCEBYTES]).unwrap();
+        let mut plaintext = secretbox::open(&ticket[secretbox::NONCEBYTES..], &nonce, &self.0)
+            .map_err(|_| TicketError::Invalid)?;
+        let result =
+            self.accept_ticket(identity, &plaintext, ticket, client_ephemeral_pk, tag, now);
+        memzero(&mut plaintext);
+        result
+    }
+
+    fn accept_ticket(&self,
+                     identity: &Identity,
+                     plaintext: &[u8],
+                     ticket: &[u8],
+                     client_ephemeral_pk: &[u8],
+                     tag: &[u8],
+                     now: SystemTime)
+                     -> Result<([u8; ACCEPT_BYTES], Outcome), TicketError> {
+        let (client_longterm_pk, rest) = plaintext.split_at(sign::PUBLICKEYBYTES);
+        let (server_longterm_pk, rest) = rest.split_at(sign::PUBLICKEYBYTES);
+        let (network_identifier, rest) = rest.split_at(NETWORK_IDENTIFIER_BYTES);
+        let (secret, expiry) = rest.split_at(SECRET_BYTES);
+
+        let key = hmacsha256::Key::from_slice(secret).unwrap();
+        let tag = hmacsha256::Tag::from_slice(tag).unwrap();
+        let mut input = Vec::with_capacity(TICKET_BYTES + box_::PUBLICKEYBYTES);
+        input.extend_from_slice(ticket);
+        input.extend_from_slice(client_ephemeral_pk);
+        if !hmacsha256::verify(&tag, &input, &key) {
+            return Err(TicketError::Invalid);
+        }
+
+        if server_longterm_pk != &identity.longterm_pk().0[..] ||
+           network_identifier != &identity.network_identifier()[..] {
+            return Err(TicketError::WrongServer);
+        }
+
+        let expiry = expiry.iter().fold(0u64, |acc, byte| (acc << 8) | (*byte as u64));
+        if now > UNIX_EPOCH + Duration::from_secs(expiry) {
+            return Err(TicketError::Expired);
+        }
+
+        let client_longterm_pk = sign::PublicKey::from_slice(client_longterm_pk).unwrap();
+        let client_ephemeral_pk = box_::PublicKey::from_slice(client_ephemeral_pk).unwrap();
+        let (server_ephemeral_pk, server_ephemeral_sk) = box_::gen_keypair();
+
+        let mut session = Session::new(&key,
+                                       &server_ephemeral_sk,
+                                       &client_ephemeral_pk,
+                                       &client_ephemeral_pk,
+                                       &server_ephemeral_pk);
+        let mut reply = [0; ACCEPT_BYTES];
+        reply[..box_::PUBLICKEYBYTES].copy_from_slice(&server_ephemeral_pk.0);
+        reply[box_::PUBLICKEYBYTES..].copy_from_slice(&session.accept_tag().0);
+
+        let outcome = session.outcome(identity.network_identifier(),
+                                      (identity.longterm_pk(), &server_ephemeral_pk),
+                                      (&client_longterm_pk, &client_ephemeral_pk));
+        session.wipe();
+        Ok((reply, outcome))
+    }
+}
+
+// The secret of a resumed session, from which its keys are derived.
+struct Session([u8; hmacsha256::TAGBYTES]);
+
+impl Session {
+    fn new(resumption_key: &hmacsha256::Key,
+           ephemeral_sk: &box_::SecretKey,
+           peer_ephemeral_pk: &box_::PublicKey,
+           client_ephemeral_pk: &box_::PublicKey,
+           server_ephemeral_pk: &box_::PublicKey)
+           -> Session {
+        let mut shared = scalarmult::scalarmult(&scalarmult::Scalar(ephemeral_sk.0),
+                                                &scalarmult::GroupElement(peer_ephemeral_pk.0));
+
+        let mut input = Vec::with_capacity(SESSION_LABEL.len() +
+                                           scalarmult::GROUPELEMENTBYTES +
+                                           2 * box_::PUBLICKEYBYTES);
+        input.extend_from_slice(SESSION_LABEL);
+        input.extend_from_slice(&shared.0);
+        input.extend_from_slice(&client_ephemeral_pk.0);
+        input.extend_from_slice(&server_ephemeral_pk.0);
+        let mut tag = hmacsha256::authenticate(&input, resumption_key);
+        let session = Session(tag.0);
+
+        memzero(&mut shared.0);
+        memzero(&mut input);
+        memzero(&mut tag.0);
+        session
+    }
+
+    fn accept_tag(&self) -> hmacsha256::Tag {
+        hmacsha256::authenticate(ACCEPT_LABEL, &hmacsha256::Key(self.0))
+    }
+
+    // The key for messages sent to the peer with the given longterm public
+    // key, as in a full handshake.
+    fn key_to(&self, longterm_pk: &sign::PublicKey) -> secretbox::Key {
+        let mut input = [0; hmacsha256::TAGBYTES + sign::PUBLICKEYBYTES];
+        input[..hmacsha256::TAGBYTES].copy_from_slice(&self.0);
+        input[hmacsha256::TAGBYTES..].copy_from_slice(&longterm_pk.0);
+        let key = secretbox::Key(sha256::hash(&input).0);
+        memzero(&mut input);
+        key
+    }
+
+    fn outcome(&self,
+               network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+               local: (&sign::PublicKey, &box_::PublicKey),
+               peer: (&sign::PublicKey, &box_::PublicKey))
+               -> Outcome {
+        let send = EncryptionParams {
+            key: self.key_to(peer.0),
+            nonce: initial_nonce(network_identifier, peer.1),
+        };
+        let recv = DecryptionParams {
+            key: self.key_to(local.0),
+            nonce: initial_nonce(network_identifier, local.1),
+        };
+        Outcome::resumed(&send, &recv, *network_identifier, local, peer)
+    }
+
+    fn wipe(&mut self) {
+        memzero(&mut self.0);
+    }
+}
+
+// The initial nonce for messages sent to the peer with the given ephemeral
+// public key, as in a full handshake.
+fn initial_nonce(network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                 ephemeral_pk: &box_::PublicKey)
+                 -> secretbox::Nonce {
+    let tag = auth::authenticate(&ephemeral_pk.0, &auth::Key(*network_identifier));
+    secretbox::Nonce::from_slice(&tag.0[..secretbox::NONCEBYTES]).unwrap()
+}
+
+/// A ticket held by a client, together with the resumption secret.
+///
+/// The secret is zeroed when dropped, and the `Debug` output does not show
+/// it.
+#[derive(Clone)]
+pub struct ClientTicket {
+    ticket: [u8; TICKET_BYTES],
+    secret: [u8; SECRET_BYTES],
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    client_longterm_pk: sign::PublicKey,
+    server_longterm_pk: sign::PublicKey,
+}
+
+impl Debug for ClientTicket {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("ClientTicket")
+            .field("secret", &Redacted)
+            .field("client_longterm_pk", &self.client_longterm_pk)
+            .field("server_longterm_pk", &self.server_longterm_pk)
+            .finish()
+    }
+}
+
+impl Drop for ClientTicket {
+    fn drop(&mut self) {
+        memzero(&mut self.secret);
+    }
+}
+
+impl ClientTicket {
+    /// Stores a `ticket` received from the server, given the outcome of the
+    /// client of the handshake during which it was issued.
+    pub fn new(ticket: [u8; TICKET_BYTES], outcome: &Outcome) -> ClientTicket {
+        ClientTicket {
+            ticket,
+            secret: resumption_secret(outcome),
+            network_identifier: outcome.network_identifier(),
+            client_longterm_pk: outcome.local_longterm_pk(),
+            server_longterm_pk: outcome.peer_longterm_pk(),
+        }
+    }
+
+    /// The longterm public key of the server that issued the ticket.
+    pub fn server_longterm_pk(&self) -> &sign::PublicKey {
+        &self.server_longterm_pk
+    }
+
+    fn resume_msg(&self, ephemeral_pk: &box_::PublicKey) -> [u8; RESUME_BYTES] {
+        let mut msg = [0; RESUME_BYTES];
+        msg[..TICKET_BYTES].copy_from_slice(&self.ticket);
+        msg[TICKET_BYTES..TICKET_BYTES + box_::PUBLICKEYBYTES].copy_from_slice(&ephemeral_pk.0);
+        let tag = hmacsha256::authenticate(&msg[..TICKET_BYTES + box_::PUBLICKEYBYTES],
+                                           &hmacsha256::Key(self.secret));
+        msg[TICKET_BYTES + box_::PUBLICKEYBYTES..].copy_from_slice(&tag.0);
+        msg
+    }
+
+    // Verifies the reply of the server and computes the outcome.
+    fn finish(&self,
+              ephemeral_pk: &box_::PublicKey,
+              ephemeral_sk: &box_::SecretKey,
+              reply: &[u8; ACCEPT_BYTES])
+              -> Option<Outcome> {
+        let server_ephemeral_pk = box_::PublicKey::from_slice(&reply[..box_::PUBLICKEYBYTES])
+            .unwrap();
+        let mut session = Session::new(&hmacsha256::Key(self.secret),
+                                       ephemeral_sk,
+                                       &server_ephemeral_pk,
+                                       ephemeral_pk,
+                                       &server_ephemeral_pk);
+
+        let tag = hmacsha256::Tag::from_slice(&reply[box_::PUBLICKEYBYTES..]).unwrap();
+        let outcome = if session.accept_tag() == tag {
+            Some(session.outcome(&self.network_identifier,
+                                 (&self.client_longterm_pk, ephemeral_pk),
+                                 (&self.server_longterm_pk, &server_ephemeral_pk)))
+        } else {
+            None
+        };
+        session.wipe();
+        outcome
+    }
+}
+
+/// Resumes a session with the server that issued the `ticket`, over the
+/// `stream`, using a fresh ephemeral keypair.
+pub fn resume<S>(stream: S, ticket: &ClientTicket) -> Resume<S>
+    where S: AsyncRead + AsyncWrite
+{
+    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+    Resume {
+        stream: Some(stream),
+        msg: ticket.resume_msg(&ephemeral_pk),
+        written: 0,
+        flushed: false,
+        reply: [0; ACCEPT_BYTES],
+        read: 0,
+        ticket: ticket.clone(),
+        ephemeral_pk,
+        ephemeral_sk,
+    }
+}
+
+/// Future that resumes a session as the client, see `resume`.
+///
+/// Fails with `HandshakeError::CryptoError` if the server did not prove
+/// knowledge of the session secret, e.g. because it rejected the ticket.
+pub struct Resume<S> {
+    stream: Option<S>,
+    msg: [u8; RESUME_BYTES],
+    written: usize,
+    flushed: bool,
+    reply: [u8; ACCEPT_BYTES],
+    read: usize,
+    ticket: ClientTicket,
+    ephemeral_pk: box_::PublicKey,
+    ephemeral_sk: box_::SecretKey,
+}
+
+impl<S: AsyncRead + AsyncWrite> Resume<S> {
+    fn poll_exchange(&mut self, cx: &mut Context) -> Result<bool, io::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            // The resumption has already completed, stay in that terminal state.
+            None => return Ok(false),
+        };
+
+        while self.written < RESUME_BYTES {
+            match stream.poll_write(cx, &self.msg[self.written..])? {
+                Ready(0) => return Err(io::Error::new(WriteZero, "failed to write message")),
+                Ready(written) => self.written += written,
+                Pending => return Ok(false),
+            }
+        }
+        if !self.flushed {
+            match stream.poll_flush(cx)? {
+                Ready(()) => self.flushed = true,
+                Pending => return Ok(false),
+            }
+        }
+
+        while self.read < ACCEPT_BYTES {
+            match stream.poll_read(cx, &mut self.reply[self.read..])? {
+                Ready(0) => return Err(io::Error::new(UnexpectedEof, "failed to read reply")),
+                Ready(read) => self.read += read,
+                Pending => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for Resume<S> {
+    type Item = (Outcome, S);
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.poll_exchange(cx) {
+            Ok(false) => return Ok(Pending),
+            Ok(true) => {}
+            Err(err) => {
+                return Err((HandshakeError::IoError(err), self.stream.take().unwrap()));
+            }
+        }
+
+        let stream = self.stream.take().unwrap();
+        match self.ticket.finish(&self.ephemeral_pk, &self.ephemeral_sk, &self.reply) {
+            Some(outcome) => Ok(Ready((outcome, stream))),
+            None => Err((HandshakeError::CryptoError, stream)),
+        }
+    }
+}
+
+/// Accepts the resumption of a session over the `stream`, for tickets issued
+/// under the `ticket_key` by the server with the given `identity`.
+pub fn accept_resumption<S>(stream: S,
+                            ticket_key: &TicketKey,
+                            identity: &Identity)
+                            -> AcceptResumption<S>
+    where S: AsyncRead + AsyncWrite
+{
+    AcceptResumption {
+        stream: Some(stream),
+        msg: [0; RESUME_BYTES],
+        read: 0,
+        reply: [0; ACCEPT_BYTES],
+        written: 0,
+        outcome: None,
+        ticket_key: ticket_key.clone(),
+        identity: identity.clone(),
+    }
+}
+
+/// Future that accepts the resumption of a session as the server, see
+/// `accept_resumption`.
+///
+/// Fails with `HandshakeError::CryptoError` if the ticket was rejected, in
+/// which case no reply is sent. Use `TicketKey::accept` to learn why.
+pub struct AcceptResumption<S> {
+    stream: Option<S>,
+    msg: [u8; RESUME_BYTES],
+    read: usize,
+    reply: [u8; ACCEPT_BYTES],
+    written: usize,
+    outcome: Option<Outcome>,
+    ticket_key: TicketKey,
+    identity: Identity,
+}
+
+impl<S: AsyncRead + AsyncWrite> AcceptResumption<S> {
+    fn poll_exchange(&mut self, cx: &mut Context) -> Result<bool, HandshakeError> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            // The resumption has already completed, stay in that terminal state.
+            None => return Ok(false),
+        };
+
+        while self.read < RESUME_BYTES {
+            match stream.poll_read(cx, &mut self.msg[self.read..])? {
+                Ready(0) => {
+                    return Err(io::Error::new(UnexpectedEof, "failed to read message").into())
+                }
+                Ready(read) => self.read += read,
+                Pending => return Ok(false),
+            }
+        }
+
+        if self.outcome.is_none() {
+            let (reply, outcome) = self.ticket_key
+                .accept(&self.identity, &self.msg, SystemTime::now())
+                .map_err(|_| HandshakeError::CryptoError)?;
+            self.reply = reply;
+            self.outcome = Some(outcome);
+        }
+
+        while self.written < ACCEPT_BYTES {
+            match stream.poll_write(cx, &self.reply[self.written..])? {
+                Ready(0) => {
+                    return Err(io::Error::new(WriteZero, "failed to write reply").into())
+                }
+                Ready(written) => self.written += written,
+                Pending => return Ok(false),
+            }
+        }
+        Ok(stream.poll_flush(cx)?.is_ready())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for AcceptResumption<S> {
+    type Item = (Outcome, S);
+    type Error = (HandshakeError, S);
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        match self.poll_exchange(cx) {
+            Ok(false) => Ok(Pending),
+            Ok(true) => {
+                Ok(Ready((self.outcome.take().unwrap(), self.stream.take().unwrap())))
+            }
+            Err(err) => Err((err, self.stream.take().unwrap())),
+        }
+    }
+}
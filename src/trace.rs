@@ -0,0 +1,114 @@
+// Events about the progress of handshakes.
+//
+// Behind the `tracing` feature, these are emitted via the `tracing` crate,
+// behind the `log` feature as records via the `log` crate. Otherwise they
+// compile to nothing. Peer keys are base64 encoded, like in ssb identities,
+// and abbreviated in log records.
+
+#![cfg_attr(not(all(feature = "tracing", feature = "log")), allow(unused_variables))]
+
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::sign;
+
+use errors::FilteringHandshakeError;
+
+// Which side of the handshake an event concerns.
+#[derive(Clone, Copy)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    #[cfg(any(feature = "tracing", feature = "log"))]
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+// A handshake has been created.
+pub(crate) fn started(side: Side) {
+    #[cfg(feature = "tracing")]
+    ::tracing::debug!(side = side.as_str(), "handshake started");
+    #[cfg(feature = "log")]
+    ::log::debug!("{} handshake started", side.as_str());
+}
+
+// Message `msg` (1 to 4) has been written and flushed.
+pub(crate) fn msg_sent(side: Side, msg: u8) {
+    #[cfg(feature = "tracing")]
+    ::tracing::debug!(side = side.as_str(), msg, "handshake message sent");
+}
+
+// Message `msg` (1 to 4) has been received and checked.
+pub(crate) fn msg_verified(side: Side, msg: u8, valid: bool) {
+    if valid {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!(side = side.as_str(), msg, "handshake message verified");
+    } else {
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!(side = side.as_str(), msg, "invalid handshake message");
+    }
+}
+
+// The filter function decided whether to accept the authenticated peer.
+pub(crate) fn filter_decision(side: Side, peer: &sign::PublicKey, accepted: bool) {
+    #[cfg(feature = "tracing")]
+    ::tracing::info!(side = side.as_str(),
+                     peer = %encode(peer),
+                     accepted,
+                     "filtered peer");
+}
+
+// The handshake with the given peer completed.
+pub(crate) fn outcome_computed(side: Side, peer: &sign::PublicKey) {
+    #[cfg(feature = "tracing")]
+    ::tracing::info!(side = side.as_str(), peer = %encode(peer), "handshake completed");
+    #[cfg(feature = "log")]
+    ::log::debug!("{} handshake with {} completed",
+                  side.as_str(),
+                  abbreviate(peer));
+}
+
+// The handshake failed for the given reason.
+pub(crate) fn failed<R: Display>(side: Side, reason: &R) {
+    #[cfg(feature = "tracing")]
+    ::tracing::warn!(side = side.as_str(), reason = %reason, "handshake failed");
+    #[cfg(feature = "log")]
+    ::log::warn!("{} handshake failed: {}", side.as_str(), reason);
+}
+
+// Describes why a filtering handshake failed, without requiring the error of
+// the filter function to implement `Display`.
+pub(crate) struct Reason<'a, E: 'a>(pub(crate) &'a FilteringHandshakeError<E>);
+
+impl<'a, E> Display for Reason<'a, E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self.0 {
+            FilteringHandshakeError::IoError(ref err) => write!(f, "io error: {}", err),
+            FilteringHandshakeError::FilterError(_) => write!(f, "the filter function errored"),
+            FilteringHandshakeError::CryptoError => write!(f, "invalid handshake message"),
+            FilteringHandshakeError::Rejected => write!(f, "rejected by the filter function"),
+            FilteringHandshakeError::SelfConnection => write!(f, "connection to self"),
+            FilteringHandshakeError::BudgetExceeded => write!(f, "budget exceeded"),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn encode(peer: &sign::PublicKey) -> String {
+    ::base64::encode(&peer.0)
+}
+
+// The first characters of the base64 encoded key, enough to tell peers apart
+// in logs.
+#[cfg(feature = "log")]
+fn abbreviate(peer: &sign::PublicKey) -> String {
+    let mut encoded = ::base64::encode(&peer.0);
+    encoded.truncate(8);
+    format!("@{}...", encoded)
+}
@@ -0,0 +1,219 @@
+//! Explain a captured handshake, for debugging interoperability issues.
+//!
+//! Given the keys of both parties (e.g. in a test setup) and the bytes each
+//! of them sent, `explain` replays the handshake and reports for each of the
+//! four messages whether it is valid, and if not, which check failed.
+
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::{auth, box_};
+
+use crypto::*;
+use identity::Identity;
+use messages::{ClientHello, ServerHello, ClientAuth, ServerAck};
+
+/// The result of checking a single message of a transcript.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message is valid.
+    Valid,
+    /// The transcript ends before the message is complete.
+    Missing,
+    /// The message was not checked because an earlier message was invalid.
+    NotChecked,
+    /// The hello carries a different ephemeral public key than the one given
+    /// for its sender.
+    UnexpectedEphemeralKey,
+    /// The hello was authenticated with a different network identifier.
+    InvalidHmac,
+    /// The client authentication is valid, but for a different client
+    /// longterm public key than the one given.
+    UnexpectedLongtermKey,
+    /// The message could not be decrypted, or its signature is invalid.
+    Invalid,
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        let explanation = match *self {
+            Verdict::Valid => "valid",
+            Verdict::Missing => "missing or truncated",
+            Verdict::NotChecked => "not checked, an earlier message is invalid",
+            Verdict::UnexpectedEphemeralKey => "unexpected ephemeral public key",
+            Verdict::InvalidHmac => "invalid hmac, the network identifiers differ",
+            Verdict::UnexpectedLongtermKey => "valid, but for a different client longterm key",
+            Verdict::Invalid => "decryption or signature verification failed",
+        };
+        write!(f, "{}", explanation)
+    }
+}
+
+/// The verdicts on all messages of a transcript.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Explanation {
+    /// The client hello.
+    pub msg1: Verdict,
+    /// The server hello.
+    pub msg2: Verdict,
+    /// The client authentication.
+    pub msg3: Verdict,
+    /// The server acknowledgement.
+    pub msg4: Verdict,
+}
+
+impl Explanation {
+    /// Returns whether all messages are valid.
+    pub fn is_valid(&self) -> bool {
+        self.msg1 == Verdict::Valid && self.msg2 == Verdict::Valid &&
+        self.msg3 == Verdict::Valid && self.msg4 == Verdict::Valid
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        writeln!(f, "msg1 (client hello): {}", self.msg1)?;
+        writeln!(f, "msg2 (server hello): {}", self.msg2)?;
+        writeln!(f, "msg3 (client auth): {}", self.msg3)?;
+        write!(f, "msg4 (server ack): {}", self.msg4)
+    }
+}
+
+/// Replays a captured handshake between the `client` and the `server`, and
+/// explains each message.
+///
+/// `client_to_server` are the bytes sent by the client (msg1 followed by
+/// msg3), `server_to_client` the bytes sent by the server (msg2 followed by
+/// msg4). Any bytes following the handshake are ignored. The client uses the
+/// network identifier of the `client` identity, the server that of the
+/// `server` identity.
+pub fn explain(client: &Identity,
+               client_ephemeral_pk: &box_::PublicKey,
+               client_ephemeral_sk: &box_::SecretKey,
+               server: &Identity,
+               server_ephemeral_pk: &box_::PublicKey,
+               server_ephemeral_sk: &box_::SecretKey,
+               client_to_server: &[u8],
+               server_to_client: &[u8])
+               -> Explanation {
+    let mut explanation = Explanation {
+        msg1: Verdict::NotChecked,
+        msg2: Verdict::NotChecked,
+        msg3: Verdict::NotChecked,
+        msg4: Verdict::NotChecked,
+    };
+
+    let mut c = Client::new(client.network_identifier(),
+                            &client.longterm_pk().0,
+                            &client.longterm_sk().0,
+                            &client_ephemeral_pk.0,
+                            &client_ephemeral_sk.0,
+                            &server.longterm_pk().0);
+    let mut s = Server::new(server.network_identifier(),
+                            &server.longterm_pk().0,
+                            &server.longterm_sk().0,
+                            &server_ephemeral_pk.0,
+                            &server_ephemeral_sk.0);
+
+    // The client state has to advance, even though its own hello is not used.
+    c.client_hello();
+    explanation.msg1 = match ClientHello::from_slice(segment(client_to_server, 0, MSG1_BYTES)) {
+        None => Verdict::Missing,
+        Some(msg1) => {
+            if s.verify_client_hello(&msg1) {
+                check_ephemeral_key(msg1.as_bytes(), client_ephemeral_pk)
+            } else {
+                diagnose_hello(msg1.as_bytes(), server.network_identifier(), client_ephemeral_pk)
+            }
+        }
+    };
+    if explanation.msg1 != Verdict::Valid {
+        return explanation;
+    }
+
+    s.server_hello();
+    explanation.msg2 = match ServerHello::from_slice(segment(server_to_client, 0, MSG2_BYTES)) {
+        None => Verdict::Missing,
+        Some(msg2) => {
+            if c.verify_server_hello(&msg2) {
+                check_ephemeral_key(msg2.as_bytes(), server_ephemeral_pk)
+            } else {
+                diagnose_hello(msg2.as_bytes(), client.network_identifier(), server_ephemeral_pk)
+            }
+        }
+    };
+    if explanation.msg2 != Verdict::Valid {
+        return explanation;
+    }
+
+    c.client_auth();
+    explanation.msg3 = match ClientAuth::from_slice(segment(client_to_server,
+                                                            MSG1_BYTES,
+                                                            MSG3_BYTES)) {
+        None => Verdict::Missing,
+        Some(msg3) => {
+            if !s.verify_client_auth(&msg3) {
+                Verdict::Invalid
+            } else if unsafe { s.client_longterm_pub() } != client.longterm_pk().0 {
+                Verdict::UnexpectedLongtermKey
+            } else {
+                Verdict::Valid
+            }
+        }
+    };
+    if explanation.msg3 != Verdict::Valid {
+        return explanation;
+    }
+
+    s.server_ack();
+    explanation.msg4 = match ServerAck::from_slice(segment(server_to_client,
+                                                           MSG2_BYTES,
+                                                           MSG4_BYTES)) {
+        None => Verdict::Missing,
+        Some(msg4) => {
+            if c.verify_server_ack(&msg4) {
+                Verdict::Valid
+            } else {
+                Verdict::Invalid
+            }
+        }
+    };
+
+    explanation
+}
+
+// Returns the `len` bytes starting at `start`, or as many as there are.
+fn segment(bytes: &[u8], start: usize, len: usize) -> &[u8] {
+    if bytes.len() <= start {
+        &[]
+    } else if bytes.len() < start + len {
+        &bytes[start..]
+    } else {
+        &bytes[start..start + len]
+    }
+}
+
+// A hello consists of an hmac of the ephemeral public key, followed by the
+// ephemeral public key.
+fn check_ephemeral_key(hello: &[u8; MSG1_BYTES], expected: &box_::PublicKey) -> Verdict {
+    if hello[auth::TAGBYTES..] == expected.0[..] {
+        Verdict::Valid
+    } else {
+        Verdict::UnexpectedEphemeralKey
+    }
+}
+
+fn diagnose_hello(hello: &[u8; MSG1_BYTES],
+                  network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                  expected: &box_::PublicKey)
+                  -> Verdict {
+    if check_ephemeral_key(hello, expected) != Verdict::Valid {
+        return Verdict::UnexpectedEphemeralKey;
+    }
+
+    let tag = auth::Tag::from_slice(&hello[..auth::TAGBYTES]).unwrap();
+    if auth::verify(&tag, &hello[auth::TAGBYTES..], &auth::Key(*network_identifier)) {
+        Verdict::Invalid
+    } else {
+        Verdict::InvalidHmac
+    }
+}
@@ -0,0 +1,239 @@
+//! Abstract over how connections to servers are established.
+//!
+//! A [`Transport`](trait.Transport.html) turns an address into a future of a
+//! connected stream, over which a handshake can be performed. The dialing
+//! utilities of this crate that take addresses, such as `connect` in this
+//! module and `batch::BatchDial`, are generic over the transport, so TCP,
+//! tunnels through a proxy, and in-memory streams for tests all share the
+//! same handshake machinery.
+//!
+//! Any function from a reference to an address to a future of a stream is a
+//! transport. With the `tokio` feature, `Tcp` connects via tokio, and
+//! `Proxied` tunnels the connections of another transport through a
+//! `proxy::Proxy`, e.g. to reach onion addresses via tor.
+//!
+//! ```rust,ignore
+//! let tor = Proxied::new(Tcp, Proxy::Socks5(tor_socks_addr));
+//! let mut batch = BatchDial::new(pubs, &identity, 4, tor);
+//! ```
+//!
+//! A `retry::Retry` dials a single address, so it takes a closure calling
+//! `Transport::connect` with that address.
+
+use std::io;
+use std::net::SocketAddr;
+#[cfg(all(feature = "tokio", unix))]
+use std::path::Path;
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_core::{Poll, Future};
+use futures_core::Async::{Ready, Pending};
+use futures_core::task::Context;
+use futures_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tokio")]
+use tokio::net::{TcpStream, ConnectFuture};
+#[cfg(all(feature = "tokio", unix))]
+use tokio::net::unix::{UnixStream, ConnectFuture as UnixConnectFuture};
+
+use client::OwningClientHandshaker;
+use crypto::Outcome;
+use errors::ConnectError;
+use identity::Identity;
+use proxy::{Proxy, Tunnel};
+
+/// Establishes connections to addresses of type `A`.
+pub trait Transport<A: ?Sized> {
+    /// The connections of this transport.
+    type Stream: AsyncRead + AsyncWrite;
+    /// Future of an established connection.
+    type Connecting: Future<Item = Self::Stream, Error = io::Error>;
+
+    /// Starts connecting to `addr`.
+    fn connect(&mut self, addr: &A) -> Self::Connecting;
+}
+
+impl<A: ?Sized, F, Connecting> Transport<A> for F
+    where F: FnMut(&A) -> Connecting,
+          Connecting: Future<Error = io::Error>,
+          Connecting::Item: AsyncRead + AsyncWrite
+{
+    type Stream = Connecting::Item;
+    type Connecting = Connecting;
+
+    fn connect(&mut self, addr: &A) -> Connecting {
+        self(addr)
+    }
+}
+
+/// Connects to socket addresses via tokio.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tcp;
+
+#[cfg(feature = "tokio")]
+impl Transport<SocketAddr> for Tcp {
+    type Stream = TcpStream;
+    type Connecting = ConnectFuture;
+
+    fn connect(&mut self, addr: &SocketAddr) -> ConnectFuture {
+        TcpStream::connect(addr)
+    }
+}
+
+/// Connects to unix domain sockets via tokio.
+#[cfg(all(feature = "tokio", unix))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unix;
+
+#[cfg(all(feature = "tokio", unix))]
+impl Transport<Path> for Unix {
+    type Stream = UnixStream;
+    type Connecting = UnixConnectFuture;
+
+    fn connect(&mut self, path: &Path) -> UnixConnectFuture {
+        UnixStream::connect(path)
+    }
+}
+
+/// Tunnels the connections of another transport through a proxy. The
+/// addresses are pairs of a host name and a port, the host is resolved by
+/// the proxy.
+#[derive(Debug, Clone)]
+pub struct Proxied<T> {
+    transport: T,
+    proxy: Proxy,
+}
+
+impl<T: Transport<SocketAddr>> Proxied<T> {
+    /// Creates a new `Proxied`, which connects to the `proxy` via the
+    /// `transport`.
+    pub fn new(transport: T, proxy: Proxy) -> Proxied<T> {
+        Proxied { transport, proxy }
+    }
+
+    /// Consumes the `Proxied`, returning the underlying transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: Transport<SocketAddr>> Transport<(String, u16)> for Proxied<T> {
+    type Stream = T::Stream;
+    type Connecting = ProxiedConnecting<T::Connecting, T::Stream>;
+
+    fn connect(&mut self, &(ref host, port): &(String, u16)) -> Self::Connecting {
+        ProxiedConnecting {
+            connecting: Some(self.transport.connect(&self.proxy.addr())),
+            tunneling: None,
+            proxy: self.proxy,
+            host: host.clone(),
+            port,
+        }
+    }
+}
+
+/// Future that connects to a proxy and negotiates a tunnel through it, see
+/// `Proxied`.
+pub struct ProxiedConnecting<Connecting, S> {
+    connecting: Option<Connecting>,
+    tunneling: Option<Tunnel<S>>,
+    proxy: Proxy,
+    host: String,
+    port: u16,
+}
+
+impl<Connecting, S> Future for ProxiedConnecting<Connecting, S>
+    where Connecting: Future<Item = S, Error = io::Error>,
+          S: AsyncRead + AsyncWrite
+{
+    type Item = S;
+    type Error = io::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        if let Some(mut connecting) = self.connecting.take() {
+            match connecting.poll(cx)? {
+                Ready(stream) => {
+                    self.tunneling = Some(self.proxy.tunnel(stream, &self.host, self.port))
+                }
+                Pending => {
+                    self.connecting = Some(connecting);
+                    return Ok(Pending);
+                }
+            }
+        }
+
+        match self.tunneling.as_mut() {
+            Some(tunneling) => tunneling.poll(cx),
+            // Connecting to the proxy has already failed, stay in that terminal state.
+            None => Ok(Pending),
+        }
+    }
+}
+
+/// Connects to `addr` via the `transport`, and performs the client side of a
+/// handshake with the server with the given longterm public key over the
+/// connection, using fresh ephemeral keys.
+pub fn connect<A, T>(transport: &mut T,
+                     addr: &A,
+                     identity: &Identity,
+                     server_longterm_pk: &sign::PublicKey)
+                     -> Connect<T::Connecting, T::Stream>
+    where A: ?Sized,
+          T: Transport<A>
+{
+    Connect {
+        connecting: Some(transport.connect(addr)),
+        handshaking: None,
+        identity: identity.clone(),
+        server_longterm_pk: server_longterm_pk.clone(),
+    }
+}
+
+/// Future that connects via a transport and performs the client side of a
+/// handshake, see `connect`.
+pub struct Connect<Connecting, S> {
+    connecting: Option<Connecting>,
+    handshaking: Option<OwningClientHandshaker<S>>,
+    identity: Identity,
+    server_longterm_pk: sign::PublicKey,
+}
+
+impl<Connecting, S> Future for Connect<Connecting, S>
+    where Connecting: Future<Item = S, Error = io::Error>,
+          S: AsyncRead + AsyncWrite
+{
+    type Item = (Outcome, S);
+    type Error = ConnectError;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        if let Some(mut connecting) = self.connecting.take() {
+            match connecting.poll(cx)? {
+                Ready(stream) => {
+                    let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+                    self.handshaking =
+                        Some(OwningClientHandshaker::new(stream,
+                                                         *self.identity.network_identifier(),
+                                                         self.identity.longterm_pk().clone(),
+                                                         self.identity.longterm_sk().clone(),
+                                                         ephemeral_pk,
+                                                         ephemeral_sk,
+                                                         self.server_longterm_pk.clone()))
+                }
+                Pending => {
+                    self.connecting = Some(connecting);
+                    return Ok(Pending);
+                }
+            }
+        }
+
+        match self.handshaking.as_mut() {
+            Some(handshaking) => {
+                handshaking
+                    .poll(cx)
+                    .map_err(|(err, _)| ConnectError::Handshake(err))
+            }
+            // Connecting has already failed, stay in that terminal state.
+            None => Ok(Pending),
+        }
+    }
+}
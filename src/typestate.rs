@@ -0,0 +1,236 @@
+//! A message-level api whose types enforce the order of the protocol.
+//!
+//! This is meant for implementing custom drivers, e.g. for transports that
+//! are not byte streams. Each state only exposes the next legal operation
+//! and consumes itself, so verify and create calls can not be made out of
+//! order like they can against the raw `crypto::Client` and `crypto::Server`.
+//!
+//! The client goes through `ClientState`, `AwaitingServerHello`,
+//! `ServerHelloReceived` and `AwaitingServerAck`, the server through
+//! `ServerState`, `ClientHelloReceived`, `AwaitingClientAuth` and
+//! `ClientAuthReceived`. Both end with an `Outcome`.
+
+use std::mem::uninitialized;
+use std::ptr;
+
+use sodiumoxide::crypto::{box_, sign};
+
+use crypto::*;
+use errors::HandshakeError;
+
+// The keys of a client together with the core pointing at them. Boxed, so
+// that the pointers remain valid when the state is moved.
+struct ClientCore {
+    // Declared first so that it is cleaned before the keys are dropped.
+    client: Client,
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: sign::PublicKey,
+    longterm_sk: sign::SecretKey,
+    ephemeral_pk: box_::PublicKey,
+    ephemeral_sk: box_::SecretKey,
+    server_longterm_pk: sign::PublicKey,
+}
+
+// The keys of a server together with the core pointing at them.
+struct ServerCore {
+    server: Server,
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    longterm_pk: sign::PublicKey,
+    longterm_sk: sign::SecretKey,
+    ephemeral_pk: box_::PublicKey,
+    ephemeral_sk: box_::SecretKey,
+}
+
+/// A client that has not sent anything yet.
+pub struct ClientState(Box<ClientCore>);
+
+/// A client that has sent its hello (msg1) and awaits the server's hello.
+pub struct AwaitingServerHello(Box<ClientCore>);
+
+/// A client that has verified the server's hello (msg2) and has to send its
+/// authentication next.
+pub struct ServerHelloReceived(Box<ClientCore>);
+
+/// A client that has sent its authentication (msg3) and awaits the server's
+/// acknowledgement.
+pub struct AwaitingServerAck(Box<ClientCore>);
+
+impl ClientState {
+    /// Creates the initial state of a client that connects to the server with
+    /// the given longterm public key.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: sign::PublicKey,
+               client_longterm_sk: sign::SecretKey,
+               client_ephemeral_pk: box_::PublicKey,
+               client_ephemeral_sk: box_::SecretKey,
+               server_longterm_pk: sign::PublicKey)
+               -> ClientState {
+        let mut core = Box::new(ClientCore {
+                                    client: Client::new(ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null()),
+                                    network_identifier,
+                                    longterm_pk: client_longterm_pk,
+                                    longterm_sk: client_longterm_sk,
+                                    ephemeral_pk: client_ephemeral_pk,
+                                    ephemeral_sk: client_ephemeral_sk,
+                                    server_longterm_pk,
+                                });
+
+        {
+            let core = &mut *core;
+            core.client
+                .set_inputs(&core.network_identifier,
+                            &core.longterm_pk.0,
+                            &core.longterm_sk.0,
+                            &core.ephemeral_pk.0,
+                            &core.ephemeral_sk.0,
+                            &core.server_longterm_pk.0);
+        }
+
+        ClientState(core)
+    }
+
+    /// Writes the client hello (msg1) into `msg1`.
+    pub fn send_hello(mut self, msg1: &mut [u8; MSG1_BYTES]) -> AwaitingServerHello {
+        self.0.client.create_msg1(msg1);
+        AwaitingServerHello(self.0)
+    }
+}
+
+impl AwaitingServerHello {
+    /// Verifies the server hello (msg2).
+    pub fn receive_hello(mut self,
+                         msg2: &[u8; MSG2_BYTES])
+                         -> Result<ServerHelloReceived, HandshakeError> {
+        if self.0.client.verify_msg2(msg2) {
+            Ok(ServerHelloReceived(self.0))
+        } else {
+            Err(HandshakeError::CryptoError)
+        }
+    }
+}
+
+impl ServerHelloReceived {
+    /// Writes the client authentication (msg3) into `msg3`.
+    pub fn send_auth(mut self, msg3: &mut [u8; MSG3_BYTES]) -> AwaitingServerAck {
+        self.0.client.create_msg3(msg3);
+        AwaitingServerAck(self.0)
+    }
+}
+
+impl AwaitingServerAck {
+    /// Verifies the server acknowledgement (msg4), and returns the outcome of
+    /// the handshake.
+    pub fn receive_ack(mut self, msg4: &[u8; MSG4_BYTES]) -> Result<Outcome, HandshakeError> {
+        if self.0.client.verify_msg4(msg4) {
+            let mut outcome = unsafe { uninitialized() };
+            self.0.client.outcome(&mut outcome);
+            Ok(outcome)
+        } else {
+            Err(HandshakeError::CryptoError)
+        }
+    }
+}
+
+/// A server that awaits the client hello (msg1).
+pub struct ServerState(Box<ServerCore>);
+
+/// A server that has verified the client hello and has to send its own hello
+/// (msg2) next.
+pub struct ClientHelloReceived(Box<ServerCore>);
+
+/// A server that has sent its hello and awaits the client's authentication
+/// (msg3).
+pub struct AwaitingClientAuth(Box<ServerCore>);
+
+/// A server that has verified the client's authentication and has to send
+/// its acknowledgement (msg4) next.
+pub struct ClientAuthReceived(Box<ServerCore>);
+
+impl ServerState {
+    /// Creates the initial state of a server.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               server_longterm_pk: sign::PublicKey,
+               server_longterm_sk: sign::SecretKey,
+               server_ephemeral_pk: box_::PublicKey,
+               server_ephemeral_sk: box_::SecretKey)
+               -> ServerState {
+        let mut core = Box::new(ServerCore {
+                                    server: Server::new(ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null(),
+                                                        ptr::null()),
+                                    network_identifier,
+                                    longterm_pk: server_longterm_pk,
+                                    longterm_sk: server_longterm_sk,
+                                    ephemeral_pk: server_ephemeral_pk,
+                                    ephemeral_sk: server_ephemeral_sk,
+                                });
+
+        {
+            let core = &mut *core;
+            core.server
+                .set_inputs(&core.network_identifier,
+                            &core.longterm_pk.0,
+                            &core.longterm_sk.0,
+                            &core.ephemeral_pk.0,
+                            &core.ephemeral_sk.0);
+        }
+
+        ServerState(core)
+    }
+
+    /// Verifies the client hello (msg1).
+    pub fn receive_hello(mut self,
+                         msg1: &[u8; MSG1_BYTES])
+                         -> Result<ClientHelloReceived, HandshakeError> {
+        if self.0.server.verify_msg1(msg1) {
+            Ok(ClientHelloReceived(self.0))
+        } else {
+            Err(HandshakeError::CryptoError)
+        }
+    }
+}
+
+impl ClientHelloReceived {
+    /// Writes the server hello (msg2) into `msg2`.
+    pub fn send_hello(mut self, msg2: &mut [u8; MSG2_BYTES]) -> AwaitingClientAuth {
+        self.0.server.create_msg2(msg2);
+        AwaitingClientAuth(self.0)
+    }
+}
+
+impl AwaitingClientAuth {
+    /// Verifies the client authentication (msg3).
+    pub fn receive_auth(mut self,
+                        msg3: &[u8; MSG3_BYTES])
+                        -> Result<ClientAuthReceived, HandshakeError> {
+        if self.0.server.verify_msg3(msg3) {
+            Ok(ClientAuthReceived(self.0))
+        } else {
+            Err(HandshakeError::CryptoError)
+        }
+    }
+}
+
+impl ClientAuthReceived {
+    /// The longterm public key of the authenticated client, e.g. to decide
+    /// whether to complete the handshake.
+    pub fn client_longterm_pk(&self) -> sign::PublicKey {
+        sign::PublicKey(unsafe { self.0.server.client_longterm_pub() })
+    }
+
+    /// Writes the server acknowledgement (msg4) into `msg4`, and returns the
+    /// outcome of the handshake.
+    pub fn send_ack(mut self, msg4: &mut [u8; MSG4_BYTES]) -> Outcome {
+        self.0.server.create_msg4(msg4);
+        let mut outcome = unsafe { uninitialized() };
+        self.0.server.outcome(&mut outcome);
+        outcome
+    }
+}
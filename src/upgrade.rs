@@ -0,0 +1,54 @@
+//! Upgrade a connection to an encrypted
+//! [box-stream](https://github.com/AljoschaMeyer/box-stream-rs) once the
+//! handshake has completed.
+//!
+//! To construct box-stream readers and writers manually, convert an `Outcome`
+//! into the `KeyNonce` pairs for both directions:
+//!
+//! ```rust,ignore
+//! let (encryption, decryption): (KeyNonce, KeyNonce) = (&outcome).into();
+//! ```
+//!
+//! This module requires the `box-stream` feature.
+
+use box_stream::{BoxDuplex, KeyNonce};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::Outcome;
+
+/// Wraps the `stream` over which a handshake resulted in the given `outcome`
+/// in a box-stream, encrypting all writes and decrypting all reads with the
+/// keys and nonces of the outcome.
+pub fn upgrade<S: AsyncRead + AsyncWrite>(outcome: &Outcome, stream: S) -> BoxDuplex<S> {
+    BoxDuplex::new(stream,
+                   outcome.send().key,
+                   outcome.recv().key,
+                   outcome.send().nonce,
+                   outcome.recv().nonce)
+}
+
+impl Outcome {
+    /// The key and initial nonce for encrypting data sent to the peer.
+    pub fn encryption_key_nonce(&self) -> KeyNonce {
+        KeyNonce {
+            key: self.send().key,
+            nonce: self.send().nonce,
+        }
+    }
+
+    /// The key and initial nonce for decrypting data received from the peer.
+    pub fn decryption_key_nonce(&self) -> KeyNonce {
+        KeyNonce {
+            key: self.recv().key,
+            nonce: self.recv().nonce,
+        }
+    }
+}
+
+/// Converts into the `KeyNonce`s for encryption and decryption, in that
+/// order.
+impl<'a> From<&'a Outcome> for (KeyNonce, KeyNonce) {
+    fn from(outcome: &'a Outcome) -> (KeyNonce, KeyNonce) {
+        (outcome.encryption_key_nonce(), outcome.decryption_key_nonce())
+    }
+}
@@ -0,0 +1,14 @@
+//! Version 1 of the secret-handshake protocol.
+//!
+//! The handshakers exported at the crate root implement version 1, this
+//! module re-exports them together with the message sizes, so that code can
+//! name the protocol version explicitly. A later revision of the protocol
+//! would live in a sibling module with its own message sizes and state
+//! machines, sharing the key types, `Identity` and `Outcome` with this one.
+//! See `Version` for selecting a version per connection.
+
+pub use client::{ClientHandshaker, OwningClientHandshaker, ClientHandshakerWithFilter,
+                 OwningClientHandshakerWithFilter};
+pub use server::{ServerHandshaker, OwningServerHandshaker, ServerHandshakerWithFilter,
+                 OwningServerHandshakerWithFilter};
+pub use crypto::{MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES};
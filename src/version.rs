@@ -0,0 +1,45 @@
+//! Versions of the secret-handshake protocol.
+
+use std::fmt::{self, Display, Formatter};
+
+use crypto::{MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES};
+
+/// A version of the secret-handshake protocol supported by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    /// Version 1, implemented by the handshakers in the `v1` module.
+    V1,
+}
+
+impl Version {
+    /// Returns the version identified by the name of a multiserver transform
+    /// protocol, e.g. `shs` for version 1.
+    pub fn from_multiserver_name(name: &str) -> Option<Version> {
+        match name {
+            "shs" => Some(Version::V1),
+            _ => None,
+        }
+    }
+
+    /// The name of the multiserver transform protocol for this version.
+    pub fn multiserver_name(&self) -> &'static str {
+        match *self {
+            Version::V1 => "shs",
+        }
+    }
+
+    /// The lengths of the four handshake messages in bytes.
+    pub fn msg_bytes(&self) -> [usize; 4] {
+        match *self {
+            Version::V1 => [MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES],
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Version::V1 => write!(f, "secret-handshake v1"),
+        }
+    }
+}
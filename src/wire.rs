@@ -0,0 +1,131 @@
+//! Label the fields of handshake messages, without any keys.
+//!
+//! The layout of the handshake messages is fixed, so the bytes sent in
+//! either direction can be split into their fields without being able to
+//! verify or decrypt them. This is what dissectors and hex dumps need.
+//!
+//! | message | fields                                                         |
+//! |---------|----------------------------------------------------------------|
+//! | msg1    | hmac (32), ephemeral pk (32)                                   |
+//! | msg2    | hmac (32), ephemeral pk (32)                                   |
+//! | msg3    | mac (16), encrypted signature (64), encrypted longterm pk (32) |
+//! | msg4    | mac (16), encrypted signature (64)                             |
+//!
+//! ```rust,ignore
+//! for field in wire::annotate(Direction::ClientToServer, &captured) {
+//!     println!("{:?} {}: {}", field.message, field.kind, hex::encode(&captured[field.range]));
+//! }
+//! ```
+
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use sodiumoxide::crypto::{auth, box_, sign, secretbox};
+
+use stage::Stage;
+
+/// Which peer sent the bytes to annotate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Bytes sent by the client, i.e. msg1 followed by msg3.
+    ClientToServer,
+    /// Bytes sent by the server, i.e. msg2 followed by msg4.
+    ServerToClient,
+}
+
+/// The kinds of fields of the handshake messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FieldKind {
+    /// The hmac of the ephemeral public key, keyed with the network
+    /// identifier.
+    Hmac,
+    /// The ephemeral public key of the sender, in plaintext.
+    EphemeralPk,
+    /// The authenticator of the encrypted part of the message.
+    Mac,
+    /// The encrypted signature of the sender.
+    EncryptedSignature,
+    /// The encrypted longterm public key of the client.
+    EncryptedLongtermPk,
+}
+
+impl FieldKind {
+    /// The length of fields of this kind in bytes.
+    pub fn size(&self) -> usize {
+        match *self {
+            FieldKind::Hmac => auth::TAGBYTES,
+            FieldKind::EphemeralPk => box_::PUBLICKEYBYTES,
+            FieldKind::Mac => secretbox::MACBYTES,
+            FieldKind::EncryptedSignature => sign::SIGNATUREBYTES,
+            FieldKind::EncryptedLongtermPk => sign::PUBLICKEYBYTES,
+        }
+    }
+}
+
+impl Display for FieldKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        let name = match *self {
+            FieldKind::Hmac => "hmac",
+            FieldKind::EphemeralPk => "ephemeral public key",
+            FieldKind::Mac => "mac",
+            FieldKind::EncryptedSignature => "encrypted signature",
+            FieldKind::EncryptedLongtermPk => "encrypted longterm public key",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A field of a handshake message, located in the annotated bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Field {
+    /// The message containing the field, `Stage::Msg1` to `Stage::Msg4`.
+    pub message: Stage,
+    /// What the field holds.
+    pub kind: FieldKind,
+    /// The position of the field in the annotated bytes. This is shorter
+    /// than `kind.size()` if the bytes end within the field.
+    pub range: Range<usize>,
+}
+
+const CLIENT_FIELDS: &'static [(Stage, FieldKind)] =
+    &[(Stage::Msg1, FieldKind::Hmac),
+      (Stage::Msg1, FieldKind::EphemeralPk),
+      (Stage::Msg3, FieldKind::Mac),
+      (Stage::Msg3, FieldKind::EncryptedSignature),
+      (Stage::Msg3, FieldKind::EncryptedLongtermPk)];
+
+const SERVER_FIELDS: &'static [(Stage, FieldKind)] =
+    &[(Stage::Msg2, FieldKind::Hmac),
+      (Stage::Msg2, FieldKind::EphemeralPk),
+      (Stage::Msg4, FieldKind::Mac),
+      (Stage::Msg4, FieldKind::EncryptedSignature)];
+
+/// Labels the fields of the handshake messages in the bytes sent in the
+/// given direction, starting at the first byte of the connection.
+///
+/// Fields that lie beyond the end of the `bytes` are omitted. Bytes after the
+/// last field are not part of the handshake, e.g. the start of a box stream.
+pub fn annotate(direction: Direction, bytes: &[u8]) -> Vec<Field> {
+    let layout = match direction {
+        Direction::ClientToServer => CLIENT_FIELDS,
+        Direction::ServerToClient => SERVER_FIELDS,
+    };
+
+    let mut fields = Vec::with_capacity(layout.len());
+    let mut start = 0;
+    for &(message, kind) in layout {
+        if start >= bytes.len() {
+            break;
+        }
+
+        let end = start + kind.size();
+        fields.push(Field {
+                        message,
+                        kind,
+                        range: start..min(end, bytes.len()),
+                    });
+        start = end;
+    }
+    fields
+}
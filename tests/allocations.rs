@@ -0,0 +1,108 @@
+// Checks that the owning client handshaker does not allocate. This lives in
+// its own test binary, so that no other tests run concurrently and inflate
+// the count of the global allocator.
+
+extern crate async_ringbuffer;
+extern crate atm_io_utils;
+extern crate futures;
+extern crate secret_handshake;
+extern crate sodiumoxide;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use async_ringbuffer::ring_buffer;
+use atm_io_utils::Duplex;
+use futures::prelude::*;
+use futures::executor::block_on;
+use futures::future::ok;
+use futures::task::Context;
+use sodiumoxide::crypto::{box_, sign};
+
+use secret_handshake::{OwningClientHandshaker, ServerHandshaker};
+
+static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+// Counts the allocations performed while polling the inner future.
+struct Counted<F> {
+    inner: F,
+    allocations: usize,
+}
+
+impl<F: Future> Future for Counted<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+        let before = allocations();
+        let result = self.inner.poll(cx);
+        self.allocations += allocations() - before;
+        result
+    }
+}
+
+#[test]
+fn owning_client_does_not_allocate() {
+    sodiumoxide::init();
+
+    let app = [42u8; 32];
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+
+    let (writer_a, reader_a) = ring_buffer(2);
+    let (writer_b, reader_b) = ring_buffer(2);
+    let client_duplex = Duplex::new(reader_a, writer_b);
+    let server_duplex = Duplex::new(reader_b, writer_a);
+
+    let before = allocations();
+    let client = OwningClientHandshaker::new(client_duplex,
+                                             app,
+                                             client_pk,
+                                             client_sk,
+                                             client_eph_pk,
+                                             client_eph_sk,
+                                             server_pk.clone());
+    assert_eq!(allocations() - before, 0);
+
+    let server = ServerHandshaker::new(server_duplex,
+                                       &app,
+                                       &server_pk,
+                                       &server_sk,
+                                       &server_eph_pk,
+                                       &server_eph_sk);
+
+    let mut client = Counted {
+        inner: client,
+        allocations: 0,
+    };
+
+    let (client_result, server_result) = block_on((&mut client)
+                                                      .then(|r| ok::<_, ()>(r))
+                                                      .join(server.then(|r| ok::<_, ()>(r))))
+            .unwrap();
+    assert!(client_result.is_ok());
+    assert!(server_result.is_ok());
+    assert_eq!(client.allocations, 0);
+}
@@ -0,0 +1,99 @@
+// Compile-time checks of the auto traits of the public types. Losing one of
+// these is a breaking change, so this fails to compile instead.
+
+extern crate futures;
+extern crate secret_handshake;
+extern crate sodiumoxide;
+
+use std::marker::Unpin;
+
+use futures::Never;
+use futures::future::FutureResult;
+use sodiumoxide::crypto::sign;
+
+use secret_handshake::*;
+use secret_handshake::errors::HandshakeError;
+use secret_handshake::sans_io::HandshakeState;
+
+fn assert_send<T: Send>() {}
+fn assert_sync<T: Sync>() {}
+fn assert_unpin<T: Unpin>() {}
+fn assert_static<T: 'static>() {}
+
+// Stands in for a stream that is `Send`, `Sync` and `Unpin`.
+struct Stream;
+
+type Verdict = FutureResult<bool, Never>;
+type Filter = fn(&sign::PublicKey) -> Verdict;
+
+#[test]
+fn handshakers_are_send_sync_and_unpin() {
+    assert_send::<ClientHandshaker<Stream>>();
+    assert_sync::<ClientHandshaker<Stream>>();
+    assert_unpin::<ClientHandshaker<Stream>>();
+
+    assert_send::<OwningClientHandshaker<Stream>>();
+    assert_sync::<OwningClientHandshaker<Stream>>();
+    assert_unpin::<OwningClientHandshaker<Stream>>();
+
+    assert_send::<ClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_sync::<ClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_unpin::<ClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+
+    assert_send::<OwningClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_sync::<OwningClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_unpin::<OwningClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+
+    assert_send::<ServerHandshaker<Stream>>();
+    assert_sync::<ServerHandshaker<Stream>>();
+    assert_unpin::<ServerHandshaker<Stream>>();
+
+    assert_send::<OwningServerHandshaker<Stream>>();
+    assert_sync::<OwningServerHandshaker<Stream>>();
+    assert_unpin::<OwningServerHandshaker<Stream>>();
+
+    assert_send::<ServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_sync::<ServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_unpin::<ServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+
+    assert_send::<OwningServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_sync::<OwningServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_unpin::<OwningServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+
+    assert_send::<HandshakeState>();
+    assert_sync::<HandshakeState>();
+    assert_unpin::<HandshakeState>();
+}
+
+#[test]
+fn owning_handshakers_are_static() {
+    assert_static::<OwningClientHandshaker<Stream>>();
+    assert_static::<OwningClientHandshakerWithFilter<Stream, Filter, Verdict>>();
+    assert_static::<OwningServerHandshaker<Stream>>();
+    assert_static::<OwningServerHandshakerWithFilter<Stream, Filter, Verdict>>();
+}
+
+#[test]
+fn outcomes_are_send_sync_and_static() {
+    assert_send::<Outcome>();
+    assert_sync::<Outcome>();
+    assert_unpin::<Outcome>();
+    assert_static::<Outcome>();
+
+    assert_send::<SessionKeys>();
+    assert_sync::<SessionKeys>();
+    assert_static::<SessionKeys>();
+
+    assert_send::<EncryptionParams>();
+    assert_sync::<EncryptionParams>();
+    assert_send::<DecryptionParams>();
+    assert_sync::<DecryptionParams>();
+
+    assert_send::<Identity>();
+    assert_sync::<Identity>();
+    assert_static::<Identity>();
+
+    assert_send::<HandshakeError>();
+    assert_sync::<HandshakeError>();
+    assert_static::<HandshakeError>();
+}
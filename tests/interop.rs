@@ -0,0 +1,120 @@
+// Runs handshakes against the reference node.js implementation of
+// secret-handshake, to catch divergence from the rest of the ecosystem.
+//
+// These tests are ignored by default, as they need node.js and the
+// dependencies in `interop/`. Run them with:
+//
+// ```sh
+// (cd interop && npm install)
+// cargo test --test interop -- --ignored
+// ```
+//
+// The node executable can be overridden via the `SHS_NODE` environment
+// variable.
+
+extern crate futures;
+extern crate secret_handshake;
+extern crate sodiumoxide;
+
+mod support;
+
+use std::env;
+use std::process::Command;
+
+use futures::executor::block_on;
+use sodiumoxide::crypto::{box_, sign};
+
+use secret_handshake::{ClientHandshaker, ServerHandshaker};
+
+use support::{hex, ChildStream};
+
+static NETWORK_IDENTIFIER: [u8; 32] = [0xd4, 0xa1, 0xcb, 0x88, 0xa6, 0x6f, 0x02, 0xf8, 0xdb,
+                                       0x63, 0x5c, 0xe2, 0x64, 0x41, 0xcc, 0x5d, 0xac, 0x1b,
+                                       0x08, 0x42, 0x0c, 0xea, 0xac, 0x23, 0x08, 0x39, 0xb7,
+                                       0x55, 0x84, 0x5a, 0x9f, 0xfb];
+
+fn node() -> Command {
+    let node = env::var("SHS_NODE").unwrap_or_else(|_| "node".to_string());
+    let mut command = Command::new(node);
+    command.arg(concat!(env!("CARGO_MANIFEST_DIR"), "/interop/shs-stdio.js"));
+    command
+}
+
+#[test]
+#[ignore]
+fn rust_client_js_server() {
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+
+    let stream = ChildStream::spawn(node()
+                                        .arg("server")
+                                        .arg(hex(&NETWORK_IDENTIFIER))
+                                        .arg(hex(&server_sk.0)))
+            .unwrap();
+
+    let client = ClientHandshaker::new(stream,
+                                       &NETWORK_IDENTIFIER,
+                                       &client_pk,
+                                       &client_sk,
+                                       &client_eph_pk,
+                                       &client_eph_sk,
+                                       &server_pk);
+    let (outcome, stream) = block_on(client).ok().unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), server_pk);
+
+    assert!(stream.wait().unwrap().success());
+}
+
+#[test]
+#[ignore]
+fn js_client_rust_server() {
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+
+    let stream = ChildStream::spawn(node()
+                                        .arg("client")
+                                        .arg(hex(&NETWORK_IDENTIFIER))
+                                        .arg(hex(&client_sk.0))
+                                        .arg(hex(&server_pk.0)))
+            .unwrap();
+
+    let server = ServerHandshaker::new(stream,
+                                       &NETWORK_IDENTIFIER,
+                                       &server_pk,
+                                       &server_sk,
+                                       &server_eph_pk,
+                                       &server_eph_sk);
+    let (outcome, stream) = block_on(server).ok().unwrap();
+    assert_eq!(outcome.peer_longterm_pk(), client_pk);
+
+    assert!(stream.wait().unwrap().success());
+}
+
+#[test]
+#[ignore]
+fn js_server_rejects_wrong_network() {
+    let (client_pk, client_sk) = sign::gen_keypair();
+    let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+    let (server_pk, server_sk) = sign::gen_keypair();
+    let other_network = [0u8; 32];
+
+    let stream = ChildStream::spawn(node()
+                                        .arg("server")
+                                        .arg(hex(&other_network))
+                                        .arg(hex(&server_sk.0)))
+            .unwrap();
+
+    let client = ClientHandshaker::new(stream,
+                                       &NETWORK_IDENTIFIER,
+                                       &client_pk,
+                                       &client_sk,
+                                       &client_eph_pk,
+                                       &client_eph_sk,
+                                       &server_pk);
+    match block_on(client) {
+        Ok(_) => panic!("handshake succeeded across networks"),
+        Err((_, stream)) => assert!(!stream.wait().unwrap().success()),
+    }
+}
@@ -0,0 +1,79 @@
+// Plumbing for running handshakes against other implementations in child
+// processes.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+
+use futures::Async::Ready;
+use futures::Poll;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::Context;
+
+// A child process, with its stdout as the readable and its stdin as the
+// writable half of a duplex stream.
+//
+// Reads and writes block the current thread, which is fine for driving a
+// single handshaker via `block_on`.
+pub struct ChildStream {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl ChildStream {
+    // Spawns the command with piped stdin and stdout. Stderr is inherited, so
+    // that diagnostics of the child show up in the test output.
+    pub fn spawn(command: &mut Command) -> io::Result<ChildStream> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+
+        Ok(ChildStream {
+               child,
+               stdin,
+               stdout,
+           })
+    }
+
+    // Closes stdin of the child and waits for it to exit.
+    pub fn wait(mut self) -> io::Result<ExitStatus> {
+        self.stdin.take();
+        self.child.wait()
+    }
+}
+
+impl AsyncRead for ChildStream {
+    fn poll_read(&mut self, _cx: &mut Context, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        Ok(Ready(self.stdout.read(buf)?))
+    }
+}
+
+impl AsyncWrite for ChildStream {
+    fn poll_write(&mut self, _cx: &mut Context, buf: &[u8]) -> Poll<usize, io::Error> {
+        match self.stdin {
+            Some(ref mut stdin) => Ok(Ready(stdin.write(buf)?)),
+            None => Ok(Ready(0)),
+        }
+    }
+
+    fn poll_flush(&mut self, _cx: &mut Context) -> Poll<(), io::Error> {
+        match self.stdin {
+            Some(ref mut stdin) => Ok(Ready(stdin.flush()?)),
+            None => Ok(Ready(())),
+        }
+    }
+
+    fn poll_close(&mut self, _cx: &mut Context) -> Poll<(), io::Error> {
+        self.stdin.take();
+        Ok(Ready(()))
+    }
+}
+
+// Hex encodes the given bytes, for passing keys as arguments.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,176 @@
+// Runs all test vectors in `test-vectors/shs1.json` against both the
+// low-level crypto calls and the async handshakers. Failures name the index
+// of the vector. See the `source` field of the file for where the vectors
+// come from.
+
+extern crate async_ringbuffer;
+extern crate atm_io_utils;
+extern crate futures;
+extern crate secret_handshake;
+extern crate serde_json;
+extern crate sodiumoxide;
+
+use async_ringbuffer::ring_buffer;
+use atm_io_utils::Duplex;
+use futures::prelude::*;
+use futures::executor::block_on;
+use serde_json::Value;
+use sodiumoxide::crypto::{box_, sign};
+
+use secret_handshake::{ClientHandshaker, ServerHandshaker, NETWORK_IDENTIFIER_BYTES};
+use secret_handshake::crypto::{Client, Server};
+use secret_handshake::messages::{ClientHello, ServerHello, ClientAuth, ServerAck};
+
+static VECTORS: &'static str = include_str!("../test-vectors/shs1.json");
+
+struct Vector {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    client_longterm_pk: sign::PublicKey,
+    client_longterm_sk: sign::SecretKey,
+    client_ephemeral_pk: box_::PublicKey,
+    client_ephemeral_sk: box_::SecretKey,
+    server_longterm_pk: sign::PublicKey,
+    server_longterm_sk: sign::SecretKey,
+    server_ephemeral_pk: box_::PublicKey,
+    server_ephemeral_sk: box_::SecretKey,
+    msg1: ClientHello,
+    msg2: ServerHello,
+    msg3: ClientAuth,
+    msg4: ServerAck,
+    client_encryption_key: Vec<u8>,
+    client_encryption_nonce: Vec<u8>,
+    client_decryption_key: Vec<u8>,
+    client_decryption_nonce: Vec<u8>,
+}
+
+fn hex(vector: &Value, field: &str) -> Vec<u8> {
+    let s = vector[field].as_str().expect(field);
+    (0..s.len() / 2)
+        .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).expect(field))
+        .collect()
+}
+
+fn vectors() -> Vec<Vector> {
+    let json: Value = serde_json::from_str(VECTORS).unwrap();
+    let vectors = json["vectors"].as_array().unwrap();
+    assert!(!vectors.is_empty(), "no test vectors");
+
+    vectors
+        .iter()
+        .map(|v| {
+            let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+            network_identifier.copy_from_slice(&hex(v, "network_identifier"));
+
+            Vector {
+                network_identifier,
+                client_longterm_pk: sign::PublicKey::from_slice(&hex(v, "client_longterm_pk"))
+                    .unwrap(),
+                client_longterm_sk: sign::SecretKey::from_slice(&hex(v, "client_longterm_sk"))
+                    .unwrap(),
+                client_ephemeral_pk: box_::PublicKey::from_slice(&hex(v, "client_ephemeral_pk"))
+                    .unwrap(),
+                client_ephemeral_sk: box_::SecretKey::from_slice(&hex(v, "client_ephemeral_sk"))
+                    .unwrap(),
+                server_longterm_pk: sign::PublicKey::from_slice(&hex(v, "server_longterm_pk"))
+                    .unwrap(),
+                server_longterm_sk: sign::SecretKey::from_slice(&hex(v, "server_longterm_sk"))
+                    .unwrap(),
+                server_ephemeral_pk: box_::PublicKey::from_slice(&hex(v, "server_ephemeral_pk"))
+                    .unwrap(),
+                server_ephemeral_sk: box_::SecretKey::from_slice(&hex(v, "server_ephemeral_sk"))
+                    .unwrap(),
+                msg1: ClientHello::from_slice(&hex(v, "msg1")).unwrap(),
+                msg2: ServerHello::from_slice(&hex(v, "msg2")).unwrap(),
+                msg3: ClientAuth::from_slice(&hex(v, "msg3")).unwrap(),
+                msg4: ServerAck::from_slice(&hex(v, "msg4")).unwrap(),
+                client_encryption_key: hex(v, "client_encryption_key"),
+                client_encryption_nonce: hex(v, "client_encryption_nonce"),
+                client_decryption_key: hex(v, "client_decryption_key"),
+                client_decryption_nonce: hex(v, "client_decryption_nonce"),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn crypto_vectors() {
+    for (i, v) in vectors().into_iter().enumerate() {
+        let mut client = Client::new(&v.network_identifier,
+                                     &v.client_longterm_pk.0,
+                                     &v.client_longterm_sk.0,
+                                     &v.client_ephemeral_pk.0,
+                                     &v.client_ephemeral_sk.0,
+                                     &v.server_longterm_pk.0);
+        let mut server = Server::new(&v.network_identifier,
+                                     &v.server_longterm_pk.0,
+                                     &v.server_longterm_sk.0,
+                                     &v.server_ephemeral_pk.0,
+                                     &v.server_ephemeral_sk.0);
+
+        assert_eq!(client.client_hello(), v.msg1, "vector {}", i);
+        assert!(server.verify_client_hello(&v.msg1), "vector {}", i);
+        assert_eq!(server.server_hello(), v.msg2, "vector {}", i);
+        assert!(client.verify_server_hello(&v.msg2), "vector {}", i);
+        assert_eq!(client.client_auth(), v.msg3, "vector {}", i);
+        assert!(server.verify_client_auth(&v.msg3), "vector {}", i);
+        assert_eq!(server.server_ack(), v.msg4, "vector {}", i);
+        assert!(client.verify_server_ack(&v.msg4), "vector {}", i);
+    }
+}
+
+#[test]
+fn handshaker_vectors() {
+    for (i, v) in vectors().into_iter().enumerate() {
+        let (writer_a, reader_a) = ring_buffer(2);
+        let (writer_b, reader_b) = ring_buffer(2);
+        let client_duplex = Duplex::new(reader_a, writer_b);
+        let server_duplex = Duplex::new(reader_b, writer_a);
+
+        let client = ClientHandshaker::new(client_duplex,
+                                           &v.network_identifier,
+                                           &v.client_longterm_pk,
+                                           &v.client_longterm_sk,
+                                           &v.client_ephemeral_pk,
+                                           &v.client_ephemeral_sk,
+                                           &v.server_longterm_pk);
+        let server = ServerHandshaker::new(server_duplex,
+                                           &v.network_identifier,
+                                           &v.server_longterm_pk,
+                                           &v.server_longterm_sk,
+                                           &v.server_ephemeral_pk,
+                                           &v.server_ephemeral_sk);
+
+        let ((client_outcome, _), (server_outcome, _)) = block_on(client.join(server))
+            .ok()
+            .expect(&format!("vector {}", i));
+
+        assert_eq!(&client_outcome.send_params().key.0[..],
+                   &v.client_encryption_key[..],
+                   "vector {}",
+                   i);
+        assert_eq!(&client_outcome.send_params().nonce.0[..],
+                   &v.client_encryption_nonce[..],
+                   "vector {}",
+                   i);
+        assert_eq!(&client_outcome.recv_params().key.0[..],
+                   &v.client_decryption_key[..],
+                   "vector {}",
+                   i);
+        assert_eq!(&client_outcome.recv_params().nonce.0[..],
+                   &v.client_decryption_nonce[..],
+                   "vector {}",
+                   i);
+        assert_eq!(server_outcome.send_params().key.0,
+                   client_outcome.recv_params().key.0,
+                   "vector {}",
+                   i);
+        assert_eq!(server_outcome.peer_longterm_pk(),
+                   v.client_longterm_pk,
+                   "vector {}",
+                   i);
+        assert_eq!(client_outcome.peer_longterm_pk(),
+                   v.server_longterm_pk,
+                   "vector {}",
+                   i);
+    }
+}